@@ -0,0 +1,8 @@
+ALTER TABLE pages ADD _status INT CHECK (_status IN (1, 2, 3, 4)) DEFAULT 1;
+UPDATE pages SET _status = status;
+DROP INDEX page_status_depth;
+DROP INDEX page_type_status;
+ALTER TABLE pages DROP status;
+ALTER TABLE pages RENAME _status TO status;
+CREATE INDEX page_status_depth ON pages (status, depth);
+CREATE INDEX page_type_status ON pages (type, status);
@@ -0,0 +1,4 @@
+ALTER TABLE pages ADD http_status INT NULL;
+ALTER TABLE pages ADD final_url TEXT NULL;
+ALTER TABLE pages ADD content_type TEXT NULL;
+ALTER TABLE pages ADD headers TEXT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE pages ADD request TEXT NULL;
+DROP INDEX page_url;
+CREATE UNIQUE INDEX page_url ON pages (url, IFNULL(request, ''));
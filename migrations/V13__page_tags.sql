@@ -0,0 +1,6 @@
+CREATE TABLE page_tags (
+  page_id INTEGER NOT NULL,
+  tag TEXT NOT NULL,
+  PRIMARY KEY (page_id, tag)
+);
+CREATE INDEX page_tags_tag ON page_tags (tag);
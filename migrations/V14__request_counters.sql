@@ -0,0 +1,7 @@
+CREATE TABLE request_counters (
+  host TEXT NOT NULL,
+  window TEXT NOT NULL,
+  window_start INTEGER NOT NULL,
+  count INTEGER NOT NULL,
+  PRIMARY KEY (host, window)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE pages ADD lease_owner TEXT NULL;
+ALTER TABLE pages ADD lease_expires_at INTEGER NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE pages ADD redirects TEXT NULL;
+CREATE INDEX pages_final_url ON pages(final_url);
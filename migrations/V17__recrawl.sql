@@ -0,0 +1 @@
+CREATE INDEX page_type_status_downloaded_at ON pages (type, status, downloaded_at);
@@ -0,0 +1,4 @@
+ALTER TABLE pages ADD content_hash TEXT NULL;
+ALTER TABLE pages ADD changed_at INTEGER NULL;
+UPDATE pages SET changed_at = downloaded_at WHERE downloaded_at IS NOT NULL;
+CREATE INDEX page_changed_at ON pages (changed_at);
@@ -0,0 +1 @@
+ALTER TABLE pages ADD truncated INTEGER NOT NULL DEFAULT 0;
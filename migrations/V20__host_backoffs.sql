@@ -0,0 +1,6 @@
+CREATE TABLE host_backoffs (
+  host TEXT NOT NULL PRIMARY KEY,
+  delay_ms INTEGER NOT NULL,
+  rate_limited_at INTEGER NOT NULL,
+  retry_at INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE pages ADD ttfb_ms INTEGER NULL;
+ALTER TABLE pages ADD total_ms INTEGER NULL;
+ALTER TABLE pages ADD body_bytes INTEGER NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE pages ADD failure_reason INTEGER NULL;
+ALTER TABLE pages ADD failure_message TEXT NULL;
+ALTER TABLE pages ADD failed_at INTEGER NULL;
+CREATE INDEX page_failure_reason ON pages (failure_reason);
@@ -0,0 +1,3 @@
+ALTER TABLE pages ADD simhash INTEGER NULL;
+ALTER TABLE pages ADD duplicate_of INTEGER NULL;
+CREATE INDEX page_content_hash ON pages (content_hash);
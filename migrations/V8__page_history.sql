@@ -0,0 +1,8 @@
+ALTER TABLE pages ADD downloaded_at INT NULL;
+CREATE TABLE page_history (
+  page_id INTEGER NOT NULL REFERENCES pages (id),
+  downloaded_at INT NULL,
+  content BLOB NOT NULL,
+  compressed INT NOT NULL
+);
+CREATE INDEX page_history_page_id ON page_history (page_id, downloaded_at);
@@ -0,0 +1,4 @@
+ALTER TABLE pages ADD url_hash INT NULL;
+CREATE INDEX page_url_hash ON pages (url_hash);
+CREATE INDEX page_status_depth ON pages (status, depth);
+CREATE INDEX page_type_status ON pages (type, status);
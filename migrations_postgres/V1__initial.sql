@@ -0,0 +1,70 @@
+-- Tables are created only if missing, so databases created before migrations were introduced are
+-- taken over as they are. Mirrors SQLite schema (see `migrations`), except all integers are
+-- `BIGINT` and content is always compressed.
+CREATE TABLE IF NOT EXISTS pages (
+    id BIGSERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    url_hash BIGINT NOT NULL,
+    type BIGINT NOT NULL,
+    depth BIGINT NOT NULL,
+    status BIGINT NOT NULL,
+    skip_reason BIGINT,
+    request TEXT,
+    content BYTEA,
+    downloaded_at BIGINT,
+    http_status BIGINT,
+    final_url TEXT,
+    content_type TEXT,
+    headers TEXT,
+    redirects TEXT,
+    truncated BOOLEAN NOT NULL DEFAULT FALSE,
+    ttfb_ms BIGINT,
+    total_ms BIGINT,
+    body_bytes BIGINT,
+    failure_reason BIGINT,
+    failure_message TEXT,
+    failed_at BIGINT,
+    content_hash TEXT,
+    changed_at BIGINT,
+    simhash BIGINT,
+    duplicate_of BIGINT,
+    lease_owner TEXT,
+    lease_expires_at BIGINT
+);
+CREATE UNIQUE INDEX IF NOT EXISTS page_url ON pages (url, COALESCE(request, ''));
+CREATE INDEX IF NOT EXISTS page_url_hash ON pages (url_hash);
+CREATE INDEX IF NOT EXISTS page_status_depth ON pages (status, depth);
+CREATE INDEX IF NOT EXISTS page_type_status_downloaded_at ON pages (type, status, downloaded_at);
+CREATE INDEX IF NOT EXISTS pages_final_url ON pages (final_url);
+CREATE INDEX IF NOT EXISTS page_changed_at ON pages (changed_at);
+CREATE INDEX IF NOT EXISTS page_content_hash ON pages (content_hash);
+
+CREATE TABLE IF NOT EXISTS page_history (
+    id BIGSERIAL PRIMARY KEY,
+    page_id BIGINT NOT NULL REFERENCES pages (id),
+    downloaded_at BIGINT,
+    content BYTEA NOT NULL
+);
+CREATE INDEX IF NOT EXISTS page_history_page_id ON page_history (page_id, downloaded_at);
+
+CREATE TABLE IF NOT EXISTS page_tags (
+    page_id BIGINT NOT NULL,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (page_id, tag)
+);
+CREATE INDEX IF NOT EXISTS page_tags_tag ON page_tags (tag);
+
+CREATE TABLE IF NOT EXISTS request_counters (
+    host TEXT NOT NULL,
+    "window" TEXT NOT NULL,
+    window_start BIGINT NOT NULL,
+    count BIGINT NOT NULL,
+    PRIMARY KEY (host, "window")
+);
+
+CREATE TABLE IF NOT EXISTS host_backoffs (
+    host TEXT NOT NULL PRIMARY KEY,
+    delay_ms BIGINT NOT NULL,
+    rate_limited_at BIGINT NOT NULL,
+    retry_at BIGINT NOT NULL
+);
@@ -0,0 +1,428 @@
+//! Notifications about failed checks of unattended crawls
+//!
+//! ```toml
+//! [alerts]
+//! max_error_rate = 0.05
+//!
+//! [[alerts.channels]]
+//! type = "slack"
+//! webhook_url = "keyring:slack-webhook"
+//!
+//! [[alerts.channels]]
+//! type = "webhook"
+//! url = "https://example.com/hooks/crab"
+//!
+//! [[alerts.channels]]
+//! type = "email"
+//! smtp_host = "localhost"
+//! from = "crab@example.com"
+//! to = ["ops@example.com"]
+//! ```
+//!
+//! Alert is sent when data contracts (see [`crate::contracts`]) are violated or the share of pages
+//! failed to download is above `max_error_rate`. Webhook receives a JSON object with `subject` and
+//! `problems` fields, Slack incoming webhook – a text message. URLs can reference secrets (see
+//! [`crate::secrets`]).
+//!
+//! Email is sent over SMTP, plain by default (for a relay running on the same host or in the same
+//! private network, eg. postfix or msmtpd), with STARTTLS (`smtp_tls = "starttls"`) or over TLS
+//! (`smtp_tls = "tls"`). If `smtp_user` is given, crab authenticates with `smtp_password` which
+//! can reference a secret as well.
+use crate::{crawler::CrawlerState, prelude::*, secrets};
+use anyhow::Context;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Tls, TlsParameters},
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+/// Timeout of a single SMTP command
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AlertsConfig {
+    /// maximum share of failed pages among downloaded ones, eg. `0.05` (not checked by default)
+    pub(crate) max_error_rate: Option<f64>,
+    /// where alerts are sent to
+    #[serde(default)]
+    pub(crate) channels: Vec<ChannelConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChannelConfig {
+    Webhook {
+        url: String,
+    },
+    Slack {
+        webhook_url: String,
+    },
+    Email {
+        smtp_host: String,
+        /// 25 by default, 587 with STARTTLS and 465 with TLS
+        smtp_port: Option<u16>,
+        /// encryption of SMTP connection (none by default)
+        #[serde(default)]
+        smtp_tls: SmtpTls,
+        /// user name to authenticate with (no authentication by default)
+        smtp_user: Option<String>,
+        /// password of `smtp_user`, can reference a secret
+        smtp_password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// plain connection
+    #[default]
+    None,
+    /// plain connection upgraded to TLS, failing if the server doesn't support it
+    Starttls,
+    /// TLS from the start
+    Tls,
+}
+
+impl SmtpTls {
+    fn default_port(self) -> u16 {
+        match self {
+            SmtpTls::None => 25,
+            SmtpTls::Starttls => 587,
+            SmtpTls::Tls => 465,
+        }
+    }
+}
+
+/// Failed checks of a crawl
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub subject: String,
+    pub problems: Vec<String>,
+}
+
+impl AlertsConfig {
+    /// See [`crate::CrabConfig::redact_secrets()`]
+    ///
+    /// Webhook URLs are redacted entirely, because they are often credentials themselves.
+    pub(crate) fn redact_secrets(&mut self) {
+        for channel in &mut self.channels {
+            match channel {
+                ChannelConfig::Webhook { url } => secrets::redact(url),
+                ChannelConfig::Slack { webhook_url } => secrets::redact(webhook_url),
+                ChannelConfig::Email { smtp_password, .. } => {
+                    if let Some(password) = smtp_password {
+                        secrets::redact(password);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a problem if too many pages failed to download
+    pub fn check_error_rate(&self, state: &CrawlerState) -> Option<String> {
+        let max_error_rate = self.max_error_rate?;
+        let total = state.successfull_requests + state.failed_pages;
+        if total == 0 {
+            return None;
+        }
+        let error_rate = state.failed_pages as f64 / total as f64;
+        (error_rate > max_error_rate).then(|| {
+            format!(
+                "Error rate {:.1}% is above {:.1}% ({} of {} pages failed)",
+                error_rate * 100.,
+                max_error_rate * 100.,
+                state.failed_pages,
+                total
+            )
+        })
+    }
+
+    /// Sends an alert to all channels
+    ///
+    /// A channel failing doesn't prevent others from getting the alert, failures are logged.
+    pub async fn send(&self, alert: &Alert) {
+        for channel in &self.channels {
+            if let Err(e) = channel.send(alert).await {
+                error!("Sending alert failed: {:?}", e);
+            }
+        }
+    }
+}
+
+impl ChannelConfig {
+    async fn send(&self, alert: &Alert) -> Result<()> {
+        match self {
+            ChannelConfig::Webhook { url } => {
+                let payload = json!({"subject": alert.subject, "problems": alert.problems});
+                post_json(url, &payload).await
+            }
+            ChannelConfig::Slack { webhook_url } => {
+                let payload = json!({"text": text(alert, "• ")});
+                post_json(webhook_url, &payload).await
+            }
+            ChannelConfig::Email {
+                smtp_host,
+                smtp_port,
+                smtp_tls,
+                smtp_user,
+                smtp_password,
+                from,
+                to,
+            } => {
+                let message = email(from, to, alert)?;
+                let tls = TlsParameters::new(smtp_host.clone())?;
+                let transport =
+                    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(smtp_host.as_str())
+                        .port(smtp_port.unwrap_or(smtp_tls.default_port()))
+                        .timeout(Some(SMTP_TIMEOUT))
+                        .tls(match smtp_tls {
+                            SmtpTls::None => Tls::None,
+                            SmtpTls::Starttls => Tls::Required(tls),
+                            SmtpTls::Tls => Tls::Wrapper(tls),
+                        });
+                let transport = match smtp_user {
+                    Some(user) => {
+                        let password = smtp_password.as_deref().unwrap_or_default();
+                        let password = secrets::resolve(password)?;
+                        transport.credentials(Credentials::new(user.clone(), password))
+                    }
+                    None => transport,
+                };
+                transport
+                    .build()
+                    .send(message)
+                    .await
+                    .context(AppError::SendingEmail(smtp_host.clone()))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn post_json(url: &str, payload: &serde_json::Value) -> Result<()> {
+    Client::new()
+        .post(secrets::resolve(url)?)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn text(alert: &Alert, bullet: &str) -> String {
+    let mut text = alert.subject.clone();
+    for problem in &alert.problems {
+        text.push('\n');
+        text.push_str(bullet);
+        text.push_str(problem);
+    }
+    text
+}
+
+/// Builds email message, non-ASCII subject and body are encoded by lettre
+fn email(from: &str, to: &[String], alert: &Alert) -> Result<Message> {
+    let mut message = Message::builder()
+        .from(mailbox(from)?)
+        .subject(&alert.subject)
+        .header(ContentType::TEXT_PLAIN);
+    for to in to {
+        message = message.to(mailbox(to)?);
+    }
+    Ok(message.body(text(alert, "- "))?)
+}
+
+fn mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .with_context(|| AppError::InvalidEmailAddress(address.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Server,
+    };
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+    use tokio::sync::mpsc;
+    use url::Url;
+
+    #[test]
+    fn error_rate_threshold() {
+        let config = AlertsConfig {
+            max_error_rate: Some(0.1),
+            channels: vec![],
+        };
+        let state = |successfull_requests, failed_pages| CrawlerState {
+            successfull_requests,
+            failed_pages,
+            ..CrawlerState::default()
+        };
+        assert_eq!(config.check_error_rate(&state(0, 0)), None);
+        assert_eq!(config.check_error_rate(&state(90, 10)), None);
+        assert_eq!(
+            config.check_error_rate(&state(80, 20)).as_deref(),
+            Some("Error rate 20.0% is above 10.0% (20 of 100 pages failed)")
+        );
+    }
+
+    fn alert() -> Alert {
+        Alert {
+            subject: "Checks failed".into(),
+            problems: vec!["Error rate is too high".into()],
+        }
+    }
+
+    /// Starts HTTP server passing bodies of requests received to a channel
+    fn webhook_server() -> Result<(Url, mpsc::UnboundedReceiver<serde_json::Value>)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |request: hyper::Request<Body>| {
+                    let tx = tx.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(request.into_body()).await?;
+                        let _ = tx.send(serde_json::from_slice(&body).unwrap_or_default());
+                        Ok::<_, hyper::Error>(hyper::Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse()?).serve(make_service);
+        let url = Url::parse(&format!("http://{}/hook", server.local_addr()))?;
+        tokio::spawn(server);
+        Ok((url, rx))
+    }
+
+    #[tokio::test]
+    async fn send_webhook_alert() -> Result<()> {
+        let (url, mut requests) = webhook_server()?;
+        let channel = ChannelConfig::Webhook { url: url.into() };
+        channel.send(&alert()).await?;
+
+        let payload = requests.recv().await.unwrap();
+        assert_eq!(
+            payload,
+            json!({"subject": "Checks failed", "problems": ["Error rate is too high"]})
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_slack_alert() -> Result<()> {
+        let (url, mut requests) = webhook_server()?;
+        let channel = ChannelConfig::Slack {
+            webhook_url: url.into(),
+        };
+        channel.send(&alert()).await?;
+
+        let payload = requests.recv().await.unwrap();
+        assert_eq!(
+            payload,
+            json!({"text": "Checks failed\n• Error rate is too high"})
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_webhook_is_reported() -> Result<()> {
+        let channel = ChannelConfig::Webhook {
+            url: "env:CRAB_TEST_MISSING_WEBHOOK_URL".into(),
+        };
+        assert!(channel.send(&alert()).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn email_subject_is_encoded() -> Result<()> {
+        let alert = Alert {
+            subject: "Проверки не пройдены".into(),
+            problems: vec![],
+        };
+        let message = email("crab@example.com", &["ops@example.com".into()], &alert)?;
+        let message = String::from_utf8(message.formatted())?;
+        assert!(message.contains("Subject: =?utf-8?b?"), "{}", message);
+        assert!(email("crab", &[], &alert).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_email_alert() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let server = thread::spawn(move || -> Result<Vec<String>> {
+            let (stream, _) = listener.accept()?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut writer = stream;
+            let (mut received, mut data) = (vec![], false);
+            writer.write_all(b"220 localhost ESMTP\r\n")?;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                let reply: &[u8] = match line.as_str() {
+                    "." => {
+                        data = false;
+                        b"250 queued\r\n"
+                    }
+                    _ if data => b"",
+                    _ if line.starts_with("EHLO ") => {
+                        b"250-localhost\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME\r\n"
+                    }
+                    _ if line.starts_with("AUTH ") => b"235 authenticated\r\n",
+                    "DATA" => {
+                        data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                writer.write_all(reply)?;
+                let done = line == "QUIT";
+                received.push(line);
+                if done {
+                    break;
+                }
+            }
+            Ok(received)
+        });
+
+        let channel = ChannelConfig::Email {
+            smtp_host: "127.0.0.1".into(),
+            smtp_port: Some(port),
+            smtp_tls: SmtpTls::None,
+            smtp_user: Some("crab".into()),
+            smtp_password: Some("secret".into()),
+            from: "crab@example.com".into(),
+            to: vec!["ops@example.com".into()],
+        };
+        channel.send(&alert()).await?;
+
+        let received = server.join().unwrap()?;
+        // PLAIN credentials are `\0user\0password` in base64
+        assert!(received.contains(&"AUTH PLAIN AGNyYWIAc2VjcmV0".to_string()));
+        assert!(received.contains(&"MAIL FROM:<crab@example.com>".to_string()));
+        assert!(received.contains(&"RCPT TO:<ops@example.com>".to_string()));
+        assert!(received.contains(&"Subject: Checks failed".to_string()));
+        assert!(received.contains(&"- Error rate is too high".to_string()));
+        assert_eq!(received.last().map(String::as_str), Some("QUIT"));
+        Ok(())
+    }
+}
@@ -0,0 +1,647 @@
+//! Per-domain HTTP authentication
+use crate::{
+    prelude::*,
+    secrets,
+    signing::{AwsSigV4Signer, HmacSigner, RequestSigner},
+};
+use anyhow::Context;
+use reqwest::{header::HeaderMap, Client, Identity, Request, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use url::Url;
+
+/// Authentication settings for a domain
+///
+/// Secrets can be given either inline or as a name of an environment variable, so they do not
+/// need to be committed alongside `crab.toml`. Inline secrets can also reference an environment
+/// variable or the system keyring (see [`crate::secrets`]):
+///
+/// ```toml
+/// [auth."staging.example.com"]
+/// type = "basic"
+/// username = "crab"
+/// password_env = "STAGING_PASSWORD"
+///
+/// [auth."api.example.com"]
+/// type = "bearer"
+/// token_env = "API_TOKEN"
+///
+/// [auth."data.example.com"]
+/// type = "oauth2"
+/// token_url = "https://auth.example.com/oauth/token"
+/// client_id = "crab"
+/// client_secret_env = "CLIENT_SECRET"
+///
+/// [auth."signed.example.com"]
+/// type = "hmac"
+/// key_env = "HMAC_KEY"
+///
+/// [auth."bucket.s3.amazonaws.com"]
+/// type = "aws_sigv4"
+/// access_key_id = "AKIDEXAMPLE"
+/// secret_access_key_env = "AWS_SECRET_ACCESS_KEY"
+/// region = "us-east-1"
+/// service = "s3"
+/// ```
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AuthConfig {
+    Basic {
+        username: String,
+        password: Option<String>,
+        password_env: Option<String>,
+    },
+    Bearer {
+        token: Option<String>,
+        token_env: Option<String>,
+    },
+    /// OAuth 2.0 client credentials grant
+    OAuth2 {
+        token_url: String,
+        client_id: String,
+        client_secret: Option<String>,
+        client_secret_env: Option<String>,
+        scope: Option<String>,
+    },
+    /// HMAC-SHA256 request signature (see [`HmacSigner`])
+    Hmac {
+        key: Option<String>,
+        key_env: Option<String>,
+        #[serde(default = "default_signature_header")]
+        signature_header: String,
+        #[serde(default = "default_timestamp_header")]
+        timestamp_header: String,
+    },
+    /// AWS Signature Version 4
+    #[serde(rename = "aws_sigv4")]
+    AwsSigV4 {
+        access_key_id: String,
+        secret_access_key: Option<String>,
+        secret_access_key_env: Option<String>,
+        session_token_env: Option<String>,
+        region: String,
+        service: String,
+    },
+}
+
+impl AuthConfig {
+    /// Replaces secrets given in the config itself with a placeholder, so the config can be shown
+    ///
+    /// Names of environment variables secrets are read from and references to secrets are kept.
+    pub(crate) fn redact_secrets(&mut self) {
+        let secret = match self {
+            AuthConfig::Basic { password, .. } => password,
+            AuthConfig::Bearer { token, .. } => token,
+            AuthConfig::OAuth2 { client_secret, .. } => client_secret,
+            AuthConfig::Hmac { key, .. } => key,
+            AuthConfig::AwsSigV4 {
+                secret_access_key, ..
+            } => secret_access_key,
+        };
+        if let Some(secret) = secret {
+            secrets::redact(secret);
+        }
+    }
+}
+
+/// Client certificate presented to servers requiring mutual TLS
+///
+/// Either a PKCS#12 archive (optionally protected with a password) or a PEM certificate chain
+/// along with a PKCS#8 PEM private key:
+///
+/// ```toml
+/// [crawler.client_identity]
+/// pkcs12 = "client.p12"
+/// password_env = "CLIENT_CERT_PASSWORD"
+/// ```
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ClientIdentityConfig {
+    Pkcs12 {
+        pkcs12: PathBuf,
+        password: Option<String>,
+        password_env: Option<String>,
+    },
+    Pem {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+}
+
+impl ClientIdentityConfig {
+    /// Reads certificate and private key files
+    pub(crate) fn load(&self) -> Result<Identity> {
+        let identity = match self {
+            ClientIdentityConfig::Pkcs12 {
+                pkcs12,
+                password,
+                password_env,
+            } => {
+                let password = resolve_secret(password, password_env)?.unwrap_or_default();
+                let der =
+                    fs::read(pkcs12).context(AppError::ReadingClientIdentity(pkcs12.clone()))?;
+                Identity::from_pkcs12_der(&der, &password)
+                    .context(AppError::ReadingClientIdentity(pkcs12.clone()))?
+            }
+            ClientIdentityConfig::Pem { cert, key } => {
+                let cert_pem =
+                    fs::read(cert).context(AppError::ReadingClientIdentity(cert.clone()))?;
+                let key_pem =
+                    fs::read(key).context(AppError::ReadingClientIdentity(key.clone()))?;
+                Identity::from_pkcs8_pem(&cert_pem, &key_pem)
+                    .context(AppError::ReadingClientIdentity(cert.clone()))?
+            }
+        };
+        Ok(identity)
+    }
+
+    /// See [`AuthConfig::redact_secrets()`]
+    pub(crate) fn redact_secrets(&mut self) {
+        if let ClientIdentityConfig::Pkcs12 {
+            password: Some(password),
+            ..
+        } = self
+        {
+            secrets::redact(password);
+        }
+    }
+}
+
+fn default_signature_header() -> String {
+    "X-Signature".into()
+}
+
+fn default_timestamp_header() -> String {
+    "X-Timestamp".into()
+}
+
+#[derive(Clone, Debug)]
+enum Credentials {
+    Basic(String, Option<String>),
+    Bearer(String),
+    OAuth2(Arc<TokenManager>),
+    Signed(Arc<dyn RequestSigner>),
+    /// Headers of a session established by logging in (see [`crate::login`])
+    Session(HeaderMap),
+}
+
+/// Obtains OAuth 2.0 access tokens and refreshes them when expired
+#[derive(Debug)]
+struct TokenManager {
+    client: Client,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    token: Mutex<Option<(String, Option<Instant>)>>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Token is refreshed a bit earlier than it expires to account for network delays
+const TOKEN_EXPIRATION_MARGIN: Duration = Duration::from_secs(30);
+
+impl TokenManager {
+    /// Returns valid access token, requesting new one if needed
+    async fn token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((token, expires_at)) = token.as_ref() {
+            if expires_at.is_none_or(|t| Instant::now() < t) {
+                return Ok(token.clone());
+            }
+        }
+        self.obtain(&mut token).await
+    }
+
+    /// Returns a token other than the one rejected by the server
+    ///
+    /// Requests rejected at the same time refresh the token once: if the token is already
+    /// refreshed by another request, the new token is returned as is.
+    async fn refresh(&self, rejected: &str) -> Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some((token, _)) = token.as_ref().filter(|(t, _)| t != rejected) {
+            return Ok(token.clone());
+        }
+        self.obtain(&mut token).await
+    }
+
+    /// Requests new token from the token endpoint and stores it
+    async fn obtain(&self, token: &mut Option<(String, Option<Instant>)>) -> Result<String> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+        ];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let response: TokenResponse = self
+            .client
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context(AppError::ObtainingToken(self.token_url.clone()))?;
+        let expires_at = response.expires_in.map(|sec| {
+            Instant::now() + Duration::from_secs(sec).saturating_sub(TOKEN_EXPIRATION_MARGIN)
+        });
+        *token = Some((response.access_token.clone(), expires_at));
+        Ok(response.access_token)
+    }
+}
+
+/// Credentials resolved for each configured domain
+///
+/// Domain rule matches the domain itself as well as all its subdomains. The most specific rule wins.
+#[derive(Clone, Default, Debug)]
+pub struct AuthRules(Vec<(String, Credentials)>);
+
+impl AuthRules {
+    pub fn new(config: &HashMap<String, AuthConfig>) -> Result<Self> {
+        let mut rules = vec![];
+        for (domain, auth) in config {
+            let credentials = match auth {
+                AuthConfig::Basic {
+                    username,
+                    password,
+                    password_env,
+                } => {
+                    let password = resolve_secret(password, password_env)?;
+                    Credentials::Basic(username.clone(), password)
+                }
+                AuthConfig::Bearer { token, token_env } => {
+                    let token = resolve_secret(token, token_env)?
+                        .ok_or(AppError::MissingSecret(domain.clone()))?;
+                    Credentials::Bearer(token)
+                }
+                AuthConfig::OAuth2 {
+                    token_url,
+                    client_id,
+                    client_secret,
+                    client_secret_env,
+                    scope,
+                } => {
+                    let client_secret = resolve_secret(client_secret, client_secret_env)?
+                        .ok_or(AppError::MissingSecret(domain.clone()))?;
+                    Credentials::OAuth2(Arc::new(TokenManager {
+                        client: Client::new(),
+                        token_url: token_url.clone(),
+                        client_id: client_id.clone(),
+                        client_secret,
+                        scope: scope.clone(),
+                        token: Mutex::new(None),
+                    }))
+                }
+                AuthConfig::Hmac {
+                    key,
+                    key_env,
+                    signature_header,
+                    timestamp_header,
+                } => {
+                    let key = resolve_secret(key, key_env)?
+                        .ok_or(AppError::MissingSecret(domain.clone()))?;
+                    Credentials::Signed(Arc::new(HmacSigner {
+                        key,
+                        signature_header: signature_header.clone(),
+                        timestamp_header: timestamp_header.clone(),
+                    }))
+                }
+                AuthConfig::AwsSigV4 {
+                    access_key_id,
+                    secret_access_key,
+                    secret_access_key_env,
+                    session_token_env,
+                    region,
+                    service,
+                } => {
+                    let secret_access_key =
+                        resolve_secret(secret_access_key, secret_access_key_env)?
+                            .ok_or(AppError::MissingSecret(domain.clone()))?;
+                    Credentials::Signed(Arc::new(AwsSigV4Signer {
+                        access_key_id: access_key_id.clone(),
+                        secret_access_key,
+                        session_token: resolve_secret(&None, session_token_env)?,
+                        region: region.clone(),
+                        service: service.clone(),
+                    }))
+                }
+            };
+            rules.push((domain.to_lowercase(), credentials));
+        }
+        let mut rules = Self(rules);
+        rules.sort();
+        Ok(rules)
+    }
+
+    /// Registers custom request signer for a domain
+    pub fn with_signer(mut self, domain: &str, signer: impl RequestSigner + 'static) -> Self {
+        let credentials = Credentials::Signed(Arc::new(signer));
+        let domain = domain.to_lowercase();
+        self.0.retain(|(d, _)| *d != domain);
+        self.0.push((domain, credentials));
+        self.sort();
+        self
+    }
+
+    /// Registers session headers (eg. cookies) sent with all requests to a domain
+    pub fn with_session(mut self, domain: &str, headers: HeaderMap) -> Self {
+        let domain = domain.to_lowercase();
+        self.0.retain(|(d, _)| *d != domain);
+        self.0.push((domain, Credentials::Session(headers)));
+        self.sort();
+        self
+    }
+
+    /// Longest domains first, so most specific rule is found first
+    fn sort(&mut self) {
+        self.0
+            .sort_by_key(|(domain, _)| std::cmp::Reverse(domain.len()));
+    }
+
+    fn find(&self, url: &Url) -> Option<&Credentials> {
+        let host = url.host_str()?.to_lowercase();
+        self.0
+            .iter()
+            .find(|(domain, _)| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .map(|(_, credentials)| credentials)
+    }
+
+    /// Adds authentication headers to a request if there is a rule for the URL domain
+    async fn apply(&self, url: &Url, request: RequestBuilder) -> Result<RequestBuilder> {
+        Ok(match self.find(url) {
+            Some(Credentials::Basic(username, password)) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Some(Credentials::Bearer(token)) => request.bearer_auth(token),
+            Some(Credentials::OAuth2(tokens)) => request.bearer_auth(tokens.token().await?),
+            Some(Credentials::Session(headers)) => request.headers(headers.clone()),
+            Some(Credentials::Signed(_)) | None => request,
+        })
+    }
+
+    /// Builds a request authenticated (and signed) according to the rules without sending it
+    pub async fn prepare(&self, url: &Url, request: RequestBuilder) -> Result<(Client, Request)> {
+        let (client, request) = self.apply(url, request).await?.build_split();
+        let mut request = request?;
+        if let Some(Credentials::Signed(signer)) = self.find(url) {
+            signer.sign(&mut request)?;
+        }
+        Ok((client, request))
+    }
+
+    async fn execute(&self, url: &Url, request: RequestBuilder) -> Result<Response> {
+        let (client, request) = self.prepare(url, request).await?;
+        Ok(client.execute(request).await?)
+    }
+
+    /// Sends a request authenticated (and signed) according to the rules
+    ///
+    /// If OAuth 2.0 protected resource responds with `401 Unauthorized`, token is refreshed and
+    /// request is repeated once.
+    pub async fn send(&self, url: &Url, request: RequestBuilder) -> Result<Response> {
+        let Some(Credentials::OAuth2(tokens)) = self.find(url) else {
+            return self.execute(url, request).await;
+        };
+        let retry = request.try_clone();
+        let token = tokens.token().await?;
+        let response = request.bearer_auth(&token).send().await?;
+        match retry {
+            Some(retry) if response.status() == StatusCode::UNAUTHORIZED => {
+                debug!("Access token rejected, refreshing: {}", url);
+                let token = tokens.refresh(&token).await?;
+                Ok(retry.bearer_auth(token).send().await?)
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+fn resolve_secret(value: &Option<String>, env_name: &Option<String>) -> Result<Option<String>> {
+    match (value, env_name) {
+        (Some(value), _) => Ok(Some(secrets::resolve(value)?)),
+        (None, Some(name)) => Ok(Some(
+            env::var(name).context(AppError::MissingEnvVariable(name.clone()))?,
+        )),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_most_specific_rule() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Config {
+            auth: HashMap<String, AuthConfig>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [auth."example.com"]
+            type = "basic"
+            username = "crab"
+            password = "secret"
+
+            [auth."api.example.com"]
+            type = "bearer"
+            token = "token"
+            "#,
+        )?;
+        let rules = AuthRules::new(&config.auth)?;
+
+        let find = |url| rules.find(&Url::parse(url).unwrap());
+        fn is_basic(c: Option<&Credentials>) -> bool {
+            matches!(c, Some(Credentials::Basic(u, Some(p))) if u == "crab" && p == "secret")
+        }
+        fn is_bearer(c: Option<&Credentials>) -> bool {
+            matches!(c, Some(Credentials::Bearer(t)) if t == "token")
+        }
+        assert!(is_basic(find("http://example.com/")));
+        assert!(is_basic(find("http://www.example.com/")));
+        assert!(is_bearer(find("http://v1.api.example.com/")));
+        assert!(find("http://notexample.com/").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn custom_signer_replaces_configured_rule() -> Result<()> {
+        #[derive(Debug)]
+        struct NoopSigner;
+        impl RequestSigner for NoopSigner {
+            fn sign(&self, _: &mut Request) -> Result<()> {
+                Ok(())
+            }
+        }
+        let config = HashMap::from([(
+            "api.example.com".to_string(),
+            AuthConfig::Bearer {
+                token: Some("token".into()),
+                token_env: None,
+            },
+        )]);
+        let rules = AuthRules::new(&config)?.with_signer("API.example.com", NoopSigner);
+
+        let url = Url::parse("http://api.example.com/")?;
+        assert!(matches!(rules.find(&url), Some(Credentials::Signed(_))));
+        assert_eq!(rules.0.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn session_replaces_configured_rule() -> Result<()> {
+        let config = HashMap::from([(
+            "example.com".to_string(),
+            AuthConfig::Bearer {
+                token: Some("token".into()),
+                token_env: None,
+            },
+        )]);
+        let rules = AuthRules::new(&config)?.with_session("Example.COM", HeaderMap::new());
+
+        let url = Url::parse("http://example.com/")?;
+        assert!(matches!(rules.find(&url), Some(Credentials::Session(_))));
+        assert_eq!(rules.0.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn client_identity_config() -> Result<()> {
+        let pkcs12: ClientIdentityConfig = toml::from_str(
+            r#"
+            pkcs12 = "client.p12"
+            password_env = "CLIENT_CERT_PASSWORD"
+            "#,
+        )?;
+        assert!(matches!(
+            pkcs12,
+            ClientIdentityConfig::Pkcs12 { password: None, .. }
+        ));
+
+        let pem: ClientIdentityConfig = toml::from_str(
+            r#"
+            cert = "not-existing.pem"
+            key = "not-existing.key"
+            "#,
+        )?;
+        let error = pem.load().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AppError>(),
+            Some(AppError::ReadingClientIdentity(path)) if path.ends_with("not-existing.pem")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn redact_inline_secrets() -> Result<()> {
+        let mut basic: AuthConfig = toml::from_str(
+            r#"
+            type = "basic"
+            username = "crab"
+            password = "secret"
+            "#,
+        )?;
+        basic.redact_secrets();
+        assert!(
+            matches!(basic, AuthConfig::Basic { username, password: Some(p), .. } if username == "crab" && p == secrets::REDACTED)
+        );
+
+        let mut bearer: AuthConfig = toml::from_str(
+            r#"
+            type = "bearer"
+            token_env = "API_TOKEN"
+            "#,
+        )?;
+        bearer.redact_secrets();
+        assert!(
+            matches!(bearer, AuthConfig::Bearer { token: None, token_env: Some(e) } if e == "API_TOKEN")
+        );
+
+        let mut keyring: AuthConfig = toml::from_str(
+            r#"
+            type = "bearer"
+            token = "keyring:api-token"
+            "#,
+        )?;
+        keyring.redact_secrets();
+        assert!(
+            matches!(keyring, AuthConfig::Bearer { token: Some(t), .. } if t == "keyring:api-token")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrently_rejected_token_is_refreshed_once() -> Result<()> {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Server,
+        };
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        // The first token issued is already revoked
+        static ISSUED: AtomicU32 = AtomicU32::new(0);
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|request: hyper::Request<Body>| async move {
+                let response = match request.uri().path() {
+                    "/token" => {
+                        let n = ISSUED.fetch_add(1, Ordering::SeqCst) + 1;
+                        let body = format!(r#"{{"access_token": "t{n}"}}"#);
+                        hyper::Response::new(Body::from(body))
+                    }
+                    _ => {
+                        let authorization = request.headers().get("authorization");
+                        let valid = authorization.is_some_and(|a| a != "Bearer t1");
+                        let status = if valid { 200 } else { 401 };
+                        hyper::Response::builder()
+                            .status(status)
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                };
+                Ok::<_, hyper::Error>(response)
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse()?).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+
+        let config = HashMap::from([(
+            "127.0.0.1".to_string(),
+            AuthConfig::OAuth2 {
+                token_url: format!("http://{}/token", address),
+                client_id: "crab".into(),
+                client_secret: Some("secret".into()),
+                client_secret_env: None,
+                scope: None,
+            },
+        )]);
+        let rules = AuthRules::new(&config)?;
+        let url = Url::parse(&format!("http://{}/api", address))?;
+        let client = Client::new();
+        let requests = (0..5).map(|_| rules.send(&url, client.get(url.clone())));
+        for response in futures::future::join_all(requests).await {
+            assert_eq!(response?.status(), StatusCode::OK);
+        }
+        assert_eq!(ISSUED.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+//! Interactive browser of stored pages and their parsing results
+use crab::{
+    html,
+    prelude::*,
+    storage::{SqliteStorage, Storage},
+    Page, PageParsers,
+};
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::{collections::HashSet, fmt::Write, io, time::Duration};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::Text,
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
+    Frame, Terminal,
+};
+
+const HELP: &str =
+    "↑/↓ select page  PgUp/PgDn scroll  r mark for reset  t add tag  d remove tags  q quit";
+
+/// Number of lines content is scrolled by on PgUp/PgDn
+const SCROLL_STEP: u16 = 10;
+
+/// Page currently shown in the content panels
+#[derive(Default)]
+struct PageView {
+    text: String,
+    parsed: String,
+    tags: Vec<String>,
+    scroll: u16,
+}
+
+struct App {
+    pages: Vec<Page>,
+    list: ListState,
+    view: PageView,
+    /// pages reset when browsing is finished
+    marked_for_reset: HashSet<i64>,
+    /// text of a tag being entered, `None` if not in tag input mode
+    tag_input: Option<String>,
+}
+
+impl App {
+    fn selected(&self) -> Option<&Page> {
+        self.list.selected().and_then(|idx| self.pages.get(idx))
+    }
+
+    fn select(&mut self, offset: isize) {
+        if self.pages.is_empty() {
+            return;
+        }
+        let idx = self.list.selected().unwrap_or(0) as isize + offset;
+        let idx = idx.clamp(0, self.pages.len() as isize - 1);
+        self.list.select(Some(idx as usize));
+    }
+}
+
+/// Runs browsing UI, pages marked for reset are reset after UI is closed
+///
+/// Returns the number of reset pages.
+pub(crate) async fn browse(storage: &SqliteStorage, parsers: &PageParsers) -> Result<usize> {
+    let pages = storage.list_pages().await?;
+    let mut app = App {
+        list: ListState::default(),
+        pages,
+        view: PageView::default(),
+        marked_for_reset: HashSet::new(),
+        tag_input: None,
+    };
+    app.select(0);
+
+    let backend = CrosstermBackend::new(io::stdout());
+    let mut terminal = Terminal::new(backend)?;
+
+    // initialize terminal
+    enable_raw_mode()?;
+    execute!(terminal.backend_mut(), EnterAlternateScreen)?;
+
+    let res = run(&mut terminal, &mut app, storage, parsers).await;
+
+    // restore terminal
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    res?;
+    for page_id in &app.marked_for_reset {
+        storage.reset_page(*page_id).await?;
+    }
+    Ok(app.marked_for_reset.len())
+}
+
+async fn run<B: Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut App,
+    storage: &SqliteStorage,
+    parsers: &PageParsers,
+) -> Result<()> {
+    let mut shown_page = None;
+    loop {
+        let selected = app.selected().cloned();
+        if selected.as_ref().map(|p| p.id) != shown_page {
+            app.view = match &selected {
+                Some(page) => load_view(page, storage, parsers).await?,
+                None => PageView::default(),
+            };
+            shown_page = selected.map(|p| p.id);
+        }
+        terminal.draw(|f| draw_widgets(f, app))?;
+
+        if !event::poll(Duration::from_millis(250))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if let Some(tag) = &mut app.tag_input {
+            match key.code {
+                KeyCode::Char(c) => tag.push(c),
+                KeyCode::Backspace => {
+                    tag.pop();
+                }
+                KeyCode::Enter => {
+                    let tag = app.tag_input.take().unwrap_or_default();
+                    if let (Some(page_id), false) = (shown_page, tag.trim().is_empty()) {
+                        storage.tag_page(page_id, tag.trim()).await?;
+                        app.view.tags = storage.list_page_tags(page_id).await?;
+                    }
+                }
+                KeyCode::Esc => app.tag_input = None,
+                _ => {}
+            }
+            continue;
+        }
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => app.select(-1),
+            KeyCode::Down | KeyCode::Char('j') => app.select(1),
+            KeyCode::PageUp => app.view.scroll = app.view.scroll.saturating_sub(SCROLL_STEP),
+            KeyCode::PageDown => app.view.scroll = app.view.scroll.saturating_add(SCROLL_STEP),
+            KeyCode::Char('r') => {
+                if let Some(page_id) = shown_page {
+                    if !app.marked_for_reset.remove(&page_id) {
+                        app.marked_for_reset.insert(page_id);
+                    }
+                }
+            }
+            KeyCode::Char('t') => app.tag_input = Some(String::new()),
+            KeyCode::Char('d') => {
+                if let Some(page_id) = shown_page {
+                    storage.untag_page(page_id).await?;
+                    app.view.tags.clear();
+                }
+            }
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Reads page content, converts it to text and runs parsing rules on it
+///
+/// Errors of parsing rules are shown in place of parsing results.
+async fn load_view(
+    page: &Page,
+    storage: &SqliteStorage,
+    parsers: &PageParsers,
+) -> Result<PageView> {
+    let tags = storage.list_page_tags(page.id).await?;
+    let Some((content, _)) = storage.read_page_content(page.id).await? else {
+        return Ok(PageView {
+            text: format!("Page is {}", page.status),
+            tags,
+            ..PageView::default()
+        });
+    };
+    let text = html::to_text(&content).unwrap_or(content.clone());
+    let parsed = match parsers.parse(page, &content) {
+        Ok(tables) => format_tables(crab::into_owned_tables(tables.unwrap_or_default())),
+        Err(e) => format!("{:?}", e),
+    };
+    Ok(PageView {
+        text,
+        parsed,
+        tags,
+        scroll: 0,
+    })
+}
+
+fn format_tables(tables: crab::ParsedTables) -> String {
+    let mut out = String::new();
+    let mut tables = tables.into_iter().collect::<Vec<_>>();
+    tables.sort_by(|a, b| a.0.cmp(&b.0));
+    for (table_name, table) in tables {
+        let _ = writeln!(out, "{table_name}\n------------------------");
+        for row in table {
+            let mut row = row.into_iter().collect::<Vec<_>>();
+            row.sort();
+            for (idx, (column, value)) in row.into_iter().enumerate() {
+                let prefix = if idx == 0 { "-" } else { " " };
+                let _ = writeln!(out, "{} {}: {}", prefix, column, value);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn draw_widgets(f: &mut Frame<impl Backend>, app: &mut App) {
+    let layout = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(3), Constraint::Length(1)].as_ref())
+        .split(f.size());
+    let panels = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(
+            [
+                Constraint::Percentage(30),
+                Constraint::Percentage(40),
+                Constraint::Percentage(30),
+            ]
+            .as_ref(),
+        )
+        .split(layout[0]);
+
+    let items = app
+        .pages
+        .iter()
+        .map(|page| {
+            let mark = if app.marked_for_reset.contains(&page.id) {
+                "R"
+            } else {
+                " "
+            };
+            ListItem::new(format!("{} {:>6} {}", mark, page.id, page.url))
+        })
+        .collect::<Vec<_>>();
+    let list = List::new(items)
+        .block(create_block("Pages".to_string()))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(list, panels[0], &mut app.list);
+
+    let title = match app.selected() {
+        Some(page) if app.view.tags.is_empty() => format!("#{} {}", page.id, page.status),
+        Some(page) => format!(
+            "#{} {} [{}]",
+            page.id,
+            page.status,
+            app.view.tags.join(", ")
+        ),
+        None => "No pages".to_string(),
+    };
+    let content = Paragraph::new(Text::raw(app.view.text.as_str()))
+        .block(create_block(title))
+        .wrap(Wrap { trim: false })
+        .scroll((app.view.scroll, 0));
+    f.render_widget(content, panels[1]);
+
+    let parsed = Paragraph::new(Text::raw(app.view.parsed.as_str()))
+        .block(create_block("Parsed".to_string()))
+        .wrap(Wrap { trim: false });
+    f.render_widget(parsed, panels[2]);
+
+    let status = match &app.tag_input {
+        Some(tag) => {
+            Paragraph::new(format!("Tag: {}_", tag)).style(Style::default().fg(Color::Yellow))
+        }
+        None => Paragraph::new(HELP),
+    };
+    f.render_widget(status, layout[1]);
+}
+
+fn create_block(title: String) -> Block<'static> {
+    Block::default().borders(Borders::ALL).title(title)
+}
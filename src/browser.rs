@@ -0,0 +1,149 @@
+//! Fetching pages rendered by a headless browser
+//!
+//! Pages of types marked with `render = true` are loaded in headless Chrome/Chromium and the
+//! DOM after scripts are executed is stored as page content:
+//!
+//! ```toml
+//! [crawler]
+//! browser = "/usr/bin/chromium"
+//!
+//! [crawler.page_types.2]
+//! render = true
+//! ```
+//!
+//! Browser is started on the first rendered page and controlled over DevTools protocol, each page
+//! is loaded in a separate tab. Browser process is killed when crawler stops. Proxies,
+//! authentication and custom requests are not applied to rendered pages.
+use crate::{prelude::*, storage::ResponseMeta, CrawlerConfig};
+use anyhow::Context;
+use chromiumoxide::{
+    cdp::browser_protocol::network::SetUserAgentOverrideParams, Browser, BrowserConfig,
+};
+use futures::StreamExt;
+use std::{env, path::PathBuf, process, time::Duration};
+use tokio::{sync::OnceCell, time::timeout};
+use url::Url;
+
+/// Browser executable used if none is given in config
+const DEFAULT_BROWSER: &str = "chromium";
+
+/// Time given to a page to load if `read_timeout_sec` is not set
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Renderer {
+    executable: PathBuf,
+    user_agent: Option<String>,
+    /// time browser is given to start and each page is given to load
+    timeout: Duration,
+    /// run browser with `--no-sandbox`
+    no_sandbox: bool,
+    browser: OnceCell<Browser>,
+}
+
+impl Renderer {
+    pub fn new(opts: &CrawlerConfig, user_agent: Option<String>) -> Self {
+        Self {
+            executable: opts
+                .browser
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_BROWSER)),
+            user_agent,
+            timeout: opts
+                .read_timeout_sec
+                .map(Duration::from_secs_f32)
+                .unwrap_or(DEFAULT_TIMEOUT),
+            no_sandbox: opts.browser_no_sandbox,
+            browser: OnceCell::new(),
+        }
+    }
+
+    /// Loads page in a headless browser and returns rendered DOM
+    pub async fn render(&self, url: &Url) -> Result<(String, ResponseMeta)> {
+        let browser = self.browser.get_or_try_init(|| self.launch()).await?;
+        let page = browser
+            .new_page("about:blank")
+            .await
+            .with_context(|| AppError::RenderingPage(url.clone(), "opening tab".into()))?;
+        let load = async {
+            if let Some(user_agent) = &self.user_agent {
+                page.set_user_agent(SetUserAgentOverrideParams::new(user_agent.clone()))
+                    .await?;
+            }
+            page.goto(url.as_str()).await?;
+            let final_url = page.url().await?;
+            let content = page.content().await?;
+            Ok::<_, chromiumoxide::error::CdpError>((content, final_url))
+        };
+        let result = timeout(self.timeout, load).await;
+        // tab is closed in any case, so pages timed out do not keep loading in the background
+        let _ = page.close().await;
+        let (content, final_url) = result
+            .map_err(|_| AppError::RenderingPage(url.clone(), "timed out".into()))?
+            .map_err(|e| AppError::RenderingPage(url.clone(), e.to_string()))?;
+        let meta = ResponseMeta {
+            // Browser doesn't report response status, so only loaded pages get here
+            status: 200,
+            final_url: final_url
+                .and_then(|u| Url::parse(&u).ok())
+                .unwrap_or_else(|| url.clone()),
+            content_type: Some("text/html".to_string()),
+            headers: vec![],
+            redirects: vec![],
+            truncated: false,
+            timing: None,
+        };
+        Ok((content, meta))
+    }
+
+    async fn launch(&self) -> Result<Browser> {
+        let (browser, mut handler) = Browser::launch(self.config()?)
+            .await
+            .with_context(|| AppError::RunningBrowser(self.executable.clone()))?;
+        tokio::spawn(async move { while handler.next().await.is_some() {} });
+        Ok(browser)
+    }
+
+    fn config(&self) -> Result<BrowserConfig> {
+        let mut config = BrowserConfig::builder()
+            .chrome_executable(&self.executable)
+            .launch_timeout(self.timeout)
+            .request_timeout(self.timeout)
+            .respect_https_errors()
+            // profile is not shared with other crawler processes
+            .user_data_dir(env::temp_dir().join(format!("crab-browser-{}", process::id())));
+        if self.no_sandbox {
+            config = config.no_sandbox();
+        }
+        config
+            .build()
+            .map_err(anyhow::Error::msg)
+            .with_context(|| AppError::RunningBrowser(self.executable.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, time::Instant};
+
+    #[tokio::test]
+    async fn browser_not_responding_is_killed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let executable = dir.path().join("chromium");
+        fs::write(&executable, "#!/bin/sh\nsleep 30\n")?;
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755))?;
+
+        let renderer = Renderer {
+            executable,
+            user_agent: None,
+            timeout: Duration::from_millis(500),
+            no_sandbox: false,
+            browser: OnceCell::new(),
+        };
+        let started = Instant::now();
+        let result = renderer.render(&Url::parse("http://test.com/app")?).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
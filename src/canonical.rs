@@ -0,0 +1,101 @@
+//! Canonical form of URLs registered for downloading
+//!
+//! Same page tends to be linked under trivially different URLs, so links are normalized before
+//! being registered: fragment is removed, host is lowercased and query parameters are sorted by
+//! name. Tracking parameters can be removed as well (trailing `*` matches any suffix):
+//!
+//! ```toml
+//! [crawler]
+//! strip_query_params = ["utm_*", "fbclid"]
+//! ```
+use crate::CrawlerConfig;
+use url::Url;
+
+#[derive(Debug, Default)]
+pub struct UrlNormalizer {
+    strip_params: Vec<String>,
+}
+
+impl UrlNormalizer {
+    pub fn new(opts: &CrawlerConfig) -> Self {
+        Self {
+            strip_params: opts.strip_query_params.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn normalize(&self, mut url: Url) -> Url {
+        url.set_fragment(None);
+        if let Some(host) = url.host_str().filter(|h| h.chars().any(char::is_uppercase)) {
+            let host = host.to_lowercase();
+            // Can only fail for hosts which are invalid anyway
+            let _ = url.set_host(Some(&host));
+        }
+        if let Some(query) = url.query() {
+            // Parameters are kept encoded as they are, so the server gets exactly the same values
+            let mut params = query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .filter(|param| !self.is_stripped(param.split('=').next().unwrap_or_default()))
+                .map(str::to_string)
+                .collect::<Vec<_>>();
+            // Stable sort, so order of repeated parameters is preserved
+            params.sort_by(|a, b| a.split('=').next().cmp(&b.split('=').next()));
+            let query = params.join("&");
+            url.set_query(Some(query.as_str()).filter(|q| !q.is_empty()));
+        }
+        url
+    }
+
+    fn is_stripped(&self, name: &str) -> bool {
+        self.strip_params
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(normalizer: &UrlNormalizer, url: &str) -> String {
+        normalizer.normalize(Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn normalize_urls() {
+        let normalizer = UrlNormalizer::default();
+        assert_eq!(
+            normalize(&normalizer, "https://Example.COM/Path?b=2&a=1&b=1#top"),
+            "https://example.com/Path?a=1&b=2&b=1"
+        );
+        assert_eq!(
+            normalize(&normalizer, "https://example.com/?q=a%20b+c&&"),
+            "https://example.com/?q=a%20b+c"
+        );
+        assert_eq!(
+            normalize(&normalizer, "https://example.com/?"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn strip_tracking_params() {
+        let normalizer = UrlNormalizer {
+            strip_params: vec!["utm_*".to_string(), "fbclid".to_string()],
+        };
+        assert_eq!(
+            normalize(
+                &normalizer,
+                "https://example.com/item?utm_source=x&id=5&fbclid=abc&utm_medium=y&fbclid2=1"
+            ),
+            "https://example.com/item?fbclid2=1&id=5"
+        );
+        assert_eq!(
+            normalize(&normalizer, "https://example.com/?utm_source=x"),
+            "https://example.com/"
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Detecting anti-bot challenge pages and solving them
+//!
+//! ```toml
+//! [crawler.challenges]
+//! command = ["python3", "solve.py"]
+//! ```
+//!
+//! Challenge pages of Cloudflare and PerimeterX are told apart from validation failures, so they are
+//! counted separately and recorded with the `challenge` failure reason (see `crab failures`).
+//! Such pages are retried as other failures are.
+//!
+//! Solver is an external `command` or a `python` module with `solve(challenge)` function. It is
+//! given the challenge as a JSON object (on stdin for a command):
+//!
+//! ```json
+//! {"provider": "cloudflare", "url": "https://example.com/", "final_url": "https://example.com/",
+//!  "status": 403, "headers": {"server": "cloudflare"}, "content": "<html>..."}
+//! ```
+//!
+//! and returns the cookies and headers (eg. `User-Agent` clearance cookie is bound to) sent with all
+//! the following requests to the host of the page, or `null` if it can not solve the challenge:
+//!
+//! ```json
+//! {"cookies": {"cf_clearance": "..."}, "headers": {"User-Agent": "..."}}
+//! ```
+//!
+//! Solver is called for one challenge at a time. Challenges of the requests made before the host
+//! is solved are not passed to the solver again.
+use crate::{login, prelude::*, python, storage::ResponseMeta, CrawlerConfig, Page};
+use anyhow::Context;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{ErrorKind, Write},
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::Instant,
+};
+use tokio::task::spawn_blocking;
+use url::Url;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ChallengeConfig {
+    /// program and its arguments reading a challenge on stdin and writing a solution to stdout
+    pub(crate) command: Option<Vec<String>>,
+
+    /// python module with `solve(challenge)` function used instead of a command
+    pub(crate) python: Option<String>,
+}
+
+/// Anti-bot service a challenge page is served by
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Cloudflare,
+    PerimeterX,
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_value = match self {
+            Provider::Cloudflare => "cloudflare",
+            Provider::PerimeterX => "perimeterx",
+        };
+        f.pad(display_value)
+    }
+}
+
+/// Markers of Cloudflare challenge page, Cloudflare injects its scripts in regular pages as well,
+/// so they are only looked for in error responses
+const CLOUDFLARE_MARKERS: &[&str] = &[
+    "<title>Just a moment...</title>",
+    "window._cf_chl_opt",
+    "/cdn-cgi/challenge-platform/h/",
+    "cf-browser-verification",
+];
+
+/// Markers of PerimeterX block page ("Press & Hold" captcha)
+const PERIMETERX_MARKERS: &[&str] = &["id=\"px-captcha\"", "captcha.px-cdn.net", "_pxCaptcha"];
+
+/// Returns the provider of a challenge if the response is a challenge page
+pub fn detect(meta: &ResponseMeta, content: &str) -> Option<Provider> {
+    if meta
+        .header("cf-mitigated")
+        .is_some_and(|value| value.eq_ignore_ascii_case("challenge"))
+    {
+        return Some(Provider::Cloudflare);
+    }
+    let status = StatusCode::from_u16(meta.status).ok()?;
+    if !status.is_client_error() && !status.is_server_error() {
+        return None;
+    }
+    let contains_any = |markers: &[&str]| markers.iter().any(|m| content.contains(m));
+    if contains_any(CLOUDFLARE_MARKERS) {
+        Some(Provider::Cloudflare)
+    } else if contains_any(PERIMETERX_MARKERS) {
+        Some(Provider::PerimeterX)
+    } else {
+        None
+    }
+}
+
+/// Challenge page as it is given to the solver
+#[derive(Debug, Clone, Serialize)]
+pub struct Challenge {
+    pub provider: Provider,
+    pub url: String,
+    pub final_url: String,
+    pub status: u16,
+    pub headers: BTreeMap<String, String>,
+    pub content: String,
+}
+
+impl Challenge {
+    pub fn new(provider: Provider, page: &Page, meta: &ResponseMeta, content: &str) -> Self {
+        Self {
+            provider,
+            url: page.url.to_string(),
+            final_url: meta.final_url.to_string(),
+            status: meta.status,
+            headers: meta.headers.iter().cloned().collect(),
+            content: content.to_owned(),
+        }
+    }
+}
+
+/// Cookies and headers returned by the solver
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Solution {
+    #[serde(default)]
+    pub cookies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Solution {
+    /// Adds solution headers to request headers, cookies are appended to the ones already there
+    fn apply(&self, headers: &mut HeaderMap) -> Result<()> {
+        for (name, value) in &self.headers {
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if self.cookies.is_empty() {
+            return Ok(());
+        }
+        let cookies = self
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>();
+        login::append_cookies(headers, &cookies.join("; "))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Hook {
+    Command(Vec<String>),
+    Python(String),
+}
+
+impl Hook {
+    fn name(&self) -> String {
+        match self {
+            Hook::Command(command) => command.join(" "),
+            Hook::Python(module) => module.clone(),
+        }
+    }
+
+    /// Runs the solver, blocks until it's finished
+    fn run(&self, challenge: &Challenge) -> Result<Option<Solution>> {
+        match self {
+            Hook::Python(module) => python::solve_challenge(module, challenge),
+            Hook::Command(command) => {
+                let mut child = Command::new(&command[0])
+                    .args(&command[1..])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    // Solver is not required to read the challenge
+                    match stdin.write_all(&serde_json::to_vec(challenge)?) {
+                        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+                let output = child.wait_with_output()?;
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+                    let message = format!("{} ({})", output.status, stderr);
+                    return Err(AppError::ChallengeSolverFailed(message).into());
+                }
+                let stdout = String::from_utf8(output.stdout)?;
+                match stdout.trim() {
+                    "" => Ok(None),
+                    solution => Ok(serde_json::from_str(solution)?),
+                }
+            }
+        }
+    }
+}
+
+/// Solver of challenges and solutions it returned for each host
+pub struct Solver {
+    hook: Hook,
+    /// host → the time solution is received and the solution
+    solutions: Mutex<HashMap<String, (Instant, Solution)>>,
+    /// held while the solver is running, so a host is solved once
+    running: tokio::sync::Mutex<()>,
+}
+
+impl Solver {
+    /// Returns `None` if no solver is configured
+    pub fn new(opts: &CrawlerConfig) -> Result<Option<Self>> {
+        let Some(config) = &opts.challenges else {
+            return Ok(None);
+        };
+        let hook = match (&config.command, &config.python) {
+            (Some(command), None) if !command.is_empty() => Hook::Command(command.clone()),
+            (None, Some(module)) => Hook::Python(module.clone()),
+            _ => {
+                let message = "exactly one of `command` or `python` must be given";
+                return Err(AppError::InvalidChallengeSolver(message.into()).into());
+            }
+        };
+        Ok(Some(Self::with_hook(hook)))
+    }
+
+    fn with_hook(hook: Hook) -> Self {
+        Self {
+            hook,
+            solutions: Mutex::new(HashMap::new()),
+            running: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Solves a challenge of a request made at `requested_at`, returns `true` if it is solved
+    ///
+    /// If the host has been solved after the request is made, the solver is not run again.
+    pub async fn solve(&self, challenge: &Challenge, requested_at: Instant) -> Result<bool> {
+        let _running = self.running.lock().await;
+        let host = Url::parse(&challenge.url)?
+            .host_str()
+            .unwrap_or_default()
+            .to_lowercase();
+        if let Some((solved_at, _)) = self.solutions.lock().unwrap().get(&host) {
+            if *solved_at > requested_at {
+                return Ok(true);
+            }
+        }
+        let (hook, challenge) = (self.hook.clone(), challenge.clone());
+        let name = hook.name();
+        let solution = spawn_blocking(move || hook.run(&challenge))
+            .await?
+            .context(AppError::RunningChallengeSolver(name))?;
+        let Some(solution) = solution else {
+            return Ok(false);
+        };
+        // Checking solution is valid before it is used for requests
+        solution.apply(&mut HeaderMap::new())?;
+        let mut solutions = self.solutions.lock().unwrap();
+        solutions.insert(host, (Instant::now(), solution));
+        Ok(true)
+    }
+
+    /// Adds cookies and headers of the solution for the host of a URL to request headers
+    pub fn apply(&self, url: &Url, headers: &mut HeaderMap) -> Result<()> {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        match self.solutions.lock().unwrap().get(&host) {
+            Some((_, solution)) => solution.apply(headers),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::COOKIE;
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> ResponseMeta {
+        ResponseMeta {
+            status,
+            final_url: Url::parse("https://example.com/").unwrap(),
+            content_type: Some("text/html".into()),
+            headers: headers
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            redirects: vec![],
+            truncated: false,
+            timing: None,
+        }
+    }
+
+    fn challenge() -> Challenge {
+        let page = Page::new(1, Url::parse("https://Example.com/item").unwrap(), 1);
+        let content = "<title>Just a moment...</title>";
+        let meta = response(403, &[("server", "cloudflare")]);
+        Challenge::new(Provider::Cloudflare, &page, &meta, content)
+    }
+
+    fn solve(hook: Hook) -> Result<(bool, HeaderMap)> {
+        let solver = Solver::with_hook(hook);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let solved = runtime.block_on(solver.solve(&challenge(), Instant::now()))?;
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("session=1"));
+        solver.apply(
+            &Url::parse("https://example.com/other").unwrap(),
+            &mut headers,
+        )?;
+        Ok((solved, headers))
+    }
+
+    #[test]
+    fn detect_cloudflare() {
+        let meta = response(200, &[("cf-mitigated", "challenge")]);
+        assert_eq!(detect(&meta, ""), Some(Provider::Cloudflare));
+
+        let content = "<html><head><title>Just a moment...</title></head></html>";
+        let meta = response(403, &[("server", "cloudflare")]);
+        assert_eq!(detect(&meta, content), Some(Provider::Cloudflare));
+
+        let content = r#"<script>window._cf_chl_opt={cvId: '3'}</script>"#;
+        assert_eq!(
+            detect(&response(503, &[]), content),
+            Some(Provider::Cloudflare)
+        );
+    }
+
+    #[test]
+    fn detect_perimeterx() {
+        let content =
+            r#"<div id="px-captcha"></div><script src="//captcha.px-cdn.net/x"></script>"#;
+        assert_eq!(
+            detect(&response(403, &[]), content),
+            Some(Provider::PerimeterX)
+        );
+    }
+
+    #[test]
+    fn regular_pages_are_not_challenges() {
+        let content =
+            r#"<script src="/cdn-cgi/challenge-platform/h/b/scripts/jsd/main.js"></script>"#;
+        assert_eq!(detect(&response(200, &[]), content), None);
+        assert_eq!(detect(&response(403, &[]), "Forbidden"), None);
+        assert_eq!(
+            detect(&response(503, &[("server", "cloudflare")]), ""),
+            None
+        );
+    }
+
+    #[test]
+    fn command_solver() -> Result<()> {
+        let script = r#"grep -q '"provider":"cloudflare"' && echo '{"cookies": {"cf_clearance": "abc"}, "headers": {"User-Agent": "solver"}}'"#;
+        let hook = Hook::Command(vec!["sh".into(), "-c".into(), script.into()]);
+        let (solved, headers) = solve(hook)?;
+        assert!(solved);
+        assert_eq!(headers[COOKIE], "session=1; cf_clearance=abc");
+        assert_eq!(headers["user-agent"], "solver");
+        Ok(())
+    }
+
+    #[test]
+    fn unsolved_challenge() -> Result<()> {
+        let hook = Hook::Command(vec!["sh".into(), "-c".into(), "echo null".into()]);
+        let (solved, headers) = solve(hook)?;
+        assert!(!solved);
+        assert_eq!(headers[COOKIE], "session=1");
+
+        let hook = Hook::Command(vec!["sh".into(), "-c".into(), "exit 1".into()]);
+        assert!(solve(hook).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Shell completion generated by `clap_complete` from the command line definition
+//!
+//! Shell registration script (`crab completions <shell>`) calls crab itself to complete a command
+//! line, so besides commands and options page type ids and table names of the workspace (given
+//! with `-w` or the current one) are completed, and suggestions follow parsers as they change.
+use crate::{create_python_parsers, open_workspace, Opts};
+use clap::{Arg, Command, CommandFactory, ValueEnum};
+use clap_complete::{
+    engine::{ArgValueCandidates, CompletionCandidate, ValueCandidates},
+    env::Shells,
+    CompleteEnv,
+};
+use crab::{prelude::*, storage::Storage, PageParser};
+use std::{
+    collections::BTreeSet,
+    env,
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// Environment variable the registration script passes the shell name in
+const COMPLETE_VAR: &str = "COMPLETE";
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+    Elvish,
+}
+
+/// Values completed by querying the workspace
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DynamicValues {
+    /// page type ids of parsers (along with parser module names)
+    TypeIds,
+    /// names of tables produced by parsers
+    Tables,
+}
+
+impl DynamicValues {
+    /// Values of an argument which are completed dynamically
+    fn of(arg: &Arg) -> Option<Self> {
+        match arg.get_id().as_str() {
+            "type_id" => Some(Self::TypeIds),
+            "table" => Some(Self::Tables),
+            _ => None,
+        }
+    }
+}
+
+impl ValueCandidates for DynamicValues {
+    fn candidates(&self) -> Vec<CompletionCandidate> {
+        let workspace = workspace(env::args_os());
+        let values = match self {
+            Self::TypeIds => type_ids(&workspace),
+            // completion engine is synchronous, so the database is read on a separate runtime
+            Self::Tables => thread::scope(|s| s.spawn(|| tables(&workspace)).join())
+                .unwrap_or_else(|_| Ok(vec![])),
+        };
+        // completion must not fail, workspace might be not set up yet
+        values.unwrap_or_default()
+    }
+}
+
+/// Answers completion request and exits if crab is called by a registration script
+pub(crate) fn complete() {
+    CompleteEnv::with_factory(command)
+        .var(COMPLETE_VAR)
+        .complete();
+}
+
+/// Writes registration script for a given shell
+pub(crate) fn write_registration(shell: Shell, out: &mut impl Write) -> Result<()> {
+    let name = shell.to_possible_value().expect("shells are not skipped");
+    let shells = Shells::builtins();
+    let completer = shells
+        .completer(name.get_name())
+        .expect("all shells are supported by clap_complete");
+    let bin = Opts::command().get_name().to_string();
+    let mut script = vec![];
+    completer.write_registration(COMPLETE_VAR, &bin, &bin, &bin, &mut script)?;
+    out.write_all(&script)?;
+    Ok(())
+}
+
+/// Command line definition with dynamically completed arguments
+fn command() -> Command {
+    with_dynamic_values(Opts::command())
+}
+
+fn with_dynamic_values(command: Command) -> Command {
+    let command = command.mut_args(|arg| match DynamicValues::of(&arg) {
+        Some(values) => arg.add(ArgValueCandidates::new(values)),
+        None => arg,
+    });
+    let subcommands = command
+        .get_subcommands()
+        .map(|c| c.get_name().to_string())
+        .collect::<Vec<_>>();
+    subcommands.iter().fold(command, |command, name| {
+        command.mut_subcommand(name, with_dynamic_values)
+    })
+}
+
+/// Workspace given with `-w` on the command line being completed
+fn workspace(args: impl IntoIterator<Item = OsString>) -> PathBuf {
+    let mut args = args.into_iter();
+    let mut workspace = PathBuf::from(".");
+    while let Some(arg) = args.next() {
+        if arg == "-w" {
+            if let Some(path) = args.next() {
+                workspace = PathBuf::from(path);
+            }
+        } else if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("-w")) {
+            workspace = PathBuf::from(path.trim_start_matches('='));
+        }
+    }
+    workspace
+}
+
+fn type_ids(workspace: &Path) -> Result<Vec<CompletionCandidate>> {
+    let mut parsers = create_python_parsers(workspace)?;
+    parsers.sort_by_key(|p| p.page_type_id());
+    let candidates = parsers
+        .iter()
+        .map(|parser| {
+            let module_name = parser.module_name();
+            let name = module_name.strip_prefix("parser_").unwrap_or(module_name);
+            CompletionCandidate::new(parser.page_type_id().to_string())
+                .help(Some(name.to_string().into()))
+        })
+        .collect();
+    Ok(candidates)
+}
+
+fn tables(workspace: &Path) -> Result<Vec<CompletionCandidate>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let (_, storage, parsers) = open_workspace(workspace, false, true).await?;
+        // Tables are known only by parsing, so the first downloaded page of each type is parsed
+        let mut tables = BTreeSet::new();
+        for parser in &parsers.0 {
+            let type_id = parser.page_type_id();
+            let pages = storage.list_downloaded_pages(0, 1, Some(type_id)).await?;
+            for page in pages {
+                let Some((content, _)) = storage.read_page_content(page.id).await? else {
+                    continue;
+                };
+                if let Ok(Some(parsed)) = parsers.parse(&page, &content) {
+                    tables.extend(parsed.into_keys().map(String::from));
+                }
+            }
+        }
+        Ok(tables.into_iter().map(CompletionCandidate::new).collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap_complete::engine;
+
+    fn complete(line: &[&str]) -> Vec<String> {
+        let args = line.iter().map(OsString::from).collect::<Vec<_>>();
+        let candidates = engine::complete(&mut command(), args, line.len() - 1, None).unwrap();
+        candidates
+            .iter()
+            .map(|c| c.get_value().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn commands_and_options_are_completed() {
+        assert!(complete(&["crab", "export-t"]).contains(&"export-table".to_string()));
+        assert!(complete(&["crab", "completions", ""]).contains(&"powershell".to_string()));
+        assert!(complete(&["crab", "--no-c"]).contains(&"--no-color".to_string()));
+    }
+
+    #[test]
+    fn dynamic_values_are_attached() {
+        let command = command();
+        let export_table = command.find_subcommand("export-table").unwrap();
+        let table = export_table
+            .get_arguments()
+            .find(|a| a.get_id() == "table")
+            .unwrap();
+        assert!(table.get::<ArgValueCandidates>().is_some());
+    }
+
+    #[test]
+    fn workspace_is_taken_from_command_line() {
+        let args = |line: &[&str]| line.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(workspace(args(&["crab", "export-table"])), Path::new("."));
+        assert_eq!(
+            workspace(args(&["crab", "--", "crab", "-w", "ws", "export-table"])),
+            Path::new("ws")
+        );
+        assert_eq!(workspace(args(&["crab", "-wws", "run"])), Path::new("ws"));
+    }
+
+    #[test]
+    fn registration_scripts_are_written() -> Result<()> {
+        for shell in Shell::value_variants() {
+            let mut script = vec![];
+            write_registration(*shell, &mut script)?;
+            let script = String::from_utf8(script)?;
+            assert!(script.contains(COMPLETE_VAR), "{:?}", shell);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! Assertions on the size of the dataset checked after crawl
+//!
+//! ```toml
+//! [[contracts]]
+//! table = "items"
+//! min_rows = 10000
+//!
+//! [[contracts]]
+//! table = "items"
+//! column = "name"
+//! type_id = 2
+//! min_filled = 0.95
+//! ```
+//!
+//! `min_rows` is the minimum number of rows downloaded pages yield in the table. `min_filled` is the
+//! minimum share of pages which have a non-empty `column` in at least one row of the table. Pages of
+//! `type_id` are counted if it is given (so pages yielding no rows at all fail the check as well),
+//! otherwise only pages yielding rows of the table are.
+//!
+//! Contracts are checked by `crab check-data` and after `crab run-crawler`, so a site change
+//! silently gutting the dataset doesn't go unnoticed.
+use crate::{prelude::*, storage::Page, BorrowedTables, PageTypeId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ContractConfig {
+    /// table the contract is about
+    pub(crate) table: String,
+    /// column `min_filled` is checked for
+    pub(crate) column: Option<String>,
+    /// page type `min_filled` share is computed over (all pages yielding rows of the table by default)
+    pub(crate) type_id: Option<PageTypeId>,
+    /// minimum number of rows in the table
+    pub(crate) min_rows: Option<u64>,
+    /// minimum share of pages with non-empty `column`, eg. `0.95`
+    pub(crate) min_filled: Option<f64>,
+}
+
+/// Outcome of a single assertion of a contract
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContractResult {
+    /// what is asserted, eg. `items: at least 10000 rows`
+    pub assertion: String,
+    /// what is found, eg. `9832 rows`
+    pub actual: String,
+    pub passed: bool,
+}
+
+impl fmt::Display for ContractResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, found {}", self.assertion, self.actual)
+    }
+}
+
+/// Rows and pages counted for a contract
+#[derive(Debug, Default, Clone)]
+struct Counts {
+    rows: u64,
+    pages: u64,
+    filled_pages: u64,
+}
+
+/// Evaluates contracts over parsed pages
+#[derive(Debug)]
+pub struct ContractCheck {
+    contracts: Vec<(ContractConfig, Counts)>,
+}
+
+impl ContractCheck {
+    pub fn new(contracts: &[ContractConfig]) -> Result<Self> {
+        for contract in contracts {
+            let invalid = || AppError::InvalidContract(contract.table.clone());
+            if contract.min_rows.is_none() && contract.min_filled.is_none() {
+                return Err(invalid().into());
+            }
+            if let Some(min_filled) = contract.min_filled {
+                if contract.column.is_none() || !(0.0..=1.0).contains(&min_filled) {
+                    return Err(invalid().into());
+                }
+            }
+        }
+        let contracts = contracts
+            .iter()
+            .map(|contract| (contract.clone(), Counts::default()))
+            .collect();
+        Ok(Self { contracts })
+    }
+
+    /// Counts rows and pages of tables parsed from a page
+    pub fn add(&mut self, page: &Page, tables: &BorrowedTables) {
+        for (contract, counts) in &mut self.contracts {
+            let rows = tables
+                .get(contract.table.as_str())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            counts.rows += rows.len() as u64;
+            let counted = match contract.type_id {
+                Some(type_id) => page.type_id == type_id,
+                None => !rows.is_empty(),
+            };
+            if !counted {
+                continue;
+            }
+            counts.pages += 1;
+            if let Some(column) = &contract.column {
+                let filled = rows.iter().any(|row| {
+                    row.get(column.as_str())
+                        .is_some_and(|value| !value.trim().is_empty())
+                });
+                counts.filled_pages += filled as u64;
+            }
+        }
+    }
+
+    pub fn results(&self) -> Vec<ContractResult> {
+        let mut results = vec![];
+        for (contract, counts) in &self.contracts {
+            let table = &contract.table;
+            if let Some(min_rows) = contract.min_rows {
+                results.push(ContractResult {
+                    assertion: format!("{table}: at least {min_rows} rows"),
+                    actual: format!("{} rows", counts.rows),
+                    passed: counts.rows >= min_rows,
+                });
+            }
+            if let (Some(min_filled), Some(column)) = (contract.min_filled, &contract.column) {
+                let share = match counts.pages {
+                    0 => 0.,
+                    pages => counts.filled_pages as f64 / pages as f64,
+                };
+                let pages = match contract.type_id {
+                    Some(type_id) => format!("pages of type {type_id}"),
+                    None => "pages".to_string(),
+                };
+                results.push(ContractResult {
+                    assertion: format!(
+                        "{table}.{column}: filled on {} of {pages}",
+                        Percent(min_filled)
+                    ),
+                    actual: format!(
+                        "{} ({}/{})",
+                        Percent(share),
+                        counts.filled_pages,
+                        counts.pages
+                    ),
+                    passed: counts.pages > 0 && share >= min_filled,
+                });
+            }
+        }
+        results
+    }
+}
+
+struct Percent(f64);
+
+impl fmt::Display for Percent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}%", self.0 * 100.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::PageStatus;
+    use std::{borrow::Cow, collections::HashMap};
+
+    #[test]
+    fn check_contracts() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Config {
+            contracts: Vec<ContractConfig>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [[contracts]]
+            table = "items"
+            min_rows = 3
+
+            [[contracts]]
+            table = "items"
+            column = "name"
+            type_id = 2
+            min_filled = 0.6
+            "#,
+        )?;
+        let mut check = ContractCheck::new(&config.contracts)?;
+
+        let page = |type_id| Page {
+            status: PageStatus::Downloaded,
+            ..Page::new(1, "http://test.com".parse().unwrap(), type_id)
+        };
+        let items = |names: &[&'static str]| {
+            let rows = names
+                .iter()
+                .map(|name| HashMap::from([(Cow::from("name"), Cow::from(*name))]))
+                .collect();
+            HashMap::from([(Cow::from("items"), rows)])
+        };
+        check.add(&page(1), &HashMap::new());
+        check.add(&page(2), &items(&["Watts", ""]));
+        check.add(&page(2), &items(&[" "]));
+        check.add(&page(2), &HashMap::new());
+
+        let results = check.results();
+        assert_eq!(results[0].actual, "3 rows");
+        assert!(results[0].passed);
+        assert_eq!(
+            results[1].assertion,
+            "items.name: filled on 60.0% of pages of type 2"
+        );
+        assert_eq!(results[1].actual, "33.3% (1/3)");
+        assert!(!results[1].passed);
+
+        let invalid = ContractConfig {
+            column: None,
+            ..config.contracts[1].clone()
+        };
+        assert!(ContractCheck::new(&[invalid]).is_err());
+        Ok(())
+    }
+}
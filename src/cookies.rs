@@ -0,0 +1,188 @@
+//! Cookies imported from a browser
+//!
+//! ```toml
+//! [cookies]
+//! file = "cookies.txt"
+//! ```
+//!
+//! Crawling as an already signed in browser session doesn't need a login step. Cookies are exported
+//! from the browser either in Netscape `cookies.txt` format (eg. by `Get cookies.txt` extension or
+//! `curl -c`) or as JSON array of objects with `domain`, `name` and `value` fields (eg. by
+//! `Cookie-Editor` extension or Playwright `storageState`, where the array is in `cookies` field).
+//!
+//! Cookies are sent with all requests to their domain and its subdomains regardless of their path,
+//! replacing `[auth]` rule of the domain. Expired cookies are ignored. [`crate::login`] step replaces
+//! cookies of the login domain.
+use crate::{auth::AuthRules, prelude::*};
+use anyhow::Context;
+use chrono::Utc;
+use reqwest::header::{HeaderMap, HeaderValue, COOKIE};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+/// Prefix of `cookies.txt` lines with cookies not accessible from scripts
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CookiesConfig {
+    /// file cookies are read from, `cookies.txt` or JSON
+    pub(crate) file: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Cookie {
+    pub domain: String,
+    pub name: String,
+    pub value: String,
+    /// expiration time as unix timestamp, `None` for session cookies
+    #[serde(alias = "expirationDate")]
+    pub expires: Option<f64>,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: f64) -> bool {
+        // Session cookies are exported with zero or negative expiration time by some tools
+        self.expires
+            .is_some_and(|expires| expires > 0. && expires < now)
+    }
+}
+
+/// Reads cookies from a file and returns auth rules sending them with requests to their domains
+pub fn import(config: &CookiesConfig, mut auth: AuthRules) -> Result<AuthRules> {
+    let file = &config.file;
+    let content = fs::read_to_string(file).context(AppError::ReadingCookies(file.clone()))?;
+    let cookies = parse(&content).context(AppError::ReadingCookies(file.clone()))?;
+    let now = Utc::now().timestamp() as f64;
+    let cookies = cookies
+        .into_iter()
+        .filter(|cookie| !cookie.is_expired(now))
+        .collect::<Vec<_>>();
+    for (domain, headers) in sessions(&cookies)? {
+        auth = auth.with_session(&domain, headers);
+    }
+    info!("{} cookies imported from {}", cookies.len(), file.display());
+    Ok(auth)
+}
+
+/// Parses cookies in `cookies.txt` or JSON format
+pub fn parse(content: &str) -> Result<Vec<Cookie>> {
+    let trimmed = content.trim_start();
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        parse_json(trimmed)
+    } else {
+        parse_netscape(content)
+    }
+}
+
+fn parse_json(content: &str) -> Result<Vec<Cookie>> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Export {
+        Cookies(Vec<Cookie>),
+        State { cookies: Vec<Cookie> },
+    }
+    let cookies = match serde_json::from_str(content)? {
+        Export::Cookies(cookies) | Export::State { cookies } => cookies,
+    };
+    Ok(cookies)
+}
+
+/// Parses tab separated lines: domain, subdomains flag, path, secure flag, expiration, name, value
+fn parse_netscape(content: &str) -> Result<Vec<Cookie>> {
+    let mut cookies = vec![];
+    for (no, line) in content.lines().enumerate() {
+        let line = line.strip_prefix(HTTP_ONLY_PREFIX).unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let (domain, expires, name) = match fields[..] {
+            [domain, _, _, _, expires, name, ..] => (domain, expires, name),
+            _ => return Err(AppError::InvalidCookie(no + 1).into()),
+        };
+        let expires = expires
+            .parse::<f64>()
+            .context(AppError::InvalidCookie(no + 1))?;
+        cookies.push(Cookie {
+            domain: domain.into(),
+            name: name.into(),
+            value: fields.get(6).copied().unwrap_or_default().into(),
+            expires: Some(expires),
+        });
+    }
+    Ok(cookies)
+}
+
+/// Groups cookies into `Cookie` headers by domain
+///
+/// Cookies of a domain are sent to its subdomains as well, so the header of a subdomain includes
+/// cookies of its parent domains.
+fn sessions(cookies: &[Cookie]) -> Result<BTreeMap<String, HeaderMap>> {
+    let domain = |cookie: &Cookie| cookie.domain.trim_start_matches('.').to_lowercase();
+    let mut result = BTreeMap::new();
+    for cookie in cookies {
+        let host = domain(cookie);
+        let header = cookies
+            .iter()
+            .filter(|c| {
+                let parent = domain(c);
+                host == parent
+                    || host
+                        .strip_suffix(parent.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(&header)?);
+        result.insert(host, headers);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cookies_txt() -> Result<()> {
+        let content = "# Netscape HTTP Cookie File\n\n\
+            .example.com\tTRUE\t/\tFALSE\t0\ttheme\tdark\n\
+            #HttpOnly_www.example.com\tFALSE\t/\tTRUE\t1700000000\tsession\tabc\n\
+            www.example.com\tFALSE\t/\tFALSE\t4000000000\tempty\n";
+        let cookies = parse(content)?;
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies[1].domain, "www.example.com");
+        assert_eq!(cookies[1].value, "abc");
+        assert_eq!(cookies[2].value, "");
+        assert!(!cookies[0].is_expired(1800000000.));
+        assert!(cookies[1].is_expired(1800000000.));
+
+        assert!(parse("example.com\tTRUE\t/\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_json_export() -> Result<()> {
+        let content = r#"[
+            {"domain": ".example.com", "name": "theme", "value": "dark", "path": "/", "session": true},
+            {"domain": "www.example.com", "name": "session", "value": "abc", "expirationDate": 4000000000.5}
+        ]"#;
+        let cookies = parse(content)?;
+        assert_eq!(cookies[0].expires, None);
+        assert_eq!(cookies[1].expires, Some(4000000000.5));
+
+        let state =
+            r#"{"cookies": [{"domain": "example.com", "name": "a", "value": "1", "expires": -1}]}"#;
+        assert_eq!(parse(state)?[0].name, "a");
+
+        let sessions = sessions(&cookies)?;
+        assert_eq!(sessions["example.com"][COOKIE], "theme=dark");
+        assert_eq!(
+            sessions["www.example.com"][COOKIE],
+            "theme=dark; session=abc"
+        );
+        Ok(())
+    }
+}
@@ -1,18 +1,57 @@
 use crate::{
+    auth::{AuthRules, ClientIdentityConfig},
+    canonical::UrlNormalizer,
+    challenge::{self, Challenge, Solver},
+    dedup::{DuplicateDetection, DuplicateIndex},
+    dns::DnsCache,
+    filter::UrlFilter,
+    html::{client_redirect, strip_elements, RobotsDirectives},
+    links::LinkExtractor,
+    locale::Locales,
+    login,
+    parser_threads::ParserThreads,
+    pii::Scrubber,
     prelude::*,
-    proxy::{Proxies, ProxyStat},
-    storage::{Page, Storage},
-    CrawlerConfig, CrawlerReport, PageParsers, Shared,
+    proxy::{Proxies, ProxyId, ProxyStat},
+    quota::Quotas,
+    redirect::RedirectPolicy,
+    schedule::AllowedHours,
+    session::{SessionPool, SetCookies},
+    stats::Timings,
+    storage::{
+        EncodedContent, FailureReason, Page, PageStatus, RequestTiming, ResponseMeta, SkipReason,
+        Storage,
+    },
+    throttle::{self, Bandwidth, Throttle},
+    trace::{millis, Event, PostponeReason, Tracer},
+    work_queue::WorkQueue,
+    CrawlerConfig, Link, PageParsers, PageTypeConfig, PageTypeId,
 };
 use anyhow::Context;
+use chrono::Utc;
+use encoding_rs::{Encoding, UTF_8};
 use futures::{stream::FuturesUnordered, StreamExt};
-use reqwest::{Client, Proxy, Url};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, ETAG, FROM,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+    },
+    redirect::Policy,
+    Client, ClientBuilder, Identity, Method, Proxy, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
-    sync::atomic::Ordering,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tokio::time::sleep;
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, watch},
+    time::sleep,
+};
 
 #[derive(Clone, Default)]
 pub struct CrawlerState {
@@ -22,136 +61,1278 @@ pub struct CrawlerState {
     pub successfull_requests: u32,
     /// Number of new links has been found
     pub new_links_found: u32,
+    /// Number of found links rejected by URL filters
+    pub filtered_links: u32,
+    /// Number of pages failed to download after all the retries
+    pub failed_pages: u32,
+    /// Number of 429/503 responses (see [`crate::throttle`])
+    pub rate_limited_requests: u32,
+    /// Number of pages skipped because their final URL is an alias of another page
+    pub duplicate_pages: u32,
+    /// Number of pages which content duplicates another page (see [`crate::dedup`])
+    pub duplicate_content_pages: u32,
+    /// Number of downloaded pages scheduled for recrawl because they are older than recrawl interval
+    pub expired_pages: u32,
+    /// Number of previously downloaded pages which content changed on refresh
+    pub changed_pages: u32,
+    /// Number of pages kept as is on refresh because server responded with `304 Not Modified` (or
+    /// `HEAD` request shows they are not changed)
+    pub not_modified_pages: u32,
+    /// Number of pages which response is larger than `max_body_bytes` and truncated
+    pub truncated_pages: u32,
+    /// Number of sessions replaced after failed requests (see [`crate::session`])
+    pub retired_sessions: u32,
+    /// Number of responses which are anti-bot challenge pages (see [`crate::challenge`])
+    pub challenged_requests: u32,
+    /// Number of challenges solved by the challenge solver
+    pub solved_challenges: u32,
+    /// Timings of successful requests
+    pub timings: Timings,
     /// The set of ongoing requests
-    pub requests_in_flight: HashSet<Page>,
+    ///
+    /// Shared with reports as well as proxies, so state is cheap to clone on each report tick and
+    /// the set is copied only if it's changed after a report is taken.
+    pub requests_in_flight: Arc<HashSet<Arc<Page>>>,
+
+    pub proxies: Arc<Vec<(Proxy, ProxyStat)>>,
+
+    /// Path of the last database snapshot taken
+    pub last_snapshot: Option<PathBuf>,
+
+    /// Shutdown is requested, crawler is waiting for requests in flight to complete
+    pub shutting_down: bool,
+}
 
-    pub proxies: Vec<(Proxy, ProxyStat)>,
+/// Commands sent to a running crawler (eg. from the terminal UI)
+#[derive(Debug)]
+pub enum CrawlerCommand {
+    /// Take a snapshot of the database (see [`Storage::snapshot()`])
+    Snapshot,
+    /// Stop dispatching new requests and finish after requests in flight are completed
+    ///
+    /// Requests not completed in [`SHUTDOWN_TIMEOUT`] are abandoned, so the pages are downloaded
+    /// again on the next run.
+    Shutdown,
+}
+
+/// Time pages are leased for if `lease_sec` is not set
+const DEFAULT_LEASE_SEC: f32 = 600.;
+
+/// Maximum number of redirects followed for a single request
+pub(crate) const MAX_REDIRECTS: usize = 10;
+
+/// Time given to requests in flight to complete after shutdown is requested
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Options of a single crawler run
+#[derive(Clone, Default, Debug)]
+pub struct RunOptions {
+    /// after downloading each page parse next pages
+    pub navigate: bool,
+    /// re-download already downloaded pages using conditional requests before the rest of the frontier
+    ///
+    /// `If-None-Match`/`If-Modified-Since` headers are sent based on the stored response headers.
+    /// If server responds with `304 Not Modified` stored content is kept as is.
+    pub refresh: bool,
+    /// refresh only downloaded pages of this type (all of them by default)
+    pub refresh_type_id: Option<PageTypeId>,
+    /// stop once downloaded pages are refreshed without downloading the rest of the frontier
+    pub refresh_only: bool,
+    /// scrubs personal data from page content before it's written to storage
+    pub scrubber: Option<Arc<Scrubber>>,
+    /// seed of the crawler RNG, random if not given
+    pub seed: Option<u64>,
+    /// file scheduling decisions are written to (see [`crate::trace`])
+    pub trace: Option<PathBuf>,
+    /// queue requests are handed to remote workers through instead of being sent (see [`crate::work_queue`])
+    pub work_queue: Option<Arc<WorkQueue>>,
+    /// download one page of each type first and abort if any of them fails validation
+    ///
+    /// Canary pages are downloaded one by one before other pages and not retried, so a crawl
+    /// doesn't make thousands of requests to a site responding with captcha or error pages.
+    pub canary: bool,
+}
+
+/// Canary pages of a run (see [`RunOptions::canary`])
+struct Canaries {
+    /// ids of canary pages not yet completed
+    pending: HashSet<i64>,
+    /// description of each failed canary
+    failures: Vec<String>,
 }
 
 pub async fn run_crawler(
     parsers: PageParsers,
-    mut storage: Storage,
+    mut storage: Box<dyn Storage>,
     opts: CrawlerConfig,
-    navigate: bool,
-    report: (Shared<CrawlerReport>, Duration),
+    auth: AuthRules,
+    run_opts: RunOptions,
+    report: (watch::Sender<Arc<CrawlerState>>, Duration),
+    mut commands: UnboundedReceiver<CrawlerCommand>,
 ) -> Result<()> {
     let (report, report_tick) = report;
+    storage.set_crawl_order(opts.crawl_order);
     let mut last_report_time = Instant::now();
+    let snapshot_interval = opts.snapshot_interval_sec.map(Duration::from_secs_f32);
+    let mut last_snapshot_time = Instant::now();
 
     let mut state = CrawlerState::default();
     let delay = Duration::from_secs_f32(opts.delay_sec);
     let mut futures = FuturesUnordered::new();
     let mut pages = vec![];
+    let auth = Arc::new(auth);
+    let parsers = ParserThreads::spawn(parsers)?;
+    let content_rules = Arc::new(ContentRules {
+        strip_selectors: opts.strip_selectors.clone(),
+        scrubber: run_opts.scrubber.clone(),
+        navigate: run_opts.navigate,
+        honor_robots_meta: opts.honor_robots_meta,
+        follow_client_redirects: opts.follow_client_redirects,
+        near_duplicates: opts.detect_duplicates == DuplicateDetection::Near,
+        body_limits: BodyLimits::new(&opts),
+        links: LinkExtractor::new(&opts)?,
+    });
+    let mut retries = Retries::new(&opts);
+    let link_rules = LinkRules::new(&opts)?;
+    let mut quotas = Quotas::load(&opts, &*storage).await?;
+    let mut throttle = Throttle::load(&opts, &*storage).await?;
+    let mut duplicates = DuplicateIndex::load(&opts, &*storage).await?;
+    let mut bandwidth = Bandwidth::new(&opts);
+    let allowed_hours = AllowedHours::new(&opts)?;
+    let mut paused = false;
+    let mut shutdown_deadline = None;
+    let headers = request_headers(&opts)?;
+    let locales = Locales::new(&opts)?;
+    let solver = Solver::new(&opts)?.map(Arc::new);
+    let identity = client_identity(&opts)?;
+    let redirect_policy = RedirectPolicy::new(opts.redirects.as_ref(), opts.url_filters.as_ref())?;
+    let render_types = render_types(&opts)?;
+    let head_first_types = head_first_types(&opts)?;
+    let recrawl_intervals = recrawl_intervals(&opts)?;
+    #[cfg(not(feature = "browser"))]
+    if !render_types.is_empty() {
+        return Err(AppError::BrowserNotSupported.into());
+    }
+    #[cfg(feature = "browser")]
+    let renderer = Arc::new(crate::browser::Renderer::new(&opts, user_agent(&opts)));
+    // Id of the last downloaded page scheduled for refresh, `None` when all pages are refreshed
+    let mut refresh_cursor = run_opts.refresh.then_some(0);
+    let seed = run_opts.seed.unwrap_or_else(rand::random);
+    // Unique id of this crawler process pages are leased to
+    let lease_owner = format!("{}-{:08x}", process::id(), rand::random::<u32>());
+    let lease = Duration::from_secs_f32(opts.lease_sec.unwrap_or(DEFAULT_LEASE_SEC));
     let mut proxies = match &opts.proxies {
-        Some(path) => Proxies::from_file(path).context(AppError::LoadingProxyList(path.clone()))?,
-        None => Proxies::default(),
+        Some(path) => {
+            Proxies::from_file(path, seed).context(AppError::LoadingProxyList(path.clone()))?
+        }
+        None => Proxies::new(vec![], seed),
+    };
+    let mut sessions = match &opts.sessions {
+        Some(config) => Some(SessionPool::new(
+            config,
+            user_agent(&opts),
+            &mut proxies,
+            seed,
+        )?),
+        None => None,
     };
+    let mut tracer = Tracer::new(run_opts.trace.as_deref())?;
+    tracer.log(Event::Start {
+        seed,
+        proxies: proxies.len(),
+        threads: opts.threads,
+    })?;
+
+    let mut canaries = None;
+    if run_opts.canary {
+        pages = storage.list_canary_pages().await?;
+        info!("Checking {} canary pages", pages.len());
+        canaries = Some(Canaries {
+            pending: pages.iter().map(|p| p.id).collect(),
+            failures: vec![],
+        });
+    }
 
-    report.swap(Box::new(state.clone().into()), Ordering::Relaxed);
+    report.send_replace(Arc::new(state.clone()));
 
     'scheduler: loop {
         // REPORTING PHASE
         if last_report_time.elapsed() >= report_tick {
             let mut state = state.clone();
             state.proxies = proxies.stat();
-            report.swap(Box::new(state.into()), Ordering::Relaxed);
+            report.send_replace(Arc::new(state));
             last_report_time = Instant::now();
         }
 
+        // SNAPSHOTTING PHASE
+        let mut snapshot_requested =
+            snapshot_interval.is_some_and(|interval| last_snapshot_time.elapsed() >= interval);
+        while let Ok(command) = commands.try_recv() {
+            match command {
+                CrawlerCommand::Snapshot => snapshot_requested = true,
+                CrawlerCommand::Shutdown if shutdown_deadline.is_none() => {
+                    tracer.log(Event::Shutdown)?;
+                    info!("Shutting down, {} requests in flight", futures.len());
+                    shutdown_deadline = Some(Instant::now() + SHUTDOWN_TIMEOUT);
+                    state.shutting_down = true;
+                }
+                CrawlerCommand::Shutdown => {}
+            }
+        }
+        if snapshot_requested {
+            let dir = opts
+                .snapshot_dir
+                .as_deref()
+                .unwrap_or(Path::new("snapshots"));
+            match take_snapshot(&*storage, dir).await {
+                Ok(path) => state.last_snapshot = Some(path),
+                Err(e) => error!("Unable to take database snapshot: {:?}", e),
+            }
+            last_snapshot_time = Instant::now();
+        }
+
+        // PAUSING PHASE
+        let pause = allowed_hours.as_ref().and_then(|h| h.wait_time(Utc::now()));
+        if pause.is_some() != paused {
+            paused = pause.is_some();
+            match pause {
+                Some(wait) => {
+                    info!("Outside of allowed hours, pausing for {:?}", wait);
+                    tracer.log(Event::Pause {
+                        wait_ms: millis(wait),
+                    })?;
+                }
+                None => info!("Resuming crawling"),
+            }
+        }
+        if shutdown_deadline.is_some() {
+            // Only completing requests in flight from now on
+            if futures.is_empty() {
+                break;
+            }
+        } else if let (Some(wait), true) = (pause, futures.is_empty()) {
+            sleep(wait.min(report_tick)).await;
+            continue 'scheduler;
+        }
+
         // REFILLING PHASE
         if pages.is_empty() && futures.is_empty() {
-            pages = storage.list_not_downloaded_pages(100).await?;
+            pages = retries.take_ready();
+            // Only canary pages are downloaded until all of them are checked
+            let canary_phase = canaries.is_some();
+            if let (true, false, Some(after_id)) = (pages.is_empty(), canary_phase, refresh_cursor)
+            {
+                let type_id = run_opts.refresh_type_id;
+                pages = storage
+                    .list_downloaded_pages(after_id, 100, type_id)
+                    .await?;
+                refresh_cursor = pages.iter().map(|p| p.id).max();
+            }
+            let frontier = !canary_phase && !run_opts.refresh_only;
+            if pages.is_empty() && frontier {
+                // Expired pages are refreshed in place before the rest of the frontier
+                pages = storage
+                    .lease_expired_pages(&recrawl_intervals, 100, &lease_owner, lease)
+                    .await?;
+                state.expired_pages += pages.len() as u32;
+            }
+            if pages.is_empty() && frontier {
+                // Pages waiting for retry are still not downloaded, so listing more of them to
+                // make sure other pages are not starving
+                let count = 100 + retries.len().min(u16::MAX as usize - 100) as u16;
+                pages = storage
+                    .lease_not_downloaded_pages(count, &lease_owner, lease)
+                    .await?;
+                pages.retain(|page| !retries.is_waiting(page));
+            }
+            if !pages.is_empty() {
+                let pages = pages.iter().map(|p| p.id).collect();
+                tracer.log(Event::Refill { pages })?;
+            }
             if pages.is_empty() {
-                break;
+                match retries.next_ready_in() {
+                    Some(delay) => {
+                        sleep(delay.min(report_tick)).await;
+                        continue 'scheduler;
+                    }
+                    None => break,
+                }
             }
         }
 
         // DISPATCHING PHASE
-        while futures.len() < opts.threads && !pages.is_empty() {
+        let dispatching = !paused && shutdown_deadline.is_none();
+        let threads = if canaries.is_some() { 1 } else { opts.threads };
+        // Bandwidth only changes when a request is completed, so it's checked once per dispatching
+        let bandwidth_wait = bandwidth.wait_time(Instant::now());
+        // Pages of hosts with too many requests in flight, waiting for one of them to complete
+        let mut saturated = vec![];
+        while dispatching
+            && bandwidth_wait.is_none()
+            && futures.len() < threads
+            && !pages.is_empty()
+        {
             let next_page = pages.swap_remove(0);
-            let next_proxy = proxies.next();
+            if throttle.is_saturated(&next_page.url) {
+                saturated.push(next_page);
+                continue;
+            }
+            let now = Utc::now().timestamp();
+            if let Some(wait) = quotas.wait_time(&next_page.url, now) {
+                debug!(
+                    "Quota exhausted, postponing for {:?}: {}",
+                    wait, next_page.url
+                );
+                tracer.log(Event::Postpone {
+                    page_id: next_page.id,
+                    reason: PostponeReason::Quota,
+                    wait_ms: millis(wait),
+                })?;
+                retries.postpone(next_page, wait);
+                continue;
+            }
+            if let Some(wait) = throttle.wait_time(&next_page.url, Instant::now()) {
+                trace!(
+                    "Host is throttled, postponing for {:?}: {}",
+                    wait,
+                    next_page.url
+                );
+                tracer.log(Event::Postpone {
+                    page_id: next_page.id,
+                    reason: PostponeReason::Throttle,
+                    wait_ms: millis(wait),
+                })?;
+                retries.postpone(next_page, wait);
+                continue;
+            }
+            for counter in quotas.record(&next_page.url, now) {
+                storage.write_request_counter(&counter).await?;
+            }
+            throttle.dispatched(&next_page.url, Instant::now());
+            #[cfg(feature = "browser")]
+            if render_types.contains(&next_page.type_id) && next_page.request.is_none() {
+                let renderer = renderer.clone();
+                let (parsers, rules) = (parsers.clone(), content_rules.clone());
+                tracer.log(dispatch_event(&next_page, None, delay))?;
+                state.requests += 1;
+                Arc::make_mut(&mut state.requests_in_flight).insert(Arc::new(next_page.clone()));
+                futures.push(tokio::spawn(async move {
+                    let content = renderer.render(&next_page.url).await;
+                    sleep(delay).await;
+                    let response = process_response(&parsers, rules, &next_page, content).await;
+                    (None, next_page, response, None)
+                }));
+                continue;
+            }
+            let session = sessions
+                .as_mut()
+                .map(|sessions| sessions.dispatch(next_page.id, &next_page.url));
+            // Workers use their own network
+            let next_proxy = match (&run_opts.work_queue, &session) {
+                (Some(_), _) => None,
+                (None, Some(session)) => session.proxy.clone(),
+                (None, None) => proxies.next(),
+            };
             let (proxy, proxy_id) = next_proxy.unzip();
-            let client = create_http_client(&opts, proxy)?;
+            let (client, redirects) =
+                create_http_client(&opts, proxy, identity.as_ref(), &redirect_policy)?;
+            let mut type_headers = headers.get(&next_page.type_id).cloned().unwrap_or_default();
+            if let Some(session) = session {
+                type_headers.extend(session.headers);
+            }
+            locales.apply(&next_page.url, next_page.type_id, &mut type_headers)?;
+            if let Some(solver) = &solver {
+                solver.apply(&next_page.url, &mut type_headers)?;
+            }
+            let mut request = page_request(&client, &next_page, Some(&type_headers))?;
+            let mut head_check = None;
+            if next_page.status == PageStatus::Downloaded {
+                if let Some(meta) = storage.read_page_meta(next_page.id).await? {
+                    request = conditional_request(request, &meta);
+                    // Workers are given the GET request only
+                    if head_first_types.contains(&next_page.type_id)
+                        && next_page.request.is_none()
+                        && run_opts.work_queue.is_none()
+                    {
+                        let head = client.head(next_page.url.clone());
+                        let head = head.headers(type_headers.clone());
+                        head_check = Some((head, meta));
+                    }
+                }
+            }
+            let (auth, solver) = (auth.clone(), solver.clone());
+            let (parsers, rules) = (parsers.clone(), content_rules.clone());
 
+            tracer.log(dispatch_event(&next_page, proxy_id, delay))?;
             state.requests += 1;
-            state.requests_in_flight.insert(next_page.clone());
+            Arc::make_mut(&mut state.requests_in_flight).insert(Arc::new(next_page.clone()));
 
+            let work_queue = run_opts.work_queue.clone();
+            let requested_at = Instant::now();
             let future = tokio::spawn(async move {
-                let content = fetch_content(client, &next_page.url, delay).await;
-                (proxy_id, next_page, content)
+                if let Some((head, meta)) = head_check {
+                    if head_unchanged(&auth, head, &next_page.url, &meta).await {
+                        trace!("Not changed according to HEAD: {}", next_page.url);
+                        sleep(delay).await;
+                        return (proxy_id, next_page, Ok(Processed::NotModified), None);
+                    }
+                }
+                let content = match work_queue {
+                    Some(queue) => {
+                        let content = queue.fetch(&auth, request, &next_page.url).await;
+                        sleep(delay).await;
+                        content
+                    }
+                    None => {
+                        let (url, limits) = (&next_page.url, &rules.body_limits);
+                        fetch_content(&auth, request, &redirects, url, limits, delay).await
+                    }
+                };
+                // Taken before the response is processed, as not all outcomes keep its meta
+                let set_cookies = content.as_ref().ok().map(|(_, meta)| SetCookies::new(meta));
+                let mut response = process_response(&parsers, rules, &next_page, content).await;
+                if let (Ok(Processed::Challenged { challenge, solved }), Some(solver)) =
+                    (&mut response, solver)
+                {
+                    *solved = solver
+                        .solve(challenge, requested_at)
+                        .await
+                        .unwrap_or_else(|e| {
+                            error!(
+                                "Unable to solve challenge of page #{}: {:?}",
+                                next_page.id, e
+                            );
+                            false
+                        });
+                }
+                (proxy_id, next_page, response, set_cookies)
             });
             futures.push(future);
         }
+        pages.append(&mut saturated);
 
         // COMPLETING PHASE
+        if let (Some(wait), true) = (bandwidth_wait, futures.is_empty() && dispatching) {
+            sleep(wait.min(report_tick)).await;
+            continue 'scheduler;
+        }
         if !futures.is_empty() {
-            let Some(completed) = futures.next().await else {
+            let completed = match shutdown_deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match tokio::time::timeout(timeout, futures.next()).await {
+                        Ok(completed) => completed,
+                        Err(_) => {
+                            warn!("Abandoning {} requests in flight", futures.len());
+                            break;
+                        }
+                    }
+                }
+                // Waking up to dispatch more pages as soon as bandwidth allows
+                None => match bandwidth_wait.filter(|_| dispatching && !pages.is_empty()) {
+                    Some(wait) => match tokio::time::timeout(wait, futures.next()).await {
+                        Ok(completed) => completed,
+                        Err(_) => continue 'scheduler,
+                    },
+                    None => futures.next().await,
+                },
+            };
+            let Some(completed) = completed else {
                 continue 'scheduler;
             };
-            let (proxy, page, response) = completed?;
-            state.requests_in_flight.remove(&page);
-
-            let success = match response {
-                Ok(content) => {
-                    let valid_page = parsers.validate(page.type_id, &content)?;
-                    if valid_page {
-                        state.successfull_requests += 1;
-                        storage.write_page_content(page.id, &content).await?;
-
-                        if navigate {
-                            navigate_page(&parsers, &page, &content, &mut storage, &mut state)
-                                .await?;
+            let (proxy, page, response, set_cookies) = completed?;
+            Arc::make_mut(&mut state.requests_in_flight).remove(&page);
+            throttle.completed(&page.url);
+            // Cookies are kept whatever the outcome, challenge and rate-limiting responses set
+            // them as well
+            if let (Some(sessions), Some(set_cookies)) = (&mut sessions, set_cookies) {
+                sessions.store_cookies(page.id, set_cookies);
+            }
+
+            // `Retry-After` of a rate-limiting response if one is received
+            let mut rate_limited = None;
+            // why the page is failed, recorded in storage and reported if it is a canary page
+            let mut failure = None;
+            // challenge is solved, so the page is requested again with the solver cookies
+            let mut solved_challenge = false;
+            let mut timing = None;
+            let success = match response? {
+                Processed::NotModified => {
+                    debug!("Not modified: {}", page.url);
+                    state.successfull_requests += 1;
+                    state.not_modified_pages += 1;
+                    storage.touch_page(page.id).await?;
+                    true
+                }
+                Processed::Valid {
+                    content,
+                    meta,
+                    mut links,
+                } => {
+                    state.successfull_requests += 1;
+                    if let Some(t) = &meta.timing {
+                        state.timings.record(t);
+                        bandwidth.transferred(t.body_bytes, Instant::now());
+                    }
+                    timing = meta.timing;
+                    // Already downloaded pages are refreshed in place
+                    let duplicate = match page.status {
+                        PageStatus::Downloaded => None,
+                        _ => {
+                            storage
+                                .find_page_by_final_url(&meta.final_url, page.id)
+                                .await?
                         }
+                    };
+                    if let Some(original) = duplicate {
+                        debug!(
+                            "Page #{} is a duplicate of #{}: {}",
+                            page.id, original.id, meta.final_url
+                        );
+                        storage.skip_page(page.id, SkipReason::Duplicate).await?;
+                        state.duplicate_pages += 1;
+                    } else if let Some(content) = content {
+                        let (hash, simhash) = (content.hash().to_owned(), content.simhash());
+                        let changed = storage
+                            .write_encoded_content(page.id, content, Some(&meta))
+                            .await?;
+                        if meta.truncated {
+                            debug!("Content truncated: {}", page.url);
+                            state.truncated_pages += 1;
+                        }
+                        if changed && page.status == PageStatus::Downloaded {
+                            debug!("Content changed: {}", page.url);
+                            state.changed_pages += 1;
+                        }
+                        let original = duplicates.check(&*storage, page.id, &hash, simhash).await?;
+                        if let Some(original) = original {
+                            debug!(
+                                "Content of page #{} duplicates #{}: {}",
+                                page.id, original, page.url
+                            );
+                            state.duplicate_content_pages += 1;
+                            // Duplicate pages link to the same pages as the original
+                            links = None;
+                        }
+                    } else {
+                        debug!("Page asks not to be indexed: {}", page.url);
+                        storage.skip_page(page.id, SkipReason::Robots).await?;
                     }
-
-                    valid_page
+                    if let Some(links) = links {
+                        let registered = link_rules
+                            .register(&mut *storage, links, page.depth + 1)
+                            .await?;
+                        state.new_links_found += registered.new;
+                        state.filtered_links += registered.filtered;
+                    }
+                    true
+                }
+                Processed::Skipped(reason) => {
+                    debug!("Skipping ({}): {}", reason, page.url);
+                    storage.skip_page(page.id, reason).await?;
+                    true
+                }
+                Processed::SkippedContentType { meta, tagged } => {
+                    let content_type = meta.content_type.as_deref().unwrap_or_default();
+                    debug!("Skipping ({}): {}", content_type, page.url);
+                    storage
+                        .skip_page_with_meta(page.id, SkipReason::ContentType, &meta)
+                        .await?;
+                    if tagged {
+                        storage.tag_page(page.id, NON_TEXT_TAG).await?;
+                    }
+                    true
+                }
+                Processed::Redirected(target) => {
+                    debug!("Page #{} redirects to {}", page.id, target);
+                    // Target replaces the page, so it's registered at the same depth
+                    let link = Link {
+                        url: target,
+                        type_id: page.type_id,
+                        request: None,
+                    };
+                    let registered = link_rules
+                        .register(&mut *storage, vec![link], page.depth)
+                        .await?;
+                    state.new_links_found += registered.new;
+                    state.filtered_links += registered.filtered;
+                    storage.skip_page(page.id, SkipReason::Redirect).await?;
+                    true
                 }
-                Err(e) => {
+                Processed::Invalid(status) => {
+                    failure = Some(invalid_content_failure(status));
+                    false
+                }
+                Processed::Challenged { challenge, solved } => {
+                    let provider = challenge.provider;
+                    debug!("{} challenge (solved: {}): {}", provider, solved, page.url);
+                    state.challenged_requests += 1;
+                    if solved {
+                        state.solved_challenges += 1;
+                        solved_challenge = true;
+                    } else {
+                        let message = format!("{} challenge", provider);
+                        failure = Some((FailureReason::Challenge, message));
+                    }
+                    false
+                }
+                Processed::RateLimited(retry_after) => {
+                    state.rate_limited_requests += 1;
+                    rate_limited = Some(retry_after);
+                    failure = Some((FailureReason::HttpStatus, "rate limited".to_string()));
+                    false
+                }
+                Processed::Failed(e) => {
                     debug!("Unable to download: {}", page.url);
                     trace!("{}", e);
+                    failure = Some(download_failure(&e));
                     false
                 }
             };
 
+            tracer.log(Event::Complete {
+                page_id: page.id,
+                proxy,
+                success,
+            })?;
+            // Rate-limited requests are not failures, they are just postponed
+            if let (None, Some((reason, message))) = (rate_limited, &failure) {
+                storage.record_failure(page.id, *reason, message).await?;
+            }
+            let canary = !solved_challenge
+                && canaries
+                    .as_mut()
+                    .is_some_and(|c| c.pending.remove(&page.id));
+            if success {
+                retries.succeeded(&page);
+                if throttle.succeeded(&page.url) {
+                    write_host_backoff(&*storage, &throttle, &page.url).await?;
+                }
+            } else if solved_challenge {
+                // Solved challenges are not counted as failed attempts
+                debug!("Requesting again after solved challenge: {}", page.url);
+                tracer.log(Event::Postpone {
+                    page_id: page.id,
+                    reason: PostponeReason::Challenge,
+                    wait_ms: 0,
+                })?;
+                retries.postpone(page.clone(), Duration::ZERO);
+            } else if canary {
+                // Canary pages are not retried, the whole run is aborted instead
+                let reason = failure.map(|(_, message)| message).unwrap_or_default();
+                debug!("Canary page failed ({}): {}", reason, page.url);
+                if let Some(canaries) = canaries.as_mut() {
+                    canaries.failures.push(format!(
+                        "#{} {} (type {}): {}",
+                        page.id, page.url, page.type_id, reason
+                    ));
+                }
+            } else if let Some(retry_after) = rate_limited {
+                // Rate-limited requests are not counted as failed attempts
+                let wait = throttle.rate_limited(&page.url, retry_after, Instant::now());
+                write_host_backoff(&*storage, &throttle, &page.url).await?;
+                debug!("Rate limited, postponing for {:?}: {}", wait, page.url);
+                tracer.log(Event::Postpone {
+                    page_id: page.id,
+                    reason: PostponeReason::Throttle,
+                    wait_ms: millis(wait),
+                })?;
+                retries.postpone(page.clone(), wait);
+            } else if let Some(wait) = retries.failed(page.clone()) {
+                tracer.log(Event::Postpone {
+                    page_id: page.id,
+                    reason: PostponeReason::Retry,
+                    wait_ms: millis(wait),
+                })?;
+            } else {
+                debug!("Giving up on: {}", page.url);
+                // Previously downloaded content is still there when refresh is failed
+                if page.status != PageStatus::Downloaded {
+                    storage.fail_page(page.id).await?;
+                }
+                state.failed_pages += 1;
+            }
+
             if let Some(proxy) = proxy {
                 if success {
                     proxies.proxy_succeseed(proxy);
+                    if let Some(timing) = &timing {
+                        proxies.record_timing(proxy, timing);
+                    }
                 } else {
                     proxies.proxy_failed(proxy);
                 }
             }
+            if let Some(sessions) = &mut sessions {
+                sessions.completed(page.id, success, &mut proxies);
+                state.retired_sessions = sessions.retired;
+            }
+
+            if let Some(Canaries { pending, failures }) = &canaries {
+                if pending.is_empty() {
+                    if !failures.is_empty() {
+                        tracer.flush()?;
+                        storage.release_leases(&lease_owner).await?;
+                        return Err(AppError::CanaryFailed(failures.join("\n")).into());
+                    }
+                    info!("All canary pages passed validation");
+                    canaries = None;
+                }
+            }
         }
     }
+    tracer.flush()?;
+    storage.release_leases(&lease_owner).await?;
+    state.proxies = proxies.stat();
+    report.send_replace(Arc::new(state));
     Ok(())
 }
 
-async fn navigate_page(
-    parsers: &PageParsers,
+/// Persists throttling state of the URL host, so it's restored if crawler is restarted
+async fn write_host_backoff(storage: &dyn Storage, throttle: &Throttle, url: &Url) -> Result<()> {
+    let Some(host) = url.host_str() else {
+        return Ok(());
+    };
+    match throttle.backoff(host, Instant::now(), Utc::now()) {
+        Some(backoff) => storage.write_host_backoff(&backoff).await,
+        None => storage.delete_host_backoff(host).await,
+    }
+}
+
+fn dispatch_event(page: &Page, proxy: Option<ProxyId>, delay: Duration) -> Event {
+    Event::Dispatch {
+        page_id: page.id,
+        url: page.url.to_string(),
+        proxy,
+        delay_ms: millis(delay),
+    }
+}
+
+/// Keeps track of failed pages and schedules them for retry with exponential backoff
+///
+/// Also holds pages postponed because of exhausted request quotas.
+struct Retries {
+    max_retries: u32,
+    backoff: Duration,
+    /// Number of failed attempts for each page
+    attempts: HashMap<i64, u32>,
+    /// Pages waiting for retry and the time they can be retried at
+    waiting: Vec<(Instant, Page)>,
+}
+
+impl Retries {
+    fn new(opts: &CrawlerConfig) -> Self {
+        Self {
+            max_retries: opts.max_retries.unwrap_or(3),
+            backoff: Duration::from_secs_f32(opts.retry_backoff_sec.unwrap_or(1.)),
+            attempts: HashMap::new(),
+            waiting: vec![],
+        }
+    }
+
+    /// Registers failed attempt, returns retry delay or `None` if no retries left for the page
+    fn failed(&mut self, page: Page) -> Option<Duration> {
+        let attempts = self.attempts.entry(page.id).or_default();
+        *attempts += 1;
+        if *attempts > self.max_retries {
+            self.attempts.remove(&page.id);
+            return None;
+        }
+        let delay = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(*attempts - 1));
+        self.waiting.push((Instant::now() + delay, page));
+        Some(delay)
+    }
+
+    /// Puts page aside for a given time without counting it as a failed attempt
+    fn postpone(&mut self, page: Page, delay: Duration) {
+        self.waiting.push((Instant::now() + delay, page));
+    }
+
+    fn succeeded(&mut self, page: &Page) {
+        self.attempts.remove(&page.id);
+    }
+
+    /// Returns pages which can be retried now
+    fn take_ready(&mut self) -> Vec<Page> {
+        let now = Instant::now();
+        let (ready, waiting) = self.waiting.drain(..).partition(|(at, _)| *at <= now);
+        self.waiting = waiting;
+        ready.into_iter().map(|(_, page)| page).collect()
+    }
+
+    fn is_waiting(&self, page: &Page) -> bool {
+        self.waiting.iter().any(|(_, p)| p.id == page.id)
+    }
+
+    /// Time left until the next page can be retried
+    fn next_ready_in(&self) -> Option<Duration> {
+        let next = self.waiting.iter().map(|(at, _)| *at).min()?;
+        Some(next.saturating_duration_since(Instant::now()))
+    }
+
+    fn len(&self) -> usize {
+        self.waiting.len()
+    }
+}
+
+/// Writes database snapshot in a given directory, returns the path of the snapshot
+async fn take_snapshot(storage: &dyn Storage, dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = format!("snapshot-{}.sqlite", Utc::now().format("%Y%m%dT%H%M%S%.3f"));
+    let path = dir.join(name);
+    storage.snapshot(&path).await?;
+    info!("Database snapshot written: {}", path.display());
+    Ok(path)
+}
+
+/// Builds request headers for each page type from [`CrawlerConfig::headers`]
+fn request_headers(opts: &CrawlerConfig) -> Result<HashMap<PageTypeId, HeaderMap>> {
+    let mut result = HashMap::new();
+    for (type_id, headers) in opts.headers.iter().flatten() {
+        let context = || AppError::InvalidRequestHeaders(type_id.clone());
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).with_context(context)?;
+            let value = HeaderValue::from_str(value).with_context(context)?;
+            map.insert(name, value);
+        }
+        result.insert(type_id.parse().with_context(context)?, map);
+    }
+    Ok(result)
+}
+
+/// Returns page types rendered by a headless browser (see [`CrawlerConfig::page_types`])
+fn render_types(opts: &CrawlerConfig) -> Result<HashSet<PageTypeId>> {
+    page_types_with(opts, |config| config.render)
+}
+
+/// Returns page types checked with `HEAD` request before refreshing (see [`CrawlerConfig::page_types`])
+fn head_first_types(opts: &CrawlerConfig) -> Result<HashSet<PageTypeId>> {
+    page_types_with(opts, |config| config.head_first)
+}
+
+fn page_types_with(
+    opts: &CrawlerConfig,
+    predicate: impl Fn(&PageTypeConfig) -> bool,
+) -> Result<HashSet<PageTypeId>> {
+    let mut result = HashSet::new();
+    for (type_id, config) in opts.page_types.iter().flatten() {
+        if predicate(config) {
+            let type_id = type_id
+                .parse()
+                .with_context(|| AppError::InvalidPageTypeId(type_id.clone()))?;
+            result.insert(type_id);
+        }
+    }
+    Ok(result)
+}
+
+/// Returns recrawl interval of each page type it is set for (see [`CrawlerConfig::page_types`])
+fn recrawl_intervals(opts: &CrawlerConfig) -> Result<Vec<(PageTypeId, Duration)>> {
+    let mut result = vec![];
+    for (type_id, config) in opts.page_types.iter().flatten() {
+        if let Some(interval) = config.recrawl_after_sec {
+            let type_id = type_id
+                .parse()
+                .with_context(|| AppError::InvalidPageTypeId(type_id.clone()))?;
+            result.push((type_id, Duration::from_secs_f32(interval)));
+        }
+    }
+    Ok(result)
+}
+
+/// Builds request for a page using [`Page::request`] if given and page type headers
+fn page_request(
+    client: &Client,
     page: &Page,
-    content: &str,
-    storage: &mut Storage,
-    state: &mut CrawlerState,
-) -> Result<()> {
-    match parsers.navigate(page, content) {
-        Ok(Some(links)) => {
-            for (link, type_id) in links {
-                let page_id = storage.register_page(link, type_id, page.depth + 1).await?;
-                if page_id.is_some() {
-                    state.new_links_found += 1;
+    type_headers: Option<&HeaderMap>,
+) -> Result<RequestBuilder> {
+    let mut headers = type_headers.cloned().unwrap_or_default();
+    let Some(spec) = &page.request else {
+        return Ok(client.get(page.url.clone()).headers(headers));
+    };
+    for (name, value) in &spec.headers {
+        // Cookies of a request (eg. of a language variant) are sent along with session cookies
+        if name.eq_ignore_ascii_case(COOKIE.as_str()) {
+            login::append_cookies(&mut headers, value)?;
+            continue;
+        }
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    let method = Method::from_bytes(spec.method.as_bytes())?;
+    let mut request = client.request(method, page.url.clone()).headers(headers);
+    if !spec.form.is_empty() {
+        request = request.form(&spec.form);
+    }
+    Ok(request)
+}
+
+/// Makes a `HEAD` request and checks if the page is the same as the stored one
+///
+/// Any error or unsuccessful response is treated as a change, so the page is downloaded.
+async fn head_unchanged(
+    auth: &AuthRules,
+    request: RequestBuilder,
+    url: &Url,
+    meta: &ResponseMeta,
+) -> bool {
+    match auth.send(url, request).await {
+        Ok(response) if response.status().is_success() => is_unchanged(meta, response.headers()),
+        Ok(response) => {
+            trace!("HEAD request failed ({}): {}", response.status(), url);
+            false
+        }
+        Err(e) => {
+            trace!("HEAD request failed: {}: {}", url, e);
+            false
+        }
+    }
+}
+
+/// Compares `ETag` (or `Content-Length` and `Last-Modified` if there is no `ETag`) of the stored
+/// response with the headers of a `HEAD` response
+fn is_unchanged(meta: &ResponseMeta, headers: &HeaderMap) -> bool {
+    let header = |name: HeaderName| headers.get(&name).and_then(|v| v.to_str().ok());
+    if let (Some(stored), Some(current)) = (meta.header(ETAG.as_str()), header(ETAG)) {
+        return stored == current;
+    }
+    match (meta.header(CONTENT_LENGTH.as_str()), header(CONTENT_LENGTH)) {
+        (Some(stored), Some(current)) => {
+            stored == current && meta.header(LAST_MODIFIED.as_str()) == header(LAST_MODIFIED)
+        }
+        _ => false,
+    }
+}
+
+/// Adds validators of the previous response to a request, so server can respond with `304 Not Modified`
+fn conditional_request(mut request: RequestBuilder, meta: &ResponseMeta) -> RequestBuilder {
+    if let Some(etag) = meta.header(ETAG.as_str()) {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = meta.header(LAST_MODIFIED.as_str()) {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    request
+}
+
+/// Rules applied to downloaded content before it's written to storage
+struct ContentRules {
+    strip_selectors: Option<Vec<String>>,
+    scrubber: Option<Arc<Scrubber>>,
+    /// run navigation rules on the content
+    navigate: bool,
+    /// skip content of `noindex` pages and links marked `nofollow`
+    honor_robots_meta: bool,
+    /// detect `<meta http-equiv="refresh">` and script redirects
+    follow_client_redirects: bool,
+    /// calculate simhash of the content (see [`crate::dedup`])
+    near_duplicates: bool,
+    body_limits: BodyLimits,
+    /// link rules of page types which parsers have no navigation rules
+    links: LinkExtractor,
+}
+
+/// Response after the content is run through the page type parser
+enum Processed {
+    Failed(anyhow::Error),
+    NotModified,
+    /// server responded with 429/503, `Retry-After` is given if present
+    RateLimited(Option<Duration>),
+    /// content is rejected by validation rules, so request should be repeated (status of the
+    /// response is given)
+    Invalid(u16),
+    /// anti-bot challenge page is served instead of the content, `solved` if the solver has
+    /// provided cookies the request should be repeated with
+    Challenged {
+        challenge: Box<Challenge>,
+        solved: bool,
+    },
+    /// page should not be downloaded at all
+    Skipped(SkipReason),
+    /// content type of the response is not stored, page is tagged `non-text` if `tagged`
+    SkippedContentType {
+        meta: Box<ResponseMeta>,
+        tagged: bool,
+    },
+    /// page redirects to a given URL by itself (not with an HTTP status)
+    Redirected(Url),
+    Valid {
+        /// content is hashed and compressed on the parser thread, so the crawler loop only has to
+        /// write it (`None` if the page asks not to be indexed)
+        content: Option<EncodedContent>,
+        meta: Box<ResponseMeta>,
+        /// links found on a page if navigation is enabled
+        links: Option<Vec<Link<Url>>>,
+    },
+}
+
+/// Validates, preprocesses, navigates and encodes page content on the thread of the page type parser
+///
+/// Runs in the download task, so CPU bound work on a page is pipelined with downloading and doesn't
+/// stall the crawler loop.
+async fn process_response(
+    parsers: &ParserThreads,
+    rules: Arc<ContentRules>,
+    page: &Page,
+    response: Result<(String, ResponseMeta)>,
+) -> Result<Processed> {
+    if let Ok((content, meta)) = &response {
+        if let Some(provider) = challenge::detect(meta, content) {
+            let challenge = Box::new(Challenge::new(provider, page, meta, content));
+            let solved = false;
+            return Ok(Processed::Challenged { challenge, solved });
+        }
+    }
+    let (content, meta) = match response {
+        Ok((_, meta)) if meta.status == StatusCode::NOT_MODIFIED.as_u16() => {
+            return Ok(Processed::NotModified)
+        }
+        Ok((_, meta)) if is_abandoned_redirect(&meta) => {
+            debug!(
+                "Redirect of page #{} is not allowed: {}",
+                page.id,
+                meta.header(LOCATION.as_str()).unwrap_or_default()
+            );
+            return Ok(Processed::Skipped(SkipReason::RedirectPolicy));
+        }
+        Ok((_, meta)) if throttle::is_rate_limited(&meta) => {
+            let retry_after = throttle::retry_after(&meta, Utc::now());
+            return Ok(Processed::RateLimited(retry_after));
+        }
+        Ok((_, meta)) if !rules.body_limits.reads_body(meta.content_type.as_deref()) => {
+            let limits = &rules.body_limits;
+            let tagged = limits.non_text == NonTextContent::Mark
+                && !is_text_content_type(meta.content_type.as_deref());
+            let meta = Box::new(meta);
+            return Ok(Processed::SkippedContentType { meta, tagged });
+        }
+        Ok(response) => response,
+        Err(e) => return Ok(Processed::Failed(e)),
+    };
+    let page = page.clone();
+    let job = parsers.run(page.type_id, move |parsers| {
+        // Redirecting pages usually have no content, so they would fail validation
+        if rules.follow_client_redirects {
+            match client_redirect(&content, &page.url) {
+                Ok(Some(target)) => return Ok(Processed::Redirected(target)),
+                Ok(None) => {}
+                Err(e) => error!("Unable to read redirects of page #{}: {}", page.id, e),
+            }
+        }
+        if !parsers.validate(&page, &content)? {
+            return Ok(Processed::Invalid(meta.status));
+        }
+        let directives = if rules.honor_robots_meta {
+            RobotsDirectives::parse(&content, &page.url).unwrap_or_else(|e| {
+                error!(
+                    "Unable to read robots directives of page #{}: {}",
+                    page.id, e
+                );
+                RobotsDirectives::default()
+            })
+        } else {
+            RobotsDirectives::default()
+        };
+        let content = match &rules.strip_selectors {
+            Some(selectors) => {
+                strip_elements(&content, selectors).context(AppError::StrippingContent(page.id))?
+            }
+            None => content,
+        };
+        let mut content = parsers.preprocess(&page, content)?;
+        if let Some(scrubber) = &rules.scrubber {
+            content = scrubber.scrub_content(page.id, content)?;
+        }
+        let links = if rules.navigate {
+            rules
+                .links
+                .navigate(parsers, &page, &content)
+                .unwrap_or_else(|e| {
+                    error!("next_pages() method failed on page #{}: {}", page.id, e);
+                    None
+                })
+        } else {
+            None
+        };
+        let links = links.map(|links| directives.filter_links(links));
+        let content = match directives.noindex {
+            true => None,
+            false if rules.near_duplicates => {
+                Some(EncodedContent::new(&content)?.with_simhash(&content)?)
+            }
+            false => Some(EncodedContent::new(&content)?),
+        };
+        Ok(Processed::Valid {
+            content,
+            meta: Box::new(meta),
+            links,
+        })
+    });
+    job.await
+}
+
+/// Category and message of a download error
+fn download_failure(error: &anyhow::Error) -> (FailureReason, String) {
+    let http_error = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<reqwest::Error>());
+    let reason = match (http_error, error.downcast_ref::<AppError>()) {
+        (Some(e), _) if e.is_timeout() => FailureReason::Timeout,
+        (Some(e), _) if e.is_connect() => FailureReason::Connect,
+        (_, Some(AppError::WorkerTimeout)) => FailureReason::Timeout,
+        _ => FailureReason::Other,
+    };
+    // Some errors (eg. reqwest ones) already include their sources in the message
+    let mut message = error.to_string();
+    for cause in error.chain().skip(1) {
+        let cause = cause.to_string();
+        if !message.contains(&cause) {
+            message = format!("{}: {}", message, cause);
+        }
+    }
+    (reason, message)
+}
+
+/// Category and message of a response rejected by validation rules
+fn invalid_content_failure(status: u16) -> (FailureReason, String) {
+    match StatusCode::from_u16(status) {
+        Ok(status) if status.is_client_error() || status.is_server_error() => {
+            (FailureReason::HttpStatus, format!("HTTP {}", status))
+        }
+        _ => (FailureReason::Invalid, "content is not valid".to_string()),
+    }
+}
+
+/// Rules deciding which of the links found by navigation rules are registered and downloaded
+pub struct LinkRules {
+    max_depth: Option<u16>,
+    filter: UrlFilter,
+    /// filtered links are registered as skipped
+    record_filtered: bool,
+    normalizer: UrlNormalizer,
+    locales: Locales,
+}
+
+/// Outcome of registering links found on a page
+#[derive(Default, Debug, PartialEq)]
+pub struct RegisteredLinks {
+    /// number of new pages scheduled for downloading
+    pub new: u32,
+    /// number of links rejected by URL filters
+    pub filtered: u32,
+}
+
+impl LinkRules {
+    pub fn new(opts: &CrawlerConfig) -> Result<Self> {
+        let filter = match &opts.url_filters {
+            Some(config) => UrlFilter::new(config)?,
+            None => UrlFilter::default(),
+        };
+        Ok(Self {
+            max_depth: opts.max_depth,
+            filter,
+            record_filtered: opts.url_filters.as_ref().is_some_and(|f| f.record_filtered),
+            normalizer: UrlNormalizer::new(opts),
+            locales: Locales::new(opts)?,
+        })
+    }
+
+    /// Registers links found on a page
+    ///
+    /// Links are normalized first (see [`crate::canonical`]). Links rejected by URL filters are not
+    /// stored at all (unless `record_filtered` is set), links deeper than `max_depth` are registered
+    /// as skipped. Link is registered once per language if page type has several languages (see
+    /// [`crate::locale`]).
+    pub async fn register(
+        &self,
+        storage: &mut dyn Storage,
+        links: Vec<Link<Url>>,
+        depth: u16,
+    ) -> Result<RegisteredLinks> {
+        let mut result = RegisteredLinks::default();
+        for mut link in links {
+            link.url = self.normalizer.normalize(link.url);
+            if !self.filter.is_allowed(&link.url) {
+                result.filtered += 1;
+                if self.record_filtered {
+                    storage
+                        .register_skipped_link(link, depth, SkipReason::Pattern)
+                        .await?;
+                }
+                continue;
+            }
+            for link in self.locales.variants(link) {
+                if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+                    storage
+                        .register_skipped_link(link, depth, SkipReason::Depth)
+                        .await?;
+                } else if storage.register_link(link, depth).await?.is_some() {
+                    result.new += 1;
                 }
             }
         }
-        Ok(None) => {}
-        Err(e) => error!("next_pages() method failed on page #{}: {}", page.id, e),
+        Ok(result)
     }
-    Ok(())
 }
 
-fn create_http_client(opts: &CrawlerConfig, proxy: Option<Proxy>) -> Result<Client> {
-    let mut builder = Client::builder();
+/// Redirect response is returned by the client only if the redirect policy refused to follow it
+fn is_abandoned_redirect(meta: &ResponseMeta) -> bool {
+    let redirection = StatusCode::from_u16(meta.status).is_ok_and(|s| s.is_redirection());
+    redirection && meta.header(LOCATION.as_str()).is_some()
+}
+
+/// URLs requested while following redirects of the last request made by a client
+pub(crate) type RedirectChain = Arc<Mutex<Vec<Url>>>;
+
+/// Returns client builder which records redirects followed by a request in the returned chain
+///
+/// Redirects not allowed by the policy are not followed, the redirect response itself is returned
+/// instead (see [`is_abandoned_redirect()`]).
+pub(crate) fn redirecting_client(policy: RedirectPolicy) -> (ClientBuilder, RedirectChain) {
+    let redirects = RedirectChain::default();
+    let chain = redirects.clone();
+    let builder = Client::builder().redirect(Policy::custom(move |attempt| {
+        *chain.lock().unwrap() = attempt.previous().to_vec();
+        if !policy.allows(attempt.previous(), attempt.url()) {
+            return attempt.stop();
+        }
+        attempt.follow()
+    }));
+    (builder, redirects)
+}
+
+fn create_http_client(
+    opts: &CrawlerConfig,
+    proxy: Option<Proxy>,
+    identity: Option<&Identity>,
+    redirect_policy: &RedirectPolicy,
+) -> Result<(Client, RedirectChain)> {
+    let (mut builder, redirects) = redirecting_client(redirect_policy.clone());
     if let Some(proxy) = proxy {
         builder = builder.proxy(proxy);
     }
+    if let Some(identity) = identity {
+        builder = builder.identity(identity.clone());
+    }
+    if let Some(address) = opts.local_address {
+        builder = builder.local_address(address);
+    }
+    if let Some(user_agent) = user_agent(opts) {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(ttl) = opts.dns_cache_ttl_sec {
+        let cache = DnsCache::new(Duration::from_secs_f32(ttl));
+        builder = builder.dns_resolver(Arc::new(cache));
+    }
+    if let Some(from) = &opts.from {
+        let headers = HeaderMap::from_iter([(FROM, HeaderValue::from_str(from)?)]);
+        builder = builder.default_headers(headers);
+    }
     let connect_timeout = opts.connect_timeout_sec.unwrap_or(5.0);
     let read_timeout = opts.read_timeout_sec.unwrap_or(5.0);
     let client = builder
@@ -159,13 +1340,42 @@ fn create_http_client(opts: &CrawlerConfig, proxy: Option<Proxy>) -> Result<Clie
         .timeout(Duration::from_secs_f32(read_timeout))
         .danger_accept_invalid_certs(true)
         .build()?;
-    Ok(client)
+    Ok((client, redirects))
+}
+
+/// Loads client certificate if mutual TLS is configured
+pub(crate) fn client_identity(opts: &CrawlerConfig) -> Result<Option<Identity>> {
+    opts.client_identity
+        .as_ref()
+        .map(ClientIdentityConfig::load)
+        .transpose()
 }
 
-async fn fetch_content(client: Client, url: &Url, delay: Duration) -> Result<String> {
+/// Builds `User-Agent` identifying the crawler and its operator
+pub(crate) fn user_agent(opts: &CrawlerConfig) -> Option<String> {
+    let default = || format!("crab/{}", env!("CARGO_PKG_VERSION"));
+    match (&opts.user_agent, &opts.contact) {
+        (Some(user_agent), None) => Some(user_agent.clone()),
+        (user_agent, Some(contact)) => Some(format!(
+            "{} (+{})",
+            user_agent.clone().unwrap_or_else(default),
+            contact
+        )),
+        (None, None) => None,
+    }
+}
+
+async fn fetch_content(
+    auth: &AuthRules,
+    request: RequestBuilder,
+    redirects: &RedirectChain,
+    url: &Url,
+    limits: &BodyLimits,
+    delay: Duration,
+) -> Result<(String, ResponseMeta)> {
     trace!("Starting: {}", url);
     let instant = Instant::now();
-    let response = download(client, url.as_ref()).await;
+    let response = download(auth, request, redirects, url, limits).await;
     if response.is_ok() {
         let duration = instant.elapsed();
         trace!("Downloaded in {:.1}s: {}", duration.as_secs_f32(), &url);
@@ -174,6 +1384,536 @@ async fn fetch_content(client: Client, url: &Url, delay: Duration) -> Result<Str
     response
 }
 
-async fn download(client: Client, url: &str) -> Result<String> {
-    Ok(client.get(url).send().await?.text().await?)
+/// Makes a single request to a URL with the crawler client configuration and page type headers
+///
+/// Response is returned as is, without validation and throttling, so it can be inspected when
+/// crawler is blocked (see `crab fetch`).
+pub async fn fetch(
+    opts: &CrawlerConfig,
+    auth: &AuthRules,
+    url: &Url,
+    type_id: Option<PageTypeId>,
+    proxy: Option<Proxy>,
+) -> Result<(String, ResponseMeta)> {
+    let identity = client_identity(opts)?;
+    let redirect_policy = RedirectPolicy::new(opts.redirects.as_ref(), opts.url_filters.as_ref())?;
+    let (client, redirects) = create_http_client(opts, proxy, identity.as_ref(), &redirect_policy)?;
+    let headers = request_headers(opts)?;
+    let mut type_headers = HeaderMap::new();
+    if let Some(type_id) = type_id {
+        type_headers = headers.get(&type_id).cloned().unwrap_or_default();
+        let locales = Locales::new(opts)?;
+        locales.apply(url, type_id, &mut type_headers)?;
+    }
+    let request = client.get(url.clone()).headers(type_headers);
+    let limits = BodyLimits {
+        max_bytes: opts.max_body_bytes,
+        ..BodyLimits::default()
+    };
+    download(auth, request, &redirects, url, &limits).await
+}
+
+async fn download(
+    auth: &AuthRules,
+    request: RequestBuilder,
+    redirects: &RedirectChain,
+    url: &Url,
+    limits: &BodyLimits,
+) -> Result<(String, ResponseMeta)> {
+    let sent_at = Instant::now();
+    let response = auth.send(url, request).await?;
+    read_response(response, redirects, url, sent_at, limits).await
+}
+
+/// Limits on response bodies read by crawler
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct BodyLimits {
+    /// body is truncated once it's larger than this
+    pub(crate) max_bytes: Option<usize>,
+    /// body is not read if response content type is not one of these, eg. `text/html` or `text/*`
+    /// (all content types are allowed if empty)
+    pub(crate) content_types: Vec<String>,
+    /// what is done with responses of non-text content types
+    #[serde(default)]
+    pub(crate) non_text: NonTextContent,
+}
+
+/// What crawler does with responses of non-text content types (images, PDFs, archives etc.)
+///
+/// Text types are `text/*`, JSON, XML and JavaScript. Responses without `Content-Type` are
+/// considered text.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum NonTextContent {
+    /// body is downloaded and processed as any other page
+    #[default]
+    Store,
+    /// body is not downloaded, page is marked as skipped with `content-type` reason
+    Skip,
+    /// same as `skip`, page is tagged `non-text` as well
+    Mark,
+}
+
+/// Tag of pages skipped with [`NonTextContent::Mark`]
+pub const NON_TEXT_TAG: &str = "non-text";
+
+impl BodyLimits {
+    pub(crate) fn new(opts: &CrawlerConfig) -> Self {
+        Self {
+            max_bytes: opts.max_body_bytes,
+            content_types: opts.allowed_content_types.clone().unwrap_or_default(),
+            non_text: opts.non_text_content,
+        }
+    }
+
+    /// Returns `false` if the body of a response with a given content type is not needed
+    pub(crate) fn reads_body(&self, content_type: Option<&str>) -> bool {
+        self.allows_content_type(content_type)
+            && (self.non_text == NonTextContent::Store || is_text_content_type(content_type))
+    }
+
+    /// Responses without `Content-Type` are always allowed
+    pub(crate) fn allows_content_type(&self, content_type: Option<&str>) -> bool {
+        let Some(content_type) = content_type.filter(|_| !self.content_types.is_empty()) else {
+            return true;
+        };
+        let essence = essence(content_type);
+        self.content_types.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_suffix('*') {
+                Some(prefix) => essence.starts_with(prefix),
+                None => essence == allowed,
+            }
+        })
+    }
+}
+
+/// Media type of a content type without parameters, eg. `text/html` for `text/html; charset=utf-8`
+fn essence(content_type: &str) -> String {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence.trim().to_ascii_lowercase()
+}
+
+/// Returns `true` if content of a given type is text (responses without type are considered text)
+pub(crate) fn is_text_content_type(content_type: Option<&str>) -> bool {
+    let Some(content_type) = content_type else {
+        return true;
+    };
+    let essence = essence(content_type);
+    let (kind, subtype) = essence.split_once('/').unwrap_or((&essence, ""));
+    kind == "text"
+        || matches!(subtype, "json" | "xml" | "javascript" | "ecmascript")
+        || subtype.ends_with("+json")
+        || subtype.ends_with("+xml")
+}
+
+/// Reads response content and metadata
+///
+/// Body is read in chunks and reading stops once it's larger than [`BodyLimits::max_bytes`], so
+/// the content is truncated and [`ResponseMeta::truncated`] is set. Body of a response with content
+/// type not needed (see [`BodyLimits::reads_body()`]) is not read at all. Request is timed from `sent_at`.
+pub(crate) async fn read_response(
+    mut response: Response,
+    redirects: &RedirectChain,
+    url: &Url,
+    sent_at: Instant,
+    limits: &BodyLimits,
+) -> Result<(String, ResponseMeta)> {
+    let ttfb = sent_at.elapsed();
+    // Chain is left from the first request if it's repeated (eg. with refreshed access token)
+    // without redirects
+    let redirects = match response.url() == url {
+        true => vec![],
+        false => redirects.lock().unwrap().clone(),
+    };
+    let headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.to_string(), value)
+        })
+        .collect();
+    let mut meta = ResponseMeta {
+        status: response.status().as_u16(),
+        final_url: response.url().clone(),
+        content_type: response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from),
+        headers,
+        redirects,
+        truncated: false,
+        timing: None,
+    };
+    let mut body = vec![];
+    if !limits.reads_body(meta.content_type.as_deref()) {
+        // Dropping the response closes the connection, so the body is not downloaded
+        meta.timing = Some(RequestTiming {
+            ttfb_ms: ttfb.as_millis() as u32,
+            total_ms: sent_at.elapsed().as_millis() as u32,
+            body_bytes: 0,
+        });
+        return Ok((String::new(), meta));
+    }
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if let Some(max) = limits.max_bytes.filter(|max| body.len() > *max) {
+            // Dropping the response closes the connection, so the rest is not downloaded
+            body.truncate(max);
+            meta.truncated = true;
+            break;
+        }
+    }
+    meta.timing = Some(RequestTiming {
+        ttfb_ms: ttfb.as_millis() as u32,
+        total_ms: sent_at.elapsed().as_millis() as u32,
+        body_bytes: body.len() as u64,
+    });
+    let content = decode_body(&body, meta.content_type.as_deref());
+    Ok((content, meta))
+}
+
+/// Decodes response body using `charset` of the content type (UTF-8 by default)
+fn decode_body(body: &[u8], content_type: Option<&str>) -> String {
+    let charset = content_type
+        .into_iter()
+        .flat_map(|value| value.split(';'))
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim_matches('"'));
+    let encoding = charset
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+    let (content, _, _) = encoding.decode(body);
+    content.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::SqliteStorage, RequestSpec};
+
+    #[test]
+    fn retries_with_exponential_backoff() {
+        let mut retries = Retries {
+            max_retries: 2,
+            backoff: Duration::from_secs(10),
+            attempts: HashMap::new(),
+            waiting: vec![],
+        };
+        let page = Page::new(1, Url::parse("http://test.com").unwrap(), 1);
+
+        assert_eq!(retries.failed(page.clone()), Some(Duration::from_secs(10)));
+        assert!(retries.is_waiting(&page));
+        assert!(retries.take_ready().is_empty());
+        let delay = retries.next_ready_in().unwrap();
+        assert!(delay > Duration::from_secs(9) && delay <= Duration::from_secs(10));
+
+        retries.waiting.clear();
+        assert_eq!(retries.failed(page.clone()), Some(Duration::from_secs(20)));
+        let delay = retries.next_ready_in().unwrap();
+        assert!(delay > Duration::from_secs(19) && delay <= Duration::from_secs(20));
+
+        retries.waiting.clear();
+        assert_eq!(retries.failed(page.clone()), None);
+        assert_eq!(retries.len(), 0);
+    }
+
+    #[test]
+    fn conditional_request_headers() -> Result<()> {
+        let meta = ResponseMeta {
+            status: 200,
+            final_url: Url::parse("http://test.com")?,
+            content_type: None,
+            headers: vec![
+                ("ETag".into(), "\"abc\"".into()),
+                (
+                    "last-modified".into(),
+                    "Wed, 21 Oct 2015 07:28:00 GMT".into(),
+                ),
+            ],
+            redirects: vec![],
+            truncated: false,
+            timing: None,
+        };
+        let request = conditional_request(Client::new().get("http://test.com"), &meta).build()?;
+        let headers = request.headers();
+        assert_eq!(headers[IF_NONE_MATCH], "\"abc\"");
+        assert_eq!(headers[IF_MODIFIED_SINCE], "Wed, 21 Oct 2015 07:28:00 GMT");
+        Ok(())
+    }
+
+    #[test]
+    fn head_response_is_compared_with_stored_one() -> Result<()> {
+        let meta = |headers: &[(&str, &str)]| -> Result<ResponseMeta> {
+            Ok(ResponseMeta {
+                status: 200,
+                final_url: Url::parse("http://test.com")?,
+                content_type: None,
+                headers: headers
+                    .iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect(),
+                redirects: vec![],
+                truncated: false,
+                timing: None,
+            })
+        };
+        let head = |headers: &[(HeaderName, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+            }
+            map
+        };
+
+        let stored = meta(&[("etag", "\"abc\""), ("content-length", "10")])?;
+        assert!(is_unchanged(&stored, &head(&[(ETAG, "\"abc\"")])));
+        assert!(!is_unchanged(
+            &stored,
+            &head(&[(ETAG, "\"def\""), (CONTENT_LENGTH, "10")])
+        ));
+
+        let stored = meta(&[("Content-Length", "10")])?;
+        assert!(is_unchanged(&stored, &head(&[(CONTENT_LENGTH, "10")])));
+        assert!(!is_unchanged(&stored, &head(&[(CONTENT_LENGTH, "11")])));
+        assert!(!is_unchanged(
+            &stored,
+            &head(&[(CONTENT_LENGTH, "10"), (LAST_MODIFIED, "today")])
+        ));
+        assert!(!is_unchanged(&meta(&[])?, &head(&[])));
+        Ok(())
+    }
+
+    #[test]
+    fn per_page_type_request_headers() -> Result<()> {
+        let mut opts = crate::CrabConfig::default_config().crawler;
+        let headers = [("Accept".to_string(), "application/json".to_string())];
+        opts.headers = Some(HashMap::from([("2".into(), headers.into())]));
+        let headers = request_headers(&opts)?;
+        assert_eq!(headers[&2]["accept"], "application/json");
+        assert!(!headers.contains_key(&1));
+
+        opts.headers = Some(HashMap::from([("page".into(), HashMap::new())]));
+        assert!(request_headers(&opts).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn user_agent_with_contact() {
+        let mut opts = crate::CrabConfig::default_config().crawler;
+        assert_eq!(user_agent(&opts), None);
+
+        opts.contact = Some("https://example.com/bot".into());
+        let expected = format!(
+            "crab/{} (+https://example.com/bot)",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(user_agent(&opts), Some(expected));
+
+        opts.user_agent = Some("MyBot/1.0".into());
+        assert_eq!(
+            user_agent(&opts).as_deref(),
+            Some("MyBot/1.0 (+https://example.com/bot)")
+        );
+    }
+
+    #[test]
+    fn form_request() -> Result<()> {
+        let page = Page {
+            depth: 1,
+            request: Some(RequestSpec {
+                method: "POST".into(),
+                form: vec![
+                    ("q".into(), "rust crab".into()),
+                    ("page".into(), "2".into()),
+                ],
+                headers: vec![
+                    ("X-Requested-With".into(), "XMLHttpRequest".into()),
+                    ("Cookie".into(), "lang=de".into()),
+                ],
+            }),
+            ..Page::new(1, Url::parse("http://test.com/search")?, 2)
+        };
+        let type_headers = HeaderMap::from_iter([
+            (
+                HeaderName::from_static("accept"),
+                HeaderValue::from_static("text/html"),
+            ),
+            (COOKIE, HeaderValue::from_static("sid=abc")),
+        ]);
+        let request = page_request(&Client::new(), &page, Some(&type_headers))?.build()?;
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.headers()["accept"], "text/html");
+        assert_eq!(request.headers()["x-requested-with"], "XMLHttpRequest");
+        assert_eq!(request.headers()[COOKIE], "sid=abc; lang=de");
+        let body = request.body().and_then(|b| b.as_bytes());
+        assert_eq!(body, Some(&b"q=rust+crab&page=2"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn body_is_decoded_using_charset() {
+        let body = b"\xcf\xf0\xe8\xe2\xe5\xf2";
+        let content_type = Some("text/html; charset=\"windows-1251\"");
+        assert_eq!(decode_body(body, content_type), "Привет");
+        assert_eq!(decode_body("Привет".as_bytes(), None), "Привет");
+    }
+
+    #[tokio::test]
+    async fn download_failures_are_categorized() -> Result<()> {
+        let error = Client::new().get("http://127.0.0.1:1/").send().await;
+        let error = anyhow::Error::from(error.unwrap_err());
+        assert_eq!(download_failure(&error).0, FailureReason::Connect);
+        let error = anyhow::Error::from(AppError::WorkerTimeout);
+        assert_eq!(download_failure(&error).0, FailureReason::Timeout);
+        let error = anyhow::anyhow!("unknown");
+        assert_eq!(download_failure(&error).0, FailureReason::Other);
+
+        let (reason, message) = invalid_content_failure(403);
+        assert_eq!(reason, FailureReason::HttpStatus);
+        assert_eq!(message, "HTTP 403 Forbidden");
+        assert_eq!(invalid_content_failure(200).0, FailureReason::Invalid);
+        Ok(())
+    }
+
+    #[test]
+    fn content_types_are_filtered() {
+        let limits = BodyLimits {
+            max_bytes: None,
+            content_types: vec!["text/html".into(), "application/*".into()],
+            ..BodyLimits::default()
+        };
+        assert!(limits.allows_content_type(Some("text/html; charset=utf-8")));
+        assert!(limits.allows_content_type(Some("Application/JSON")));
+        assert!(limits.allows_content_type(None));
+        assert!(!limits.allows_content_type(Some("text/plain")));
+        assert!(!limits.allows_content_type(Some("video/mp4")));
+        assert!(BodyLimits::default().allows_content_type(Some("video/mp4")));
+    }
+
+    #[test]
+    fn non_text_bodies_are_not_read() {
+        let limits = BodyLimits {
+            content_types: vec!["text/html".into(), "application/*".into()],
+            non_text: NonTextContent::Skip,
+            ..BodyLimits::default()
+        };
+        assert!(limits.reads_body(Some("text/html; charset=utf-8")));
+        assert!(limits.reads_body(Some("application/json")));
+        assert!(limits.reads_body(Some("application/ld+json")));
+        assert!(limits.reads_body(None));
+        assert!(!limits.reads_body(Some("application/pdf")));
+        assert!(!limits.reads_body(Some("text/plain")));
+        assert!(BodyLimits::default().reads_body(Some("application/pdf")));
+
+        assert!(is_text_content_type(Some("Text/Plain")));
+        assert!(is_text_content_type(Some("image/svg+xml")));
+        assert!(!is_text_content_type(Some("image/png")));
+        assert!(!is_text_content_type(Some("application/octet-stream")));
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_truncated() -> Result<()> {
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Server,
+        };
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_| async {
+                Ok::<_, hyper::Error>(hyper::Response::new(Body::from("a".repeat(100_000))))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse()?).serve(make_service);
+        let url = Url::parse(&format!("http://{}/", server.local_addr()))?;
+        tokio::spawn(server);
+
+        let (client, redirects) = redirecting_client(RedirectPolicy::default());
+        let client = client.build()?;
+        let response = client.get(url.clone()).send().await?;
+        let limits = BodyLimits {
+            max_bytes: Some(1000),
+            ..BodyLimits::default()
+        };
+        let (content, meta) =
+            read_response(response, &redirects, &url, Instant::now(), &limits).await?;
+        assert_eq!(content.len(), 1000);
+        assert!(meta.truncated);
+
+        let response = client.get(url.clone()).send().await?;
+        let (content, meta) = read_response(
+            response,
+            &redirects,
+            &url,
+            Instant::now(),
+            &Default::default(),
+        )
+        .await?;
+        assert_eq!(content.len(), 100_000);
+        assert!(!meta.truncated);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn links_deeper_than_max_depth_are_skipped() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.sqlite");
+        fs::File::create(&path)?;
+        crate::storage::migrate(&path)?;
+        let mut storage = SqliteStorage::new(&path).await?;
+
+        let mut opts = crate::CrabConfig::default_config().crawler;
+        opts.max_depth = Some(1);
+        let link = |path: &str| Link {
+            url: Url::parse("http://test.com").unwrap().join(path).unwrap(),
+            type_id: 1,
+            request: None,
+        };
+        let rules = LinkRules::new(&opts)?;
+        let registered = rules.register(&mut storage, vec![link("a"), link("b")], 1);
+        assert_eq!(registered.await?.new, 2);
+        let registered = rules.register(&mut storage, vec![link("c")], 2);
+        assert_eq!(registered.await?.new, 0);
+
+        assert_eq!(storage.list_not_downloaded_pages(10).await?.len(), 2);
+        let skipped = storage.list_skipped_pages().await?;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0.url.path(), "/c");
+        assert_eq!(skipped[0].1, SkipReason::Depth);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn filtered_links_are_recorded() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("db.sqlite");
+        fs::File::create(&path)?;
+        crate::storage::migrate(&path)?;
+        let mut storage = SqliteStorage::new(&path).await?;
+
+        let mut opts = crate::CrabConfig::default_config().crawler;
+        opts.url_filters = Some(crate::UrlFilterConfig {
+            include: vec![],
+            exclude: vec!["/private".into()],
+            record_filtered: true,
+        });
+        let link = |path: &str| Link {
+            url: Url::parse("http://test.com").unwrap().join(path).unwrap(),
+            type_id: 1,
+            request: None,
+        };
+        let rules = LinkRules::new(&opts)?;
+        let registered = rules
+            .register(&mut storage, vec![link("a"), link("private")], 1)
+            .await?;
+        assert_eq!((registered.new, registered.filtered), (1, 1));
+
+        let skipped = storage.list_skipped_pages().await?;
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0.url.path(), "/private");
+        assert_eq!(skipped[0].1, SkipReason::Pattern);
+        Ok(())
+    }
 }
@@ -0,0 +1,192 @@
+//! Database location given in `crab.toml`
+//!
+//! ```toml
+//! database = "./db.sqlite"
+//! # or
+//! database = "sqlite://./db.sqlite?mode=rwc&cache=shared"
+//! # or (with `postgres` feature)
+//! database = "postgres://crab@localhost/crab"
+//! ```
+//!
+//! Location is either a path to SQLite file or a connection URL. URL parameters (`mode`, `cache`,
+//! `immutable`, `vfs`) are passed to SQLite as is, file name may be percent-encoded. PostgreSQL
+//! URLs are accepted only if crab is built with `postgres` feature (see `postgres` module), URLs
+//! with other schemes are rejected.
+use crate::{prelude::*, secrets};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "postgres")]
+use sqlx::postgres::PgConnectOptions;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DatabaseUrl {
+    /// location as given in config
+    url: String,
+    /// path to the database file
+    path: PathBuf,
+    /// `true` if location is given as connection URL
+    is_url: bool,
+}
+
+impl DatabaseUrl {
+    /// `true` if the database is PostgreSQL one
+    pub fn is_postgres(&self) -> bool {
+        scheme(&self.url).is_some_and(is_postgres_scheme)
+    }
+
+    /// Path to the database file
+    ///
+    /// Used where database is opened bypassing connection options (migrations, shards). Empty for
+    /// PostgreSQL database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the password of connection URL with a placeholder, so location can be shown
+    pub fn redact_password(&mut self) {
+        if self.is_url {
+            self.url = secrets::redact_url_password(&self.url);
+        }
+    }
+
+    /// Connection options built from the location
+    pub fn connect_options(&self) -> Result<SqliteConnectOptions> {
+        if self.is_url {
+            Ok(SqliteConnectOptions::from_str(&self.url)?)
+        } else {
+            Ok(SqliteConnectOptions::new().filename(&self.path))
+        }
+    }
+
+    /// Connection options of PostgreSQL database
+    #[cfg(feature = "postgres")]
+    pub fn pg_connect_options(&self) -> Result<PgConnectOptions> {
+        Ok(PgConnectOptions::from_str(&self.url)?)
+    }
+}
+
+impl FromStr for DatabaseUrl {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let Some(scheme) = scheme(url) else {
+            return Ok(Self {
+                url: url.to_string(),
+                path: PathBuf::from(url),
+                is_url: false,
+            });
+        };
+        #[cfg(feature = "postgres")]
+        if is_postgres_scheme(scheme) {
+            PgConnectOptions::from_str(url)?;
+            return Ok(Self {
+                url: url.to_string(),
+                path: PathBuf::new(),
+                is_url: true,
+            });
+        }
+        if scheme != "sqlite" {
+            return Err(AppError::UnsupportedDatabaseUrl(url.to_string()).into());
+        }
+        // validating parameters upfront, so config errors are reported when config is read
+        SqliteConnectOptions::from_str(url)?;
+        let file = url
+            .trim_start_matches("sqlite://")
+            .trim_start_matches("sqlite:");
+        let file = file.split_once('?').map_or(file, |(file, _)| file);
+        let path = PathBuf::from(&*percent_decode_str(file).decode_utf8()?);
+        Ok(Self {
+            url: url.to_string(),
+            path,
+            is_url: true,
+        })
+    }
+}
+
+impl TryFrom<String> for DatabaseUrl {
+    type Error = anyhow::Error;
+
+    fn try_from(url: String) -> Result<Self> {
+        url.parse()
+    }
+}
+
+impl From<DatabaseUrl> for String {
+    fn from(value: DatabaseUrl) -> Self {
+        value.url
+    }
+}
+
+impl fmt::Display for DatabaseUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
+/// Returns URL scheme, `None` if location is a file path
+///
+/// Single letter schemes are not considered, so Windows paths (`C:\db.sqlite`) are not URLs.
+fn scheme(url: &str) -> Option<&str> {
+    let (scheme, rest) = url.split_once(':')?;
+    let is_scheme = scheme.len() > 1
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    (is_scheme && (scheme == "sqlite" || rest.starts_with("//"))).then_some(scheme)
+}
+
+fn is_postgres_scheme(scheme: &str) -> bool {
+    scheme == "postgres" || scheme == "postgresql"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_path() -> Result<()> {
+        let url = DatabaseUrl::from_str("./db.sqlite")?;
+        assert_eq!(url.path(), Path::new("./db.sqlite"));
+
+        let url = DatabaseUrl::from_str("C:\\crab\\db.sqlite")?;
+        assert_eq!(url.path(), Path::new("C:\\crab\\db.sqlite"));
+        Ok(())
+    }
+
+    #[test]
+    fn sqlite_url() -> Result<()> {
+        let url = DatabaseUrl::from_str("sqlite://./db.sqlite?mode=rwc&cache=shared")?;
+        assert_eq!(url.path(), Path::new("./db.sqlite"));
+        url.connect_options()?;
+
+        let url = DatabaseUrl::from_str("sqlite:data/crab%3F.sqlite")?;
+        assert_eq!(url.path(), Path::new("data/crab?.sqlite"));
+        assert_eq!(url.to_string(), "sqlite:data/crab%3F.sqlite");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_urls() {
+        assert!(DatabaseUrl::from_str("sqlite://db.sqlite?mode=unknown").is_err());
+        #[cfg(not(feature = "postgres"))]
+        assert!(DatabaseUrl::from_str("postgres://localhost/crab").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "postgres")]
+    fn postgres_url() -> Result<()> {
+        let url = DatabaseUrl::from_str("postgres://crab@localhost:5432/crab")?;
+        assert!(url.is_postgres());
+        url.pg_connect_options()?;
+        assert!(!DatabaseUrl::from_str("./db.sqlite")?.is_postgres());
+        Ok(())
+    }
+}
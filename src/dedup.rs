@@ -0,0 +1,211 @@
+//! Detection of pages with duplicate content
+//!
+//! ```toml
+//! [crawler]
+//! detect_duplicates = "near"
+//! ```
+//!
+//! With `exact` detection a page is a duplicate if its content is identical to the content of
+//! another page. With `near` detection pages which text differs only slightly (eg. in a timestamp
+//! or a counter) are duplicates as well: text of each page is reduced to a 64-bit [`simhash`] and
+//! pages which simhashes differ in at most [`MAX_DISTANCE`] bits are considered the same.
+//!
+//! Duplicate pages are still stored, but their links are not followed and they can be excluded
+//! from exports (`crab export-table --skip-duplicates`).
+use crate::{html::to_text, prelude::*, storage::Storage, CrawlerConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Maximum number of different bits in simhashes of near-duplicate pages
+pub const MAX_DISTANCE: u32 = 3;
+
+/// Number of words in a feature text is split into
+const SHINGLE_SIZE: usize = 3;
+
+/// Simhash is split in this many bands, so near-duplicates have at least one band in common
+const BANDS: u32 = MAX_DISTANCE + 1;
+const BAND_BITS: u32 = u64::BITS / BANDS;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateDetection {
+    #[default]
+    Off,
+    /// content is identical
+    Exact,
+    /// text of pages is identical or almost identical
+    Near,
+}
+
+/// Returns simhash of the page text, `None` if page has too little text to be compared
+///
+/// Each [`SHINGLE_SIZE`] consecutive words of the text are a feature. Every bit of the simhash is
+/// set if the same bit is set in hashes of the majority of features, so similar texts have
+/// simhashes differing in a few bits only.
+pub fn simhash(content: &str) -> Result<Option<u64>> {
+    let text = to_text(content)?.to_lowercase();
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>();
+    if words.len() < SHINGLE_SIZE {
+        return Ok(None);
+    }
+    let mut weights = [0i64; u64::BITS as usize];
+    for shingle in words.windows(SHINGLE_SIZE) {
+        let hash = fnv1a(shingle);
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash & (1 << bit) != 0 { 1 } else { -1 };
+        }
+    }
+    let hash = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0u64, |hash, (bit, _)| hash | (1 << bit));
+    Ok(Some(hash))
+}
+
+/// Number of bits two simhashes differ in
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// 64-bit FNV-1a hash of words, stable across runs and Rust versions, so it can be stored
+fn fnv1a(words: &[&str]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for (i, word) in words.iter().enumerate() {
+        let separator = if i > 0 { " " } else { "" };
+        for byte in separator.bytes().chain(word.bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Simhashes of stored pages indexed by bands for looking up near-duplicates
+#[derive(Debug, Default)]
+pub struct DuplicateIndex {
+    detection: DuplicateDetection,
+    /// (band number, band bits) → pages having these bits in the band
+    bands: HashMap<(u32, u64), Vec<i64>>,
+    /// page → its simhash
+    hashes: HashMap<i64, u64>,
+}
+
+impl DuplicateIndex {
+    /// Loads simhashes of downloaded pages if near-duplicate detection is enabled
+    pub async fn load(opts: &CrawlerConfig, storage: &dyn Storage) -> Result<Self> {
+        let mut index = Self {
+            detection: opts.detect_duplicates,
+            ..Self::default()
+        };
+        if index.detection == DuplicateDetection::Near {
+            for (page_id, hash) in storage.list_simhashes().await? {
+                index.insert(page_id, hash);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Marks a downloaded page as a duplicate of an already stored page (or clears the mark if its
+    /// content is unique now), returns the id of the original page
+    pub async fn check(
+        &mut self,
+        storage: &dyn Storage,
+        page_id: i64,
+        hash: &str,
+        simhash: Option<u64>,
+    ) -> Result<Option<i64>> {
+        let original = match self.detection {
+            DuplicateDetection::Off => return Ok(None),
+            DuplicateDetection::Exact => storage.find_page_by_content_hash(hash, page_id).await?,
+            DuplicateDetection::Near => {
+                match storage.find_page_by_content_hash(hash, page_id).await? {
+                    Some(original) => Some(original),
+                    None => simhash.and_then(|simhash| self.find(page_id, simhash)),
+                }
+            }
+        };
+        storage.set_duplicate_of(page_id, original).await?;
+        match (original, simhash) {
+            (None, Some(simhash)) => self.insert(page_id, simhash),
+            _ => self.remove(page_id),
+        }
+        Ok(original)
+    }
+
+    /// Adds (or updates) simhash of a page
+    pub fn insert(&mut self, page_id: i64, hash: u64) {
+        if self.hashes.get(&page_id) == Some(&hash) {
+            return;
+        }
+        self.remove(page_id);
+        self.hashes.insert(page_id, hash);
+        for band in bands(hash) {
+            self.bands.entry(band).or_default().push(page_id);
+        }
+    }
+
+    /// Removes simhash of a page, so other pages are not considered its duplicates
+    pub fn remove(&mut self, page_id: i64) {
+        if let Some(previous) = self.hashes.remove(&page_id) {
+            for band in bands(previous) {
+                if let Some(pages) = self.bands.get_mut(&band) {
+                    pages.retain(|id| *id != page_id);
+                }
+            }
+        }
+    }
+
+    /// Finds the first page (other than a given one) which simhash is close enough to a given one
+    pub fn find(&self, page_id: i64, hash: u64) -> Option<i64> {
+        bands(hash)
+            .filter_map(|band| self.bands.get(&band))
+            .flatten()
+            .filter(|id| **id != page_id)
+            .filter(|id| distance(self.hashes[id], hash) <= MAX_DISTANCE)
+            .min()
+            .copied()
+    }
+}
+
+fn bands(hash: u64) -> impl Iterator<Item = (u32, u64)> {
+    let mask = (1u64 << BAND_BITS) - 1;
+    (0..BANDS).map(move |band| (band, (hash >> (band * BAND_BITS)) & mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn near_duplicate_pages() -> Result<()> {
+        let article = |footer: &str| {
+            format!(
+                "<html><h1>Rust 1.0 is released</h1><p>After years of development the first \
+                stable version of the language is available. Stability means code written today \
+                keeps compiling with future versions of the compiler, so libraries can finally \
+                be built on a solid foundation.</p><footer>{footer}</footer></html>"
+            )
+        };
+        let first = simhash(&article("Visitors: 1024"))?.unwrap();
+        let second = simhash(&article("Visitors: 1025"))?.unwrap();
+        let other = simhash("<p>Completely unrelated page about cooking pasta with tomatoes</p>")?;
+        assert!(distance(first, second) <= MAX_DISTANCE);
+        assert!(distance(first, other.unwrap()) > MAX_DISTANCE);
+        assert_eq!(simhash("<p>Too short</p>")?, None);
+
+        let mut index = DuplicateIndex::default();
+        index.insert(1, first);
+        assert_eq!(index.find(2, second), Some(1));
+        assert_eq!(index.find(1, first), None);
+        assert_eq!(index.find(3, other.unwrap()), None);
+
+        // page content changed, so it's not a duplicate anymore
+        index.insert(1, other.unwrap());
+        assert_eq!(index.find(2, second), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Process-wide DNS cache
+//!
+//! ```toml
+//! [crawler]
+//! dns_cache_ttl_sec = 300
+//! ```
+//!
+//! Crawler creates a new HTTP client for every request, so without the cache a host name is resolved
+//! each time a connection is made. Addresses resolved by any client are kept for a given time
+//! and reused by all clients of the process. Failed lookups are not cached.
+//!
+//! Requests made via `socks5h://` proxies are not affected: host names are resolved by the proxy.
+use hyper::client::connect::dns::Name;
+use lazy_static::lazy_static;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::net::lookup_host;
+
+lazy_static! {
+    /// host → time of resolving and resolved addresses
+    static ref ENTRIES: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>> = Mutex::default();
+}
+
+/// DNS resolver reusing addresses resolved not earlier than `ttl` ago
+pub(crate) struct DnsCache {
+    ttl: Duration,
+}
+
+impl DnsCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self { ttl }
+    }
+}
+
+impl Resolve for DnsCache {
+    fn resolve(&self, name: Name) -> Resolving {
+        let ttl = self.ttl;
+        Box::pin(async move {
+            let addrs = match cached(name.as_str(), ttl) {
+                Some(addrs) => addrs,
+                None => {
+                    // Port is replaced by the connector with the port of the URL
+                    let addrs = lookup_host((name.as_str(), 0)).await?.collect::<Vec<_>>();
+                    let entry = (Instant::now(), addrs.clone());
+                    ENTRIES
+                        .lock()
+                        .unwrap()
+                        .insert(name.as_str().to_owned(), entry);
+                    addrs
+                }
+            };
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn cached(host: &str, ttl: Duration) -> Option<Vec<SocketAddr>> {
+    let entries = ENTRIES.lock().unwrap();
+    let (resolved_at, addrs) = entries.get(host)?;
+    (resolved_at.elapsed() < ttl).then(|| addrs.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn resolved_addresses_are_reused() -> Result<()> {
+        let resolve = |ttl| async move {
+            let name = Name::from_str("localhost").unwrap();
+            DnsCache::new(ttl).resolve(name).await.unwrap().count()
+        };
+        assert!(resolve(Duration::from_secs(60)).await > 0);
+        assert!(cached("localhost", Duration::from_secs(60)).is_some());
+        assert!(cached("localhost", Duration::ZERO).is_none());
+        assert!(cached("example.com", Duration::from_secs(60)).is_none());
+        Ok(())
+    }
+}
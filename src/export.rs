@@ -0,0 +1,843 @@
+//! Transformations applied to parsed rows at export time
+use crate::{prelude::*, PageTypeId};
+use chrono::{DateTime, Duration, Months, NaiveDate, NaiveDateTime, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// Column name normalization rules
+///
+/// Scraped labels tend to drift (`"Core Count"` vs `"Cores"`, `"Weight (kg)"` vs `"Weight"`), so
+/// column names are normalized before being written to an export:
+///
+/// 1. whitespace is trimmed and collapsed;
+/// 2. units (trailing `(...)` or `[...]` groups) are stripped if `strip_units` is set;
+/// 3. name is lowercased if `fold_case` is set;
+/// 4. name is replaced using `rename` map. Keys of the map are normalized using the same rules.
+///
+/// Values of columns listed in `numeric` (canonical names) are parsed using [`parse_quantity()`]
+/// and exported as two columns: normalized number and `<column>_unit`.
+///
+/// Values of columns listed in `dates` are converted to ISO 8601 (see [`ColumnsConfig::parse_date()`]).
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct ColumnsConfig {
+    #[serde(default)]
+    pub fold_case: bool,
+
+    #[serde(default)]
+    pub strip_units: bool,
+
+    /// raw column name → canonical column name
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+
+    /// columns containing quantities like `3.6 GHz` or `€1,299.00`
+    #[serde(default)]
+    pub numeric: Vec<String>,
+
+    /// columns containing dates
+    #[serde(default)]
+    pub dates: Vec<String>,
+
+    /// `strftime`-like formats tried in order when parsing dates
+    ///
+    /// If not provided [`DEFAULT_DATE_FORMATS`] are used
+    #[serde(default)]
+    pub date_formats: Vec<String>,
+
+    /// localized month name → english month name
+    #[serde(default)]
+    pub month_names: HashMap<String, String>,
+}
+
+pub const DEFAULT_DATE_FORMATS: [&str; 8] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%d",
+    "%d.%m.%Y",
+    "%d/%m/%Y",
+    "%B %d, %Y",
+    "%b %d, %Y",
+    "%d %B %Y",
+];
+
+impl ColumnsConfig {
+    /// Returns canonical name of a column
+    pub fn normalize(&self, column: &str) -> String {
+        let name = self.normalize_name(column);
+        self.rename
+            .iter()
+            .find(|(raw, _)| self.normalize_name(raw) == name)
+            .map(|(_, canonical)| canonical.clone())
+            .unwrap_or(name)
+    }
+
+    /// Normalizes column names of a row, expands numeric columns and converts dates
+    ///
+    /// `fetched_at` is the time page was downloaded. Relative dates (`2 days ago`) are anchored to it.
+    pub fn apply(
+        &self,
+        row: impl IntoIterator<Item = (String, String)>,
+        fetched_at: DateTime<Utc>,
+    ) -> Vec<(String, String)> {
+        let mut result = vec![];
+        for (column, value) in row {
+            let column = self.normalize(&column);
+            if self.dates.contains(&column) {
+                let value = self.parse_date(&value, fetched_at).unwrap_or(value);
+                result.push((column, value));
+                continue;
+            }
+            if !self.numeric.contains(&column) {
+                result.push((column, value));
+                continue;
+            }
+            let unit_column = format!("{}_unit", column);
+            match parse_quantity(&value) {
+                Some(Quantity { value, unit }) => {
+                    result.push((column, value.to_string()));
+                    result.push((unit_column, unit.unwrap_or_default()));
+                }
+                None => {
+                    result.push((column, value));
+                    result.push((unit_column, String::new()));
+                }
+            }
+        }
+        result
+    }
+
+    /// Converts date to ISO 8601 format
+    ///
+    /// Absolute dates are parsed using RFC 3339, RFC 2822 and configured formats. Date only formats
+    /// are converted to `YYYY-MM-DD`. Dates without timezone are considered to be in UTC.
+    /// Relative dates (`just now`, `today`, `yesterday`, `5 minutes ago`) are anchored to `now`.
+    pub fn parse_date(&self, input: &str, now: DateTime<Utc>) -> Option<String> {
+        let mut input = input.split_whitespace().collect::<Vec<_>>().join(" ");
+        for (localized, english) in &self.month_names {
+            input = input.replace(localized.as_str(), english);
+        }
+
+        if let Ok(date) = DateTime::parse_from_rfc3339(&input) {
+            return Some(date.with_timezone(&Utc).to_rfc3339());
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(&input) {
+            return Some(date.with_timezone(&Utc).to_rfc3339());
+        }
+        let default_formats = DEFAULT_DATE_FORMATS.map(String::from);
+        let formats = if self.date_formats.is_empty() {
+            &default_formats[..]
+        } else {
+            &self.date_formats[..]
+        };
+        for format in formats {
+            if let Ok(date) = NaiveDateTime::parse_from_str(&input, format) {
+                return Some(date.and_utc().to_rfc3339());
+            }
+            if let Ok(date) = NaiveDate::parse_from_str(&input, format) {
+                return Some(date.format("%Y-%m-%d").to_string());
+            }
+        }
+        parse_relative_date(&input.to_lowercase(), now).map(|date| date.to_rfc3339())
+    }
+
+    fn normalize_name(&self, column: &str) -> String {
+        let mut name = column.split_whitespace().collect::<Vec<_>>().join(" ");
+        if self.strip_units {
+            name = strip_units(&name).to_string();
+        }
+        if self.fold_case {
+            name = name.to_lowercase();
+        }
+        name
+    }
+}
+
+/// Strips trailing unit groups like `(kg)` or `[GHz]` and dangling punctuation
+fn strip_units(name: &str) -> &str {
+    let mut name = name.trim_end();
+    loop {
+        let stripped = [('(', ')'), ('[', ']')]
+            .iter()
+            .find_map(|(open, close)| {
+                name.strip_suffix(*close)
+                    .and_then(|n| n.rfind(*open).map(|idx| &n[..idx]))
+            })
+            .map(|n| n.trim_end_matches([' ', ',', ':']));
+        match stripped {
+            Some(n) if !n.is_empty() => name = n,
+            _ => return name,
+        }
+    }
+}
+
+/// Currency normalization rules
+///
+/// Amounts in `columns` are converted to `target` currency and exported in additional
+/// `<column>_<target>` column (eg. `price_usd`) alongside the original one. Rates are defined as
+/// units of a currency per one unit of target currency (as most exchange rate APIs do), rates from
+/// `rates_file` and `rates_url` are overriding inline `rates`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CurrencyConfig {
+    /// ISO 4217 code of currency all amounts converted to
+    pub target: String,
+
+    /// columns containing amounts of money (canonical names)
+    pub columns: Vec<String>,
+
+    /// currency code → units of currency per one unit of target currency
+    #[serde(default)]
+    pub rates: HashMap<String, f64>,
+
+    /// path to TOML file with rates in the same format as `rates`
+    pub rates_file: Option<PathBuf>,
+
+    /// URL of JSON API returning rates relative to target currency: `{"rates": {"EUR": 0.92}}`
+    pub rates_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RatesResponse {
+    rates: HashMap<String, f64>,
+}
+
+pub struct ExchangeRates {
+    config: CurrencyConfig,
+    target_column_suffix: String,
+}
+
+impl ExchangeRates {
+    /// Loads exchange rates from all the sources configured
+    pub async fn load(mut config: CurrencyConfig) -> Result<Self> {
+        if let Some(path) = &config.rates_file {
+            let rates: HashMap<String, f64> = toml::from_str(&fs::read_to_string(path)?)?;
+            config.rates.extend(rates);
+        }
+        if let Some(url) = &config.rates_url {
+            let response: RatesResponse = reqwest::get(url).await?.json().await?;
+            config.rates.extend(response.rates);
+        }
+        config.rates.insert(config.target.clone(), 1.);
+        let target_column_suffix = config.target.to_lowercase();
+        Ok(Self {
+            config,
+            target_column_suffix,
+        })
+    }
+
+    /// Converts amount given in `currency` to target currency
+    pub fn convert(&self, amount: f64, currency: &str) -> Option<f64> {
+        let rate = self.config.rates.get(&currency.to_uppercase())?;
+        Some(amount / rate)
+    }
+
+    /// Adds converted amount columns to a row
+    ///
+    /// Currency of the amount is taken from the value itself (`€1,299.00`) or from `<column>_unit`
+    /// column if value was already expanded as numeric.
+    pub fn apply(&self, row: Vec<(String, String)>) -> Vec<(String, String)> {
+        let mut result = Vec::with_capacity(row.len());
+        for (column, value) in &row {
+            result.push((column.clone(), value.clone()));
+            if !self.config.columns.contains(column) {
+                continue;
+            }
+            let Some(Quantity { value, unit }) = parse_quantity(value) else {
+                continue;
+            };
+            let unit_column = format!("{}_unit", column);
+            let unit = unit.or_else(|| {
+                row.iter()
+                    .find(|(c, _)| *c == unit_column)
+                    .map(|(_, v)| v.clone())
+            });
+            let converted = unit.and_then(|unit| self.convert(value, &unit));
+            let converted_column = format!("{}_{}", column, self.target_column_suffix);
+            let converted = converted.map(|v| format!("{:.2}", v)).unwrap_or_default();
+            result.push((converted_column, converted));
+        }
+        result
+    }
+}
+
+fn parse_relative_date(input: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match input {
+        "just now" | "now" | "today" => return Some(now),
+        "yesterday" => return Some(now - Duration::days(1)),
+        _ => {}
+    }
+    let mut words = input.strip_suffix(" ago")?.split(' ');
+    let amount = match words.next()? {
+        "a" | "an" => 1,
+        n => n.parse().ok()?,
+    };
+    let unit = words.next()?;
+    if words.next().is_some() {
+        return None;
+    }
+    match unit.strip_suffix('s').unwrap_or(unit) {
+        "second" | "sec" => Some(now - Duration::seconds(amount)),
+        "minute" | "min" => Some(now - Duration::minutes(amount)),
+        "hour" => Some(now - Duration::hours(amount)),
+        "day" => Some(now - Duration::days(amount)),
+        "week" => Some(now - Duration::weeks(amount)),
+        "month" => now.checked_sub_months(Months::new(amount.try_into().ok()?)),
+        "year" => now.checked_sub_months(Months::new(u32::try_from(amount).ok()? * 12)),
+        _ => None,
+    }
+}
+
+/// Condition on a column value rows are filtered by on export, eg. `price>100` or `brand=AMD`
+///
+/// Supported operators are `=`, `!=`, `<`, `<=`, `>` and `>=`. If the operand is a quantity (see
+/// [`parse_quantity()`]), values are compared as numbers, so `€1,299.00` is greater than `100`.
+/// Values which are not quantities of the same unit (if the operand has one) are not comparable
+/// with such an operand and match `!=` only. Otherwise values are compared as strings. Rows
+/// without the column never match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowFilter {
+    column: String,
+    operator: Operator,
+    operand: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl RowFilter {
+    pub fn matches(&self, row: &[(String, String)]) -> bool {
+        let Some((_, value)) = row.iter().find(|(column, _)| *column == self.column) else {
+            return false;
+        };
+        let ordering = match (parse_quantity(&self.operand), parse_quantity(value)) {
+            (Some(operand), Some(value))
+                if operand.unit.is_none() || operand.unit == value.unit =>
+            {
+                value.value.partial_cmp(&operand.value)
+            }
+            (Some(_), _) => None,
+            (None, _) => Some(value.as_str().cmp(&self.operand)),
+        };
+        let Some(ordering) = ordering else {
+            return self.operator == Operator::Ne;
+        };
+        match self.operator {
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl FromStr for RowFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let invalid = || AppError::InvalidRowFilter(input.to_string());
+        let start = input.find(['=', '!', '<', '>']).ok_or_else(invalid)?;
+        let (column, rest) = input.split_at(start);
+        let (operator, operand) = [
+            ("!=", Operator::Ne),
+            ("<=", Operator::Le),
+            (">=", Operator::Ge),
+            ("=", Operator::Eq),
+            ("<", Operator::Lt),
+            (">", Operator::Gt),
+        ]
+        .into_iter()
+        .find_map(|(symbol, op)| rest.strip_prefix(symbol).map(|operand| (op, operand)))
+        .ok_or_else(invalid)?;
+        let column = column.trim();
+        if column.is_empty() {
+            return Err(invalid().into());
+        }
+        Ok(Self {
+            column: column.to_string(),
+            operator,
+            operand: operand.trim().to_string(),
+        })
+    }
+}
+
+/// Summary of exported rows grouped by values of given columns
+///
+/// Each group is summarized in a row with group columns, row count (`count`) and `sum_<column>`,
+/// `avg_<column>`, `min_<column>`, `max_<column>` of numeric columns. Values are parsed using
+/// [`parse_quantity()`] (units are ignored), values which are not quantities are skipped.
+/// Rows without a group column are grouped by empty value of the column.
+pub struct Aggregation {
+    group_by: Vec<String>,
+    count: bool,
+    functions: Vec<(Aggregate, String)>,
+    groups: BTreeMap<Vec<String>, Group>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Sum => "sum",
+            Self::Avg => "avg",
+            Self::Min => "min",
+            Self::Max => "max",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    rows: usize,
+    /// statistics of aggregated columns in the order of [`Aggregation::functions`]
+    columns: Vec<ColumnStats>,
+}
+
+#[derive(Default, Clone)]
+struct ColumnStats {
+    count: usize,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Aggregation {
+    pub fn new(group_by: Vec<String>, count: bool, functions: Vec<(Aggregate, String)>) -> Self {
+        Self {
+            group_by,
+            count,
+            functions,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, row: &[(String, String)]) {
+        let value_of = |column: &str| row.iter().find(|(c, _)| c == column).map(|(_, v)| v);
+        let key = self
+            .group_by
+            .iter()
+            .map(|column| value_of(column).cloned().unwrap_or_default())
+            .collect();
+        let group = self.groups.entry(key).or_default();
+        group.rows += 1;
+        group
+            .columns
+            .resize(self.functions.len(), ColumnStats::default());
+        for ((_, column), stats) in self.functions.iter().zip(group.columns.iter_mut()) {
+            let Some(quantity) = value_of(column).and_then(|v| parse_quantity(v)) else {
+                continue;
+            };
+            let value = quantity.value;
+            stats.count += 1;
+            stats.sum += value;
+            stats.min = Some(stats.min.map_or(value, |min| min.min(value)));
+            stats.max = Some(stats.max.map_or(value, |max| max.max(value)));
+        }
+    }
+
+    /// Summary rows ordered by group values
+    pub fn rows(self) -> Vec<Vec<(String, String)>> {
+        let format = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+        self.groups
+            .into_iter()
+            .map(|(key, group)| {
+                let mut row = self.group_by.iter().cloned().zip(key).collect::<Vec<_>>();
+                if self.count {
+                    row.push(("count".to_string(), group.rows.to_string()));
+                }
+                for ((function, column), stats) in self.functions.iter().zip(group.columns) {
+                    let value = match function {
+                        Aggregate::Sum => Some(stats.sum),
+                        Aggregate::Avg => {
+                            Some(stats.sum / stats.count as f64).filter(|_| stats.count > 0)
+                        }
+                        Aggregate::Min => stats.min,
+                        Aggregate::Max => stats.max,
+                    };
+                    row.push((format!("{}_{}", function.as_str(), column), format(value)));
+                }
+                row
+            })
+            .collect()
+    }
+}
+
+/// Size of a random sample of pages exported instead of all of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    Pages(usize),
+    /// fraction of pages in `(0, 1]`
+    Fraction(f64),
+}
+
+/// Random sample of pages of a dataset
+///
+/// Fixed size samples are chosen using reservoir sampling, so the dataset is read once and only
+/// the sample is kept in memory. Stratified sample has the given number of pages of each page type.
+pub struct Sampler<T> {
+    size: SampleSize,
+    stratify: bool,
+    rng: StdRng,
+    /// stratum → number of items seen and sampled items
+    reservoirs: BTreeMap<PageTypeId, (usize, Vec<T>)>,
+}
+
+impl<T> Sampler<T> {
+    pub fn new(size: SampleSize, stratify: bool, seed: u64) -> Self {
+        Self {
+            size,
+            stratify,
+            rng: StdRng::seed_from_u64(seed),
+            reservoirs: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, type_id: PageTypeId, item: T) {
+        let stratum = if self.stratify { type_id } else { 0 };
+        let (seen, sample) = self.reservoirs.entry(stratum).or_default();
+        *seen += 1;
+        match self.size {
+            SampleSize::Fraction(fraction) => {
+                if self.rng.gen_bool(fraction) {
+                    sample.push(item);
+                }
+            }
+            SampleSize::Pages(size) if sample.len() < size => sample.push(item),
+            SampleSize::Pages(_) => {
+                let idx = self.rng.gen_range(0..*seen);
+                if let Some(slot) = sample.get_mut(idx) {
+                    *slot = item;
+                }
+            }
+        }
+    }
+
+    pub fn into_sample(self) -> Vec<T> {
+        self.reservoirs
+            .into_values()
+            .flat_map(|(_, sample)| sample)
+            .collect()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Quantity {
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
+const CURRENCIES: [(char, &str); 6] = [
+    ('$', "USD"),
+    ('€', "EUR"),
+    ('£', "GBP"),
+    ('¥', "JPY"),
+    ('₽', "RUB"),
+    ('₹', "INR"),
+];
+
+/// Parses quantity like `3.6 GHz`, `€1,299.00` or `1.2k reviews`
+///
+/// - currency symbols are converted to ISO 4217 codes and returned as a unit;
+/// - comma is treated as a thousands separator if followed by exactly 3 digits, otherwise as a
+///   decimal separator (`3,6 GHz`);
+/// - `k`, `K`, `M`, `B` suffixes attached to the number are expanded (`1.2k` is `1200`).
+pub fn parse_quantity(input: &str) -> Option<Quantity> {
+    let input = input.trim();
+    let currency_of = |c: char| {
+        CURRENCIES
+            .iter()
+            .find(|(s, _)| *s == c)
+            .map(|(_, code)| *code)
+    };
+
+    let mut currency = input.chars().next().and_then(currency_of);
+    let mut rest = match currency {
+        Some(_) => input[input.chars().next()?.len_utf8()..].trim_start(),
+        None => input,
+    };
+
+    let negative = rest.starts_with('-');
+    if negative {
+        rest = &rest[1..];
+    }
+    let number_len = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == ',' || c == '.'))
+        .unwrap_or(rest.len());
+    let (number, mut rest) = rest.split_at(number_len);
+    let mut value = parse_number(number)?;
+
+    let multiplier = match rest.chars().next() {
+        Some('k' | 'K') => Some(1e3),
+        Some('M') => Some(1e6),
+        Some('B') => Some(1e9),
+        _ => None,
+    };
+    if let Some(multiplier) = multiplier {
+        if rest[1..].chars().next().is_none_or(char::is_whitespace) {
+            value *= multiplier;
+            rest = &rest[1..];
+        }
+    }
+    if negative {
+        value = -value;
+    }
+
+    let mut unit = rest.trim();
+    if currency.is_none() {
+        currency = unit.chars().last().and_then(currency_of);
+        if currency.is_some() && unit.chars().count() == 1 {
+            unit = "";
+        }
+    }
+    let unit = match (currency, unit) {
+        (Some(code), "") => Some(code.to_string()),
+        (_, "") => None,
+        (_, unit) => Some(unit.to_string()),
+    };
+    Some(Quantity { value, unit })
+}
+
+fn parse_number(number: &str) -> Option<f64> {
+    if !number.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let mut parts = number.split(',');
+    let mut normalized = parts.next()?.to_string();
+    for part in parts {
+        // thousands separator is always followed by exactly 3 digits
+        let digits = part.split('.').next().unwrap_or_default();
+        if digits.len() != 3 {
+            normalized.push('.');
+        }
+        normalized.push_str(part);
+    }
+    normalized.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_column_names() {
+        let config = ColumnsConfig {
+            fold_case: true,
+            strip_units: true,
+            rename: [("Core Count".to_string(), "cores".to_string())].into(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.normalize("  Core   Count "), "cores");
+        assert_eq!(config.normalize("CORE COUNT"), "cores");
+        assert_eq!(config.normalize("Weight (kg)"), "weight");
+        assert_eq!(config.normalize("Frequency, [GHz]"), "frequency");
+        assert_eq!(config.normalize("(kg)"), "(kg)");
+    }
+
+    #[test]
+    fn expand_numeric_columns() {
+        let config = ColumnsConfig {
+            numeric: vec!["price".into()],
+            ..Default::default()
+        };
+        let row = config.apply(
+            [
+                ("price".to_string(), "€1,299.00".to_string()),
+                ("name".to_string(), "Ryzen".to_string()),
+            ],
+            Utc::now(),
+        );
+        assert_eq!(
+            row,
+            [
+                ("price".to_string(), "1299".to_string()),
+                ("price_unit".to_string(), "EUR".to_string()),
+                ("name".to_string(), "Ryzen".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn convert_currencies() -> Result<()> {
+        let config = CurrencyConfig {
+            target: "USD".into(),
+            columns: vec!["price".into(), "old_price".into()],
+            rates: [("EUR".to_string(), 0.5)].into(),
+            rates_file: None,
+            rates_url: None,
+        };
+        let rates = ExchangeRates::load(config).await?;
+        let row = rates.apply(vec![
+            ("price".into(), "€10".into()),
+            ("old_price".into(), "12".into()),
+            ("old_price_unit".into(), "EUR".into()),
+        ]);
+        let expected = [
+            ("price", "€10"),
+            ("price_usd", "20.00"),
+            ("old_price", "12"),
+            ("old_price_usd", "24.00"),
+            ("old_price_unit", "EUR"),
+        ]
+        .map(|(c, v)| (c.to_string(), v.to_string()));
+        assert_eq!(row, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_dates() {
+        let config = ColumnsConfig {
+            month_names: [("января".to_string(), "January".to_string())].into(),
+            ..Default::default()
+        };
+        let now = DateTime::parse_from_rfc3339("2024-04-10T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let parse = |input| config.parse_date(input, now);
+
+        assert_eq!(parse("April 1, 2024").as_deref(), Some("2024-04-01"));
+        assert_eq!(parse("01.04.2024").as_deref(), Some("2024-04-01"));
+        assert_eq!(parse("5 января 2024").as_deref(), Some("2024-01-05"));
+        let expected = Some("2024-04-01T10:30:00+00:00");
+        assert_eq!(parse("2024-04-01 10:30").as_deref(), expected);
+        assert_eq!(parse("2024-04-01T12:30:00+02:00").as_deref(), expected);
+        let expected = Some("2024-04-08T12:00:00+00:00");
+        assert_eq!(parse("2 days ago").as_deref(), expected);
+        let expected = Some("2024-03-10T12:00:00+00:00");
+        assert_eq!(parse("a month ago").as_deref(), expected);
+        assert_eq!(parse("soon"), None);
+    }
+
+    #[test]
+    fn parse_quantities() {
+        fn q(value: f64, unit: &str) -> Option<Quantity> {
+            let unit = Some(unit.to_string()).filter(|u| !u.is_empty());
+            Some(Quantity { value, unit })
+        }
+
+        assert_eq!(parse_quantity("3.6 GHz"), q(3.6, "GHz"));
+        assert_eq!(parse_quantity("3,6 GHz"), q(3.6, "GHz"));
+        assert_eq!(parse_quantity("€1,299.00"), q(1299., "EUR"));
+        assert_eq!(parse_quantity("299 €"), q(299., "EUR"));
+        assert_eq!(parse_quantity("1.2k reviews"), q(1200., "reviews"));
+        assert_eq!(parse_quantity("1,234,567"), q(1234567., ""));
+        assert_eq!(parse_quantity("-5 °C"), q(-5., "°C"));
+        assert_eq!(parse_quantity("5 kg"), q(5., "kg"));
+        assert_eq!(parse_quantity("n/a"), None);
+    }
+
+    #[test]
+    fn filter_rows_by_values() -> Result<()> {
+        let row = |brand: &str, price: &str| {
+            vec![
+                ("brand".to_string(), brand.to_string()),
+                ("price".to_string(), price.to_string()),
+            ]
+        };
+        let expensive = RowFilter::from_str("price>100")?;
+        assert!(expensive.matches(&row("AMD", "€1,299.00")));
+        assert!(!expensive.matches(&row("AMD", "99")));
+        assert!(!expensive.matches(&row("AMD", "n/a")));
+
+        let amd = RowFilter::from_str(" brand = AMD ")?;
+        assert!(amd.matches(&row("AMD", "1")));
+        assert!(!amd.matches(&row("Intel", "1")));
+        assert!(RowFilter::from_str("brand!=AMD")?.matches(&row("Intel", "1")));
+        assert!(RowFilter::from_str("price<=5")?.matches(&row("AMD", "5.0")));
+        assert!(!RowFilter::from_str("cores>1")?.matches(&row("AMD", "1")));
+
+        let model = RowFilter::from_str("brand=5800X")?;
+        assert!(model.matches(&row("5800X", "1")));
+        assert!(!model.matches(&row("5800XT", "1")));
+
+        assert!(RowFilter::from_str("price").is_err());
+        assert!(RowFilter::from_str(">100").is_err());
+        assert!(RowFilter::from_str("price!100").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn aggregate_rows_by_group() {
+        let row = |brand: &str, price: &str| {
+            vec![
+                ("brand".to_string(), brand.to_string()),
+                ("price".to_string(), price.to_string()),
+            ]
+        };
+        let functions = vec![
+            (Aggregate::Avg, "price".to_string()),
+            (Aggregate::Max, "price".to_string()),
+        ];
+        let mut aggregation = Aggregation::new(vec!["brand".to_string()], true, functions);
+        aggregation.add(&row("Intel", "100"));
+        aggregation.add(&row("AMD", "$300"));
+        aggregation.add(&row("AMD", "200"));
+        aggregation.add(&row("AMD", "n/a"));
+        aggregation.add(&[("price".to_string(), "50".to_string())]);
+
+        let summary = |brand: &str, count: &str, avg: &str, max: &str| {
+            [
+                ("brand", brand),
+                ("count", count),
+                ("avg_price", avg),
+                ("max_price", max),
+            ]
+            .map(|(c, v)| (c.to_string(), v.to_string()))
+            .to_vec()
+        };
+        assert_eq!(
+            aggregation.rows(),
+            vec![
+                summary("", "1", "50", "50"),
+                summary("AMD", "3", "250", "300"),
+                summary("Intel", "1", "100", "100"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_pages() {
+        let mut sampler = Sampler::new(SampleSize::Pages(10), false, 1);
+        (0..1000).for_each(|i| sampler.add((i % 2) as PageTypeId, i));
+        let sample = sampler.into_sample();
+        assert_eq!(sample.len(), 10);
+        assert!(sample.iter().all(|i| (0..1000).contains(i)));
+
+        let mut sampler = Sampler::new(SampleSize::Pages(3), true, 1);
+        (0..100).for_each(|i| sampler.add(if i < 95 { 1 } else { 2 }, i));
+        let sample = sampler.into_sample();
+        assert_eq!(sample.iter().filter(|i| **i >= 95).count(), 3);
+        assert_eq!(sample.len(), 6);
+
+        let mut sampler = Sampler::new(SampleSize::Fraction(0.1), false, 1);
+        (0..10_000).for_each(|i| sampler.add(1, i));
+        assert!((800..1200).contains(&sampler.into_sample().len()));
+    }
+
+    #[test]
+    fn keep_column_names_by_default() {
+        let config = ColumnsConfig::default();
+        assert_eq!(config.normalize("Weight (kg)"), "Weight (kg)");
+    }
+}
@@ -0,0 +1,128 @@
+//! Include/exclude rules for links found by navigation rules
+//!
+//! ```toml
+//! [crawler.url_filters]
+//! include = ['^https://example\.com/']
+//! exclude = ['glob:**.pdf', '[?&]sort=']
+//! ```
+//!
+//! Patterns are regular expressions matched against the absolute URL. Patterns starting with
+//! `glob:` are globs which must match the whole URL, scheme and host included: `*` matches any
+//! characters except `/`, `**` – any characters and `?` – a single character. So `glob:*.pdf`
+//! matches nothing, while `glob:**.pdf` matches URLs ending with `.pdf`.
+use crate::{prelude::*, UrlFilterConfig};
+use regex::{Regex, RegexSet};
+use url::Url;
+
+const GLOB_PREFIX: &str = "glob:";
+
+#[derive(Debug, Default)]
+pub struct UrlFilter {
+    include: Option<RegexSet>,
+    exclude: Option<RegexSet>,
+}
+
+impl UrlFilter {
+    pub fn new(config: &UrlFilterConfig) -> Result<Self> {
+        Ok(Self {
+            include: compile(&config.include)?,
+            exclude: compile(&config.exclude)?,
+        })
+    }
+
+    /// URL is allowed if it matches any of include patterns (if any given) and none of exclude ones
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        let url = url.as_str();
+        let included = self.include.as_ref().is_none_or(|set| set.is_match(url));
+        let excluded = self.exclude.as_ref().is_some_and(|set| set.is_match(url));
+        included && !excluded
+    }
+}
+
+fn compile(patterns: &[String]) -> Result<Option<RegexSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let regexes = patterns
+        .iter()
+        .map(|pattern| match pattern.strip_prefix(GLOB_PREFIX) {
+            Some(glob) => glob_to_regex(glob),
+            None => pattern.clone(),
+        })
+        .collect::<Vec<_>>();
+    for (regex, pattern) in regexes.iter().zip(patterns) {
+        Regex::new(regex).map_err(|_| AppError::InvalidUrlPattern(pattern.clone()))?;
+    }
+    Ok(Some(RegexSet::new(regexes)?))
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> UrlFilter {
+        let config = UrlFilterConfig {
+            include: include.iter().map(|p| p.to_string()).collect(),
+            exclude: exclude.iter().map(|p| p.to_string()).collect(),
+            record_filtered: false,
+        };
+        UrlFilter::new(&config).unwrap()
+    }
+
+    fn allowed(filter: &UrlFilter, url: &str) -> bool {
+        filter.is_allowed(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn include_and_exclude_patterns() {
+        let filter = filter(&[r"^https://example\.com/"], &["glob:**.pdf", "[?&]sort="]);
+        assert!(allowed(&filter, "https://example.com/items?page=2"));
+        assert!(!allowed(&filter, "https://other.com/items"));
+        assert!(!allowed(&filter, "https://example.com/docs/manual.pdf"));
+        assert!(!allowed(
+            &filter,
+            "https://example.com/items?page=2&sort=price"
+        ));
+    }
+
+    #[test]
+    fn everything_is_allowed_without_patterns() {
+        assert!(allowed(&UrlFilter::default(), "https://example.com/"));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let filter = filter(&["glob:https://example.com/*/item-?"], &[]);
+        assert!(allowed(&filter, "https://example.com/shop/item-1"));
+        assert!(!allowed(&filter, "https://example.com/shop/sale/item-1"));
+        assert!(!allowed(&filter, "https://example.com/shop/item-12"));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let config = UrlFilterConfig {
+            include: vec!["(".into()],
+            exclude: vec![],
+            record_filtered: false,
+        };
+        assert!(UrlFilter::new(&config).is_err());
+    }
+}
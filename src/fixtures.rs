@@ -0,0 +1,247 @@
+//! Snapshot testing of parsers on a pinned set of fixture pages
+//!
+//! Each fixture consists of two files in the fixtures directory: `<name>.html` with page content
+//! and `<name>.json` with page URL, type id, depth and the canonical output of parsing rules. Tables
+//! and columns in the output are sorted, so changes in parsers are easy to review with `git diff`.
+//!
+//! `crab snapshot update` rewrites stored outputs and `crab snapshot check` fails if current
+//! parsers produce a different output, which allows to run parser regression tests on CI.
+use crate::{
+    into_owned_tables, prelude::*, storage::PageStatus, Page, PageParsers, PageTypeId, ParsedTables,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+/// Directory fixtures are stored in relative to the workspace
+pub const FIXTURES_DIR: &str = "fixtures";
+
+/// Parsing output with tables and columns in a stable order
+pub type Output = BTreeMap<String, Vec<BTreeMap<String, String>>>;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub page_id: i64,
+    pub url: String,
+    pub type_id: PageTypeId,
+    #[serde(default)]
+    pub depth: u16,
+    pub output: Output,
+}
+
+impl Snapshot {
+    /// Page the fixture was created from, passed to parsers as a context
+    fn page(&self) -> Result<Page> {
+        Ok(Page {
+            id: self.page_id,
+            url: self.url.parse()?,
+            type_id: self.type_id,
+            depth: self.depth,
+            status: PageStatus::Downloaded,
+            downloaded_at: None,
+            http_status: None,
+            request: None,
+            final_url: None,
+        })
+    }
+}
+
+/// Fixture which parser output differs from the stored one
+#[derive(Debug)]
+pub struct Mismatch {
+    pub name: String,
+    /// line diff of stored (`-`) and current (`+`) outputs
+    pub diff: String,
+}
+
+pub struct Fixtures {
+    dir: PathBuf,
+}
+
+impl Fixtures {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Adds page to fixtures, existing fixture of the same page is replaced
+    pub fn add(&self, page: &Page, content: &str, parsers: &PageParsers) -> Result<String> {
+        fs::create_dir_all(&self.dir)?;
+        let name = format!("page-{}", page.id);
+        fs::write(self.content_path(&name), content)?;
+        let snapshot = Snapshot {
+            page_id: page.id,
+            url: page.url.to_string(),
+            type_id: page.type_id,
+            depth: page.depth,
+            output: parse(parsers, page, content)?,
+        };
+        self.write_snapshot(&name, &snapshot)?;
+        Ok(name)
+    }
+
+    /// Names of all fixtures in alphabetical order
+    pub fn names(&self) -> Result<Vec<String>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Rewrites stored outputs of all fixtures with outputs of current parsers
+    ///
+    /// Returns names of fixtures which output has changed.
+    pub fn update(&self, parsers: &PageParsers) -> Result<Vec<String>> {
+        let mut changed = vec![];
+        for name in self.names()? {
+            let (mut snapshot, content) = self.read(&name)?;
+            let output = parse(parsers, &snapshot.page()?, &content)?;
+            if output != snapshot.output {
+                snapshot.output = output;
+                self.write_snapshot(&name, &snapshot)?;
+                changed.push(name);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Parses all fixtures and returns the ones which output differs from the stored one
+    pub fn check(&self, parsers: &PageParsers) -> Result<Vec<Mismatch>> {
+        let mut mismatches = vec![];
+        for name in self.names()? {
+            let (snapshot, content) = self.read(&name)?;
+            let output = parse(parsers, &snapshot.page()?, &content)?;
+            if output != snapshot.output {
+                let expected = serde_json::to_string_pretty(&snapshot.output)?;
+                let actual = serde_json::to_string_pretty(&output)?;
+                let diff = diff_lines(&expected, &actual);
+                mismatches.push(Mismatch { name, diff });
+            }
+        }
+        Ok(mismatches)
+    }
+
+    fn read(&self, name: &str) -> Result<(Snapshot, String)> {
+        let path = self.snapshot_path(name);
+        let snapshot = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str(&json)?))
+            .with_context(|| AppError::ReadingFixture(path))?;
+        let path = self.content_path(name);
+        let content = fs::read_to_string(&path).with_context(|| AppError::ReadingFixture(path))?;
+        Ok((snapshot, content))
+    }
+
+    fn write_snapshot(&self, name: &str, snapshot: &Snapshot) -> Result<()> {
+        let mut json = serde_json::to_string_pretty(snapshot)?;
+        json.push('\n');
+        fs::write(self.snapshot_path(name), json)?;
+        Ok(())
+    }
+
+    fn snapshot_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.json"))
+    }
+
+    fn content_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.html"))
+    }
+}
+
+fn parse(parsers: &PageParsers, page: &Page, content: &str) -> Result<Output> {
+    let tables = parsers
+        .parse(page, content)
+        .with_context(|| AppError::PageParserFailed(page.type_id))?;
+    Ok(canonical_output(into_owned_tables(
+        tables.unwrap_or_default(),
+    )))
+}
+
+pub fn canonical_output(tables: ParsedTables) -> Output {
+    tables
+        .into_iter()
+        .map(|(name, rows)| {
+            let rows = rows.into_iter().map(BTreeMap::from_iter).collect();
+            (name, rows)
+        })
+        .collect()
+}
+
+/// Line diff based on the longest common subsequence, common lines are prefixed with a space
+fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected = expected.lines().collect::<Vec<_>>();
+    let actual = actual.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] – length of the longest common subsequence of expected[i..] and actual[j..]
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        let line = if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+            format!("  {}", expected[i - 1])
+        } else if i < expected.len() && (j == actual.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            i += 1;
+            format!("- {}", expected[i - 1])
+        } else {
+            j += 1;
+            format!("+ {}", actual[j - 1])
+        };
+        diff.push_str(&line);
+        diff.push('\n');
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn output_is_sorted() -> Result<()> {
+        let row = HashMap::from([
+            ("title".to_string(), "Crab".to_string()),
+            ("price".to_string(), "10".to_string()),
+        ]);
+        let tables = HashMap::from([
+            ("products".to_string(), vec![row]),
+            ("categories".to_string(), vec![]),
+        ]);
+        let json = serde_json::to_string(&canonical_output(tables))?;
+        assert_eq!(
+            json,
+            r#"{"categories":[],"products":[{"price":"10","title":"Crab"}]}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn changed_lines_are_marked() {
+        let diff = diff_lines("a\nb\nc", "a\nc\nd");
+        assert_eq!(diff, "  a\n- b\n  c\n+ d\n");
+        let diff = diff_lines("a\nb", "a\nc");
+        assert_eq!(diff, "  a\n- b\n+ c\n");
+    }
+}
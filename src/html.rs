@@ -0,0 +1,373 @@
+//! HTML processing utilities
+use crate::{prelude::*, Link};
+use lazy_static::lazy_static;
+use lol_html::{
+    doc_comments, element, html_content::ContentType, rewrite_str, text, ElementContentHandlers,
+    RewriteStrSettings, Selector,
+};
+use regex::Regex;
+use std::{borrow::Cow, collections::HashSet};
+use url::Url;
+
+/// Elements which content is never shown to user
+const INVISIBLE_ELEMENTS: [&str; 5] = ["head", "script", "style", "noscript", "template"];
+
+/// Elements which are separated from the surrounding text by line breaks
+const BLOCK_ELEMENTS: &str = "address, article, aside, blockquote, br, dd, div, dl, dt, footer, \
+    form, h1, h2, h3, h4, h5, h6, header, hr, li, main, nav, ol, p, pre, section, table, tr, ul";
+
+/// Removes all elements matching any of given CSS selectors (as well as their content)
+pub fn strip_elements(content: &str, selectors: &[String]) -> Result<String> {
+    let mut handlers = vec![];
+    for selector in selectors {
+        let selector = selector.parse::<Selector>()?;
+        let handler = ElementContentHandlers::default().element(|el| {
+            el.remove();
+            Ok(())
+        });
+        handlers.push((Cow::Owned(selector), handler));
+    }
+    let settings = RewriteStrSettings {
+        element_content_handlers: handlers,
+        ..RewriteStrSettings::default()
+    };
+    Ok(rewrite_str(content, settings)?)
+}
+
+/// Adds `<base>` element to the document head, so relative links and resources are resolved
+/// against a given URL when document is opened from a local file
+///
+/// Documents already having `<base>` element are returned as is.
+pub fn set_base_url(content: &str, url: &url::Url) -> Result<String> {
+    if content.contains("<base ") {
+        return Ok(content.to_string());
+    }
+    let base = format!("<base href=\"{}\">", url.as_str().replace('"', "%22"));
+    // Browsers move misplaced `<base>` to the head, so documents without one are still handled
+    let selector = match (content.contains("<head"), content.contains("<html")) {
+        (true, _) => "head",
+        (false, true) => "html",
+        (false, false) => return Ok(base + content),
+    };
+    let settings = RewriteStrSettings {
+        element_content_handlers: vec![element!(selector, |el| {
+            el.prepend(&base, ContentType::Html);
+            Ok(())
+        })],
+        ..RewriteStrSettings::default()
+    };
+    Ok(rewrite_str(content, settings)?)
+}
+
+/// Converts HTML to a plain text suitable for reading in a terminal
+///
+/// Invisible elements (scripts, styles, etc.) are removed, block elements are put on separate lines.
+pub fn to_text(content: &str) -> Result<String> {
+    let selectors = INVISIBLE_ELEMENTS.map(String::from);
+    let content = strip_elements(content, &selectors)?;
+    let settings = RewriteStrSettings {
+        element_content_handlers: vec![
+            element!(BLOCK_ELEMENTS, |el| {
+                el.before("\n", ContentType::Text);
+                el.after("\n", ContentType::Text);
+                Ok(())
+            }),
+            element!("*", |el| {
+                el.remove_and_keep_content();
+                Ok(())
+            }),
+        ],
+        document_content_handlers: vec![doc_comments!(|c| {
+            c.remove();
+            Ok(())
+        })],
+        ..RewriteStrSettings::default()
+    };
+    let text = rewrite_str(&content, settings)?;
+
+    let mut lines = vec![];
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() {
+            lines.push(decode_entities(&line));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Directives of a page to crawlers: `<meta name="robots">` and links marked `rel="nofollow"`
+#[derive(Debug, Default, PartialEq)]
+pub struct RobotsDirectives {
+    /// page content should not be stored
+    pub noindex: bool,
+    /// links of the page should not be followed
+    pub nofollow: bool,
+    /// absolute URLs of links marked `rel="nofollow"`
+    pub nofollow_links: HashSet<Url>,
+}
+
+impl RobotsDirectives {
+    /// Parses directives of a page, relative URLs are resolved against a given page URL
+    pub fn parse(content: &str, page_url: &Url) -> Result<Self> {
+        let mut meta = vec![];
+        let mut nofollow_links = HashSet::new();
+        let settings = RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("meta[name][content]", |el| {
+                    let name = el.get_attribute("name").unwrap_or_default();
+                    if name.eq_ignore_ascii_case("robots") {
+                        meta.push(el.get_attribute("content").unwrap_or_default());
+                    }
+                    Ok(())
+                }),
+                element!("a[rel][href]", |el| {
+                    let rel = el.get_attribute("rel").unwrap_or_default();
+                    if rel
+                        .split_whitespace()
+                        .any(|r| r.eq_ignore_ascii_case("nofollow"))
+                    {
+                        let href = el.get_attribute("href").unwrap_or_default();
+                        if let Ok(url) = page_url.join(&decode_entities(&href)) {
+                            nofollow_links.insert(url);
+                        }
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        };
+        rewrite_str(content, settings)?;
+
+        let mut result = Self {
+            nofollow_links,
+            ..Self::default()
+        };
+        for directive in meta.iter().flat_map(|c| c.split(',')) {
+            match directive.trim().to_lowercase().as_str() {
+                "noindex" => result.noindex = true,
+                "nofollow" => result.nofollow = true,
+                "none" => (result.noindex, result.nofollow) = (true, true),
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+
+    /// Removes links which should not be followed
+    pub fn filter_links(&self, links: Vec<Link<Url>>) -> Vec<Link<Url>> {
+        if self.nofollow {
+            return vec![];
+        }
+        links
+            .into_iter()
+            .filter(|link| !self.nofollow_links.contains(&link.url))
+            .collect()
+    }
+}
+
+/// Returns absolute URLs of all links (`<a href>`) of a page in order of appearance
+pub fn find_links(content: &str, page_url: &Url) -> Result<Vec<Url>> {
+    let mut links = vec![];
+    let settings = RewriteStrSettings {
+        element_content_handlers: vec![element!("a[href]", |el| {
+            let href = el.get_attribute("href").unwrap_or_default();
+            if let Ok(url) = page_url.join(decode_entities(&href).trim()) {
+                if matches!(url.scheme(), "http" | "https") {
+                    links.push(url);
+                }
+            }
+            Ok(())
+        })],
+        ..RewriteStrSettings::default()
+    };
+    rewrite_str(content, settings)?;
+    Ok(links)
+}
+
+/// Pages refreshing later than this are considered auto-reloading rather than redirecting
+const MAX_REFRESH_DELAY_SEC: f32 = 10.;
+
+lazy_static! {
+    /// Script consisting of a single `location` assignment (or `location.replace()` call)
+    static ref LOCATION_SCRIPT: Regex = Regex::new(
+        r#"^\s*(?:(?:window|document|top|self)\.)?location(?:\.href\s*=\s*|\s*=\s*|\.(?:replace|assign)\(\s*)(["'])([^"']*)["']\s*\)?\s*;?\s*$"#
+    )
+    .unwrap();
+}
+
+/// Finds redirect made by the page itself: `<meta http-equiv="refresh">` or a trivial script
+/// assigning `window.location`
+///
+/// Only scripts consisting of nothing but the redirect are recognized, so conditional redirects
+/// (eg. to a mobile version of a site) are not followed.
+pub fn client_redirect(content: &str, page_url: &Url) -> Result<Option<Url>> {
+    let mut targets = vec![];
+    let mut script_targets = vec![];
+    let mut script = String::new();
+    let settings = RewriteStrSettings {
+        element_content_handlers: vec![
+            element!("meta[http-equiv][content]", |el| {
+                let http_equiv = el.get_attribute("http-equiv").unwrap_or_default();
+                if http_equiv.eq_ignore_ascii_case("refresh") {
+                    let refresh = el.get_attribute("content").unwrap_or_default();
+                    targets.extend(refresh_target(&decode_entities(&refresh)));
+                }
+                Ok(())
+            }),
+            text!("script", |t| {
+                script.push_str(t.as_str());
+                if t.last_in_text_node() {
+                    if let Some(captures) = LOCATION_SCRIPT.captures(&script) {
+                        script_targets.push(captures[2].to_string());
+                    }
+                    script.clear();
+                }
+                Ok(())
+            }),
+        ],
+        ..RewriteStrSettings::default()
+    };
+    rewrite_str(content, settings)?;
+
+    let target = targets
+        .iter()
+        .chain(&script_targets)
+        .filter_map(|target| page_url.join(target.trim()).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .find(|url| url.as_str() != page_url.as_str());
+    Ok(target)
+}
+
+/// Target of `<meta http-equiv="refresh" content="0; url=...">` if it's refreshed soon enough
+fn refresh_target(content: &str) -> Option<String> {
+    let (delay, target) = content.split_once([';', ','])?;
+    let delay = delay.trim().parse::<f32>().ok()?;
+    let target = target.trim();
+    let target = match target.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("url") => target[3..].trim_start(),
+        _ => target,
+    };
+    let target = target.strip_prefix('=').unwrap_or(target).trim();
+    let target = target.trim_matches(|c| c == '"' || c == '\'');
+    (delay <= MAX_REFRESH_DELAY_SEC && !target.is_empty()).then(|| target.to_string())
+}
+
+/// Decodes most common HTML character references
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_strip_elements() -> Result<()> {
+        let html = r#"<html><script>x()</script><div class="ad">Buy</div><p>Text</p></html>"#;
+        let selectors = vec!["script".into(), "div.ad".into()];
+        assert_eq!(
+            strip_elements(html, &selectors)?,
+            "<html><p>Text</p></html>"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_to_text() -> Result<()> {
+        let html = r#"<html><head><title>T</title></head><body>
+            <!-- comment --><script>x()</script>
+            <h1>Crab   &amp; Co</h1><p>First <b>bold</b> line</p><ul><li>One</li><li>Two</li></ul>
+            </body></html>"#;
+        assert_eq!(to_text(html)?, "Crab & Co\nFirst bold line\nOne\nTwo");
+        Ok(())
+    }
+
+    #[test]
+    fn check_robots_directives() -> Result<()> {
+        let url = Url::parse("http://test.com/items/")?;
+        let html = r#"<html><head><meta name="ROBOTS" content="NoIndex, follow"></head>
+            <a href="1">One</a><a rel="nofollow noopener" href="2?a=1&amp;b=2">Two</a></html>"#;
+        let directives = RobotsDirectives::parse(html, &url)?;
+        assert!(directives.noindex);
+        assert!(!directives.nofollow);
+
+        let link = |url: &str| Link {
+            url: Url::parse(url).unwrap(),
+            type_id: 1,
+            request: None,
+        };
+        let links = vec![
+            link("http://test.com/items/1"),
+            link("http://test.com/items/2?a=1&b=2"),
+        ];
+        assert_eq!(
+            directives.filter_links(links.clone()),
+            vec![link("http://test.com/items/1")]
+        );
+
+        let html = r#"<meta name="robots" content="none"><a href="1">One</a>"#;
+        let directives = RobotsDirectives::parse(html, &url)?;
+        assert!(directives.noindex && directives.nofollow);
+        assert!(directives.filter_links(links).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn check_client_redirect() -> Result<()> {
+        let url = Url::parse("http://test.com/old/page")?;
+        let redirect = |html: &str| {
+            client_redirect(html, &url)
+                .unwrap()
+                .map(|url| url.to_string())
+        };
+
+        let html = r#"<meta http-equiv="Refresh" content="0; URL='/new?a=1&amp;b=2'">"#;
+        assert_eq!(
+            redirect(html).as_deref(),
+            Some("http://test.com/new?a=1&b=2")
+        );
+        let html = r#"<meta http-equiv="refresh" content="3;url=next">"#;
+        assert_eq!(redirect(html).as_deref(), Some("http://test.com/old/next"));
+        let html = r#"<script>window.location.href = "https://test.org/";</script>"#;
+        assert_eq!(redirect(html).as_deref(), Some("https://test.org/"));
+        let html = r#"<script> location.replace('/moved') </script>"#;
+        assert_eq!(redirect(html).as_deref(), Some("http://test.com/moved"));
+
+        // auto-reloading and self-refreshing pages, conditional redirects
+        assert_eq!(
+            redirect(r#"<meta http-equiv="refresh" content="300; url=/news">"#),
+            None
+        );
+        assert_eq!(redirect(r#"<meta http-equiv="refresh" content="5">"#), None);
+        assert_eq!(
+            redirect(r#"<meta http-equiv="refresh" content="0; url=page">"#),
+            None
+        );
+        let html = r#"<script>if (mobile) { location = "/m"; }</script>"#;
+        assert_eq!(redirect(html), None);
+        Ok(())
+    }
+
+    #[test]
+    fn check_set_base_url() -> Result<()> {
+        let url = url::Url::parse("http://test.com/items/1")?;
+        let html = "<html><head><title>T</title></head><body></body></html>";
+        assert_eq!(
+            set_base_url(html, &url)?,
+            "<html><head><base href=\"http://test.com/items/1\"><title>T</title></head><body></body></html>"
+        );
+        let html = "<html><body></body></html>";
+        assert_eq!(
+            set_base_url(html, &url)?,
+            "<html><base href=\"http://test.com/items/1\"><body></body></html>"
+        );
+        let html = r#"<html><head><base href="/"></head></html>"#;
+        assert_eq!(set_base_url(html, &url)?, html);
+        Ok(())
+    }
+}
@@ -1,29 +1,83 @@
+use alerts::AlertsConfig;
 use anyhow::Context;
-use atom::Atom;
+use auth::{AuthConfig, ClientIdentityConfig};
+use challenge::ChallengeConfig;
+use contracts::ContractConfig;
+use cookies::CookiesConfig;
 use crawler::CrawlerState;
+use crawler::NonTextContent;
+use database::DatabaseUrl;
+use dedup::DuplicateDetection;
+use export::{ColumnsConfig, CurrencyConfig};
+use locale::LocaleConfig;
+use login::LoginConfig;
+use pii::PiiConfig;
 use prelude::*;
+use redirect::RedirectConfig;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
-pub use storage::Page;
+use session::SessionsConfig;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use storage::CrawlOrder;
+pub use storage::{Page, RequestSpec};
+use tokio::sync::watch;
 use url::Url;
 
+pub mod alerts;
+pub mod auth;
+#[cfg(feature = "browser")]
+pub mod browser;
+pub mod canonical;
+pub mod challenge;
+pub mod contracts;
+pub mod cookies;
 pub mod crawler;
+pub mod database;
+pub mod dedup;
+mod dns;
+pub mod export;
+pub mod filter;
+pub mod fixtures;
+pub mod html;
+pub mod links;
+pub mod locale;
+pub mod login;
+pub mod manifest;
+pub mod parser_threads;
+pub mod pii;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 mod proxy;
 pub mod python;
+pub mod quota;
+pub mod redirect;
+pub mod schedule;
+mod secrets;
+mod session;
+pub mod signing;
+pub mod sink;
+pub mod stats;
 pub mod storage;
+pub mod throttle;
+pub mod trace;
+pub mod work_queue;
 
-pub type Shared<T> = Arc<Atom<Box<T>>>;
+const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_millis(100);
 
-pub enum CrawlerReport {
-    Report(CrawlerState),
-    Finished,
-}
+/// Pages larger than this are skipped by bulk reads if `max_page_size` is not set
+const DEFAULT_MAX_PAGE_SIZE: usize = 64 * 1024 * 1024;
 
-impl From<CrawlerState> for CrawlerReport {
-    fn from(value: CrawlerState) -> Self {
-        Self::Report(value)
-    }
-}
+/// Crawler state published on each report tick
+///
+/// Any number of consumers (terminal, dashboards, exporters) can observe the latest state by cloning
+/// the receiver. Channel is closed when crawler is finished.
+pub type CrawlerReports = watch::Receiver<Arc<CrawlerState>>;
 
 pub mod prelude {
 
@@ -46,6 +100,9 @@ pub mod prelude {
         #[error("Page parser for type id {} not found", .0)]
         PageParserNotFound(PageTypeId),
 
+        #[error("Parser thread of page type {} stopped", .0)]
+        ParserThreadStopped(PageTypeId),
+
         #[error("Unable to create parser from file {}", .0.display())]
         UnableToCreateParser(PathBuf),
 
@@ -60,6 +117,201 @@ pub mod prelude {
 
         #[error("Parser for page type {} failed", .0)]
         PageParserFailed(PageTypeId),
+
+        #[error("Loading exchange rates")]
+        LoadingExchangeRates,
+
+        #[error("Stripping content of page #{}", .0)]
+        StrippingContent(i64),
+
+        #[error("No secret provided for {}", .0)]
+        MissingSecret(String),
+
+        #[error("Reading client certificate {}", .0.display())]
+        ReadingClientIdentity(PathBuf),
+
+        #[error("Reading environment variable {}", .0)]
+        MissingEnvVariable(String),
+
+        #[error("Reading secret {} from the system keyring", .0)]
+        ReadingKeyring(String),
+
+        #[error("Keyring lookup failed: {}", .0)]
+        KeyringLookupFailed(String),
+
+        #[error("Obtaining access token from {}", .0)]
+        ObtainingToken(String),
+
+        #[error("Streaming request body can not be signed")]
+        StreamingBodySigning,
+
+        #[error("Invalid frontier entry at line {}", .0)]
+        InvalidFrontierEntry(usize),
+
+        #[error("Opening content shards")]
+        OpeningShards,
+
+        #[error("Unknown built-in PII pattern: {}", .0)]
+        UnknownPiiPattern(String),
+
+        #[error("Invalid PII pattern {}", .0)]
+        InvalidPiiPattern(String),
+
+        #[error("Invalid request headers for page type {}", .0)]
+        InvalidRequestHeaders(String),
+
+        #[error("Invalid locale rule: {}", .0)]
+        InvalidLocale(String),
+
+        #[error("Size of the session pool must be positive")]
+        InvalidSessionPool,
+
+        #[error("Writing run manifest")]
+        WritingManifest,
+
+        #[error("No columns defined for table")]
+        NoColumns,
+
+        #[error("Unknown output sink: {}", .0)]
+        UnknownSink(String),
+
+        #[error("Output sink {} requires output location", .0)]
+        MissingSinkLocation(String),
+
+        #[error("Invalid page type id: {}", .0)]
+        InvalidPageTypeId(String),
+
+        #[error("Rendering pages requires crab to be built with `browser` feature")]
+        BrowserNotSupported,
+
+        #[error("Running browser {}", .0.display())]
+        RunningBrowser(PathBuf),
+
+        #[error("Rendering {} failed: {}", .0, .1)]
+        RenderingPage(url::Url, String),
+
+        #[error("Opening browser")]
+        OpeningBrowser,
+
+        #[error("Reading fixture {}", .0.display())]
+        ReadingFixture(PathBuf),
+
+        #[error("Parser output changed on {} fixture(s)", .0)]
+        SnapshotMismatch(usize),
+
+        #[error("Invalid URL pattern: {}", .0)]
+        InvalidUrlPattern(String),
+
+        #[error("Invalid allowed hours range: {}", .0)]
+        InvalidAllowedHours(String),
+
+        #[error("Invalid timezone: {} (UTC offset or `local` expected)", .0)]
+        InvalidTimezone(String),
+
+        #[error("Invalid trace record at line {}", .0)]
+        InvalidTraceRecord(usize),
+
+        #[error("Replay diverged at line {}: recorded proxy {:?}, replayed proxy {:?}", .0, .1, .2)]
+        ReplayDiverged(usize, Option<usize>, Option<usize>),
+
+        #[error("Path is not valid UTF-8: {}", .0.display())]
+        NonUtf8Path(PathBuf),
+
+        #[error("Unsupported database URL: {} (only sqlite is supported)", .0)]
+        UnsupportedDatabaseUrl(String),
+
+        #[error("Command requires SQLite database, PostgreSQL database supports only crawling and export")]
+        SqliteRequired,
+
+        #[error("Snapshots of PostgreSQL database are not supported, use pg_dump instead")]
+        PostgresSnapshot,
+
+        #[error("Content shards are not supported by PostgreSQL database")]
+        PostgresShards,
+
+        #[error("Migrating database")]
+        MigratingDatabase,
+
+        #[error("Database {} not found, run `crab migrate` to create it or pass `--auto-migrate`", .0.display())]
+        DatabaseNotFound(PathBuf),
+
+        #[error("Database has {} pending migration(s), run `crab migrate` or pass `--auto-migrate`", .0)]
+        PendingMigrations(usize),
+
+        #[error("Worker failed to download the page: {}", .0)]
+        WorkerFailed(String),
+
+        #[error("Worker didn't respond in time")]
+        WorkerTimeout,
+
+        #[error("No worker took the request in time")]
+        NoWorkers,
+
+        #[error("Work queue on a public address {} requires CRAB_QUEUE_TOKEN to be set", .0)]
+        UnauthenticatedWorkQueue(std::net::SocketAddr),
+
+        #[error("Work queue rejected the token (check CRAB_QUEUE_TOKEN)")]
+        WorkQueueUnauthorized,
+
+        #[error("Canary pages failed, crawl is aborted:\n{0}")]
+        CanaryFailed(String),
+
+        #[error("Login to {} failed: {}", .0, .1)]
+        LoginFailed(url::Url, String),
+
+        #[error("Running login hook {}", .0)]
+        RunningLoginHook(String),
+
+        #[error("{} page(s) larger than `max_page_size` were skipped", .0)]
+        OversizedPagesSkipped(u64),
+
+        #[error("Invalid challenge solver: {}", .0)]
+        InvalidChallengeSolver(String),
+
+        #[error("Running challenge solver {}", .0)]
+        RunningChallengeSolver(String),
+
+        #[error("Challenge solver failed: {}", .0)]
+        ChallengeSolverFailed(String),
+
+        #[error("Reading cookies {}", .0.display())]
+        ReadingCookies(PathBuf),
+
+        #[error("Invalid cookie on line {}", .0)]
+        InvalidCookie(usize),
+
+        #[error("Interval must be a positive number of seconds: {}", .0)]
+        InvalidInterval(String),
+
+        #[error("Invalid row filter: {} (expected `column<operator>value`, eg. `price>100`)", .0)]
+        InvalidRowFilter(String),
+
+        #[error("Fraction must be greater than 0 and not greater than 1: {}", .0)]
+        InvalidFraction(String),
+
+        #[error("URL is already used by page #{}, use `crab merge` to combine the pages", .0)]
+        PageUrlTaken(i64),
+
+        #[error("Page #{} can not be merged into itself", .0)]
+        MergingSamePage(i64),
+
+        #[error("Link rules of page type {} must have include patterns", .0)]
+        NoLinkPatterns(PageTypeId),
+
+        #[error("Contract of table {} must have `min_rows` or `min_filled` (between 0 and 1, along with `column`)", .0)]
+        InvalidContract(String),
+
+        #[error("{} data contract assertion(s) failed", .0)]
+        ContractsFailed(usize),
+
+        #[error("{} check(s) of the crawl failed", .0)]
+        CrawlChecksFailed(usize),
+
+        #[error("Sending email via {}", .0)]
+        SendingEmail(String),
+
+        #[error("Invalid email address: {}", .0)]
+        InvalidEmailAddress(String),
     }
 }
 
@@ -67,7 +319,78 @@ pub type PageTypeId = u8;
 pub type ParsedTable = Vec<HashMap<String, String>>;
 pub type ParsedTables = HashMap<String, ParsedTable>;
 
-#[derive(Deserialize, Serialize)]
+/// Parsed rows with column names and values borrowed from page content where possible
+pub type BorrowedTable<'c> = Vec<HashMap<Cow<'c, str>, Cow<'c, str>>>;
+pub type BorrowedTables<'c> = HashMap<Cow<'c, str>, BorrowedTable<'c>>;
+
+/// Converts parsed rows to owned strings, strings which are already owned are not copied
+pub fn into_owned_table(table: BorrowedTable) -> ParsedTable {
+    table
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|(column, value)| (column.into_owned(), value.into_owned()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Converts parsed tables to owned strings (see [`into_owned_table()`])
+pub fn into_owned_tables(tables: BorrowedTables) -> ParsedTables {
+    tables
+        .into_iter()
+        .map(|(name, table)| (name.into_owned(), into_owned_table(table)))
+        .collect()
+}
+
+pub(crate) fn into_borrowed_links(links: Vec<Link>) -> Vec<Link<Cow<'static, str>>> {
+    links
+        .into_iter()
+        .map(|link| Link {
+            url: link.url.into(),
+            type_id: link.type_id,
+            request: link.request,
+        })
+        .collect()
+}
+
+pub(crate) fn into_borrowed_tables(tables: ParsedTables) -> BorrowedTables<'static> {
+    tables
+        .into_iter()
+        .map(|(name, table)| {
+            let table = table
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|(column, value)| (column.into(), value.into()))
+                        .collect()
+                })
+                .collect();
+            (name.into(), table)
+        })
+        .collect()
+}
+
+/// Next page found by navigation rules
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Link<U = String> {
+    pub url: U,
+    pub type_id: PageTypeId,
+    /// request page should be downloaded with, plain `GET` if not set
+    pub request: Option<RequestSpec>,
+}
+
+impl From<(String, PageTypeId)> for Link {
+    fn from((url, type_id): (String, PageTypeId)) -> Self {
+        Self {
+            url,
+            type_id,
+            request: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct CrawlerConfig {
     /// number of threads
     pub(crate) threads: usize,
@@ -79,80 +402,511 @@ pub struct CrawlerConfig {
 
     pub(crate) connect_timeout_sec: Option<f32>,
 
+    /// local IP address requests are made from, so the egress interface of a multi-homed host
+    /// can be chosen (system default if not set)
+    pub(crate) local_address: Option<IpAddr>,
+
+    /// client certificate for sites requiring mutual TLS (see [`auth::ClientIdentityConfig`])
+    pub(crate) client_identity: Option<ClientIdentityConfig>,
+
+    /// time resolved host addresses are reused for, DNS is not cached if not set (see [`dns`])
+    pub(crate) dns_cache_ttl_sec: Option<f32>,
+
     /// path to proxies list
     pub(crate) proxies: Option<PathBuf>,
+
+    /// CSS selectors of boilerplate elements removed from the page before storing it
+    ///
+    /// Validation is done on the original content, navigation is done on the stripped one
+    pub(crate) strip_selectors: Option<Vec<String>>,
+
+    /// number of times failed download is retried before page is marked as failed (3 by default)
+    pub(crate) max_retries: Option<u32>,
+
+    /// delay before the first retry, doubled on each subsequent one (1 second by default)
+    pub(crate) retry_backoff_sec: Option<f32>,
+
+    /// directory database snapshots are written to (`snapshots` by default)
+    pub(crate) snapshot_dir: Option<PathBuf>,
+
+    /// interval between periodic database snapshots, no periodic snapshots if not set
+    pub(crate) snapshot_interval_sec: Option<f32>,
+
+    /// directory run manifests are written to (`manifests` by default)
+    pub(crate) manifest_dir: Option<PathBuf>,
+
+    /// pages found deeper than this are registered as skipped and never downloaded (no limit by default)
+    ///
+    /// Seed pages have depth 0, pages found on them – depth 1 and so on.
+    pub(crate) max_depth: Option<u16>,
+
+    /// include/exclude patterns links found by navigation rules are checked against (see [`filter`])
+    pub(crate) url_filters: Option<UrlFilterConfig>,
+
+    /// maximum number of redirects and redirects not followed (see [`redirect`])
+    pub(crate) redirects: Option<RedirectConfig>,
+
+    /// skip links marked `rel="nofollow"` and respect `<meta name="robots">` of pages: content of
+    /// `noindex` pages is not stored, links of `nofollow` pages are not followed
+    #[serde(default)]
+    pub(crate) honor_robots_meta: bool,
+
+    /// register targets of `<meta http-equiv="refresh">` and trivial `window.location` redirects,
+    /// so they are downloaded instead of the redirecting page (see [`html::client_redirect()`])
+    #[serde(default)]
+    pub(crate) follow_client_redirects: bool,
+
+    /// mark pages which content duplicates an already downloaded page: `off` (default), `exact`
+    /// or `near` (see [`dedup`]). Links of duplicate pages are not followed
+    #[serde(default)]
+    pub(crate) detect_duplicates: DuplicateDetection,
+
+    /// query parameters removed from links before registering them, eg. `["utm_*"]` (see [`canonical`])
+    pub(crate) strip_query_params: Option<Vec<String>>,
+
+    /// order pages are downloaded in: `breadth_first` (default), `depth_first` or `random`
+    #[serde(default)]
+    pub(crate) crawl_order: CrawlOrder,
+
+    /// host → quota of requests to the host (see [`quota`])
+    pub(crate) quotas: Option<HashMap<String, QuotaConfig>>,
+
+    /// time pages listed by crawler are leased for (600 seconds by default)
+    ///
+    /// Leased pages are not downloaded by other crawler processes working on the same database.
+    pub(crate) lease_sec: Option<f32>,
+
+    /// maximum delay between requests to a host responding with 429/503 (300 seconds by default,
+    /// values below 1 second act as 1 second, see [`throttle`])
+    pub(crate) max_throttle_sec: Option<f32>,
+
+    /// maximum number of requests in flight to a single host (not limited by default, see [`throttle`])
+    pub(crate) max_concurrent_per_domain: Option<usize>,
+
+    /// maximum size of a response body in bytes, larger responses are truncated (not limited by default)
+    pub(crate) max_body_bytes: Option<usize>,
+
+    /// content types of pages stored, eg. `["text/html", "text/*"]` (all by default)
+    ///
+    /// Body of a response with other content type is not downloaded, page is marked as skipped.
+    pub(crate) allowed_content_types: Option<Vec<String>>,
+
+    /// what to do with non-text responses: `store` (default), `skip` or `mark` (see [`NonTextContent`])
+    #[serde(default)]
+    pub(crate) non_text_content: NonTextContent,
+
+    /// maximum number of downloaded bytes per second across all hosts (not limited by default, see [`throttle`])
+    pub(crate) max_bytes_per_sec: Option<u64>,
+
+    /// hour ranges crawler is allowed to make requests in, eg. `["22-6"]` (see [`schedule`])
+    pub(crate) allowed_hours: Option<Vec<String>>,
+
+    /// UTC offset (eg. `+03:00`) or `local` allowed hours are given in (UTC by default)
+    pub(crate) timezone: Option<String>,
+
+    /// page type id → additional request headers sent when downloading pages of this type
+    ///
+    /// ```toml
+    /// [crawler.headers.2]
+    /// Accept = "application/json"
+    /// X-Requested-With = "XMLHttpRequest"
+    /// ```
+    pub(crate) headers: Option<HashMap<String, HashMap<String, String>>>,
+
+    /// languages pages are requested in (see [`locale`])
+    pub(crate) locales: Option<Vec<LocaleConfig>>,
+
+    /// pool of sessions (user agent, proxy and cookies) requests are spread across (see [`session`])
+    pub(crate) sessions: Option<SessionsConfig>,
+
+    /// solver of anti-bot challenge pages (see [`challenge`])
+    pub(crate) challenges: Option<ChallengeConfig>,
+
+    /// `User-Agent` sent with all requests (`crab/<version>` by default if `contact` is set)
+    pub(crate) user_agent: Option<String>,
+
+    /// URL or email crawler operator can be reached at, appended to `User-Agent` as `(+<contact>)`
+    pub(crate) contact: Option<String>,
+
+    /// email sent in `From` header with all requests
+    pub(crate) from: Option<String>,
+
+    /// page type id → settings of downloading pages of this type
+    pub(crate) page_types: Option<HashMap<String, PageTypeConfig>>,
+
+    /// headless Chrome/Chromium executable used for rendering pages (`chromium` by default)
+    pub(crate) browser: Option<PathBuf>,
+
+    /// run browser without sandbox (required if crab runs as root, eg. in a container)
+    #[serde(default)]
+    pub(crate) browser_no_sandbox: bool,
+
+    /// interval crawler reports its state to the crawler screen at (0.1 seconds by default)
+    pub(crate) report_interval_sec: Option<f32>,
+
+    /// interval crawler screen is redrawn at (`report_interval_sec` by default)
+    pub(crate) ui_refresh_sec: Option<f32>,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct PageTypeConfig {
+    /// render pages in a headless browser and store resulting DOM (requires `browser` feature)
+    #[serde(default)]
+    pub(crate) render: bool,
+
+    /// downloaded pages older than this are downloaded again, never by default
+    pub(crate) recrawl_after_sec: Option<f32>,
+
+    /// check already downloaded pages with a `HEAD` request first and skip `GET` if `ETag` (or
+    /// `Content-Length` and `Last-Modified`) didn't change
+    #[serde(default)]
+    pub(crate) head_first: bool,
+
+    /// rules links are extracted by if the parser of the type has no navigation rules (see [`links`])
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) links: Vec<LinkRuleConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LinkRuleConfig {
+    /// links matching any of these patterns are registered
+    pub(crate) include: Vec<String>,
+    /// links matching any of these patterns are not registered
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+    /// page type links are registered with
+    pub(crate) type_id: PageTypeId,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct UrlFilterConfig {
+    /// only links matching any of these patterns are registered
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+    /// links matching any of these patterns are never registered
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+    /// register links rejected by the patterns as skipped (`pattern` reason), so they can be
+    /// audited with `crab skipped`
+    #[serde(default)]
+    pub(crate) record_filtered: bool,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct QuotaConfig {
+    /// maximum number of requests in a minute
+    pub(crate) per_minute: Option<u32>,
+    /// maximum number of requests in a day (UTC)
+    pub(crate) per_day: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct CrabConfig {
-    pub database: PathBuf,
+    /// path to the database file or connection URL (see [`DatabaseUrl`])
+    pub database: DatabaseUrl,
+
+    /// pages larger than this size (in bytes) are skipped when processing all downloaded pages
+    /// (see [`CrabConfig::page_size_limit()`]). Commands reading all pages report the number of
+    /// skipped pages and exit with an error
+    pub max_page_size: Option<usize>,
+
+    /// number of files page content is sharded across (see [`storage::SqliteStorage::open_shards()`])
+    pub shards: Option<u16>,
+
     pub crawler: CrawlerConfig,
+
+    /// column name normalization rules applied on export
+    pub columns: Option<ColumnsConfig>,
+
+    /// currency normalization rules applied on export
+    pub currency: Option<CurrencyConfig>,
+
+    /// personal data scrubbing rules applied on store and/or export
+    pub pii: Option<PiiConfig>,
+
+    /// domain → authentication used for requests to the domain
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub auth: HashMap<String, AuthConfig>,
+
+    /// cookies exported from a browser sent with requests (see [`cookies`])
+    pub cookies: Option<CookiesConfig>,
+
+    /// login step run before crawling (see [`login`])
+    pub login: Option<LoginConfig>,
+
+    /// assertions on the dataset checked after crawl (see [`contracts`])
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contracts: Vec<ContractConfig>,
+
+    /// notifications about failed checks of a crawl (see [`alerts`])
+    pub alerts: Option<AlertsConfig>,
 }
 
 impl CrabConfig {
+    /// Directory run manifests are written to (see [`manifest::Manifest`])
+    pub fn manifest_dir(&self) -> &Path {
+        self.crawler
+            .manifest_dir
+            .as_deref()
+            .unwrap_or(Path::new("manifests"))
+    }
+
+    /// Size of the largest page processed by commands reading all downloaded pages (64 MiB by
+    /// default)
+    ///
+    /// Page content is read and decompressed whole, so processing a page takes up to about twice
+    /// the limit of memory.
+    pub fn page_size_limit(&self) -> usize {
+        self.max_page_size.unwrap_or(DEFAULT_MAX_PAGE_SIZE)
+    }
+
+    /// Interval crawler reports its state at
+    pub fn report_interval(&self) -> Duration {
+        let interval = self.crawler.report_interval_sec;
+        interval.map_or(DEFAULT_REPORT_INTERVAL, Duration::from_secs_f32)
+    }
+
+    /// Interval crawler screen is redrawn at, if it differs from the report interval
+    pub fn ui_refresh_interval(&self) -> Option<Duration> {
+        self.crawler.ui_refresh_sec.map(Duration::from_secs_f32)
+    }
+
+    /// Robots directives of pages (`<meta name="robots">`, `rel="nofollow"`) are respected
+    pub fn honor_robots_meta(&self) -> bool {
+        self.crawler.honor_robots_meta
+    }
+
+    /// Replaces secrets given in the config itself with a placeholder, so it's safe to print
+    ///
+    /// Used for both `crab config` and run manifests. References to secrets are kept.
+    pub fn redact_secrets(&mut self) {
+        self.database.redact_password();
+        for auth in self.auth.values_mut() {
+            auth.redact_secrets();
+        }
+        if let Some(identity) = &mut self.crawler.client_identity {
+            identity.redact_secrets();
+        }
+        if let Some(login) = &mut self.login {
+            login.redact_secrets();
+        }
+        if let Some(alerts) = &mut self.alerts {
+            alerts.redact_secrets();
+        }
+    }
+
     /// Returns config for a new workspace
     ///
     /// This method doesn't use [`Default`] trait intentionally.
     pub fn default_config() -> Self {
         Self {
-            database: PathBuf::from("./db.sqlite"),
+            database: "./db.sqlite"
+                .parse()
+                .expect("file path is a valid location"),
+            max_page_size: None,
+            shards: None,
             crawler: CrawlerConfig {
                 threads: 1,
                 delay_sec: 5.,
                 read_timeout_sec: Some(10.),
                 connect_timeout_sec: Some(10.),
+                local_address: None,
+                client_identity: None,
+                dns_cache_ttl_sec: None,
                 proxies: None,
+                strip_selectors: None,
+                max_retries: Some(3),
+                retry_backoff_sec: Some(1.),
+                snapshot_dir: None,
+                snapshot_interval_sec: None,
+                manifest_dir: None,
+                max_depth: None,
+                url_filters: None,
+                redirects: None,
+                honor_robots_meta: false,
+                follow_client_redirects: false,
+                detect_duplicates: DuplicateDetection::default(),
+                strip_query_params: None,
+                crawl_order: CrawlOrder::default(),
+                quotas: None,
+                lease_sec: None,
+                max_throttle_sec: None,
+                max_concurrent_per_domain: None,
+                max_body_bytes: None,
+                allowed_content_types: None,
+                non_text_content: NonTextContent::default(),
+                max_bytes_per_sec: None,
+                allowed_hours: None,
+                timezone: None,
+                headers: None,
+                locales: None,
+                sessions: None,
+                challenges: None,
+                user_agent: None,
+                contact: None,
+                from: None,
+                page_types: None,
+                browser: None,
+                browser_no_sandbox: false,
+                report_interval_sec: None,
+                ui_refresh_sec: None,
             },
+            columns: None,
+            currency: None,
+            pii: None,
+            auth: HashMap::new(),
+            cookies: None,
+            login: None,
+            contracts: vec![],
+            alerts: None,
         }
     }
 }
 
 /// Base type allowing user to provide parsing rules
-pub trait PageParser {
+///
+/// Content is borrowed for the lifetime `'c`, so URLs and parsed values can be returned as slices
+/// of the content without copying. Parsers producing owned strings can implement
+/// [`OwnedPageParser`] instead.
+pub trait PageParser: Send {
     /// Parse next pages referenced in the content
-    fn navigate(&self, content: &str) -> Result<Option<Vec<(String, PageTypeId)>>>;
+    ///
+    /// Besides URLs, links can contain requests (eg. form submissions) crawler should make to get
+    /// the next page
+    fn navigate<'c>(
+        &self,
+        page: &Page,
+        content: &'c str,
+    ) -> Result<Option<Vec<Link<Cow<'c, str>>>>>;
 
-    /// Returns parsed key-value pairs for the page]
-    fn parse(&self, content: &str) -> Result<Option<ParsedTables>>;
+    /// Returns parsed key-value pairs for the page
+    fn parse<'c>(&self, page: &Page, content: &'c str) -> Result<Option<BorrowedTables<'c>>>;
 
     /// Validates page content
     ///
     /// If page is not valid it's content will not be written to storage
     /// and crawler will repeat request to the page
+    fn validate(&self, _page: &Page, _content: &str) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Transforms page content before it's written to storage
+    ///
+    /// Allows to strip unneeded parts of the page or mask sensitive data, so it never hits the database
+    fn preprocess(&self, _page: &Page, content: String) -> Result<String> {
+        Ok(content)
+    }
+
+    fn page_type_id(&self) -> PageTypeId;
+
+    /// Version of parsing rules
+    ///
+    /// Allows to trace exported data back to the code which produced it
+    fn version(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Parsing rules producing owned strings and working on page content only
+///
+/// All types implementing this trait are [`PageParser`]s as well.
+pub trait OwnedPageParser: Send {
+    /// See [`PageParser::navigate()`]
+    fn navigate(&self, content: &str) -> Result<Option<Vec<Link>>>;
+
+    /// See [`PageParser::parse()`]
+    fn parse(&self, content: &str) -> Result<Option<ParsedTables>>;
+
+    /// See [`PageParser::validate()`]
     fn validate(&self, _content: &str) -> Result<bool> {
         Ok(true)
     }
 
+    /// See [`PageParser::preprocess()`]
+    fn preprocess(&self, content: String) -> Result<String> {
+        Ok(content)
+    }
+
     fn page_type_id(&self) -> PageTypeId;
+
+    /// See [`PageParser::version()`]
+    fn version(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl<T: OwnedPageParser> PageParser for T {
+    fn navigate<'c>(
+        &self,
+        _page: &Page,
+        content: &'c str,
+    ) -> Result<Option<Vec<Link<Cow<'c, str>>>>> {
+        let links = OwnedPageParser::navigate(self, content)?;
+        Ok(links.map(into_borrowed_links))
+    }
+
+    fn parse<'c>(&self, _page: &Page, content: &'c str) -> Result<Option<BorrowedTables<'c>>> {
+        Ok(OwnedPageParser::parse(self, content)?.map(into_borrowed_tables))
+    }
+
+    fn validate(&self, _page: &Page, content: &str) -> Result<bool> {
+        OwnedPageParser::validate(self, content)
+    }
+
+    fn preprocess(&self, _page: &Page, content: String) -> Result<String> {
+        OwnedPageParser::preprocess(self, content)
+    }
+
+    fn page_type_id(&self) -> PageTypeId {
+        OwnedPageParser::page_type_id(self)
+    }
+
+    fn version(&self) -> Option<&str> {
+        OwnedPageParser::version(self)
+    }
 }
 
 pub struct PageParsers(pub Vec<Box<dyn PageParser>>);
 
 impl PageParsers {
-    pub fn navigate(&self, page: &Page, content: &str) -> Result<Option<Vec<(Url, PageTypeId)>>> {
+    pub fn navigate(&self, page: &Page, content: &str) -> Result<Option<Vec<Link<Url>>>> {
         let urls = page_parser(&self.0[..], page.type_id)?
-            .navigate(content)
+            .navigate(page, content)
             .context(AppError::PageParserFailed(page.type_id))?;
-        Ok(urls.map(|urls| create_absolute_urls(urls, &page.url)))
+        Ok(urls.map(|urls| dedup_links(create_absolute_urls(urls, &page.url))))
     }
 
     /// Returns parsed key-value pairs for the page
-    pub fn parse(&self, type_id: PageTypeId, content: &str) -> Result<Option<ParsedTables>> {
-        page_parser(&self.0[..], type_id)?
-            .parse(content)
-            .context(AppError::PageParserFailed(type_id))
+    ///
+    /// Use [`into_owned_tables()`] if owned strings are needed.
+    pub fn parse<'c>(&self, page: &Page, content: &'c str) -> Result<Option<BorrowedTables<'c>>> {
+        page_parser(&self.0[..], page.type_id)?
+            .parse(page, content)
+            .context(AppError::PageParserFailed(page.type_id))
     }
 
     /// Validates page content
     ///
     /// If page is not valid it's content will not be written to storage
     /// and crawler will repeat request to the page
-    pub fn validate(&self, type_id: PageTypeId, content: &str) -> Result<bool> {
-        let is_valid = page_parser(&self.0[..], type_id)?
-            .validate(content)
-            .context(AppError::PageParserFailed(type_id))?;
+    pub fn validate(&self, page: &Page, content: &str) -> Result<bool> {
+        let is_valid = page_parser(&self.0[..], page.type_id)?
+            .validate(page, content)
+            .context(AppError::PageParserFailed(page.type_id))?;
         Ok(is_valid)
     }
+
+    /// Transforms page content before it's written to storage
+    pub fn preprocess(&self, page: &Page, content: String) -> Result<String> {
+        page_parser(&self.0[..], page.type_id)?
+            .preprocess(page, content)
+            .context(AppError::PageParserFailed(page.type_id))
+    }
+
+    /// Returns version of parsing rules for a given page type
+    pub fn version(&self, type_id: PageTypeId) -> Result<Option<&str>> {
+        Ok(page_parser(&self.0[..], type_id)?.version())
+    }
 }
 
 fn page_parser(parsers: &[Box<dyn PageParser>], type_id: PageTypeId) -> Result<&dyn PageParser> {
@@ -163,25 +917,40 @@ fn page_parser(parsers: &[Box<dyn PageParser>], type_id: PageTypeId) -> Result<&
         .ok_or_else(|| AppError::PageParserNotFound(type_id).into())
 }
 
-fn create_absolute_urls(
-    input: Vec<(String, PageTypeId)>,
-    base_url: &Url,
-) -> Vec<(Url, PageTypeId)> {
+fn create_absolute_urls(input: Vec<Link<Cow<str>>>, base_url: &Url) -> Vec<Link<Url>> {
     input
         .into_iter()
         .filter_map(|link| create_absolute_url(link, base_url))
         .collect()
 }
 
-fn create_absolute_url(item: (String, PageTypeId), base_url: &Url) -> Option<(Url, PageTypeId)> {
-    let (url, type_id) = item;
+/// Removes repeated links keeping the order of the first occurrences
+///
+/// Parsers often return the same link many times (eg. from a header and a footer of a page), links
+/// are compared after they are made absolute, so `/a` and `http://host/a` are the same link.
+fn dedup_links(mut links: Vec<Link<Url>>) -> Vec<Link<Url>> {
+    let mut seen = HashSet::new();
+    links.retain(|link| seen.insert(link.clone()));
+    links
+}
+
+fn create_absolute_url(link: Link<Cow<str>>, base_url: &Url) -> Option<Link<Url>> {
+    let Link {
+        url,
+        type_id,
+        request,
+    } = link;
     let absolute_url = if url.starts_with("http://") || url.starts_with("https://") {
         Url::parse(&url)
     } else {
         base_url.join(&url)
     };
     match absolute_url {
-        Ok(url) => Some((url, type_id)),
+        Ok(url) => Some(Link {
+            url,
+            type_id,
+            request,
+        }),
         Err(e) => {
             warn!(
                 "Unable to build absolute URL from: {}, base url: {}",
@@ -192,3 +961,75 @@ fn create_absolute_url(item: (String, PageTypeId), base_url: &Url) -> Option<(Ur
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_links_are_removed() -> Result<()> {
+        let base = Url::parse("http://test.com/list")?;
+        let link = |url: &'static str, type_id| Link {
+            url: Cow::from(url),
+            type_id,
+            request: None,
+        };
+        let links = create_absolute_urls(
+            vec![
+                link("/a", 2),
+                link("http://test.com/a", 2),
+                link("/b", 2),
+                link("/a", 3),
+                link("/a", 2),
+            ],
+            &base,
+        );
+        let links = dedup_links(links)
+            .into_iter()
+            .map(|l| (l.url.path().to_string(), l.type_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            [("/a".into(), 2), ("/b".into(), 2), ("/a".into(), 3)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn secrets_are_redacted() -> Result<()> {
+        let mut config = CrabConfig::default_config();
+        config.auth = toml::from_str(
+            r#"
+            "example.com" = { type = "basic", username = "crab", password = "secret" }
+            "api.example.com" = { type = "bearer", token = "env:API_TOKEN" }
+            "#,
+        )?;
+        config.login = Some(toml::from_str(
+            r#"
+            url = "https://example.com/login"
+            form = { user = "crab", password = "secret", otp = "keyring:otp" }
+            "#,
+        )?);
+        config.alerts = Some(toml::from_str(
+            r#"
+            channels = [
+                { type = "slack", webhook_url = "https://hooks.slack.com/services/T0/B0/secret" },
+                { type = "webhook", url = "env:WEBHOOK_URL" },
+                { type = "email", smtp_host = "localhost", smtp_password = "secret", from = "", to = [] },
+            ]
+            "#,
+        )?);
+        config.redact_secrets();
+
+        let config = serde_json::to_value(&config)?;
+        assert_eq!(config["auth"]["example.com"]["password"], "***");
+        assert_eq!(config["auth"]["example.com"]["username"], "crab");
+        assert_eq!(config["auth"]["api.example.com"]["token"], "env:API_TOKEN");
+        assert_eq!(config["login"]["form"]["password"], "***");
+        assert_eq!(config["login"]["form"]["otp"], "keyring:otp");
+        assert_eq!(config["alerts"]["channels"][0]["webhook_url"], "***");
+        assert_eq!(config["alerts"]["channels"][1]["url"], "env:WEBHOOK_URL");
+        assert_eq!(config["alerts"]["channels"][2]["smtp_password"], "***");
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Generic link extraction for page types without navigation rules
+//!
+//! Simple crawls don't need navigation code. Links (`<a href>`) of pages which parser has no
+//! navigation rules are registered with the type of the first rule they match. Only links matching
+//! include patterns of a rule are registered (patterns are the same as in [`filter`](crate::filter)):
+//!
+//! ```toml
+//! [[crawler.page_types.1.links]]
+//! include = ['glob:https://example.com/items/*']
+//! type_id = 2
+//!
+//! [[crawler.page_types.1.links]]
+//! include = ['[?&]page=\d+$']
+//! type_id = 1
+//! ```
+use crate::{
+    dedup_links, filter::UrlFilter, html::find_links, prelude::*, CrawlerConfig, Link, Page,
+    PageParsers, PageTypeId, UrlFilterConfig,
+};
+use anyhow::Context;
+use std::collections::HashMap;
+use url::Url;
+
+#[derive(Debug, Default)]
+pub struct LinkExtractor {
+    /// page type → filter of each rule and the type of links matching it
+    rules: HashMap<PageTypeId, Vec<(UrlFilter, PageTypeId)>>,
+}
+
+impl LinkExtractor {
+    pub fn new(opts: &CrawlerConfig) -> Result<Self> {
+        let mut rules = HashMap::new();
+        for (type_id, config) in opts.page_types.iter().flatten() {
+            if config.links.is_empty() {
+                continue;
+            }
+            let type_id = type_id
+                .parse()
+                .with_context(|| AppError::InvalidPageTypeId(type_id.clone()))?;
+            let mut type_rules = vec![];
+            for rule in &config.links {
+                if rule.include.is_empty() {
+                    return Err(AppError::NoLinkPatterns(type_id).into());
+                }
+                let filter = UrlFilter::new(&UrlFilterConfig {
+                    include: rule.include.clone(),
+                    exclude: rule.exclude.clone(),
+                    record_filtered: false,
+                })?;
+                type_rules.push((filter, rule.type_id));
+            }
+            rules.insert(type_id, type_rules);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Runs navigation rules of the page parser, extracts links by rules of the page type if
+    /// parser has no navigation rules
+    pub fn navigate(
+        &self,
+        parsers: &PageParsers,
+        page: &Page,
+        content: &str,
+    ) -> Result<Option<Vec<Link<Url>>>> {
+        match parsers.navigate(page, content)? {
+            Some(links) => Ok(Some(links)),
+            None => self.extract(page, content),
+        }
+    }
+
+    /// Extracts links of the page matching rules of its type, `None` if there are no rules
+    pub fn extract(&self, page: &Page, content: &str) -> Result<Option<Vec<Link<Url>>>> {
+        let Some(rules) = self.rules.get(&page.type_id) else {
+            return Ok(None);
+        };
+        let links = find_links(content, &page.url)?
+            .into_iter()
+            .filter_map(|url| {
+                let (_, type_id) = rules.iter().find(|(filter, _)| filter.is_allowed(&url))?;
+                Some(Link {
+                    url,
+                    type_id: *type_id,
+                    request: None,
+                })
+            })
+            .collect();
+        Ok(Some(dedup_links(links)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::PageStatus, LinkRuleConfig, PageTypeConfig};
+
+    #[test]
+    fn extract_links_by_rules() -> Result<()> {
+        let rule = |include: &str, type_id| LinkRuleConfig {
+            include: vec![include.to_string()],
+            exclude: vec![],
+            type_id,
+        };
+        let page_type = PageTypeConfig {
+            links: vec![
+                rule("[?&]page=", 1),
+                rule("glob:http://test.com/items/*", 2),
+            ],
+            ..PageTypeConfig::default()
+        };
+        let mut opts = crate::CrabConfig::default_config().crawler;
+        opts.page_types = Some(HashMap::from([("1".to_string(), page_type)]));
+        let extractor = LinkExtractor::new(&opts)?;
+
+        let mut page = Page {
+            status: PageStatus::Downloaded,
+            ..Page::new(1, Url::parse("http://test.com/items/")?, 1)
+        };
+        let html = r#"<a href="1">One</a> <a href="/items/1">Again</a> <a href="/about">About</a>
+            <a href="?page=2">Next</a> <a href="/items/1/reviews">Reviews</a>"#;
+        let links = extractor
+            .extract(&page, html)?
+            .unwrap()
+            .into_iter()
+            .map(|link| (link.url.to_string(), link.type_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            vec![
+                ("http://test.com/items/1".to_string(), 2),
+                ("http://test.com/items/?page=2".to_string(), 1),
+            ]
+        );
+
+        page.type_id = 2;
+        assert!(extractor.extract(&page, html)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+//! Capturing pages in specific languages
+//!
+//! ```toml
+//! [[crawler.locales]]
+//! domain = "example.de"
+//! languages = ["de-DE"]
+//! cookies = { currency = "EUR" }
+//!
+//! [[crawler.locales]]
+//! type_id = 2
+//! languages = ["en-US", "fr-FR"]
+//! headers = { X-Region = "eu" }
+//! ```
+//!
+//! Rule applies to pages of the `domain` (subdomains included) and page type `type_id`, the first
+//! matching rule wins. With a single language pages are requested with `Accept-Language` set to
+//! it along with given headers and cookies. With several languages each matching link is registered
+//! once per language with its own request (see [`crate::storage::RequestSpec`]), so language
+//! variants of the same URL are downloaded and stored as separate pages.
+//!
+//! Cookies are appended to the cookies of the session (see [`crate::session`]) a page is requested
+//! in, cookies of the login (see [`crate::login`]) replace them on its domain.
+use crate::{login, prelude::*, storage::RequestSpec, CrawlerConfig, Link, PageTypeId};
+use anyhow::Context;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, COOKIE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use url::Url;
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LocaleConfig {
+    /// domain the rule applies to, its subdomains included (all domains by default)
+    pub(crate) domain: Option<String>,
+    /// page type the rule applies to (all page types by default)
+    pub(crate) type_id: Option<PageTypeId>,
+    /// values of `Accept-Language`, eg. `["de-DE", "en;q=0.5"]`
+    pub(crate) languages: Vec<String>,
+    /// additional headers sent with every language
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) headers: BTreeMap<String, String>,
+    /// cookies sent with every language (eg. language or region preference of the site)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) cookies: BTreeMap<String, String>,
+}
+
+struct Rule {
+    domain: Option<String>,
+    type_id: Option<PageTypeId>,
+    /// headers of each language
+    variants: Vec<Vec<(String, String)>>,
+}
+
+impl Rule {
+    fn matches(&self, url: &Url, type_id: PageTypeId) -> bool {
+        if self.type_id.is_some_and(|t| t != type_id) {
+            return false;
+        }
+        let Some(domain) = &self.domain else {
+            return true;
+        };
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        host == *domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|sub| sub.ends_with('.'))
+    }
+}
+
+/// Locale rules of the crawler
+#[derive(Default)]
+pub struct Locales(Vec<Rule>);
+
+impl Locales {
+    pub fn new(opts: &CrawlerConfig) -> Result<Self> {
+        Self::from_configs(opts.locales.as_deref().unwrap_or_default())
+    }
+
+    fn from_configs(configs: &[LocaleConfig]) -> Result<Self> {
+        let mut rules = vec![];
+        for config in configs {
+            let name = config.domain.clone().unwrap_or_else(|| "*".into());
+            if config.languages.is_empty() {
+                return Err(AppError::InvalidLocale(name).into());
+            }
+            let cookies = config
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join("; ");
+            let mut variants = vec![];
+            for language in &config.languages {
+                let mut headers = vec![(ACCEPT_LANGUAGE.to_string(), language.clone())];
+                headers.extend(config.headers.clone());
+                if !cookies.is_empty() {
+                    headers.push((COOKIE.to_string(), cookies.clone()));
+                }
+                for (name, value) in &headers {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| AppError::InvalidLocale(name.clone()))?;
+                    HeaderValue::from_str(value)
+                        .with_context(|| AppError::InvalidLocale(name.clone()))?;
+                }
+                variants.push(headers);
+            }
+            rules.push(Rule {
+                domain: config.domain.as_ref().map(|d| d.to_lowercase()),
+                type_id: config.type_id,
+                variants,
+            });
+        }
+        Ok(Self(rules))
+    }
+
+    fn find(&self, url: &Url, type_id: PageTypeId) -> Option<&Rule> {
+        self.0.iter().find(|rule| rule.matches(url, type_id))
+    }
+
+    /// Adds headers of a single language rule matching the page to request headers
+    ///
+    /// Pages of rules with several languages carry headers in their requests already.
+    pub fn apply(&self, url: &Url, type_id: PageTypeId, headers: &mut HeaderMap) -> Result<()> {
+        let Some([variant]) = self.find(url, type_id).map(|rule| rule.variants.as_slice()) else {
+            return Ok(());
+        };
+        for (name, value) in variant {
+            if name == COOKIE.as_str() {
+                login::append_cookies(headers, value)?;
+            } else {
+                headers.insert(
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(value)?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns links to be registered in place of a given one: one per language if a matching rule
+    /// has several languages, the link itself otherwise
+    ///
+    /// Links with explicit requests (eg. form submissions) are never split.
+    pub fn variants(&self, link: Link<Url>) -> Vec<Link<Url>> {
+        let rule = self.find(&link.url, link.type_id);
+        match rule.map(|rule| rule.variants.as_slice()) {
+            Some(variants) if variants.len() > 1 && link.request.is_none() => variants
+                .iter()
+                .map(|headers| Link {
+                    request: Some(RequestSpec {
+                        method: "GET".into(),
+                        form: vec![],
+                        headers: headers.clone(),
+                    }),
+                    ..link.clone()
+                })
+                .collect(),
+            _ => vec![link],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_variants() -> Result<()> {
+        #[derive(Deserialize)]
+        struct Config {
+            locales: Vec<LocaleConfig>,
+        }
+        let config: Config = toml::from_str(
+            r#"
+            [[locales]]
+            domain = "example.de"
+            languages = ["de-DE"]
+            cookies = { currency = "EUR", lang = "de" }
+
+            [[locales]]
+            type_id = 2
+            languages = ["en-US", "fr-FR"]
+            "#,
+        )?;
+        let locales = Locales::from_configs(&config.locales)?;
+        let link = |url: &str, type_id| Link {
+            url: Url::parse(url).unwrap(),
+            type_id,
+            request: None,
+        };
+
+        let mut headers = HeaderMap::new();
+        let url = Url::parse("https://shop.example.de/a")?;
+        locales.apply(&url, 2, &mut headers)?;
+        assert_eq!(headers[ACCEPT_LANGUAGE], "de-DE");
+        assert_eq!(headers[COOKIE], "currency=EUR; lang=de");
+
+        // Session cookies are kept
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("sid=abc"));
+        locales.apply(&url, 2, &mut headers)?;
+        assert_eq!(headers[COOKIE], "sid=abc; currency=EUR; lang=de");
+        assert_eq!(locales.variants(link("https://example.de/a", 2)).len(), 1);
+
+        let variants = locales.variants(link("https://example.com/a", 2));
+        let languages = variants
+            .iter()
+            .map(|link| link.request.as_ref().unwrap().headers[0].1.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(languages, ["en-US", "fr-FR"]);
+        let mut headers = HeaderMap::new();
+        locales.apply(&variants[0].url, 2, &mut headers)?;
+        assert!(headers.is_empty());
+
+        let variants = locales.variants(link("https://example.com/a", 1));
+        assert_eq!(variants[0].request, None);
+
+        let invalid = LocaleConfig {
+            languages: vec![],
+            ..LocaleConfig::default()
+        };
+        assert!(Locales::from_configs(&[invalid]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+//! Pre-crawl login step
+//!
+//! Sites behind a sign-in are crawled by logging in before crawling. Login page is requested first
+//! (so cookies it sets are sent back), then the form is submitted with given fields (secrets can be
+//! read from environment variables):
+//!
+//! ```toml
+//! [login]
+//! url = "https://example.com/login"
+//! form = { username = "crab" }
+//! form_env = { password = "SITE_PASSWORD" }
+//! ```
+//!
+//! If the form is not enough (eg. CSRF token needs to be read from the login page), `python`
+//! names a module in the workspace with `login(client)` function doing the requests:
+//!
+//! ```python
+//! def login(client):
+//!     page = client.get(client.url)
+//!     token = re.search(r'name="csrf" value="(.+?)"', page.text).group(1)
+//!     client.post(client.url, {"csrf": token, "password": os.environ["SITE_PASSWORD"]})
+//!     client.set_header("X-Requested-With", "XMLHttpRequest")
+//! ```
+//!
+//! Cookies set by login responses (including redirects) and headers set by the hook are sent with
+//! all requests to the domain of login URL and its subdomains, replacing `[auth]` rule of the domain.
+use crate::{auth::AuthRules, crawler, prelude::*, python, secrets, CrawlerConfig};
+use anyhow::Context;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, COOKIE, LOCATION, SET_COOKIE},
+    redirect::Policy,
+    Client, Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use tokio::{runtime::Handle, task::spawn_blocking};
+use url::Url;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LoginConfig {
+    /// URL login form is submitted to, session is used for requests to its domain
+    pub(crate) url: String,
+
+    /// form fields submitted to the login URL (values can reference secrets, see [`crate::secrets`])
+    #[serde(default)]
+    pub(crate) form: HashMap<String, String>,
+
+    /// form field → environment variable with the field value
+    #[serde(default)]
+    pub(crate) form_env: HashMap<String, String>,
+
+    /// python module with `login(client)` function used instead of submitting the form
+    pub(crate) python: Option<String>,
+}
+
+impl LoginConfig {
+    /// See [`crate::CrabConfig::redact_secrets()`]
+    ///
+    /// All form fields are redacted, because it's unknown which of them are secrets.
+    pub(crate) fn redact_secrets(&mut self) {
+        self.form.values_mut().for_each(secrets::redact);
+    }
+}
+
+/// Cookies and headers obtained by logging in
+#[derive(Default, Debug, Clone)]
+pub struct Session {
+    cookies: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+}
+
+/// Response to a login request after following redirects
+#[derive(Debug)]
+pub struct LoginResponse {
+    pub status: StatusCode,
+    pub url: Url,
+    pub text: String,
+}
+
+impl Session {
+    /// Makes a request with session cookies following redirects manually, so cookies set by
+    /// intermediate responses are not lost
+    pub async fn request(
+        &mut self,
+        client: &Client,
+        method: Method,
+        url: &Url,
+        form: &[(String, String)],
+    ) -> Result<LoginResponse> {
+        let (mut method, mut url) = (method, url.clone());
+        for _ in 0..=crawler::MAX_REDIRECTS {
+            let mut request = client.request(method.clone(), url.clone());
+            request = request.headers(self.headers()?);
+            if method == Method::POST {
+                request = request.form(form);
+            }
+            let response = request.send().await?;
+            for cookie in response.headers().get_all(SET_COOKIE) {
+                self.set_cookie(cookie.to_str()?);
+            }
+            let status = response.status();
+            let location = response.headers().get(LOCATION).cloned();
+            match location {
+                Some(location) if status.is_redirection() => {
+                    url = url.join(location.to_str()?)?;
+                    if status != StatusCode::TEMPORARY_REDIRECT
+                        && status != StatusCode::PERMANENT_REDIRECT
+                    {
+                        method = Method::GET;
+                    }
+                }
+                _ => {
+                    let text = response.text().await?;
+                    return Ok(LoginResponse { status, url, text });
+                }
+            }
+        }
+        Err(AppError::LoginFailed(url, "too many redirects".into()).into())
+    }
+
+    /// Stores a cookie from `Set-Cookie` header value, attributes are ignored
+    fn set_cookie(&mut self, header: &str) {
+        if let Some((name, value)) = parse_set_cookie(header) {
+            self.cookies.retain(|(n, _)| n != name);
+            self.cookies.push((name.into(), value.into()));
+        }
+    }
+
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.into(), value.into()));
+    }
+
+    /// Headers sent with requests of the session
+    pub fn headers(&self) -> Result<HeaderMap> {
+        let mut result = HeaderMap::new();
+        for (name, value) in &self.headers {
+            result.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        if !self.cookies.is_empty() {
+            let cookies = self
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            result.insert(COOKIE, HeaderValue::from_str(&cookies)?);
+        }
+        Ok(result)
+    }
+}
+
+/// Appends cookies (`name=value; ...`) to `Cookie` header, so cookies of a session, a locale and
+/// a challenge solution are all sent with a request instead of replacing each other
+pub(crate) fn append_cookies(headers: &mut HeaderMap, cookies: &str) -> Result<()> {
+    let cookies = match headers.get(COOKIE) {
+        Some(existing) => format!("{}; {}", existing.to_str()?, cookies),
+        None => cookies.to_owned(),
+    };
+    headers.insert(COOKIE, HeaderValue::from_str(&cookies)?);
+    Ok(())
+}
+
+/// Returns name and value of a cookie from `Set-Cookie` header value, attributes are ignored
+pub(crate) fn parse_set_cookie(header: &str) -> Option<(&str, &str)> {
+    let pair = header.split(';').next().unwrap_or_default();
+    let (name, value) = pair.split_once('=')?;
+    Some((name.trim(), value.trim()))
+}
+
+/// Logs in and returns auth rules sending the session with requests to the login domain
+pub async fn login(
+    config: &LoginConfig,
+    opts: &CrawlerConfig,
+    auth: AuthRules,
+) -> Result<AuthRules> {
+    let url = &Url::parse(&config.url)?;
+    let domain = url
+        .host_str()
+        .ok_or_else(|| AppError::LoginFailed(url.clone(), "URL has no host".into()))?;
+    let client = login_client(opts)?;
+    let session = match &config.python {
+        Some(module) => {
+            let (module, url) = (module.clone(), url.clone());
+            let runtime = Handle::current();
+            spawn_blocking(move || python::login(&module, client, url, runtime)).await??
+        }
+        None => {
+            let mut form = config
+                .form
+                .iter()
+                .map(|(name, value)| Ok((name.clone(), secrets::resolve(value)?)))
+                .collect::<Result<Vec<_>>>()?;
+            for (name, env_name) in &config.form_env {
+                let value = std::env::var(env_name)
+                    .context(AppError::MissingEnvVariable(env_name.clone()))?;
+                form.push((name.clone(), value));
+            }
+            let mut session = Session::default();
+            session.request(&client, Method::GET, url, &[]).await?;
+            let response = session.request(&client, Method::POST, url, &form).await?;
+            if !response.status.is_success() {
+                let status = response.status.to_string();
+                return Err(AppError::LoginFailed(url.clone(), status).into());
+            }
+            session
+        }
+    };
+    info!("Logged in to {}", domain);
+    Ok(auth.with_session(domain, session.headers()?))
+}
+
+fn login_client(opts: &CrawlerConfig) -> Result<Client> {
+    let mut builder = Client::builder().redirect(Policy::none());
+    if let Some(user_agent) = crawler::user_agent(opts) {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(identity) = crawler::client_identity(opts)? {
+        builder = builder.identity(identity);
+    }
+    if let Some(address) = opts.local_address {
+        builder = builder.local_address(address);
+    }
+    let connect_timeout = opts.connect_timeout_sec.unwrap_or(5.0);
+    let read_timeout = opts.read_timeout_sec.unwrap_or(5.0);
+    Ok(builder
+        .connect_timeout(Duration::from_secs_f32(connect_timeout))
+        .timeout(Duration::from_secs_f32(read_timeout))
+        .danger_accept_invalid_certs(true)
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Server,
+    };
+
+    #[tokio::test]
+    async fn cookies_are_collected_across_redirects() -> Result<()> {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|request: hyper::Request<Body>| async move {
+                let response = match request.uri().path() {
+                    "/login" => hyper::Response::builder()
+                        .status(302)
+                        .header(header::LOCATION, "/home")
+                        .header(header::SET_COOKIE, "session=abc; Path=/; HttpOnly")
+                        .header(header::SET_COOKIE, "theme=dark")
+                        .body(Body::empty()),
+                    _ => {
+                        let cookies = request.headers().get(header::COOKIE).cloned();
+                        let ok = cookies.is_some_and(|c| c == "session=abc; theme=dark");
+                        hyper::Response::builder()
+                            .status(if ok { 200 } else { 401 })
+                            .header(header::SET_COOKIE, "session=def")
+                            .body(Body::from("home"))
+                    }
+                };
+                Ok::<_, hyper::Error>(response.unwrap())
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse()?).serve(make_service);
+        let url = Url::parse(&format!("http://{}/login", server.local_addr()))?;
+        tokio::spawn(server);
+
+        let opts = crate::CrabConfig::default_config().crawler;
+        let mut session = Session::default();
+        let form = [("user".to_string(), "crab".to_string())];
+        let response = session
+            .request(&login_client(&opts)?, Method::POST, &url, &form)
+            .await?;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.url.path(), "/home");
+        assert_eq!(response.text, "home");
+        assert_eq!(session.headers()?[COOKIE], "theme=dark; session=def");
+        Ok(())
+    }
+}
@@ -1,37 +1,96 @@
 use anyhow::Context;
-use atom::Atom;
+use chrono::{DateTime, NaiveDate, Utc};
 use clap::Parser;
+use completions::Shell;
 use crab::{
-    crawler::run_crawler,
+    alerts::{Alert, AlertsConfig},
+    auth::AuthRules,
+    canonical::UrlNormalizer,
+    contracts::{ContractCheck, ContractConfig, ContractResult},
+    cookies,
+    crawler::{self, run_crawler, CrawlerCommand, CrawlerState, LinkRules, RunOptions},
+    export::{
+        Aggregate, Aggregation, CurrencyConfig, ExchangeRates, RowFilter, SampleSize, Sampler,
+    },
+    fixtures::{Fixtures, FIXTURES_DIR},
+    html::{self, RobotsDirectives},
+    into_owned_table, into_owned_tables,
+    links::LinkExtractor,
+    locale::Locales,
+    login,
+    manifest::Manifest,
+    parser_threads::ParserThreads,
+    pii::Scrubber,
     prelude::*,
     python::{self, PythonPageParser},
-    storage::{self, Storage},
-    CrabConfig, CrawlerReport, PageParser, PageParsers, PageTypeId,
+    sink::{MissingValue, SinkRegistry, SinkTarget},
+    stats,
+    storage::{self, PageStatus, RequestSpec, SqliteStorage, Storage},
+    trace::{self, Event, Replay},
+    work_queue::{self, WorkQueue},
+    CrabConfig, Link, Page, PageParser, PageParsers, PageTypeId,
+};
+use crossterm::style::Color;
+use futures::{future::try_join_all, select, stream, FutureExt, StreamExt};
+use output::{Cell, OutputStyle, Table};
+use progress::Progress;
+use reqwest::Proxy;
+use serde::{Deserialize, Serialize};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
 };
-use futures::{select, FutureExt, StreamExt};
 use std::{
+    env,
+    ffi::OsStr,
     fs::{self, File},
-    io::stdout,
+    io::{stdin, stdout, BufRead, BufReader, IsTerminal, Write},
+    net::SocketAddr,
     path::{Path, PathBuf},
-    sync::{atomic::Ordering, Arc},
+    process::{self, Command},
+    sync::Arc,
+    thread,
     time::Duration,
 };
-use table::Table;
-use tokio::task::spawn_blocking;
+use tokio::{
+    sync::{mpsc, watch},
+    task::spawn_blocking,
+};
+use url::Url;
 
-mod table;
+mod browse;
+mod completions;
+mod output;
+mod progress;
 mod terminal;
 
+/// Number of pages read from the database at once by commands processing all downloaded pages
+const PAGES_BATCH_SIZE: usize = 100;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Opts {
     #[arg(short = 'w', default_value = ".")]
     workspace: PathBuf,
 
+    /// disable colors in the output
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// print tables as tab separated values without alignment and colors
+    #[arg(long, global = true)]
+    plain: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Opts {
+    fn output_style(&self) -> OutputStyle {
+        OutputStyle::new(self.no_color, self.plain)
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 enum Commands {
@@ -49,6 +108,62 @@ enum Commands {
         /// after downloading each page parse next pages
         #[arg(long, default_value = "false")]
         navigate: bool,
+        /// re-download already downloaded pages using conditional requests
+        #[arg(long)]
+        refresh: bool,
+        /// seed of the crawler RNG (random by default)
+        #[arg(long)]
+        seed: Option<u64>,
+        /// write scheduling decisions to a given file for `crab replay`
+        #[arg(long)]
+        trace: Option<PathBuf>,
+        /// create the database if it doesn't exist and apply pending migrations
+        #[arg(long)]
+        auto_migrate: bool,
+        /// hand requests to `crab worker` processes connecting to a given address instead of sending them
+        #[arg(long)]
+        listen_workers: Option<SocketAddr>,
+        /// download one page of each type first and abort if any of them fails validation
+        #[arg(long)]
+        canary: bool,
+        /// interval in seconds crawler reports its state at (overrides `report_interval_sec`)
+        #[arg(long, value_parser = parse_seconds)]
+        report_interval: Option<Duration>,
+        /// interval in seconds crawler screen is redrawn at (overrides `ui_refresh_sec`)
+        #[arg(long, value_parser = parse_seconds)]
+        ui_refresh: Option<Duration>,
+    },
+
+    /// re-download downloaded pages using conditional requests and report how many of them changed
+    ///
+    /// Lighter alternative to resetting pages, the rest of the frontier is not downloaded.
+    Refresh {
+        /// refresh only pages of a given type
+        #[arg(long)]
+        type_id: Option<PageTypeId>,
+    },
+
+    /// download pages for a crawler started with `--listen-workers`
+    Worker {
+        /// URL of the crawler work queue, eg. `http://crawler-host:7878`
+        queue: Url,
+        /// number of pages downloaded simultaneously
+        #[arg(long, default_value_t = 4)]
+        threads: usize,
+    },
+
+    /// make a single request the way crawler does and print response status, headers and content
+    Fetch {
+        url: Url,
+        /// page type which request headers are sent (required by `--store`)
+        #[arg(long)]
+        type_id: Option<PageTypeId>,
+        /// proxy to make the request through, eg. `socks5://127.0.0.1:1080`
+        #[arg(long)]
+        proxy: Option<String>,
+        /// write the response to the database as the page content
+        #[arg(long, requires = "type_id")]
+        store: bool,
     },
 
     /// add page to the database
@@ -67,6 +182,9 @@ enum Commands {
         columns: Vec<String>,
         // page id to parse
         page_id: i64,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
     },
 
     /// run parsing rules on all pages and exports CSV
@@ -74,18 +192,164 @@ enum Commands {
         /// list of comma separated column names to filter
         #[arg(short = 'n')]
         columns: Vec<String>,
+        /// export dataset as it was at a given time (RFC 3339 or `YYYY-MM-DD` which is midnight UTC)
+        #[arg(long, value_parser = parse_timestamp)]
+        as_of: Option<DateTime<Utc>>,
+        /// export only pages which content changed since a given time (RFC 3339 or `YYYY-MM-DD`)
+        #[arg(long, value_parser = parse_timestamp, conflicts_with = "as_of")]
+        changed_since: Option<DateTime<Utc>>,
+        /// include source page id, url, fetch time and parser version columns in each row
+        #[arg(long)]
+        provenance: bool,
+        /// do not export pages marked as duplicates of other pages (see `detect_duplicates`)
+        #[arg(long, conflicts_with = "as_of")]
+        skip_duplicates: bool,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+        /// output sink: csv, json, sqlite or webhook
+        #[arg(long, default_value = "csv")]
+        sink: String,
+        /// output file, database or URL depending on the sink (csv and json are written to stdout if not given)
+        #[arg(short = 'o', long)]
+        output: Option<String>,
+        /// export only rows matching a condition, eg. `price>100` or `brand=AMD` (all of them if repeated)
+        #[arg(long = "where")]
+        filters: Vec<RowFilter>,
+        /// how cells missing in a row are written by csv and json sinks: empty, null or any other text
+        #[arg(long, default_value = "empty")]
+        missing: MissingValue,
+        /// export rows of a given number of randomly chosen pages instead of all of them
+        #[arg(long, conflicts_with = "sample_frac")]
+        sample: Option<usize>,
+        /// export rows of a given fraction of randomly chosen pages, eg. `0.01`
+        #[arg(long, value_parser = parse_fraction)]
+        sample_frac: Option<f64>,
+        /// sample a given number of pages of each page type
+        #[arg(long, requires = "sample")]
+        stratify: bool,
+        /// seed of the sampling RNG, so the same sample can be exported again (random by default)
+        #[arg(long)]
+        seed: Option<u64>,
         /// table name to print
         table: String,
     },
 
+    /// summarize parsed rows of a table and export CSV, eg. average price by brand
+    Aggregate {
+        /// table name to summarize
+        #[arg(long)]
+        table: String,
+        /// columns rows are grouped by (all rows are summarized in one row if not given)
+        #[arg(long)]
+        group_by: Vec<String>,
+        /// number of rows in a group
+        #[arg(long)]
+        count: bool,
+        /// sum of numeric column values
+        #[arg(long)]
+        sum: Vec<String>,
+        /// average of numeric column values
+        #[arg(long)]
+        avg: Vec<String>,
+        /// minimum of numeric column values
+        #[arg(long)]
+        min: Vec<String>,
+        /// maximum of numeric column values
+        #[arg(long)]
+        max: Vec<String>,
+        /// summarize only rows matching a condition (same as in `export-table`)
+        #[arg(long = "where")]
+        filters: Vec<RowFilter>,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+    },
+
+    /// interactively browse stored pages side by side with parsing results
+    ///
+    /// Pages can be tagged and marked for reset, marked pages are reset on exit.
+    Browse,
+
     /// list pages in the database
     ListPages {
         /// disable header output
         #[arg(short = 'n', long, default_value_t = false)]
         no_header: bool,
+        /// list only pages with a given tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+    },
+
+    /// list pages which content changed since a given time (RFC 3339 or `YYYY-MM-DD`)
+    ChangedPages {
+        #[arg(value_parser = parse_timestamp)]
+        since: DateTime<Utc>,
+        /// disable header output
+        #[arg(short = 'n', long, default_value_t = false)]
+        no_header: bool,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+    },
+
+    /// list pages crawler chose not to download and the reason of skipping
+    Skipped {
+        /// disable header output
+        #[arg(short = 'n', long, default_value_t = false)]
+        no_header: bool,
+    },
+
+    /// print effective configuration: `crab.toml` with default values filled in (secrets are hidden)
+    Config {
+        /// print as JSON instead of TOML
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// list pages which content duplicates another page (see `detect_duplicates`)
+    Duplicates {
+        /// disable header output
+        #[arg(short = 'n', long, default_value_t = false)]
+        no_header: bool,
+    },
+
+    /// list pages which last download attempt failed and the reason of the failure
+    Failures {
+        /// disable header output
+        #[arg(short = 'n', long, default_value_t = false)]
+        no_header: bool,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+    },
+
+    /// print request timings of downloaded pages aggregated by host
+    Stats {
+        /// disable header output
+        #[arg(short = 'n', long, default_value_t = false)]
+        no_header: bool,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+    },
+
+    /// checks data contracts (`[[contracts]]` in crab.toml) and fails if any of them is violated
+    CheckData {
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+        /// send violated assertions to alert channels (see `[alerts]`)
+        #[arg(long)]
+        alert: bool,
     },
 
     /// prints pages failed validation check
+    ///
+    /// Parsers of different page types are run in parallel, progress is reported to stderr.
     Validate {
         /// resets not valid pages to initial state
         #[arg(short, long)]
@@ -93,41 +357,230 @@ enum Commands {
     },
 
     /// prints a page
-    Dump { page_id: i64 },
+    Dump {
+        /// print HTTP response metadata instead of the content
+        #[arg(long)]
+        meta: bool,
+        /// open database in read-only mode, safe to use while crawler is running
+        #[arg(long)]
+        read_only: bool,
+        page_id: i64,
+    },
+
+    /// open stored page content in the default browser
+    Open {
+        /// open the page URL instead of the stored content
+        #[arg(long)]
+        live: bool,
+        page_id: i64,
+    },
 
     /// resets page download status
     Reset { page_id: i64 },
 
+    /// changes type of a page (or all pages which URL contains a given text)
+    ///
+    /// Content of downloaded pages is kept, so they are parsed by parsers of the new type.
+    #[command(allow_missing_positional = true)]
+    Retype {
+        #[arg(required_unless_present = "url_contains")]
+        page_id: Option<i64>,
+        /// change type of all pages which URL contains a given text
+        #[arg(long, conflicts_with = "page_id")]
+        url_contains: Option<String>,
+        type_id: PageTypeId,
+    },
+
+    /// changes URL of a page keeping its content (eg. after the site moved pages)
+    Move { page_id: i64, url: String },
+
+    /// merges a page into another one, combining content history and tags of both pages
+    ///
+    /// The first page is removed, URL and type of the second page are kept. The most recently
+    /// downloaded content becomes the current content of the page.
+    Merge {
+        from_page_id: i64,
+        into_page_id: i64,
+    },
+
+    /// removes old versions of page content from the history, current content is kept
+    PruneHistory {
+        /// number of the latest previous versions kept for each page
+        #[arg(long, default_value_t = 0)]
+        keep: u32,
+    },
+
     /// display information about parsers
     Parsers,
+
+    /// export or import queue of not downloaded pages
+    #[command(subcommand)]
+    Frontier(FrontierCommands),
+
+    /// print scheduling decisions written by `run-crawler --trace` and check they can be reproduced
+    Replay { trace: PathBuf },
+
+    /// snapshot testing of parsers on fixture pages stored in `fixtures` directory
+    #[command(subcommand)]
+    Snapshot(SnapshotCommands),
+
+    /// print shell completion script, eg. `source <(crab completions bash)`
+    Completions { shell: Shell },
+}
+
+#[derive(Parser, Debug)]
+enum FrontierCommands {
+    /// print not downloaded pages as NDJSON
+    Export,
+
+    /// register pages from NDJSON file (or stdin if no file given)
+    Import { path: Option<PathBuf> },
+}
+
+#[derive(Parser, Debug)]
+enum SnapshotCommands {
+    /// add given pages to fixtures and rewrite parser outputs of all fixtures
+    Update { page_ids: Vec<i64> },
+
+    /// fail if parser outputs on fixtures differ from the stored ones (doesn't require database)
+    Check,
+}
+
+/// Frontier line in NDJSON format
+#[derive(Serialize, Deserialize)]
+struct FrontierEntry {
+    url: String,
+    type_id: PageTypeId,
+    #[serde(default)]
+    depth: u16,
+    /// request page is downloaded with, plain `GET` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request: Option<RequestSpec>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    completions::complete();
     // temporary workaround of https://github.com/rust-lang/rust-analyzer/issues/14137
     entrypoint().await
 }
 
+/// Makes sure the database exists and is migrated to the latest version
+///
+/// Database is created and migrated if `auto_migrate` is set, otherwise an error is returned.
+fn check_database(path: &Path, auto_migrate: bool) -> Result<()> {
+    if auto_migrate {
+        if !path.exists() {
+            info!("Creating database {}", path.display());
+        }
+        return migrate_database(path);
+    }
+    if !path.exists() {
+        return Err(AppError::DatabaseNotFound(path.to_path_buf()).into());
+    }
+    let pending = storage::pending_migrations(path)?;
+    if pending > 0 {
+        return Err(AppError::PendingMigrations(pending).into());
+    }
+    Ok(())
+}
+
+/// Migrates the database reporting progress of applying migrations
+fn migrate_database(path: &Path) -> Result<()> {
+    let mut progress = None;
+    storage::migrate_with_progress(path, |applied, pending| {
+        progress
+            .get_or_insert_with(|| Progress::new(pending as i64).unit("migrations"))
+            .set(applied as i64);
+    })?;
+    if let Some(progress) = progress {
+        progress.finish(format_args!("database is up to date"));
+    }
+    Ok(())
+}
+
 fn read_config(path: impl AsRef<Path>) -> Result<CrabConfig> {
     let toml = fs::read_to_string(&path)?;
     Ok(toml::from_str(&toml)?)
 }
 
-async fn read_env(opts: &Opts) -> Result<(CrabConfig, Storage, PageParsers)> {
-    let config_path = opts.workspace.join("crab.toml");
+async fn read_env(opts: &Opts) -> Result<(CrabConfig, SqliteStorage, PageParsers)> {
+    open_env(opts, false).await
+}
+
+/// Reads workspace config, opens the database (optionally in read-only mode) and loads parsers
+async fn open_env(
+    opts: &Opts,
+    read_only: bool,
+) -> Result<(CrabConfig, SqliteStorage, PageParsers)> {
+    let auto_migrate = matches!(
+        opts.command,
+        Commands::RunCrawler {
+            auto_migrate: true,
+            ..
+        }
+    );
+    open_workspace(&opts.workspace, auto_migrate, read_only).await
+}
+
+/// Same as [`open_env()`], but for a given workspace
+async fn open_workspace(
+    workspace: &Path,
+    auto_migrate: bool,
+    read_only: bool,
+) -> Result<(CrabConfig, SqliteStorage, PageParsers)> {
+    let config_path = workspace.join("crab.toml");
     let config = read_config(&config_path).context(AppError::ReadingConfig(config_path.clone()))?;
+    if config.database.is_postgres() {
+        return Err(AppError::SqliteRequired.into());
+    }
+
+    check_database(config.database.path(), auto_migrate && !read_only)?;
 
-    let database_path = config.database.to_str().unwrap();
-    let storage = Storage::new(database_path)
+    let options = config.database.connect_options()?;
+    let mut storage = SqliteStorage::connect(options, read_only)
         .await
         .context(AppError::OpeningDatabase)?;
+    storage.set_max_page_size(Some(config.page_size_limit()));
+    if let Some(shards) = config.shards {
+        storage.open_shards(config.database.path(), shards).await?;
+    }
 
-    let parsers =
-        create_dyn_python_parsers(&opts.workspace).context(AppError::LoadingPythonParsers)?;
+    let parsers = create_dyn_python_parsers(workspace).context(AppError::LoadingPythonParsers)?;
     let parsers = PageParsers(parsers);
     Ok((config, storage, parsers))
 }
 
+/// Same as [`open_env()`], but opens PostgreSQL database as well (see `postgres` module)
+///
+/// Used by commands which need only [`Storage`] methods (crawling and reading downloaded pages).
+/// Content shards are not supported by PostgreSQL database, so such configs are rejected.
+async fn open_storage(
+    opts: &Opts,
+    read_only: bool,
+) -> Result<(CrabConfig, Box<dyn Storage>, PageParsers)> {
+    #[cfg(feature = "postgres")]
+    {
+        let config_path = opts.workspace.join("crab.toml");
+        let config =
+            read_config(&config_path).context(AppError::ReadingConfig(config_path.clone()))?;
+        if config.database.is_postgres() {
+            if config.shards.is_some() {
+                return Err(AppError::PostgresShards.into());
+            }
+            let mut storage = crab::postgres::PgStorage::connect(&config.database, read_only)
+                .await
+                .context(AppError::OpeningDatabase)?;
+            storage.set_max_page_size(Some(config.page_size_limit()));
+            let parsers = create_dyn_python_parsers(&opts.workspace)
+                .context(AppError::LoadingPythonParsers)?;
+            return Ok((config, Box::new(storage), PageParsers(parsers)));
+        }
+    }
+    let (config, storage, parsers) = open_env(opts, read_only).await?;
+    Ok((config, Box::new(storage), parsers))
+}
+
 async fn entrypoint() -> Result<()> {
     env_logger::init();
     let app_opts = Opts::parse();
@@ -139,7 +592,7 @@ async fn entrypoint() -> Result<()> {
             let config = CrabConfig::default_config();
             fs::write(workspace.join("crab.toml"), toml::to_string(&config)?)?;
 
-            let database_path = workspace.join(&config.database);
+            let database_path = workspace.join(config.database.path());
             File::create(&database_path)?;
             storage::migrate(database_path)?;
             fs::write(
@@ -148,88 +601,300 @@ async fn entrypoint() -> Result<()> {
             )?;
         }
 
+        Commands::Worker { queue, threads } => {
+            work_queue::run_worker(queue.clone(), *threads).await?;
+        }
+
         Commands::Migrate => {
-            let (config, _, _) = read_env(&app_opts).await?;
-            storage::migrate(config.database)?;
+            let config_path = app_opts.workspace.join("crab.toml");
+            let config =
+                read_config(&config_path).context(AppError::ReadingConfig(config_path.clone()))?;
+            #[cfg(feature = "postgres")]
+            if config.database.is_postgres() {
+                crab::postgres::PgStorage::connect(&config.database, false)
+                    .await
+                    .context(AppError::OpeningDatabase)?;
+                return Ok(());
+            }
+            migrate_database(config.database.path())?;
         }
 
-        Commands::RunCrawler { navigate } => {
-            let (config, storage, parsers) = read_env(&app_opts).await?;
-            let report = Arc::new(Atom::empty());
-            let tick_interval = Duration::from_millis(100);
-            let terminal_handle = {
-                let report = report.clone();
-                spawn_blocking(move || terminal::ui(report, tick_interval))
+        Commands::RunCrawler {
+            navigate,
+            refresh,
+            seed,
+            trace,
+            listen_workers,
+            canary,
+            report_interval,
+            ui_refresh,
+            ..
+        } => {
+            let (config, storage, parsers) = open_storage(&app_opts, false).await?;
+            let work_queue = match listen_workers {
+                Some(addr) => {
+                    let queue = Arc::new(WorkQueue::new(&config.crawler));
+                    let (_, server) = queue.clone().serve(*addr)?;
+                    tokio::spawn(async move {
+                        if let Err(e) = server.await {
+                            error!("Work queue server failed: {:?}", e);
+                        }
+                    });
+                    Some(queue)
+                }
+                None => None,
             };
+            Manifest::new(&app_opts.workspace, &config, &*storage)
+                .await
+                .and_then(|manifest| manifest.write(config.manifest_dir()))
+                .context(AppError::WritingManifest)?;
+            let auth = auth_rules(&config).await?;
+            let scrubber = config.pii.as_ref().map(Scrubber::new).transpose()?;
+            let (report, reports) = watch::channel(Arc::new(CrawlerState::default()));
+            let last_report = reports.clone();
+            let report_interval = report_interval.unwrap_or_else(|| config.report_interval());
+            let ui_refresh = ui_refresh
+                .or_else(|| config.ui_refresh_interval())
+                .unwrap_or(report_interval);
+            let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+            shutdown_on_signal(commands_tx.clone())?;
+            let terminal_handle =
+                spawn_blocking(move || terminal::ui(reports, commands_tx, ui_refresh));
             let crawling_handle = run_crawler(
                 parsers,
                 storage,
                 config.crawler,
-                *navigate,
-                (report.clone(), tick_interval),
+                auth,
+                RunOptions {
+                    navigate: *navigate,
+                    refresh: *refresh,
+                    refresh_type_id: None,
+                    refresh_only: false,
+                    scrubber: scrubber.map(Arc::new),
+                    seed: *seed,
+                    trace: trace.clone(),
+                    work_queue,
+                    canary: *canary,
+                },
+                (report, report_interval),
+                commands_rx,
             );
 
             let mut crawler_handle = Box::pin(crawling_handle.fuse());
             let mut terminal_handle = Box::pin(terminal_handle.fuse());
 
-            select! {
+            let completed = select! {
                 // If terminal is finished first we do not want to wait on crawler
-                result = terminal_handle => result??,
-                // If crawler is finished first we still need to wait on terminal
+                result = terminal_handle => {
+                    result??;
+                    false
+                },
+                // If crawler is finished first we still need to wait on terminal. Reports channel
+                // is closed at this point, so terminal is finishing as well
                 result = crawler_handle => {
-                    report.swap(Box::new(CrawlerReport::Finished), Ordering::Relaxed);
                     result?;
                     terminal_handle.await??;
+                    true
                 },
             };
+            // Checks of an interrupted crawl would fail just because the dataset is incomplete
+            if completed {
+                let state = last_report.borrow().clone();
+                let mut problems = vec![];
+                let alerts = config.alerts.as_ref();
+                if let Some(problem) = alerts.and_then(|a| a.check_error_rate(&state)) {
+                    eprintln!("{}", problem);
+                    problems.push(problem);
+                }
+                if !config.contracts.is_empty() {
+                    let (_, storage, parsers) = open_storage(&app_opts, true).await?;
+                    let style = app_opts.output_style();
+                    let failed = check_data(&config.contracts, &*storage, &parsers, style).await?;
+                    problems.extend(failed.iter().map(|r| format!("Data contract failed: {r}")));
+                }
+                if !problems.is_empty() {
+                    let count = problems.len();
+                    send_alert(alerts, &app_opts.workspace, problems).await;
+                    return Err(AppError::CrawlChecksFailed(count).into());
+                }
+            }
+        }
+
+        Commands::Refresh { type_id } => {
+            let (config, storage, parsers) = open_storage(&app_opts, false).await?;
+            let total = storage.count_downloaded_pages(*type_id).await?;
+            let auth = auth_rules(&config).await?;
+            let scrubber = config.pii.as_ref().map(Scrubber::new).transpose()?;
+            let (report, mut reports) = watch::channel(Arc::new(CrawlerState::default()));
+            let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+            shutdown_on_signal(commands_tx)?;
+            let report_interval = config.report_interval();
+            let progress_handle = tokio::spawn(async move {
+                let mut progress = Progress::new(total);
+                while reports.changed().await.is_ok() {
+                    let state = reports.borrow_and_update().clone();
+                    progress.set((state.successfull_requests + state.failed_pages) as i64);
+                }
+                let state = reports.borrow().clone();
+                (progress, state)
+            });
+            run_crawler(
+                parsers,
+                storage,
+                config.crawler,
+                auth,
+                RunOptions {
+                    navigate: false,
+                    refresh: true,
+                    refresh_type_id: *type_id,
+                    refresh_only: true,
+                    scrubber: scrubber.map(Arc::new),
+                    seed: None,
+                    trace: None,
+                    work_queue: None,
+                    canary: false,
+                },
+                (report, report_interval),
+                commands_rx,
+            )
+            .await?;
+            let (progress, state) = progress_handle.await?;
+            let unchanged = state.successfull_requests - state.changed_pages;
+            progress.finish(format_args!(
+                "{} changed, {} not changed ({} not modified), {} failed",
+                state.changed_pages, unchanged, state.not_modified_pages, state.failed_pages
+            ));
+        }
+
+        Commands::Fetch {
+            url,
+            type_id,
+            proxy,
+            store,
+        } => {
+            let config_path = app_opts.workspace.join("crab.toml");
+            let config =
+                read_config(&config_path).context(AppError::ReadingConfig(config_path.clone()))?;
+            let auth = auth_rules(&config).await?;
+            let proxy = proxy.as_deref().map(Proxy::all).transpose()?;
+            let (content, meta) =
+                crawler::fetch(&config.crawler, &auth, url, *type_id, proxy).await?;
+            println!("Status: {}", meta.status);
+            println!("Final URL: {}", meta.final_url);
+            for redirect in &meta.redirects {
+                println!("Redirected from: {}", redirect);
+            }
+            if meta.truncated {
+                println!("Truncated: content is larger than max_body_bytes");
+            }
+            for (name, value) in &meta.headers {
+                println!("{}: {}", name, value);
+            }
+            println!();
+            println!("{}", content);
+
+            if let (true, Some(type_id)) = (store, type_id) {
+                let (_, mut storage, _) = read_env(&app_opts).await?;
+                let page_id = match storage.find_page_by_url(url).await? {
+                    Some(page) => page.id,
+                    None => storage
+                        .register_page(url.clone(), *type_id, 0)
+                        .await?
+                        .expect("page with the URL is not registered yet"),
+                };
+                storage
+                    .write_page_content(page_id, &content, Some(&meta))
+                    .await?;
+                eprintln!("Response is written to page #{}", page_id);
+            }
         }
 
         Commands::Register { url, type_id } => {
-            let (_, mut storage, _) = read_env(&app_opts).await?;
-            storage.register_page(url.as_str(), *type_id, 0).await?;
+            let (config, mut storage, _) = open_storage(&app_opts, false).await?;
+            let url = UrlNormalizer::new(&config.crawler).normalize(Url::parse(url)?);
+            let link = Link {
+                url,
+                type_id: *type_id,
+                request: None,
+            };
+            for link in Locales::new(&config.crawler)?.variants(link) {
+                storage.register_link(link, 0).await?;
+            }
         }
 
         Commands::Navigate { page_id } => {
-            let (_, storage, parsers) = read_env(&app_opts).await?;
+            let (config, storage, parsers) = read_env(&app_opts).await?;
             let content = storage.read_page_content(*page_id).await?;
             let page = storage.read_page(*page_id).await?;
             let (page, (content, _)) = page.zip(content).ok_or(AppError::PageNotFound(*page_id))?;
-            for (link, type_id) in parsers.navigate(&page, &content)?.unwrap_or_default() {
-                println!("{:3}  {}", type_id, link);
+            let extractor = LinkExtractor::new(&config.crawler)?;
+            for link in extractor
+                .navigate(&parsers, &page, &content)?
+                .unwrap_or_default()
+            {
+                match link.request {
+                    Some(request) => {
+                        println!("{:3}  {} {}", link.type_id, request.method, link.url)
+                    }
+                    None => println!("{:3}  {}", link.type_id, link.url),
+                }
             }
         }
 
         Commands::NavigateAll => {
-            let (_, mut storage, parsers) = read_env(&app_opts).await?;
+            let (config, mut storage, parsers) = open_storage(&app_opts, false).await?;
+            // Links of duplicate pages are the same as links of the original page
+            storage.set_skip_duplicates(true);
             // Need to buffer all found page links so iterating over downloaded pages doesn't
             // interfere with page registering process
             let mut links = vec![];
+            let extractor = LinkExtractor::new(&config.crawler)?;
+            let mut progress = Progress::new(storage.count_downloaded_pages(None).await?);
 
-            let mut pages = storage.read_downloaded_pages();
-            while let Some(row) = pages.next().await {
-                let (page, content) = row?;
-                let page_links = parsers.navigate(&page, &content)?;
-                links.push((page.depth, page_links));
+            let mut batches = storage.read_downloaded_pages_batched(PAGES_BATCH_SIZE);
+            while let Some(batch) = batches.next().await {
+                for (page, content) in batch? {
+                    let mut page_links = extractor.navigate(&parsers, &page, &content)?;
+                    if config.honor_robots_meta() {
+                        let directives = RobotsDirectives::parse(&content, &page.url)?;
+                        page_links = page_links.map(|links| directives.filter_links(links));
+                    }
+                    links.push((page.depth, page_links));
+                    progress.inc();
+                }
             }
-            drop(pages);
+            drop(batches);
 
+            let rules = LinkRules::new(&config.crawler)?;
+            let (mut new_links, mut filtered_links) = (0, 0);
             for (page_depth, page_links) in links {
-                for (link, type_id) in page_links.unwrap_or_default() {
-                    storage
-                        .register_page(link.as_str(), type_id, page_depth)
-                        .await?;
-                }
+                let page_links = page_links.unwrap_or_default();
+                let registered = rules
+                    .register(&mut *storage, page_links, page_depth + 1)
+                    .await?;
+                new_links += registered.new;
+                filtered_links += registered.filtered;
             }
+            progress.finish(format_args!(
+                "{} new links found, {} filtered{}",
+                new_links,
+                filtered_links,
+                oversized_summary(&*storage)
+            ));
+            check_oversized_pages(&*storage)?;
         }
 
-        Commands::Parse { columns, page_id } => {
-            let (_, storage, parsers) = read_env(&app_opts).await?;
-            let (content, type_id) = storage
-                .read_page_content(*page_id)
-                .await?
-                .ok_or(AppError::PageNotFound(*page_id))?;
-            let tables = parsers.parse(type_id, &content)?.unwrap_or_default();
-            for (table_name, table) in tables.into_iter() {
+        Commands::Parse {
+            columns,
+            page_id,
+            read_only,
+        } => {
+            let (_, storage, parsers) = open_env(&app_opts, *read_only).await?;
+            let content = storage.read_page_content(*page_id).await?;
+            let page = storage.read_page(*page_id).await?;
+            let (page, (content, _)) = page.zip(content).ok_or(AppError::PageNotFound(*page_id))?;
+            let tables = parsers.parse(&page, &content)?.unwrap_or_default();
+            for (table_name, table) in into_owned_tables(tables) {
                 println!("{table_name}");
                 println!("------------------------");
                 for row in table.into_iter() {
@@ -243,64 +908,434 @@ async fn entrypoint() -> Result<()> {
             }
         }
 
-        Commands::ExportTable { table, columns } => {
-            let (_, storage, parsers) = read_env(&app_opts).await?;
-            let mut csv = Table::default();
-            let mut pages = storage.read_downloaded_pages();
+        Commands::ExportTable {
+            table,
+            columns,
+            as_of,
+            changed_since,
+            provenance,
+            skip_duplicates,
+            read_only,
+            sink,
+            output,
+            filters,
+            missing,
+            sample,
+            sample_frac,
+            stratify,
+            seed,
+        } => {
+            let target = SinkTarget {
+                table: table.clone(),
+                location: output.clone(),
+                missing: missing.clone(),
+            };
+            let mut sink = SinkRegistry::with_builtins().create(sink, &target)?;
+            let (config, mut storage, parsers) = open_storage(&app_opts, *read_only).await?;
+            storage.set_skip_duplicates(*skip_duplicates);
+            let columns_config = config.columns.unwrap_or_default();
+            let exchange_rates = load_exchange_rates(config.currency).await?;
+            let scrubber = config.pii.as_ref().map(Scrubber::new).transpose()?;
+            let (mut pages, mut progress) = match (as_of, changed_since) {
+                (Some(as_of), _) => (
+                    storage.read_downloaded_pages_as_of(*as_of),
+                    Progress::unbounded(),
+                ),
+                (None, Some(since)) => (storage.read_changed_pages(*since), Progress::unbounded()),
+                (None, None) => (
+                    storage.read_downloaded_pages(),
+                    Progress::new(storage.count_downloaded_pages(None).await?),
+                ),
+            };
+            let sample_size = sample
+                .map(SampleSize::Pages)
+                .or(sample_frac.map(SampleSize::Fraction));
+            if let Some(size) = sample_size {
+                let seed = seed.unwrap_or_else(rand::random);
+                let mut sampler = Sampler::new(size, *stratify, seed);
+                while let Some(row) = pages.next().await {
+                    let (page, content) = row?;
+                    sampler.add(page.type_id, (page, content));
+                }
+                let mut sample = sampler.into_sample();
+                sample.sort_by_key(|(page, _)| page.id);
+                progress = Progress::new(sample.len() as i64);
+                pages = stream::iter(sample.into_iter().map(Ok)).boxed();
+            }
+
+            // Rows written to the terminal would be mixed up with the progress line
+            let mut progress = progress.hide_if(output.is_none() && stdout().is_terminal());
+            let mut exported_rows = 0;
+            while let Some(row) = pages.next().await {
+                let (page, content) = row?;
+                let mut tables = parsers.parse(&page, &content)?.unwrap_or_default();
+                let table = into_owned_table(tables.remove(table.as_str()).unwrap_or_default());
+                let provenance = if *provenance {
+                    provenance_columns(&page, parsers.version(page.type_id)?)
+                } else {
+                    vec![]
+                };
+                for row in table.into_iter() {
+                    let fetched_at = page.downloaded_at.unwrap_or_else(Utc::now);
+                    let mut row = columns_config.apply(row, fetched_at);
+                    if let Some(exchange_rates) = &exchange_rates {
+                        row = exchange_rates.apply(row);
+                    }
+                    if !filters.iter().all(|filter| filter.matches(&row)) {
+                        continue;
+                    }
+                    if let Some(scrubber) = &scrubber {
+                        row = scrubber.scrub_row(page.id, row)?;
+                    }
+                    let row = row.into_iter().filter(column_contains(columns));
+                    sink.write_row(provenance.iter().cloned().chain(row).collect())
+                        .await?;
+                    exported_rows += 1;
+                }
+                progress.inc();
+            }
+            sink.finish().await?;
+            progress.finish(format_args!(
+                "{} rows exported{}",
+                exported_rows,
+                oversized_summary(&*storage)
+            ));
+            check_oversized_pages(&*storage)?;
+        }
 
+        Commands::Aggregate {
+            table,
+            group_by,
+            count,
+            sum,
+            avg,
+            min,
+            max,
+            filters,
+            read_only,
+        } => {
+            let (config, storage, parsers) = open_env(&app_opts, *read_only).await?;
+            let columns_config = config.columns.unwrap_or_default();
+            let exchange_rates = load_exchange_rates(config.currency).await?;
+            let functions = [
+                (Aggregate::Sum, sum),
+                (Aggregate::Avg, avg),
+                (Aggregate::Min, min),
+                (Aggregate::Max, max),
+            ]
+            .into_iter()
+            .flat_map(|(function, columns)| columns.iter().map(move |c| (function, c.clone())))
+            .collect();
+            let mut aggregation = Aggregation::new(group_by.clone(), *count, functions);
+            let mut pages = storage.read_downloaded_pages();
             while let Some(row) = pages.next().await {
                 let (page, content) = row?;
-                let mut tables = parsers.parse(page.type_id, &content)?.unwrap_or_default();
-                let table = tables.remove(table).unwrap_or_default();
+                let mut tables = parsers.parse(&page, &content)?.unwrap_or_default();
+                let table = into_owned_table(tables.remove(table.as_str()).unwrap_or_default());
                 for row in table.into_iter() {
-                    csv.add_row(row.into_iter().filter(column_contains(columns)));
+                    let fetched_at = page.downloaded_at.unwrap_or_else(Utc::now);
+                    let mut row = columns_config.apply(row, fetched_at);
+                    if let Some(exchange_rates) = &exchange_rates {
+                        row = exchange_rates.apply(row);
+                    }
+                    if filters.iter().all(|filter| filter.matches(&row)) {
+                        aggregation.add(&row);
+                    }
                 }
             }
-            csv.write(&mut stdout())?;
+            let target = SinkTarget {
+                table: table.clone(),
+                location: None,
+                missing: MissingValue::Empty,
+            };
+            let mut sink = SinkRegistry::with_builtins().create("csv", &target)?;
+            for row in aggregation.rows() {
+                sink.write_row(row).await?;
+            }
+            sink.finish().await?;
+            check_oversized_pages(&storage)?;
+        }
+
+        Commands::Browse => {
+            let (_, storage, parsers) = read_env(&app_opts).await?;
+            let reset = browse::browse(&storage, &parsers).await?;
+            if reset > 0 {
+                println!("{} page(s) reset", reset);
+            }
+        }
+
+        Commands::ListPages {
+            no_header,
+            tag,
+            read_only,
+        } => {
+            let (_, storage, _) = open_env(&app_opts, *read_only).await?;
+            let table = Table::new(app_opts.output_style())
+                .right("id", 7)
+                .right("type_id", 7)
+                .right("depth", 5)
+                .left("status", 15)
+                .right("http", 4)
+                .left("url", 20);
+            if !no_header {
+                table.print_header();
+            }
+            let pages = match tag {
+                Some(tag) => storage.list_tagged_pages(tag).await?,
+                None => storage.list_pages().await?,
+            };
+            for page in pages {
+                let url = match &page.request {
+                    Some(request) => {
+                        let language = request
+                            .headers
+                            .iter()
+                            .find(|(name, _)| name.eq_ignore_ascii_case("accept-language"));
+                        match language {
+                            // Language variant of the page (see `crawler.locales`)
+                            Some((_, language)) => {
+                                format!("{} {} ({})", request.method, page.url, language)
+                            }
+                            None => format!("{} {}", request.method, page.url),
+                        }
+                    }
+                    None => page.url.to_string(),
+                };
+                table.print_row([
+                    page.id.into(),
+                    page.type_id.into(),
+                    page.depth.into(),
+                    Cell::page_status(page.status),
+                    Cell::http_status(page.http_status),
+                    url.into(),
+                ]);
+            }
         }
 
-        Commands::ListPages { no_header } => {
+        Commands::Skipped { no_header } => {
             let (_, storage, _) = read_env(&app_opts).await?;
+            let table = Table::new(app_opts.output_style())
+                .right("id", 7)
+                .right("type_id", 7)
+                .right("depth", 5)
+                .left("reason", 10)
+                .left("url", 20);
             if !no_header {
-                println!(
-                    "{:>7}  {:>7}  {:>5}  {:<15}  {:<20}",
-                    "id", "type_id", "depth", "status", "url"
-                );
-                println!("{}", "-".repeat(120));
+                table.print_header();
             }
-            for page in storage.list_pages().await? {
-                println!(
-                    "{:>7}  {:>7}  {:>5}  {:<15}  {:<20}",
-                    page.id, page.type_id, page.depth, page.status, page.url
-                )
+            for (page, reason) in storage.list_skipped_pages().await? {
+                table.print_row([
+                    page.id.into(),
+                    page.type_id.into(),
+                    page.depth.into(),
+                    Cell::colored(reason, Color::Yellow),
+                    page.url.into(),
+                ]);
+            }
+        }
+
+        Commands::Config { json } => {
+            let config_path = app_opts.workspace.join("crab.toml");
+            let mut config =
+                read_config(&config_path).context(AppError::ReadingConfig(config_path))?;
+            config.redact_secrets();
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&config)?);
+            } else {
+                print!("{}", toml::to_string_pretty(&config)?);
+            }
+        }
+
+        Commands::Duplicates { no_header } => {
+            let (_, storage, _) = read_env(&app_opts).await?;
+            let table = Table::new(app_opts.output_style())
+                .right("id", 7)
+                .right("type_id", 7)
+                .right("original_id", 11)
+                .left("url", 20);
+            if !no_header {
+                table.print_header();
+            }
+            for (page, original_id) in storage.list_duplicate_pages().await? {
+                table.print_row([
+                    page.id.into(),
+                    page.type_id.into(),
+                    original_id.into(),
+                    page.url.into(),
+                ]);
+            }
+        }
+
+        Commands::Failures {
+            no_header,
+            read_only,
+        } => {
+            let (_, storage, _) = open_env(&app_opts, *read_only).await?;
+            let table = Table::new(app_opts.output_style())
+                .right("id", 7)
+                .right("type_id", 7)
+                .left("failed_at", 19)
+                .left("reason", 11)
+                .left("url", 40)
+                .left("message", 20);
+            if !no_header {
+                table.print_header();
+            }
+            for (page, failure) in storage.list_failed_downloads().await? {
+                table.print_row([
+                    page.id.into(),
+                    page.type_id.into(),
+                    failure.failed_at.format("%Y-%m-%d %H:%M:%S").into(),
+                    Cell::colored(failure.reason, Color::Red),
+                    page.url.into(),
+                    failure.message.into(),
+                ]);
+            }
+        }
+
+        Commands::Stats {
+            no_header,
+            read_only,
+        } => {
+            let (_, storage, _) = open_env(&app_opts, *read_only).await?;
+            let table = Table::new(app_opts.output_style())
+                .right("pages", 7)
+                .right("ttfb_ms", 8)
+                .right("total_ms", 8)
+                .right("bytes", 12)
+                .left("host", 20);
+            if !no_header {
+                table.print_header();
+            }
+            let timings = storage.list_request_timings().await?;
+            for (host, timings) in stats::by_host(&timings) {
+                let millis = |d: Option<Duration>| d.unwrap_or_default().as_millis();
+                table.print_row([
+                    timings.requests.into(),
+                    millis(timings.average_ttfb()).into(),
+                    millis(timings.average_total()).into(),
+                    timings.body_bytes.into(),
+                    host.into(),
+                ]);
+            }
+        }
+
+        Commands::ChangedPages {
+            since,
+            no_header,
+            read_only,
+        } => {
+            let (_, storage, _) = open_env(&app_opts, *read_only).await?;
+            let table = Table::new(app_opts.output_style())
+                .right("id", 7)
+                .right("type_id", 7)
+                .left("changed_at", 20)
+                .left("url", 20);
+            if !no_header {
+                table.print_header();
+            }
+            for (page, changed_at) in storage.list_changed_pages(*since).await? {
+                table.print_row([
+                    page.id.into(),
+                    page.type_id.into(),
+                    changed_at.format("%Y-%m-%d %H:%M:%S").into(),
+                    page.url.into(),
+                ]);
+            }
+        }
+
+        Commands::CheckData { read_only, alert } => {
+            let (config, storage, parsers) = open_storage(&app_opts, *read_only).await?;
+            let style = app_opts.output_style();
+            let failed = check_data(&config.contracts, &*storage, &parsers, style).await?;
+            if !failed.is_empty() {
+                if *alert {
+                    let problems = failed.iter().map(|r| format!("Data contract failed: {r}"));
+                    let alerts = config.alerts.as_ref();
+                    send_alert(alerts, &app_opts.workspace, problems.collect()).await;
+                }
+                return Err(AppError::ContractsFailed(failed.len()).into());
             }
         }
 
         Commands::Validate { reset } => {
             let (_, storage, parsers) = read_env(&app_opts).await?;
+            let parsers = ParserThreads::spawn(parsers)?;
+            let mut progress = Progress::new(storage.count_downloaded_pages(None).await?);
+            let table = Table::new(app_opts.output_style())
+                .right("id", 7)
+                .left("url", 20);
 
             let mut invalid_pages = vec![];
-            let mut pages = storage.read_downloaded_pages();
-            while let Some(row) = pages.next().await {
-                let (page, content) = row?;
-                if !parsers.validate(page.type_id, &content)? {
-                    println!("{}\t{}", page.id, page.url);
-                    invalid_pages.push(page.id);
+            let mut batches = storage.read_downloaded_pages_batched(PAGES_BATCH_SIZE);
+            while let Some(batch) = batches.next().await {
+                // Pages of a batch are queued on parser threads at once, results are in page order
+                let results = batch?.into_iter().map(|(page, content)| {
+                    parsers.run(page.type_id, move |parsers| {
+                        let valid = parsers.validate(&page, &content)?;
+                        Ok((page, valid))
+                    })
+                });
+                for (page, valid) in try_join_all(results).await? {
+                    if !valid {
+                        let row =
+                            table.format([page.id.into(), Cell::colored(&page.url, Color::Red)]);
+                        progress.println(format_args!("{}", row));
+                        invalid_pages.push(page.id);
+                    }
+                    progress.inc();
                 }
             }
+            progress.finish(format_args!(
+                "{} invalid pages{}",
+                invalid_pages.len(),
+                oversized_summary(&storage)
+            ));
 
             // Page reset should be done after page iteration process is completed.
             // Lock timeout will be generated otherwise.
             if *reset {
-                drop(pages);
-                for page_id in invalid_pages.into_iter() {
-                    storage.reset_page(page_id).await?;
+                drop(batches);
+                storage.reset_pages(&invalid_pages).await?;
+            }
+            check_oversized_pages(&storage)?;
+        }
+
+        Commands::Dump {
+            meta: true,
+            page_id,
+            read_only,
+        } => {
+            let (_, storage, _) = open_env(&app_opts, *read_only).await?;
+            let page = storage
+                .read_page(*page_id)
+                .await?
+                .ok_or(AppError::PageNotFound(*page_id))?;
+            let fetched_at = page.downloaded_at.map(|t| t.to_rfc3339());
+            println!("Fetched at: {}", fetched_at.unwrap_or_default());
+            if let Some(meta) = storage.read_page_meta(*page_id).await? {
+                println!("Status: {}", meta.status);
+                println!("Final URL: {}", meta.final_url);
+                println!("Content type: {}", meta.content_type.unwrap_or_default());
+                if meta.truncated {
+                    println!("Truncated: content is larger than max_body_bytes");
+                }
+                if let Some(timing) = meta.timing {
+                    println!("Time to first byte: {} ms", timing.ttfb_ms);
+                    println!("Total time: {} ms", timing.total_ms);
+                    println!("Size: {} bytes", timing.body_bytes);
+                }
+                println!();
+                for (name, value) in meta.headers {
+                    println!("{}: {}", name, value);
                 }
             }
         }
 
-        Commands::Dump { page_id } => {
-            let (_, storage, _) = read_env(&app_opts).await?;
+        Commands::Dump {
+            page_id, read_only, ..
+        } => {
+            let (_, storage, _) = open_env(&app_opts, *read_only).await?;
             let (content, _) = storage
                 .read_page_content(*page_id)
                 .await?
@@ -308,32 +1343,252 @@ async fn entrypoint() -> Result<()> {
             println!("{}", content);
         }
 
+        Commands::Open { live, page_id } => {
+            let (_, storage, _) = open_env(&app_opts, true).await?;
+            let page = storage
+                .read_page(*page_id)
+                .await?
+                .ok_or(AppError::PageNotFound(*page_id))?;
+            if *live {
+                open_in_browser(page.url.as_str())?;
+            } else {
+                let (content, _) = storage
+                    .read_page_content(*page_id)
+                    .await?
+                    .ok_or(AppError::PageNotFound(*page_id))?;
+                let path = env::temp_dir().join(format!("crab-page-{}.html", page_id));
+                fs::write(&path, html::set_base_url(&content, &page.url)?)?;
+                open_in_browser(path.as_os_str())?;
+            }
+        }
+
         Commands::Reset { page_id } => {
             let (_, storage, _) = read_env(&app_opts).await?;
             storage.reset_page(*page_id).await?
         }
 
+        Commands::Retype {
+            page_id,
+            url_contains,
+            type_id,
+        } => {
+            let (_, storage, _) = read_env(&app_opts).await?;
+            match (page_id, url_contains) {
+                (Some(page_id), _) => {
+                    if !storage.set_page_type(*page_id, *type_id).await? {
+                        return Err(AppError::PageNotFound(*page_id).into());
+                    }
+                }
+                (None, Some(url_part)) => {
+                    let count = storage.set_page_type_by_url(url_part, *type_id).await?;
+                    println!("{} pages changed", count);
+                }
+                (None, None) => unreachable!("clap requires either page id or URL text"),
+            }
+        }
+
+        Commands::Move { page_id, url } => {
+            let (config, storage, _) = read_env(&app_opts).await?;
+            let url = UrlNormalizer::new(&config.crawler).normalize(Url::parse(url)?);
+            storage.move_page(*page_id, &url).await?
+        }
+
+        Commands::Merge {
+            from_page_id,
+            into_page_id,
+        } => {
+            let (_, storage, _) = read_env(&app_opts).await?;
+            storage.merge_pages(*from_page_id, *into_page_id).await?
+        }
+
+        Commands::PruneHistory { keep } => {
+            let (_, storage, _) = read_env(&app_opts).await?;
+            let removed = storage.prune_history(*keep).await?;
+            println!("{} versions removed", removed);
+        }
+
         Commands::Parsers => {
-            println!(
-                "{:<25}   {:>8}   {:<12} {:<12} {:<12}",
-                "MODULE NAME", "TYPE ID", "NAVIGATION", "PARSING", "VALIDATION"
-            );
+            let table = Table::new(app_opts.output_style())
+                .left("module_name", 25)
+                .right("type_id", 7)
+                .left("navigation", 10)
+                .left("parsing", 10)
+                .left("validation", 10)
+                .left("preprocess", 10);
+            table.print_header();
             for parser in create_python_parsers(&app_opts.workspace)? {
-                println!(
-                    "{:<25}   {:>8}   {:<12} {:<12} {:<12}",
-                    parser.module_name(),
-                    parser.page_type_id(),
-                    label(parser.support_navigation(), "yes", "no"),
-                    label(parser.support_parsing(), "yes", "no"),
-                    label(parser.support_validation(), "yes", "no")
-                )
+                table.print_row([
+                    parser.module_name().into(),
+                    parser.page_type_id().into(),
+                    Cell::flag(parser.support_navigation()),
+                    Cell::flag(parser.support_parsing()),
+                    Cell::flag(parser.support_validation()),
+                    Cell::flag(parser.support_preprocessing()),
+                ]);
+            }
+        }
+
+        Commands::Frontier(FrontierCommands::Export) => {
+            let (_, storage, _) = read_env(&app_opts).await?;
+            let mut out = stdout().lock();
+            for page in storage.list_pages().await? {
+                if page.status != PageStatus::NotDownloaded {
+                    continue;
+                }
+                let entry = FrontierEntry {
+                    url: page.url.to_string(),
+                    type_id: page.type_id,
+                    depth: page.depth,
+                    request: page.request,
+                };
+                serde_json::to_writer(&mut out, &entry)?;
+                writeln!(out)?;
+            }
+        }
+
+        Commands::Frontier(FrontierCommands::Import { path }) => {
+            let (_, mut storage, _) = read_env(&app_opts).await?;
+            let input: Box<dyn BufRead> = match path {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(stdin().lock()),
+            };
+            let (mut registered, mut known) = (0, 0);
+            for (line_no, line) in input.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: FrontierEntry = serde_json::from_str(&line)
+                    .context(AppError::InvalidFrontierEntry(line_no + 1))?;
+                let link = Link {
+                    url: Url::parse(&entry.url)?,
+                    type_id: entry.type_id,
+                    request: entry.request,
+                };
+                let page_id = storage.register_link(link, entry.depth).await?;
+                match page_id {
+                    Some(_) => registered += 1,
+                    None => known += 1,
+                }
+            }
+            eprintln!("{} pages registered, {} already known", registered, known);
+        }
+
+        Commands::Replay { trace } => {
+            let input = BufReader::new(File::open(trace)?);
+            let result = trace::replay(input, |record| {
+                println!("{:>8}  {}", record.at_ms, format_event(&record.event));
+            })?;
+            match result {
+                Replay::Reproduced(records) => {
+                    eprintln!("{} records replayed, all decisions reproduced", records)
+                }
+                Replay::Diverged {
+                    line,
+                    recorded,
+                    replayed,
+                } => {
+                    return Err(AppError::ReplayDiverged(line, recorded, replayed).into());
+                }
+            }
+        }
+
+        Commands::Snapshot(SnapshotCommands::Update { page_ids }) => {
+            let fixtures = Fixtures::new(app_opts.workspace.join(FIXTURES_DIR));
+            let parsers = create_dyn_python_parsers(&app_opts.workspace)
+                .context(AppError::LoadingPythonParsers)?;
+            let parsers = PageParsers(parsers);
+            if !page_ids.is_empty() {
+                let (_, storage, _) = read_env(&app_opts).await?;
+                for page_id in page_ids {
+                    let page = storage.read_page(*page_id).await?;
+                    let content = storage.read_page_content(*page_id).await?;
+                    let (page, (content, _)) =
+                        page.zip(content).ok_or(AppError::PageNotFound(*page_id))?;
+                    let name = fixtures.add(&page, &content, &parsers)?;
+                    println!("added {}", name);
+                }
+            }
+            for name in fixtures.update(&parsers)? {
+                println!("updated {}", name);
+            }
+        }
+
+        Commands::Snapshot(SnapshotCommands::Check) => {
+            let fixtures = Fixtures::new(app_opts.workspace.join(FIXTURES_DIR));
+            let parsers = create_dyn_python_parsers(&app_opts.workspace)
+                .context(AppError::LoadingPythonParsers)?;
+            let mismatches = fixtures.check(&PageParsers(parsers))?;
+            for mismatch in &mismatches {
+                println!("--- {}\n{}", mismatch.name, mismatch.diff);
+            }
+            if !mismatches.is_empty() {
+                return Err(AppError::SnapshotMismatch(mismatches.len()).into());
             }
         }
+
+        Commands::Completions { shell } => {
+            completions::write_registration(*shell, &mut stdout().lock())?;
+        }
     }
 
     Ok(())
 }
 
+/// Requests crawler shutdown on SIGINT/SIGTERM, exits immediately on the second signal
+fn shutdown_on_signal(commands: mpsc::UnboundedSender<CrawlerCommand>) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        signals.next();
+        if commands.send(CrawlerCommand::Shutdown).is_ok() {
+            signals.next();
+        }
+        let _ = terminal::restore();
+        process::exit(130);
+    });
+    Ok(())
+}
+
+fn format_event(event: &Event) -> String {
+    let proxy = |proxy: &Option<usize>| match proxy {
+        Some(id) => format!("proxy #{}", id),
+        None => "no proxy".to_string(),
+    };
+    match event {
+        Event::Start {
+            seed,
+            proxies,
+            threads,
+        } => format!("start     seed {seed}, {proxies} proxies, {threads} threads"),
+        Event::Refill { pages } => format!("refill    {} pages", pages.len()),
+        Event::Dispatch {
+            page_id,
+            url,
+            proxy: p,
+            delay_ms,
+        } => format!(
+            "dispatch  #{page_id} via {}, delay {delay_ms}ms  {url}",
+            proxy(p)
+        ),
+        Event::Postpone {
+            page_id,
+            reason,
+            wait_ms,
+        } => format!("postpone  #{page_id} for {wait_ms}ms ({reason:?})"),
+        Event::Complete {
+            page_id,
+            proxy: p,
+            success,
+        } => {
+            let outcome = label(*success, "ok", "failed");
+            format!("complete  #{page_id} via {} {outcome}", proxy(p))
+        }
+        Event::Pause { wait_ms } => format!("pause     {wait_ms}ms"),
+        Event::Shutdown => "shutdown".to_string(),
+    }
+}
+
 fn label<'a>(v: bool, yes: &'a str, no: &'a str) -> &'a str {
     if v {
         yes
@@ -353,6 +1608,19 @@ fn heap_allocate<T: PageParser + 'static>(parser: T) -> Box<dyn PageParser> {
     Box::new(parser)
 }
 
+/// Creates auth rules for requests with imported cookies, logging in first if login step is
+/// configured
+async fn auth_rules(config: &CrabConfig) -> Result<AuthRules> {
+    let mut auth = AuthRules::new(&config.auth)?;
+    if let Some(cookies) = &config.cookies {
+        auth = cookies::import(cookies, auth)?;
+    }
+    match &config.login {
+        Some(login) => login::login(login, &config.crawler, auth).await,
+        None => Ok(auth),
+    }
+}
+
 /// Initialize python environment and create python parser.
 ///
 /// Python parsers created using following convention:
@@ -388,6 +1656,157 @@ fn create_python_parsers(path: impl AsRef<Path>) -> Result<Vec<PythonPageParser>
     Ok(parsers)
 }
 
+/// Returns columns describing where exported row came from
+fn provenance_columns(page: &Page, parser_version: Option<&str>) -> Vec<(String, String)> {
+    let fetched_at = page.downloaded_at.map(|t| t.to_rfc3339());
+    vec![
+        ("source_page_id".into(), page.id.to_string()),
+        ("source_url".into(), page.url.to_string()),
+        ("fetched_at".into(), fetched_at.unwrap_or_default()),
+        (
+            "parser_version".into(),
+            parser_version.unwrap_or_default().into(),
+        ),
+    ]
+}
+
+/// Opens file or URL in the default browser
+fn open_in_browser(target: impl AsRef<OsStr>) -> Result<()> {
+    #[cfg(target_os = "macos")]
+    let mut command = Command::new("open");
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    };
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let mut command = Command::new("xdg-open");
+
+    let status = command
+        .arg(target.as_ref())
+        .status()
+        .context(AppError::OpeningBrowser)?;
+    if !status.success() {
+        return Err(AppError::OpeningBrowser.into());
+    }
+    Ok(())
+}
+
+async fn load_exchange_rates(currency: Option<CurrencyConfig>) -> Result<Option<ExchangeRates>> {
+    match currency {
+        Some(currency) => Ok(Some(
+            ExchangeRates::load(currency)
+                .await
+                .context(AppError::LoadingExchangeRates)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Parses point in time given either as RFC 3339 timestamp or as a date (midnight UTC)
+fn parse_timestamp(input: &str) -> Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).expect("midnight is always valid");
+        return Ok(DateTime::from_naive_utc_and_offset(midnight, Utc));
+    }
+    Ok(DateTime::parse_from_rfc3339(input)?.with_timezone(&Utc))
+}
+
+/// Parses positive number of seconds, eg. `0.5`
+fn parse_seconds(input: &str) -> Result<Duration> {
+    let seconds = input.parse::<f32>()?;
+    Ok(Duration::try_from_secs_f32(seconds)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or(AppError::InvalidInterval(input.into()))?)
+}
+
+fn parse_fraction(input: &str) -> Result<f64> {
+    let fraction = input.parse::<f64>()?;
+    if fraction > 0. && fraction <= 1. {
+        Ok(fraction)
+    } else {
+        Err(AppError::InvalidFraction(input.into()).into())
+    }
+}
+
+/// Part of a command summary telling how many pages are skipped because of `max_page_size`
+fn oversized_summary(storage: &dyn Storage) -> String {
+    match storage.count_oversized_pages() {
+        0 => String::new(),
+        count => format!(", {} pages skipped (larger than max_page_size)", count),
+    }
+}
+
+/// Fails a command which has skipped pages larger than `max_page_size`, so incomplete results
+/// are not taken for complete ones
+fn check_oversized_pages(storage: &dyn Storage) -> Result<()> {
+    match storage.count_oversized_pages() {
+        0 => Ok(()),
+        count => Err(AppError::OversizedPagesSkipped(count).into()),
+    }
+}
+
+/// Parses all downloaded pages and prints results of data contracts, returns violated assertions
+async fn check_data(
+    contracts: &[ContractConfig],
+    storage: &dyn Storage,
+    parsers: &PageParsers,
+    style: OutputStyle,
+) -> Result<Vec<ContractResult>> {
+    let mut check = ContractCheck::new(contracts)?;
+    let mut progress = Progress::new(storage.count_downloaded_pages(None).await?);
+    let mut batches = storage.read_downloaded_pages_batched(PAGES_BATCH_SIZE);
+    while let Some(batch) = batches.next().await {
+        for (page, content) in batch? {
+            let tables = parsers.parse(&page, &content)?.unwrap_or_default();
+            check.add(&page, &tables);
+            progress.inc();
+        }
+    }
+    let results = check.results();
+    let failed = results.iter().filter(|r| !r.passed).count();
+    progress.finish(format_args!(
+        "{} of {} assertions failed{}",
+        failed,
+        results.len(),
+        oversized_summary(storage)
+    ));
+    check_oversized_pages(storage)?;
+
+    let table = Table::new(style)
+        .left("status", 6)
+        .left("actual", 24)
+        .left("assertion", 20);
+    table.print_header();
+    for result in &results {
+        let status = match result.passed {
+            true => Cell::colored("ok", Color::Green),
+            false => Cell::colored("FAILED", Color::Red),
+        };
+        table.print_row([status, (&result.actual).into(), (&result.assertion).into()]);
+    }
+    Ok(results.into_iter().filter(|r| !r.passed).collect())
+}
+
+/// Notifies alert channels about failed checks of the workspace
+async fn send_alert(alerts: Option<&AlertsConfig>, workspace: &Path, problems: Vec<String>) {
+    let Some(alerts) = alerts else {
+        return;
+    };
+    let workspace = fs::canonicalize(workspace).unwrap_or_else(|_| workspace.to_path_buf());
+    let alert = Alert {
+        subject: format!(
+            "crab: {} check(s) failed in {}",
+            problems.len(),
+            workspace.display()
+        ),
+        problems,
+    };
+    alerts.send(&alert).await;
+}
+
 /// Returns a closure for a filtering on a key contains a string
 fn column_contains<S: AsRef<str>, T>(needles: &[S]) -> impl Fn(&(S, T)) -> bool + '_ {
     fn eq_ignore_case<S: AsRef<str>>(s1: &S, s2: &S) -> bool {
@@ -0,0 +1,123 @@
+//! Run manifests allowing to trace datasets back to the exact code and config that produced them
+//!
+//! Each crawler run writes `manifest-<time>.json` with crab version, config snapshot, hashes of
+//! parser files, seed pages and git commit of the workspace (if it is a git repository).
+use crate::{prelude::*, storage::Storage, CrabConfig};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub crab_version: String,
+    /// RFC 3339 time the run started at
+    pub started_at: String,
+    /// `crab.toml` contents with inline secrets redacted
+    pub config: Value,
+    pub parsers: Vec<ParserFile>,
+    /// URLs of pages with depth 0
+    pub seeds: Vec<String>,
+    /// HEAD commit of the workspace, `None` if workspace is not a git repository
+    pub git_commit: Option<String>,
+    #[serde(skip)]
+    time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ParserFile {
+    pub file: String,
+    pub sha256: String,
+}
+
+impl Manifest {
+    pub async fn new(workspace: &Path, config: &CrabConfig, storage: &dyn Storage) -> Result<Self> {
+        let mut config = config.clone();
+        config.redact_secrets();
+        let config = serde_json::to_value(config)?;
+        let seeds = storage
+            .list_seed_pages()
+            .await?
+            .into_iter()
+            .map(|page| page.url.to_string())
+            .collect();
+        let time = Utc::now();
+        Ok(Self {
+            crab_version: env!("CARGO_PKG_VERSION").to_string(),
+            started_at: time.to_rfc3339(),
+            config,
+            parsers: parser_files(workspace)?,
+            seeds,
+            git_commit: git_commit(workspace),
+            time,
+        })
+    }
+
+    /// Writes manifest to a given directory, returns path of the written file
+    pub fn write(&self, dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let file_name = format!("manifest-{}.json", self.time.format("%Y%m%dT%H%M%S%.3f"));
+        let path = dir.join(file_name);
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        Ok(path)
+    }
+}
+
+/// Returns SHA-256 of all `parser_*.py` files in the workspace sorted by file name
+fn parser_files(workspace: &Path) -> Result<Vec<ParserFile>> {
+    let mut parsers = vec![];
+    for entry in fs::read_dir(workspace)? {
+        let path = entry?.path();
+        let Some(file) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        if path.is_file() && file.starts_with("parser_") && file.ends_with(".py") {
+            parsers.push(ParserFile {
+                file: file.to_string(),
+                sha256: format!("{:x}", Sha256::digest(fs::read(&path)?)),
+            });
+        }
+    }
+    parsers.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(parsers)
+}
+
+fn git_commit(workspace: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(workspace)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parser_files_are_hashed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("parser_b.py"), "")?;
+        fs::write(dir.path().join("parser_a.py"), "TYPE_ID = 1")?;
+        fs::write(dir.path().join("helpers.py"), "")?;
+        let files = parser_files(dir.path())?;
+        let names = files.iter().map(|f| f.file.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["parser_a.py", "parser_b.py"]);
+        // SHA-256 of an empty file
+        assert_eq!(
+            files[1].sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+//! Tables printed by listing commands
+//!
+//! Columns are aligned and statuses are colored when stdout is a terminal. `--no-color` (or
+//! `NO_COLOR` environment variable) disables colors, `--plain` prints tab separated values without
+//! alignment and decorations, so output is easy to process with `cut`/`awk`. Tabs, line breaks
+//! and backslashes in plain values are escaped as `\t`, `\n`, `\r` and `\\`.
+use crab::storage::PageStatus;
+use crossterm::style::{Color, Stylize};
+use std::{
+    env,
+    fmt::{Display, Write},
+    io::{stdout, IsTerminal},
+};
+
+/// Width of the line under the table header
+const SEPARATOR_WIDTH: usize = 120;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OutputStyle {
+    color: bool,
+    plain: bool,
+}
+
+impl OutputStyle {
+    pub(crate) fn new(no_color: bool, plain: bool) -> Self {
+        let color =
+            !no_color && !plain && env::var_os("NO_COLOR").is_none() && stdout().is_terminal();
+        Self { color, plain }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Right,
+}
+
+/// Value of a table cell, optionally colored
+pub(crate) struct Cell {
+    text: String,
+    color: Option<Color>,
+}
+
+impl Cell {
+    pub(crate) fn colored(text: impl Display, color: Color) -> Self {
+        Self {
+            text: text.to_string(),
+            color: Some(color),
+        }
+    }
+
+    /// Page status colored by its outcome
+    pub(crate) fn page_status(status: PageStatus) -> Self {
+        let color = match status {
+            PageStatus::NotDownloaded => return status.into(),
+            PageStatus::Downloaded => Color::Green,
+            PageStatus::Skipped => Color::Yellow,
+            PageStatus::Failed => Color::Red,
+        };
+        Self::colored(status, color)
+    }
+
+    /// HTTP status colored by its class, empty if there is no response
+    pub(crate) fn http_status(status: Option<u16>) -> Self {
+        match status {
+            None => "".into(),
+            Some(status @ 200..=299) => Self::colored(status, Color::Green),
+            Some(status @ 300..=399) => Self::colored(status, Color::Cyan),
+            Some(status) => Self::colored(status, Color::Red),
+        }
+    }
+
+    /// `yes` or `no` of a supported feature
+    pub(crate) fn flag(value: bool) -> Self {
+        match value {
+            true => Self::colored("yes", Color::Green),
+            false => Self::colored("no", Color::DarkGrey),
+        }
+    }
+}
+
+impl<T: Display> From<T> for Cell {
+    fn from(value: T) -> Self {
+        Self {
+            text: value.to_string(),
+            color: None,
+        }
+    }
+}
+
+/// Table with fixed width columns, the last column is not padded
+pub(crate) struct Table {
+    style: OutputStyle,
+    columns: Vec<(&'static str, usize, Align)>,
+}
+
+impl Table {
+    pub(crate) fn new(style: OutputStyle) -> Self {
+        Self {
+            style,
+            columns: vec![],
+        }
+    }
+
+    pub(crate) fn left(mut self, name: &'static str, width: usize) -> Self {
+        self.columns.push((name, width, Align::Left));
+        self
+    }
+
+    pub(crate) fn right(mut self, name: &'static str, width: usize) -> Self {
+        self.columns.push((name, width, Align::Right));
+        self
+    }
+
+    /// Prints column names followed by a separator line (the line is omitted in plain mode)
+    pub(crate) fn print_header(&self) {
+        let names = self.columns.iter().map(|(name, ..)| Cell {
+            text: name.to_string(),
+            color: None,
+        });
+        println!("{}", self.format(names));
+        if !self.style.plain {
+            println!("{}", "-".repeat(SEPARATOR_WIDTH));
+        }
+    }
+
+    pub(crate) fn print_row(&self, cells: impl IntoIterator<Item = Cell>) {
+        println!("{}", self.format(cells));
+    }
+
+    /// Formats a row without printing it (eg. to print it above a progress line)
+    pub(crate) fn format(&self, cells: impl IntoIterator<Item = Cell>) -> String {
+        let mut line = String::new();
+        let count = self.columns.len();
+        for (i, (cell, (_, width, align))) in cells.into_iter().zip(&self.columns).enumerate() {
+            let last = i + 1 == count;
+            if self.style.plain {
+                if i > 0 {
+                    line.push('\t');
+                }
+                line.push_str(&escape_plain(&cell.text));
+                continue;
+            }
+            if i > 0 {
+                line.push_str("  ");
+            }
+            // Padding is added before coloring, escape sequences would be counted as characters
+            let text = match (align, last) {
+                (Align::Left, true) => cell.text,
+                (Align::Left, false) => format!("{:<width$}", cell.text),
+                (Align::Right, _) => format!("{:>width$}", cell.text),
+            };
+            let _ = match cell.color.filter(|_| self.style.color) {
+                Some(color) => write!(line, "{}", text.with(color)),
+                None => write!(line, "{}", text),
+            };
+        }
+        line
+    }
+}
+
+/// Escapes characters which would break a tab separated line
+fn escape_plain(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(plain: bool) -> Table {
+        let style = OutputStyle {
+            color: false,
+            plain,
+        };
+        Table::new(style)
+            .right("id", 4)
+            .left("status", 8)
+            .left("url", 10)
+    }
+
+    #[test]
+    fn columns_are_aligned() {
+        let row = table(false).format(["1".into(), "ok".into(), "http://test.com".into()]);
+        assert_eq!(row, "   1  ok        http://test.com");
+    }
+
+    #[test]
+    fn last_column_is_not_padded() {
+        let row = table(false).format(["1".into(), "ok".into(), "a".into()]);
+        assert_eq!(row, "   1  ok        a");
+    }
+
+    #[test]
+    fn colors_are_not_counted_in_width() {
+        let style = OutputStyle {
+            color: true,
+            plain: false,
+        };
+        let table = Table::new(style).left("status", 4).left("url", 4);
+        let row = table.format([Cell::colored("ok", Color::Green), "a".into()]);
+        assert_eq!(row, format!("{}  a", "ok  ".with(Color::Green)));
+    }
+
+    #[test]
+    fn plain_rows_are_tab_separated() {
+        let row = table(true).format([Cell::colored(1, Color::Red), "ok".into(), "a".into()]);
+        assert_eq!(row, "1\tok\ta");
+    }
+
+    #[test]
+    fn plain_values_are_escaped() {
+        let row = table(true).format(["1".into(), "a\tb".into(), "c\nd\\e\r".into()]);
+        assert_eq!(row, "1\ta\\tb\tc\\nd\\\\e\\r");
+    }
+}
@@ -0,0 +1,154 @@
+//! Dedicated threads running parsers of each page type
+//!
+//! Crawler runs validation, preprocessing and navigation rules of each page type on its own
+//! thread, so a slow parser only delays pages of its own type and never blocks the scheduler.
+//! Python parsers still share a single interpreter (subinterpreters are not supported by pyo3),
+//! but the interpreter switches between threads holding the GIL, so pages of other types keep
+//! being processed while a slow parser is running.
+use crate::{prelude::*, PageParsers, PageTypeId};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{mpsc, Arc},
+    thread,
+};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&PageParsers) + Send>;
+
+#[derive(Clone)]
+pub struct ParserThreads {
+    /// page type → queue of the thread owning the parser
+    queues: Arc<HashMap<PageTypeId, mpsc::Sender<Job>>>,
+}
+
+impl ParserThreads {
+    /// Moves each parser to its own thread
+    ///
+    /// Threads are stopped once all the copies of `ParserThreads` are dropped.
+    pub fn spawn(parsers: PageParsers) -> Result<Self> {
+        let mut queues = HashMap::new();
+        for parser in parsers.0 {
+            let type_id = parser.page_type_id();
+            // Only the first parser of a type is used, same as `PageParsers` does
+            if queues.contains_key(&type_id) {
+                continue;
+            }
+            let (sender, receiver) = mpsc::channel::<Job>();
+            thread::Builder::new()
+                .name(format!("parser-{type_id}"))
+                .spawn(move || {
+                    let parsers = PageParsers(vec![parser]);
+                    for job in receiver {
+                        job(&parsers);
+                    }
+                })?;
+            queues.insert(type_id, sender);
+        }
+        Ok(Self {
+            queues: Arc::new(queues),
+        })
+    }
+
+    /// Queues a job on the thread of a given page type
+    ///
+    /// Job is queued immediately, returned future resolves once it is completed.
+    pub fn run<T: Send + 'static>(
+        &self,
+        type_id: PageTypeId,
+        job: impl FnOnce(&PageParsers) -> Result<T> + Send + 'static,
+    ) -> impl Future<Output = Result<T>> {
+        let (result_sender, result) = oneshot::channel();
+        let queued = match self.queues.get(&type_id) {
+            Some(queue) => queue
+                .send(Box::new(move |parsers| {
+                    let _ = result_sender.send(job(parsers));
+                }))
+                .map_err(|_| AppError::ParserThreadStopped(type_id)),
+            None => Err(AppError::PageParserNotFound(type_id)),
+        };
+        async move {
+            queued?;
+            // Sender is dropped without a result only if the parser panicked
+            result
+                .await
+                .map_err(|_| AppError::ParserThreadStopped(type_id))?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::PageStatus, BorrowedTables, Link, Page, PageParser};
+    use std::{borrow::Cow, sync::Mutex, time::Duration};
+
+    /// Parser which blocks parsing until released if `gate` is given
+    struct TestParser {
+        type_id: PageTypeId,
+        gate: Option<Mutex<mpsc::Receiver<()>>>,
+    }
+
+    impl PageParser for TestParser {
+        fn navigate<'c>(
+            &self,
+            _page: &Page,
+            _content: &'c str,
+        ) -> Result<Option<Vec<Link<Cow<'c, str>>>>> {
+            Ok(None)
+        }
+
+        fn parse<'c>(&self, _page: &Page, content: &'c str) -> Result<Option<BorrowedTables<'c>>> {
+            if let Some(gate) = &self.gate {
+                gate.lock().unwrap().recv()?;
+            }
+            let row = HashMap::from([("content".into(), content.into())]);
+            Ok(Some(HashMap::from([("rows".into(), vec![row])])))
+        }
+
+        fn page_type_id(&self) -> PageTypeId {
+            self.type_id
+        }
+    }
+
+    fn page(type_id: PageTypeId) -> Page {
+        Page {
+            status: PageStatus::Downloaded,
+            ..Page::new(1, "http://test.com".parse().unwrap(), type_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_parser_does_not_block_other_types() -> Result<()> {
+        let (release, gate) = mpsc::channel();
+        let threads = ParserThreads::spawn(PageParsers(vec![
+            Box::new(TestParser {
+                type_id: 1,
+                gate: Some(Mutex::new(gate)),
+            }),
+            Box::new(TestParser {
+                type_id: 2,
+                gate: None,
+            }),
+        ]))?;
+
+        let slow = threads.run(1, |p| p.parse(&page(1), "slow"));
+        let fast = threads.run(2, |p| p.parse(&page(2), "fast"));
+        let fast = tokio::time::timeout(Duration::from_secs(5), fast).await??;
+        let value = &fast.unwrap()["rows"][0]["content"];
+        // value is a slice of the content
+        assert!(matches!(value, Cow::Borrowed("fast")));
+
+        release.send(())?;
+        assert_eq!(slow.await?.unwrap()["rows"][0]["content"], "slow");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unknown_page_type() -> Result<()> {
+        let threads = ParserThreads::spawn(PageParsers(vec![]))?;
+        let result = threads.run(1, |p| p.parse(&page(1), "")).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+}
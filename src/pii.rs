@@ -0,0 +1,250 @@
+//! Scrubbing of personally identifiable information
+//!
+//! Rules are configured in `[pii]` section of `crab.toml` and can be applied when page content is
+//! written to the database and/or when parsed rows are exported:
+//!
+//! ```toml
+//! [pii]
+//! on_store = true
+//! on_export = true
+//! builtin = ["email", "phone"]
+//! mask_columns = ["customer name"]
+//! audit_log = "pii_audit.log"
+//!
+//! [[pii.patterns]]
+//! name = "ssn"
+//! regex = '\d{3}-\d{2}-\d{4}'
+//! replacement = "[ssn]"
+//! ```
+//!
+//! Every scrubbing is recorded in the audit log as a tab separated line with the time, stage,
+//! page id, rule name and the number of replacements. Original values are never written to the log.
+use crate::prelude::*;
+use anyhow::Context;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+lazy_static! {
+    static ref EMAIL: Regex =
+        Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap();
+    static ref PHONE: Regex = Regex::new(r"\+?\d[\d\s().-]{7,}\d").unwrap();
+}
+
+/// Value masked columns are replaced with
+const MASK: &str = "***";
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct PiiConfig {
+    /// scrub page content before it is written to the database
+    #[serde(default)]
+    pub on_store: bool,
+
+    /// scrub values of exported rows
+    #[serde(default)]
+    pub on_export: bool,
+
+    /// names of built-in patterns to use: `email`, `phone`
+    #[serde(default)]
+    pub builtin: Vec<String>,
+
+    /// custom patterns
+    #[serde(default)]
+    pub patterns: Vec<PiiPattern>,
+
+    /// columns which values are masked completely on export
+    #[serde(default)]
+    pub mask_columns: Vec<String>,
+
+    /// file all scrubbing is recorded to
+    pub audit_log: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct PiiPattern {
+    pub name: String,
+    pub regex: String,
+    pub replacement: String,
+}
+
+/// Stage scrubbing is applied at
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    Store,
+    Export,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Store => "store",
+            Stage::Export => "export",
+        }
+    }
+}
+
+/// Applies [`PiiConfig`] rules to page content and exported rows
+#[derive(Debug)]
+pub struct Scrubber {
+    on_store: bool,
+    on_export: bool,
+    /// name, pattern and replacement
+    rules: Vec<(String, Regex, String)>,
+    mask_columns: HashSet<String>,
+    audit_log: Option<Mutex<File>>,
+}
+
+impl Scrubber {
+    pub fn new(config: &PiiConfig) -> Result<Self> {
+        let mut rules = vec![];
+        // Custom patterns are usually more specific, so they are applied first
+        for pattern in &config.patterns {
+            let regex = Regex::new(&pattern.regex)
+                .context(AppError::InvalidPiiPattern(pattern.name.clone()))?;
+            rules.push((pattern.name.clone(), regex, pattern.replacement.clone()));
+        }
+        for name in &config.builtin {
+            let (regex, replacement) = match name.as_str() {
+                "email" => (EMAIL.clone(), "[email]"),
+                "phone" => (PHONE.clone(), "[phone]"),
+                _ => return Err(AppError::UnknownPiiPattern(name.clone()).into()),
+            };
+            rules.push((name.clone(), regex, replacement.to_string()));
+        }
+        let audit_log = match &config.audit_log {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            on_store: config.on_store,
+            on_export: config.on_export,
+            rules,
+            mask_columns: config.mask_columns.iter().cloned().collect(),
+            audit_log,
+        })
+    }
+
+    /// Scrubs page content if scrubbing on store is enabled
+    pub fn scrub_content(&self, page_id: i64, content: String) -> Result<String> {
+        if !self.on_store {
+            return Ok(content);
+        }
+        self.scrub(Stage::Store, page_id, content)
+    }
+
+    /// Masks and scrubs values of an exported row if scrubbing on export is enabled
+    pub fn scrub_row(
+        &self,
+        page_id: i64,
+        row: Vec<(String, String)>,
+    ) -> Result<Vec<(String, String)>> {
+        if !self.on_export {
+            return Ok(row);
+        }
+        let mut result = Vec::with_capacity(row.len());
+        for (column, value) in row {
+            let value = if self.mask_columns.contains(&column) {
+                self.audit(Stage::Export, page_id, &format!("column:{}", column), 1)?;
+                MASK.to_string()
+            } else {
+                self.scrub(Stage::Export, page_id, value)?
+            };
+            result.push((column, value));
+        }
+        Ok(result)
+    }
+
+    fn scrub(&self, stage: Stage, page_id: i64, mut text: String) -> Result<String> {
+        for (name, regex, replacement) in &self.rules {
+            let count = regex.find_iter(&text).count();
+            if count > 0 {
+                text = regex.replace_all(&text, replacement.as_str()).into_owned();
+                self.audit(stage, page_id, name, count)?;
+            }
+        }
+        Ok(text)
+    }
+
+    fn audit(&self, stage: Stage, page_id: i64, rule: &str, count: usize) -> Result<()> {
+        if let Some(log) = &self.audit_log {
+            let mut log = log.lock().unwrap();
+            writeln!(
+                log,
+                "{}\t{}\t{}\t{}\t{}",
+                Utc::now().to_rfc3339(),
+                stage.name(),
+                page_id,
+                rule,
+                count
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrubber(builtin: &[&str], mask_columns: &[&str]) -> Scrubber {
+        let config = PiiConfig {
+            on_store: true,
+            on_export: true,
+            builtin: builtin.iter().map(|s| s.to_string()).collect(),
+            patterns: vec![PiiPattern {
+                name: "ssn".into(),
+                regex: r"\d{3}-\d{2}-\d{4}".into(),
+                replacement: "[ssn]".into(),
+            }],
+            mask_columns: mask_columns.iter().map(|s| s.to_string()).collect(),
+            audit_log: None,
+        };
+        Scrubber::new(&config).unwrap()
+    }
+
+    #[test]
+    fn scrub_content() -> Result<()> {
+        let scrubber = scrubber(&["email", "phone"], &[]);
+        let content =
+            "Contact john.doe@example.com or +1 (555) 123-4567, SSN 123-45-6789".to_string();
+        assert_eq!(
+            scrubber.scrub_content(1, content)?,
+            "Contact [email] or [phone], SSN [ssn]"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mask_columns() -> Result<()> {
+        let scrubber = scrubber(&["email"], &["name"]);
+        let row = vec![
+            ("name".to_string(), "John Doe".to_string()),
+            ("contact".to_string(), "john@example.com".to_string()),
+            ("price".to_string(), "100".to_string()),
+        ];
+        let row = scrubber.scrub_row(1, row)?;
+        assert_eq!(row[0].1, "***");
+        assert_eq!(row[1].1, "[email]");
+        assert_eq!(row[2].1, "100");
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_builtin_pattern() {
+        let config = PiiConfig {
+            builtin: vec!["passport".into()],
+            ..Default::default()
+        };
+        assert!(Scrubber::new(&config).is_err());
+    }
+}
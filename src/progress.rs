@@ -0,0 +1,125 @@
+//! Progress of long running commands reported to stderr
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+use std::fmt::{Arguments, Write};
+
+/// Progress line redraws per second
+const REDRAW_RATE: u8 = 5;
+
+/// Progress line of a known total
+const BOUNDED_TEMPLATE: &str =
+    "[{bar:30}] {pos}/{len} {msg} ({percent}%), {rate} {msg}/s, ETA {eta}";
+
+/// Progress line of an unknown total
+const UNBOUNDED_TEMPLATE: &str = "{pos} {msg}, {rate} {msg}/s";
+
+/// Reports number of processed items out of the total along with the estimated time left
+///
+/// Progress line is redrawn in place and only if stderr is a terminal, so output redirected to a
+/// file is not cluttered. If the total is not known in advance only the count and rate are shown.
+pub(crate) struct Progress {
+    bar: ProgressBar,
+    unit: &'static str,
+}
+
+impl Progress {
+    pub(crate) fn new(total: i64) -> Self {
+        Self::with_total(Some(total.max(0) as u64))
+    }
+
+    /// Progress with unknown total
+    pub(crate) fn unbounded() -> Self {
+        Self::with_total(None)
+    }
+
+    fn with_total(total: Option<u64>) -> Self {
+        let target = ProgressDrawTarget::stderr_with_hz(REDRAW_RATE);
+        let bar = ProgressBar::with_draw_target(total, target).with_style(style(total.is_some()));
+        bar.set_message("pages");
+        Self { bar, unit: "pages" }
+    }
+
+    /// Sets the name of items counted (`pages` by default)
+    pub(crate) fn unit(mut self, unit: &'static str) -> Self {
+        self.unit = unit;
+        self.bar.set_message(unit);
+        self
+    }
+
+    /// Hides the progress line (eg. when stdout is written to the same terminal)
+    pub(crate) fn hide_if(self, hidden: bool) -> Self {
+        if hidden {
+            self.bar.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        self
+    }
+
+    pub(crate) fn inc(&mut self) {
+        self.bar.inc(1);
+    }
+
+    pub(crate) fn set(&mut self, done: i64) {
+        self.bar.set_position(done.max(0) as u64);
+    }
+
+    /// Prints a line to stdout without mixing it up with the progress line
+    pub(crate) fn println(&mut self, line: Arguments) {
+        self.bar.suspend(|| println!("{}", line));
+    }
+
+    pub(crate) fn finish(self, summary: Arguments) {
+        self.bar.finish_and_clear();
+        let elapsed = self.bar.elapsed().as_secs_f32();
+        eprintln!(
+            "{} {} in {:.1}s, {}",
+            self.bar.position(),
+            self.unit,
+            elapsed,
+            summary
+        );
+    }
+}
+
+/// Command failed, so the error is printed on a clean line
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if !self.bar.is_finished() {
+            self.bar.finish_and_clear();
+        }
+    }
+}
+
+fn style(bounded: bool) -> ProgressStyle {
+    let template = match bounded {
+        true => BOUNDED_TEMPLATE,
+        false => UNBOUNDED_TEMPLATE,
+    };
+    ProgressStyle::with_template(template)
+        .expect("progress template is valid")
+        .with_key("rate", |state: &ProgressState, w: &mut dyn Write| {
+            let _ = write!(w, "{:.0}", state.per_sec());
+        })
+        .progress_chars("=> ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_are_valid() {
+        style(true);
+        style(false);
+    }
+
+    #[test]
+    fn progress_is_counted() {
+        let mut progress = Progress::new(10).unit("rows").hide_if(true);
+        progress.inc();
+        progress.inc();
+        assert_eq!(progress.bar.position(), 2);
+        progress.set(7);
+        assert_eq!(progress.bar.position(), 7);
+        assert_eq!(progress.bar.length(), Some(10));
+        assert_eq!(Progress::unbounded().bar.length(), None);
+    }
+}
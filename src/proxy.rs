@@ -1,11 +1,12 @@
-use crate::prelude::*;
-use rand::{rngs::ThreadRng, seq::SliceRandom, thread_rng};
+use crate::{prelude::*, secrets, stats::Timings, storage::RequestTiming};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use reqwest::Proxy;
 use std::{
     fs::File,
     io::{BufRead, BufReader},
     ops::{AddAssign, SubAssign},
     path::Path,
+    sync::Arc,
 };
 
 type AliveCounter = SaturatedI8<-2, 2>;
@@ -16,10 +17,13 @@ pub type ProxyId = usize;
 /// Tracks which proxies are alive and which are dead. Each proxy get saturated counter in a range `-2..=2`.
 /// Each time request has been processed proxy counter is incremented (in case of successfull response)
 /// or decremented (in case of failure). Dead proxy is defined as a proxy with undersaturated counter (`-2`).
-#[derive(Default)]
+///
+/// Proxies are chosen using seeded RNG, so the choice can be reproduced given the same seed and
+/// the same sequence of request outcomes (see [`crate::trace`]).
 pub struct Proxies {
-    proxies: Vec<(Proxy, ProxyStat)>,
-    rng: ThreadRng,
+    /// shared with crawler reports, so the list is copied only if it's changed after a report
+    proxies: Arc<Vec<(Proxy, ProxyStat)>>,
+    rng: StdRng,
 }
 
 #[derive(Default, Clone)]
@@ -30,26 +34,44 @@ pub struct ProxyStat {
     /// Number of requests executed successfully via proxy
     pub successfull_requests: u32,
 
+    /// Timings of successful requests via proxy
+    pub timings: Timings,
+
     alive_counter: AliveCounter,
 }
 
 impl Proxies {
-    pub(crate) fn from_file(proxy_list: impl AsRef<Path>) -> Result<Self> {
+    pub(crate) fn new(proxies: Vec<Proxy>, seed: u64) -> Self {
+        Self {
+            proxies: Arc::new(
+                proxies
+                    .into_iter()
+                    .map(|proxy| (proxy, ProxyStat::default()))
+                    .collect(),
+            ),
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub(crate) fn from_file(proxy_list: impl AsRef<Path>, seed: u64) -> Result<Self> {
         let file = BufReader::new(File::open(proxy_list.as_ref())?);
         let mut proxies = vec![];
         for line in file.lines() {
             let line = line?.trim().to_owned();
             if !line.is_empty() {
-                proxies.push((Proxy::all(line)?, ProxyStat::default()));
+                proxies.push(Proxy::all(secrets::resolve_url_password(&line)?)?);
             }
         }
-        let rng = thread_rng();
-        Ok(Self { proxies, rng })
+        Ok(Self::new(proxies, seed))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.proxies.len()
     }
 
     /// Called when proxy failed to process a request
     pub(crate) fn proxy_failed(&mut self, proxy_id: ProxyId) {
-        let Some((proxy, stat)) = self.proxies.get_mut(proxy_id) else {
+        let Some((proxy, stat)) = Arc::make_mut(&mut self.proxies).get_mut(proxy_id) else {
             return;
         };
         stat.requests += 1;
@@ -61,16 +83,23 @@ impl Proxies {
 
     /// Called when proxy successfully process a request
     pub(crate) fn proxy_succeseed(&mut self, proxy_id: ProxyId) {
-        let Some((_, stat)) = self.proxies.get_mut(proxy_id) else {
-            return
+        let Some((_, stat)) = Arc::make_mut(&mut self.proxies).get_mut(proxy_id) else {
+            return;
         };
         stat.requests += 1;
         stat.successfull_requests += 1;
         stat.alive_counter += 1;
     }
 
-    pub(crate) fn stat(&self) -> Vec<(Proxy, ProxyStat)> {
-        self.proxies.clone()
+    /// Called when timing of a successful request via proxy is known
+    pub(crate) fn record_timing(&mut self, proxy_id: ProxyId, timing: &RequestTiming) {
+        if let Some((_, stat)) = Arc::make_mut(&mut self.proxies).get_mut(proxy_id) {
+            stat.timings.record(timing);
+        }
+    }
+
+    pub(crate) fn stat(&self) -> Arc<Vec<(Proxy, ProxyStat)>> {
+        Arc::clone(&self.proxies)
     }
 }
 
@@ -159,10 +188,38 @@ mod test {
         let mut file = File::create(&proxy_list)?;
         writeln!(&mut file, "socks5://127.1")?;
         writeln!(&mut file, "socks5://127.2")?;
+        // host names are resolved by the proxy
+        writeln!(&mut file, "socks5h://127.3")?;
+
+        let proxies = Proxies::from_file(proxy_list, 0)?;
+        assert_eq!(proxies.proxies.len(), 3);
+
+        Ok(())
+    }
 
-        let proxies = Proxies::from_file(proxy_list)?;
-        assert_eq!(proxies.proxies.len(), 2);
+    #[test]
+    fn proxy_choice_is_reproducible() -> Result<()> {
+        let list = (1..=5)
+            .map(|i| Proxy::all(format!("socks5://127.0.0.{i}")))
+            .collect::<StdResult<Vec<_>, _>>()?;
+        let choices = |seed| {
+            let proxies = Proxies::new(list.clone(), seed);
+            proxies.take(10).map(|(_, id)| id).collect::<Vec<_>>()
+        };
+        assert_eq!(choices(42), choices(42));
+        Ok(())
+    }
 
+    #[test]
+    fn reported_stat_is_not_changed_by_later_requests() -> Result<()> {
+        let mut proxies = Proxies::new(vec![Proxy::all("socks5://127.0.0.1")?], 0);
+        proxies.proxy_succeseed(0);
+        let reported = proxies.stat();
+        assert!(Arc::ptr_eq(&reported, &proxies.stat()));
+
+        proxies.proxy_failed(0);
+        assert_eq!(reported[0].1.requests, 1);
+        assert_eq!(proxies.stat()[0].1.requests, 2);
         Ok(())
     }
 
@@ -1,17 +1,100 @@
-use crate::{prelude::*, PageParser, PageTypeId, ParsedTables};
+use crate::{
+    challenge::{Challenge, Solution},
+    export, into_borrowed_links, into_borrowed_tables,
+    login::Session,
+    prelude::*,
+    BorrowedTables, Link, Page, PageParser, PageTypeId, RequestSpec,
+};
+use anyhow::Context;
 use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
     prelude::*,
-    types::{PyDict, PyList, PyTuple},
+    types::{PyDict, PyList},
     PyErr,
 };
-use std::collections::HashMap;
+use reqwest::{Client, Method};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, collections::HashMap, fs, sync::Once};
+use tokio::runtime::Handle;
+use url::Url;
 
 pub struct PythonPageParser {
     module_name: String,
     page_type_id: PageTypeId,
-    navigate_func: Option<PyObject>,
-    parse_func: Option<PyObject>,
-    validate_func: Option<PyObject>,
+    navigate_func: Option<ParserFunction>,
+    parse_func: Option<ParserFunction>,
+    validate_func: Option<ParserFunction>,
+    preprocess_func: Option<ParserFunction>,
+    version: Option<String>,
+}
+
+/// Function of a python parser
+///
+/// Functions are called with page content and, if they accept the second argument, with the
+/// page itself (eg. `def parse(content, page)`), so parsers can use page URL or depth.
+struct ParserFunction {
+    function: PyObject,
+    takes_page: bool,
+}
+
+impl ParserFunction {
+    fn load(module: &PyModule, name: &str) -> PyResult<Option<Self>> {
+        let Ok(function) = module.getattr(name) else {
+            return Ok(None);
+        };
+        let inspect = module.py().import("inspect")?;
+        let parameters = inspect
+            .call_method1("signature", (function,))?
+            .getattr("parameters")?
+            .len()?;
+        Ok(Some(Self {
+            function: function.into(),
+            takes_page: parameters > 1,
+        }))
+    }
+
+    fn call(&self, py: Python, content: impl IntoPy<PyObject>, page: &Page) -> PyResult<PyObject> {
+        if self.takes_page {
+            self.function.call1(py, (content, PyPage::from(page)))
+        } else {
+            self.function.call1(py, (content,))
+        }
+    }
+}
+
+/// Page passed to python parsers, available as `crab.Page`
+#[pyclass(name = "Page", module = "crab", frozen)]
+struct PyPage {
+    #[pyo3(get)]
+    id: i64,
+    #[pyo3(get)]
+    url: String,
+    #[pyo3(get)]
+    type_id: PageTypeId,
+    #[pyo3(get)]
+    depth: u16,
+    /// URL of the last download after following redirects, `None` if page is not downloaded yet
+    #[pyo3(get)]
+    final_url: Option<String>,
+}
+
+#[pymethods]
+impl PyPage {
+    fn __repr__(&self) -> String {
+        format!("Page(id={}, url='{}')", self.id, self.url)
+    }
+}
+
+impl From<&Page> for PyPage {
+    fn from(page: &Page) -> Self {
+        Self {
+            id: page.id,
+            url: page.url.to_string(),
+            type_id: page.type_id,
+            depth: page.depth,
+            final_url: page.final_url.as_ref().map(ToString::to_string),
+        }
+    }
 }
 
 impl PythonPageParser {
@@ -19,17 +102,24 @@ impl PythonPageParser {
         Python::with_gil(|py| {
             let module_name = module_name.to_string();
             let module = PyModule::import(py, module_name.as_str())?;
-            let navigate_func = module.getattr("navigate").map(Into::into).ok();
-            let parse_func = module.getattr("parse").map(Into::into).ok();
-            let validate_func = module.getattr("validate").map(Into::into).ok();
+            let navigate_func = ParserFunction::load(module, "navigate")?;
+            let parse_func = ParserFunction::load(module, "parse")?;
+            let validate_func = ParserFunction::load(module, "validate")?;
+            let preprocess_func = ParserFunction::load(module, "preprocess")?;
             let page_type_id: PyObject = module.getattr("TYPE_ID").map(Into::into)?;
             let page_type_id = page_type_id.extract::<u8>(py)?;
+            let version = match module.getattr("VERSION") {
+                Ok(version) => Some(version.extract::<String>()?),
+                Err(_) => source_hash(module),
+            };
             Ok(Self {
                 module_name,
                 navigate_func,
                 parse_func,
                 validate_func,
+                preprocess_func,
                 page_type_id,
+                version,
             })
         })
     }
@@ -49,35 +139,49 @@ impl PythonPageParser {
     pub fn support_validation(&self) -> bool {
         self.validate_func.is_some()
     }
+
+    pub fn support_preprocessing(&self) -> bool {
+        self.preprocess_func.is_some()
+    }
 }
 
 impl PageParser for PythonPageParser {
-    fn navigate(&self, content: &str) -> Result<Option<Vec<(String, crate::PageTypeId)>>> {
+    fn navigate<'c>(
+        &self,
+        page: &Page,
+        content: &'c str,
+    ) -> Result<Option<Vec<Link<Cow<'c, str>>>>> {
         let Some(navigate) = &self.navigate_func else {
-            return Ok(None)
+            return Ok(None);
         };
         let list = Python::with_gil(|py| {
-            let args = PyTuple::new(py, [content]);
-            let result = navigate.call1(py, args)?;
-            let mut urls = vec![];
+            let result = navigate.call(py, content, page)?;
+            let mut links = vec![];
             for tuple in result.downcast::<PyList>(py)? {
                 let url = tuple.get_item(0)?.extract::<String>()?;
                 let type_id = tuple.get_item(1)?.extract::<u8>()?;
-                urls.push((url, type_id));
+                let request = match tuple.get_item(2) {
+                    Ok(request) => Some(to_request_spec(request.downcast::<PyDict>()?)?),
+                    Err(_) => None,
+                };
+                links.push(Link {
+                    url,
+                    type_id,
+                    request,
+                });
             }
-            Ok::<_, PyErr>(urls)
+            Ok::<_, PyErr>(links)
         })?;
 
-        Ok(Some(list))
+        Ok(Some(into_borrowed_links(list)))
     }
 
-    fn parse(&self, content: &str) -> Result<Option<ParsedTables>> {
+    fn parse<'c>(&self, page: &Page, content: &'c str) -> Result<Option<BorrowedTables<'c>>> {
         let Some(parse) = &self.parse_func else {
-            return Ok(None)
+            return Ok(None);
         };
         let tables = Python::with_gil(|py| {
-            let args = PyTuple::new(py, [content]);
-            let return_value = parse.call1(py, args)?;
+            let return_value = parse.call(py, content, page)?;
             let return_value = return_value.downcast::<PyDict>(py)?;
 
             let mut tables = HashMap::new();
@@ -92,25 +196,47 @@ impl PageParser for PythonPageParser {
             Ok::<_, PyErr>(tables)
         })?;
 
-        Ok(Some(tables))
+        Ok(Some(into_borrowed_tables(tables)))
     }
 
-    fn validate(&self, content: &str) -> Result<bool> {
+    fn validate(&self, page: &Page, content: &str) -> Result<bool> {
         let Some(validate) = &self.validate_func else {
-            return Ok(true)
+            return Ok(true);
         };
         let valid = Python::with_gil(|py| {
-            let args = PyTuple::new(py, [content]);
-            let result = validate.call1(py, args)?;
+            let result = validate.call(py, content, page)?;
             let valid = result.extract::<bool>(py)?;
             Ok::<_, PyErr>(valid)
         })?;
         Ok(valid)
     }
 
+    fn preprocess(&self, page: &Page, content: String) -> Result<String> {
+        let Some(preprocess) = &self.preprocess_func else {
+            return Ok(content);
+        };
+        let content = Python::with_gil(|py| {
+            let result = preprocess.call(py, content, page)?;
+            result.extract::<String>(py)
+        })?;
+        Ok(content)
+    }
+
     fn page_type_id(&self) -> crate::PageTypeId {
         self.page_type_id
     }
+
+    fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+}
+
+/// Returns shortened SHA-256 of module source file
+fn source_hash(module: &PyModule) -> Option<String> {
+    let path = module.getattr("__file__").ok()?.extract::<String>().ok()?;
+    let source = fs::read(path).ok()?;
+    let hash = format!("{:x}", Sha256::digest(source));
+    Some(hash[..12].to_string())
 }
 
 fn to_hashmap(input: &PyDict) -> StdResult<HashMap<String, String>, PyErr> {
@@ -123,7 +249,173 @@ fn to_hashmap(input: &PyDict) -> StdResult<HashMap<String, String>, PyErr> {
     Ok(result)
 }
 
+/// Creates request from a dict like `{'method': 'POST', 'form': {...}, 'headers': {...}}`
+///
+/// `method` is `POST` if not given.
+fn to_request_spec(input: &PyDict) -> StdResult<RequestSpec, PyErr> {
+    let method = match input.get_item("method") {
+        Some(method) => method.extract::<String>()?.to_uppercase(),
+        None => "POST".to_string(),
+    };
+    if Method::from_bytes(method.as_bytes()).is_err() {
+        return Err(PyValueError::new_err(format!(
+            "Invalid HTTP method: {method}"
+        )));
+    }
+    let pairs = |key| match input.get_item(key) {
+        Some(dict) => to_pairs(dict.downcast::<PyDict>()?),
+        None => Ok(vec![]),
+    };
+    Ok(RequestSpec {
+        method,
+        form: pairs("form")?,
+        headers: pairs("headers")?,
+    })
+}
+
+/// Returns key-value pairs of a dict in insertion order
+fn to_pairs(input: &PyDict) -> StdResult<Vec<(String, String)>, PyErr> {
+    input
+        .iter()
+        .map(|(key, value)| Ok((key.extract::<String>()?, value.extract::<String>()?)))
+        .collect()
+}
+
+/// Helpers available to python parsers as `crab` module
+#[pymodule]
+#[pyo3(name = "crab")]
+fn crab_module(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(parse_quantity, module)?)?;
+    module.add_class::<PyPage>()?;
+    module.add_class::<PyLoginClient>()?;
+    module.add_class::<PyLoginResponse>()?;
+    Ok(())
+}
+
+/// Parses quantity like `3.6 GHz` or `€1,299.00` into `(value, unit)` tuple
+///
+/// Returns `None` if text doesn't start with a number.
+#[pyfunction]
+fn parse_quantity(text: &str) -> Option<(f64, Option<String>)> {
+    export::parse_quantity(text).map(|q| (q.value, q.unit))
+}
+
+/// HTTP client passed to python `login(client)` hook, available as `crab.LoginClient`
+///
+/// Cookies set by responses are kept in the session and sent with subsequent requests.
+#[pyclass(name = "LoginClient", module = "crab")]
+struct PyLoginClient {
+    base: Url,
+    client: Client,
+    session: Session,
+    runtime: Handle,
+}
+
+/// Response of a `crab.LoginClient` request after following redirects
+#[pyclass(name = "LoginResponse", module = "crab", frozen)]
+struct PyLoginResponse {
+    #[pyo3(get)]
+    status: u16,
+    #[pyo3(get)]
+    url: String,
+    #[pyo3(get)]
+    text: String,
+}
+
+#[pymethods]
+impl PyLoginClient {
+    /// Login URL from `crab.toml`, relative URLs of requests are resolved against it
+    #[getter]
+    fn url(&self) -> String {
+        self.base.to_string()
+    }
+
+    fn get(&mut self, py: Python, url: &str) -> PyResult<PyLoginResponse> {
+        self.request(py, Method::GET, url, vec![])
+    }
+
+    fn post(&mut self, py: Python, url: &str, data: Option<&PyDict>) -> PyResult<PyLoginResponse> {
+        let form = data.map(to_pairs).transpose()?.unwrap_or_default();
+        self.request(py, Method::POST, url, form)
+    }
+
+    /// Sets header sent with the login requests as well as with all crawler requests
+    fn set_header(&mut self, name: &str, value: &str) {
+        self.session.set_header(name, value);
+    }
+}
+
+impl PyLoginClient {
+    fn request(
+        &mut self,
+        py: Python,
+        method: Method,
+        url: &str,
+        form: Vec<(String, String)>,
+    ) -> PyResult<PyLoginResponse> {
+        let url = self
+            .base
+            .join(url)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let (client, session, runtime) = (&self.client, &mut self.session, &self.runtime);
+        let response = py
+            .allow_threads(|| runtime.block_on(session.request(client, method, &url, &form)))
+            .map_err(|e| PyRuntimeError::new_err(format!("{:#}", e)))?;
+        Ok(PyLoginResponse {
+            status: response.status.as_u16(),
+            url: response.url.to_string(),
+            text: response.text,
+        })
+    }
+}
+
+/// Calls `login(client)` function of a python module and returns session it has established
+///
+/// Blocks on the given runtime while requests are made, so must not be called from async code.
+pub fn login(module_name: &str, client: Client, url: Url, runtime: Handle) -> Result<Session> {
+    prepare();
+    Python::with_gil(|py| -> Result<Session> {
+        let module = PyModule::import(py, module_name)?;
+        let client = PyCell::new(
+            py,
+            PyLoginClient {
+                base: url,
+                client,
+                session: Session::default(),
+                runtime,
+            },
+        )?;
+        module.getattr("login")?.call1((client,))?;
+        let session = client.borrow().session.clone();
+        Ok(session)
+    })
+    .context(AppError::RunningLoginHook(module_name.into()))
+}
+
+/// Calls `solve(challenge)` function of a python module, the challenge is given as a dict and
+/// the solution is expected as a dict (or `None` if the challenge is not solved)
+pub fn solve_challenge(module_name: &str, challenge: &Challenge) -> Result<Option<Solution>> {
+    prepare();
+    let challenge = serde_json::to_string(challenge)?;
+    let solution = Python::with_gil(|py| -> PyResult<Option<String>> {
+        let module = PyModule::import(py, module_name)?;
+        let json = PyModule::import(py, "json")?;
+        let challenge = json.getattr("loads")?.call1((challenge,))?;
+        let solution = module.getattr("solve")?.call1((challenge,))?;
+        if solution.is_none() {
+            return Ok(None);
+        }
+        json.getattr("dumps")?
+            .call1((solution,))?
+            .extract()
+            .map(Some)
+    })?;
+    Ok(solution.map(|s| serde_json::from_str(&s)).transpose()?)
+}
+
 pub fn prepare() {
+    static REGISTER_MODULE: Once = Once::new();
+    REGISTER_MODULE.call_once(|| pyo3::append_to_inittab!(crab_module));
     pyo3::prepare_freethreaded_python();
 
     // Ensuring current working durectory is in Python search path
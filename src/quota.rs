@@ -0,0 +1,190 @@
+//! Request quotas of rate-limited APIs
+//!
+//! ```toml
+//! [crawler.quotas."api.example.com"]
+//! per_minute = 60
+//! per_day = 10000
+//! ```
+//!
+//! Requests are counted in fixed windows (minute and UTC day) and counters are persisted in the
+//! database, so requests made by previous runs are taken into account. Once quota of a host is
+//! exhausted, pages of this host wait until the next window starts.
+use crate::{
+    prelude::*,
+    storage::{RequestCounter, Storage},
+    CrawlerConfig, QuotaConfig,
+};
+use std::{collections::HashMap, time::Duration};
+use url::Url;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Window {
+    Minute,
+    Day,
+}
+
+impl Window {
+    const ALL: [Window; 2] = [Window::Minute, Window::Day];
+
+    fn name(self) -> &'static str {
+        match self {
+            Window::Minute => "minute",
+            Window::Day => "day",
+        }
+    }
+
+    fn seconds(self) -> i64 {
+        match self {
+            Window::Minute => 60,
+            Window::Day => 24 * 60 * 60,
+        }
+    }
+
+    fn limit(self, config: &QuotaConfig) -> Option<u32> {
+        match self {
+            Window::Minute => config.per_minute,
+            Window::Day => config.per_day,
+        }
+    }
+
+    /// Unix time the window containing a given time started at
+    fn start(self, now: i64) -> i64 {
+        now - now.rem_euclid(self.seconds())
+    }
+}
+
+#[derive(Default)]
+pub struct Quotas {
+    /// host → quota
+    limits: HashMap<String, QuotaConfig>,
+    counters: HashMap<(String, Window), RequestCounter>,
+}
+
+impl Quotas {
+    /// Creates quotas from config restoring request counters of previous runs
+    pub async fn load(opts: &CrawlerConfig, storage: &dyn Storage) -> Result<Self> {
+        let mut quotas = Self {
+            limits: opts.quotas.clone().unwrap_or_default(),
+            counters: HashMap::new(),
+        };
+        if quotas.limits.is_empty() {
+            return Ok(quotas);
+        }
+        for counter in storage.list_request_counters().await? {
+            let window = Window::ALL.into_iter().find(|w| w.name() == counter.window);
+            if let Some(window) = window {
+                quotas
+                    .counters
+                    .insert((counter.host.clone(), window), counter);
+            }
+        }
+        Ok(quotas)
+    }
+
+    /// Time left until a request to the URL host is allowed, `None` if request can be made now
+    ///
+    /// `now` is a unix time in seconds.
+    pub fn wait_time(&self, url: &Url, now: i64) -> Option<Duration> {
+        let host = url.host_str()?;
+        let config = self.limits.get(host)?;
+        Window::ALL
+            .into_iter()
+            .filter_map(|window| {
+                let limit = window.limit(config)?;
+                let counter = self.counters.get(&(host.to_string(), window))?;
+                let window_start = window.start(now);
+                let exhausted = counter.window_start == window_start && counter.count >= limit;
+                let wait = window_start + window.seconds() - now;
+                exhausted.then(|| Duration::from_secs(wait as u64))
+            })
+            .max()
+    }
+
+    /// Counts a request to the URL host, returns counters which should be persisted
+    pub fn record(&mut self, url: &Url, now: i64) -> Vec<RequestCounter> {
+        let Some((host, config)) = url.host_str().and_then(|h| self.limits.get_key_value(h)) else {
+            return vec![];
+        };
+        let mut changed = vec![];
+        for window in Window::ALL {
+            if window.limit(config).is_none() {
+                continue;
+            }
+            let window_start = window.start(now);
+            let counter = self
+                .counters
+                .entry((host.clone(), window))
+                .or_insert_with(|| RequestCounter {
+                    host: host.clone(),
+                    window: window.name().to_string(),
+                    window_start,
+                    count: 0,
+                });
+            if counter.window_start != window_start {
+                counter.window_start = window_start;
+                counter.count = 0;
+            }
+            counter.count += 1;
+            changed.push(counter.clone());
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(per_minute: Option<u32>, per_day: Option<u32>) -> Quotas {
+        let config = QuotaConfig {
+            per_minute,
+            per_day,
+        };
+        Quotas {
+            limits: HashMap::from([("api.test.com".to_string(), config)]),
+            counters: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn requests_wait_for_the_next_window() -> Result<()> {
+        let mut quotas = quotas(Some(2), None);
+        let url = Url::parse("http://api.test.com/items")?;
+        // 10 seconds into a minute
+        let now = 60 * 1000 + 10;
+
+        assert_eq!(quotas.wait_time(&url, now), None);
+        quotas.record(&url, now);
+        let counters = quotas.record(&url, now);
+        assert_eq!(counters[0].count, 2);
+        assert_eq!(quotas.wait_time(&url, now), Some(Duration::from_secs(50)));
+
+        // next minute
+        assert_eq!(quotas.wait_time(&url, now + 50), None);
+        let counters = quotas.record(&url, now + 50);
+        assert_eq!(counters[0].count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn longest_window_wins() -> Result<()> {
+        let mut quotas = quotas(Some(10), Some(1));
+        let url = Url::parse("http://api.test.com/items")?;
+        let now = 24 * 60 * 60 * 100;
+
+        assert_eq!(quotas.record(&url, now).len(), 2);
+        let wait = quotas.wait_time(&url, now);
+        assert_eq!(wait, Some(Duration::from_secs(24 * 60 * 60)));
+        Ok(())
+    }
+
+    #[test]
+    fn other_hosts_are_not_limited() -> Result<()> {
+        let mut quotas = quotas(Some(1), None);
+        let url = Url::parse("http://test.com/")?;
+        assert!(quotas.record(&url, 0).is_empty());
+        assert!(quotas.record(&url, 0).is_empty());
+        assert_eq!(quotas.wait_time(&url, 0), None);
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Policy of following HTTP redirects
+//!
+//! ```toml
+//! [crawler.redirects]
+//! max_hops = 5
+//! cross_domain = false
+//! drop_filtered = true
+//! ```
+//!
+//! Redirects are followed up to `max_hops` times for a single request. With `cross_domain = false`
+//! redirect is followed only if the target is on the domain of the requested page, its subdomain
+//! or parent domain (so `example.com` → `www.example.com` is still followed). With
+//! `drop_filtered = true` redirects to URLs not allowed by `url_filters` (see [`crate::filter`])
+//! are not followed as well.
+//!
+//! Page which redirect is not followed is marked as skipped with `redirect-policy` reason.
+use crate::{crawler::MAX_REDIRECTS, filter::UrlFilter, prelude::*, UrlFilterConfig};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use url::Url;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RedirectConfig {
+    /// maximum number of redirects followed for a single request (10 by default)
+    pub(crate) max_hops: Option<usize>,
+    /// follow redirects to other domains (true by default)
+    pub(crate) cross_domain: Option<bool>,
+    /// don't follow redirects to URLs not allowed by `url_filters`
+    #[serde(default)]
+    pub(crate) drop_filtered: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RedirectPolicy {
+    max_hops: usize,
+    cross_domain: bool,
+    filter: Option<Arc<UrlFilter>>,
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self {
+            max_hops: MAX_REDIRECTS,
+            cross_domain: true,
+            filter: None,
+        }
+    }
+}
+
+impl RedirectPolicy {
+    pub fn new(
+        config: Option<&RedirectConfig>,
+        url_filters: Option<&UrlFilterConfig>,
+    ) -> Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        let filter = match (config.drop_filtered, url_filters) {
+            (true, Some(url_filters)) => Some(Arc::new(UrlFilter::new(url_filters)?)),
+            _ => None,
+        };
+        Ok(Self {
+            max_hops: config.max_hops.unwrap_or(MAX_REDIRECTS),
+            cross_domain: config.cross_domain.unwrap_or(true),
+            filter,
+        })
+    }
+
+    /// Returns `true` if redirect to a given URL should be followed
+    ///
+    /// `previous` are URLs requested so far, the first one is the URL of the page.
+    pub fn allows(&self, previous: &[Url], target: &Url) -> bool {
+        if previous.len() > self.max_hops {
+            return false;
+        }
+        if let (false, Some(origin)) = (self.cross_domain, previous.first()) {
+            if !same_domain(origin, target) {
+                return false;
+            }
+        }
+        self.filter.as_ref().is_none_or(|f| f.is_allowed(target))
+    }
+}
+
+/// Hosts are the same or one is a subdomain of another
+fn same_domain(a: &Url, b: &Url) -> bool {
+    let is_subdomain = |host: &str, parent: &str| {
+        host.strip_suffix(parent)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+    };
+    match (a.host_str(), b.host_str()) {
+        (Some(a), Some(b)) => a == b || is_subdomain(a, b) || is_subdomain(b, a),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_allowed_by_policy() -> Result<()> {
+        let config: RedirectConfig = toml::from_str(
+            r#"
+            max_hops = 2
+            cross_domain = false
+            drop_filtered = true
+            "#,
+        )?;
+        let url_filters = UrlFilterConfig {
+            include: vec![],
+            exclude: vec!["/login".into()],
+            record_filtered: false,
+        };
+        let policy = RedirectPolicy::new(Some(&config), Some(&url_filters))?;
+        let origin = [Url::parse("https://example.com/a")?];
+        let url = |url| Url::parse(url).unwrap();
+
+        assert!(policy.allows(&origin, &url("https://www.example.com/a")));
+        assert!(policy.allows(&[url("https://www.example.com/a")], &origin[0]));
+        assert!(!policy.allows(&origin, &url("https://other.com/a")));
+        assert!(!policy.allows(&origin, &url("https://notexample.com/a")));
+        assert!(!policy.allows(&origin, &url("https://example.com/login")));
+
+        let hops = [origin[0].clone(), url("https://example.com/b")];
+        assert!(policy.allows(&hops, &url("https://example.com/c")));
+        let hops = [
+            hops[0].clone(),
+            hops[1].clone(),
+            url("https://example.com/c"),
+        ];
+        assert!(!policy.allows(&hops, &url("https://example.com/d")));
+
+        let policy = RedirectPolicy::default();
+        assert!(policy.allows(&origin, &url("https://example.com/login")));
+        assert!(policy.allows(&origin, &url("https://other.com/a")));
+        Ok(())
+    }
+}
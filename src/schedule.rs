@@ -0,0 +1,131 @@
+//! Hours of the day crawler is allowed to make requests in
+//!
+//! ```toml
+//! [crawler]
+//! allowed_hours = ["22-6"]
+//! timezone = "+03:00"
+//! ```
+//!
+//! Each range is `<start hour>-<end hour>` with the end hour excluded, ranges crossing midnight
+//! are allowed. Timezone is either a fixed UTC offset or `local` (UTC by default). Outside of
+//! allowed hours crawler finishes requests in flight and waits for the next allowed range.
+use crate::{prelude::*, CrawlerConfig};
+use chrono::{DateTime, FixedOffset, Local, Timelike, Utc};
+use std::time::Duration;
+
+const SECONDS_IN_DAY: u32 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Timezone {
+    Local,
+    Fixed(FixedOffset),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AllowedHours {
+    /// start and end hour of each range
+    ranges: Vec<(u32, u32)>,
+    timezone: Timezone,
+}
+
+impl AllowedHours {
+    /// Returns allowed hours from config, `None` if crawling is allowed at any time
+    pub fn new(opts: &CrawlerConfig) -> Result<Option<Self>> {
+        let Some(ranges) = &opts.allowed_hours else {
+            return Ok(None);
+        };
+        let ranges = ranges
+            .iter()
+            .map(|range| {
+                parse_range(range).ok_or_else(|| AppError::InvalidAllowedHours(range.clone()))
+            })
+            .collect::<StdResult<Vec<_>, _>>()?;
+        let timezone = match opts.timezone.as_deref() {
+            None => Timezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+            Some("local") => Timezone::Local,
+            Some(offset) => Timezone::Fixed(
+                offset
+                    .parse()
+                    .map_err(|_| AppError::InvalidTimezone(offset.to_string()))?,
+            ),
+        };
+        Ok(Some(Self { ranges, timezone }))
+    }
+
+    /// Time left until crawling is allowed, `None` if it is allowed now
+    pub fn wait_time(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let seconds = match self.timezone {
+            Timezone::Local => now.with_timezone(&Local).num_seconds_from_midnight(),
+            Timezone::Fixed(offset) => now.with_timezone(&offset).num_seconds_from_midnight(),
+        };
+        let hour = seconds / 3600;
+        let allowed = self.ranges.iter().any(|&(start, end)| {
+            if start < end {
+                start <= hour && hour < end
+            } else {
+                hour >= start || hour < end
+            }
+        });
+        if allowed {
+            return None;
+        }
+        self.ranges
+            .iter()
+            .map(|(start, _)| (start * 3600 + SECONDS_IN_DAY - seconds) % SECONDS_IN_DAY)
+            .min()
+            .map(|wait| Duration::from_secs(wait.into()))
+    }
+}
+
+/// Parses `<start>-<end>` hour range
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (start, end) = range.split_once('-')?;
+    let start = start.trim().parse::<u32>().ok().filter(|h| *h < 24)?;
+    let end = end.trim().parse::<u32>().ok().filter(|h| *h <= 24)?;
+    (start != end).then_some((start, end % 24))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed_hours(ranges: &[&str], timezone: Option<&str>) -> Result<Option<AllowedHours>> {
+        let mut opts = crate::CrabConfig::default_config().crawler;
+        opts.allowed_hours = Some(ranges.iter().map(|r| r.to_string()).collect());
+        opts.timezone = timezone.map(String::from);
+        AllowedHours::new(&opts)
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time).unwrap().into()
+    }
+
+    #[test]
+    fn range_crossing_midnight() -> Result<()> {
+        let hours = allowed_hours(&["22-6"], None)?.unwrap();
+        assert_eq!(hours.wait_time(at("2023-01-01T23:30:00Z")), None);
+        assert_eq!(hours.wait_time(at("2023-01-01T05:59:59Z")), None);
+        let wait = hours.wait_time(at("2023-01-01T06:00:00Z"));
+        assert_eq!(wait, Some(Duration::from_secs(16 * 3600)));
+        Ok(())
+    }
+
+    #[test]
+    fn nearest_range_with_offset() -> Result<()> {
+        let hours = allowed_hours(&["1-3", "12-24"], Some("+03:00"))?.unwrap();
+        // 10:30 at +03:00
+        let wait = hours.wait_time(at("2023-01-01T07:30:00Z"));
+        assert_eq!(wait, Some(Duration::from_secs(90 * 60)));
+        // 23:00 at +03:00
+        assert_eq!(hours.wait_time(at("2023-01-01T20:00:00Z")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(allowed_hours(&["6-6"], None).is_err());
+        assert!(allowed_hours(&["22-25"], None).is_err());
+        assert!(allowed_hours(&["night"], None).is_err());
+        assert!(allowed_hours(&["22-6"], Some("Europe/Moscow")).is_err());
+    }
+}
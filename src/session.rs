@@ -0,0 +1,299 @@
+//! Pool of browsing identities requests are spread across
+//!
+//! ```toml
+//! [crawler.sessions]
+//! size = 10
+//! rotate = "domain"
+//! max_failures = 3
+//! user_agents = [
+//!     "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0 Safari/537.36",
+//!     "Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+//! ]
+//! ```
+//!
+//! Session bundles a `User-Agent`, a proxy (see `proxies` option of `[crawler]` section) and cookies
+//! set by responses, so a site sees requests of a session as a single visitor. With
+//! `rotate = "request"` (default) each request is made in a random session, with
+//! `rotate = "domain"` all requests to a host are made in the same session. Session which requests
+//! fail `max_failures` times in a row (3 by default) is retired and replaced with a fresh one with
+//! the next user agent and proxy.
+//!
+//! Cookies are sent back to the host which set them. Session headers of `[auth]`, `[cookies]` and
+//! `[login]` replace them on their domains.
+use crate::{
+    login::parse_set_cookie,
+    prelude::*,
+    proxy::{Proxies, ProxyId},
+    storage::ResponseMeta,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE, USER_AGENT},
+    Proxy,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use url::Url;
+
+const DEFAULT_MAX_FAILURES: u32 = 3;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SessionsConfig {
+    /// number of sessions used at the same time
+    pub(crate) size: usize,
+    /// how requests are spread across sessions: `request` (default) or `domain`
+    #[serde(default)]
+    pub(crate) rotate: Rotation,
+    /// number of failed requests in a row after which session is replaced (3 by default)
+    pub(crate) max_failures: Option<u32>,
+    /// user agents given to sessions in turn (`user_agent` of `[crawler]` section by default)
+    #[serde(default)]
+    pub(crate) user_agents: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotation {
+    /// each request is made in a random session
+    #[default]
+    Request,
+    /// requests to a host are made in the same session
+    Domain,
+}
+
+struct Session {
+    /// incremented each time the session in the slot is replaced, so outcomes of requests made in
+    /// a retired session don't count towards its replacement
+    generation: u32,
+    user_agent: Option<String>,
+    proxy: Option<(Proxy, ProxyId)>,
+    /// host → cookies set by its responses
+    cookies: HashMap<String, Vec<(String, String)>>,
+    /// number of failed requests in a row
+    failures: u32,
+}
+
+/// Identity a request is made with
+pub(crate) struct SessionRequest {
+    pub(crate) proxy: Option<(Proxy, ProxyId)>,
+    /// `User-Agent` and `Cookie` headers of the session
+    pub(crate) headers: HeaderMap,
+}
+
+/// Cookies set by a response along with the host they are sent back to
+pub(crate) struct SetCookies {
+    host: String,
+    cookies: Vec<(String, String)>,
+}
+
+impl SetCookies {
+    pub(crate) fn new(meta: &ResponseMeta) -> Self {
+        let cookies = meta
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(SET_COOKIE.as_str()))
+            .filter_map(|(_, value)| parse_set_cookie(value))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()));
+        Self {
+            host: meta.final_url.host_str().unwrap_or_default().to_owned(),
+            cookies: cookies.collect(),
+        }
+    }
+}
+
+pub(crate) struct SessionPool {
+    sessions: Vec<Session>,
+    rotate: Rotation,
+    max_failures: u32,
+    user_agents: Vec<String>,
+    /// index of the user agent given to the next session created
+    next_user_agent: usize,
+    /// host → session requests to the host are made in (with `rotate = "domain"`)
+    hosts: HashMap<String, usize>,
+    /// page id → session and its generation the page is requested in
+    in_flight: HashMap<i64, (usize, u32)>,
+    rng: StdRng,
+    /// number of sessions replaced after failures
+    pub(crate) retired: u32,
+}
+
+impl SessionPool {
+    pub(crate) fn new(
+        config: &SessionsConfig,
+        user_agent: Option<String>,
+        proxies: &mut Proxies,
+        seed: u64,
+    ) -> Result<Self> {
+        if config.size == 0 {
+            return Err(AppError::InvalidSessionPool.into());
+        }
+        let user_agents = match config.user_agents.is_empty() {
+            true => user_agent.into_iter().collect(),
+            false => config.user_agents.clone(),
+        };
+        let mut pool = Self {
+            sessions: vec![],
+            rotate: config.rotate,
+            max_failures: config.max_failures.unwrap_or(DEFAULT_MAX_FAILURES),
+            user_agents,
+            next_user_agent: 0,
+            hosts: HashMap::new(),
+            in_flight: HashMap::new(),
+            rng: StdRng::seed_from_u64(seed),
+            retired: 0,
+        };
+        for _ in 0..config.size {
+            let session = pool.create(0, proxies);
+            pool.sessions.push(session);
+        }
+        Ok(pool)
+    }
+
+    fn create(&mut self, generation: u32, proxies: &mut Proxies) -> Session {
+        let user_agent = match self.user_agents.len() {
+            0 => None,
+            len => Some(self.user_agents[self.next_user_agent % len].clone()),
+        };
+        self.next_user_agent += 1;
+        Session {
+            generation,
+            user_agent,
+            proxy: proxies.next(),
+            cookies: HashMap::new(),
+            failures: 0,
+        }
+    }
+
+    /// Chooses a session for the page request
+    pub(crate) fn dispatch(&mut self, page_id: i64, url: &Url) -> SessionRequest {
+        let host = url.host_str().unwrap_or_default();
+        let id = match self.rotate {
+            Rotation::Request => self.rng.gen_range(0..self.sessions.len()),
+            Rotation::Domain => match self.hosts.get(host) {
+                Some(id) => *id,
+                None => {
+                    let id = self.rng.gen_range(0..self.sessions.len());
+                    self.hosts.insert(host.to_owned(), id);
+                    id
+                }
+            },
+        };
+        let session = &self.sessions[id];
+        self.in_flight.insert(page_id, (id, session.generation));
+
+        let mut headers = HeaderMap::new();
+        let user_agent = session.user_agent.as_deref().map(HeaderValue::from_str);
+        if let Some(Ok(user_agent)) = user_agent {
+            headers.insert(USER_AGENT, user_agent);
+        }
+        let cookies = session.cookies.get(host).map(|cookies| {
+            let cookies = cookies
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"));
+            HeaderValue::from_str(&cookies.collect::<Vec<_>>().join("; "))
+        });
+        if let Some(Ok(cookies)) = cookies {
+            headers.insert(COOKIE, cookies);
+        }
+        SessionRequest {
+            proxy: session.proxy.clone(),
+            headers,
+        }
+    }
+
+    /// Stores cookies set by the response to the page request in its session
+    pub(crate) fn store_cookies(&mut self, page_id: i64, set_cookies: SetCookies) {
+        let Some(session) = self.in_flight_session(page_id) else {
+            return;
+        };
+        for (name, value) in set_cookies.cookies {
+            let cookies = session.cookies.entry(set_cookies.host.clone()).or_default();
+            cookies.retain(|(n, _)| *n != name);
+            cookies.push((name, value));
+        }
+    }
+
+    /// Records the outcome of the page request, session is replaced after too many failures
+    pub(crate) fn completed(&mut self, page_id: i64, success: bool, proxies: &mut Proxies) {
+        let Some((id, generation)) = self.in_flight.remove(&page_id) else {
+            return;
+        };
+        let session = &mut self.sessions[id];
+        if session.generation != generation {
+            return;
+        }
+        session.failures = match success {
+            true => 0,
+            false => session.failures + 1,
+        };
+        if session.failures >= self.max_failures {
+            info!(
+                "Session #{} retired after {} failed requests",
+                id, session.failures
+            );
+            let generation = session.generation + 1;
+            self.sessions[id] = self.create(generation, proxies);
+            self.retired += 1;
+        }
+    }
+
+    /// Session the page is requested in unless it's retired since then
+    fn in_flight_session(&mut self, page_id: i64) -> Option<&mut Session> {
+        let (id, generation) = *self.in_flight.get(&page_id)?;
+        let session = &mut self.sessions[id];
+        (session.generation == generation).then_some(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_rotated() -> Result<()> {
+        let config: SessionsConfig = toml::from_str(
+            r#"
+            size = 2
+            rotate = "domain"
+            max_failures = 2
+            user_agents = ["first", "second"]
+            "#,
+        )?;
+        let mut proxies = Proxies::new(vec![], 0);
+        let mut pool = SessionPool::new(&config, None, &mut proxies, 0)?;
+        let url = Url::parse("https://example.com/a")?;
+
+        let request = pool.dispatch(1, &url);
+        let user_agent = request.headers[USER_AGENT].clone();
+        let meta = ResponseMeta {
+            status: 200,
+            final_url: url.clone(),
+            content_type: None,
+            headers: vec![("set-cookie".into(), "sid=abc; Path=/; HttpOnly".into())],
+            redirects: vec![],
+            truncated: false,
+            timing: None,
+        };
+        pool.store_cookies(1, SetCookies::new(&meta));
+        pool.completed(1, true, &mut proxies);
+
+        // Requests to the same host are made in the same session with its cookies
+        let request = pool.dispatch(2, &url);
+        assert_eq!(request.headers[USER_AGENT], user_agent);
+        assert_eq!(request.headers[COOKIE], "sid=abc");
+        pool.completed(2, false, &mut proxies);
+        let request = pool.dispatch(3, &url);
+        assert_eq!(request.headers[COOKIE], "sid=abc");
+        pool.completed(3, false, &mut proxies);
+        assert_eq!(pool.retired, 1);
+
+        // Retired session is replaced with a fresh one
+        let request = pool.dispatch(4, &url);
+        assert!(request.headers.get(COOKIE).is_none());
+        assert_eq!(request.headers[USER_AGENT], "first");
+
+        let config = SessionsConfig { size: 0, ..config };
+        assert!(SessionPool::new(&config, None, &mut proxies, 0).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+//! Request signing for APIs requiring signed requests
+//!
+//! Signers are applied to fully built requests just before sending, so signature covers the final
+//! URL, headers and body. Besides built-in [`HmacSigner`] and [`AwsSigV4Signer`] library users can
+//! provide their own [`RequestSigner`] (see [`crate::auth::AuthRules::with_signer()`]).
+use crate::prelude::*;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    Request,
+};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fmt::Debug};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub trait RequestSigner: Send + Sync + Debug {
+    /// Adds signature to a request
+    fn sign(&self, request: &mut Request) -> Result<()>;
+}
+
+/// Signs requests with HMAC-SHA256
+///
+/// Signature is calculated over the following string:
+///
+/// ```text
+/// <METHOD>\n<path and query>\n<unix timestamp>\n<hex encoded SHA-256 of body>
+/// ```
+///
+/// Hex encoded signature and timestamp are sent in `signature_header` and `timestamp_header`.
+#[derive(Debug)]
+pub struct HmacSigner {
+    pub key: String,
+    pub signature_header: String,
+    pub timestamp_header: String,
+}
+
+impl RequestSigner for HmacSigner {
+    fn sign(&self, request: &mut Request) -> Result<()> {
+        self.sign_at(request, Utc::now())
+    }
+}
+
+impl HmacSigner {
+    fn sign_at(&self, request: &mut Request, time: DateTime<Utc>) -> Result<()> {
+        let timestamp = time.timestamp().to_string();
+        let url = request.url();
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            request.method(),
+            path,
+            timestamp,
+            hex_sha256(body_bytes(request)?)
+        );
+        let signature = hex_hmac(self.key.as_bytes(), string_to_sign.as_bytes())?;
+
+        let headers = request.headers_mut();
+        headers.insert(
+            self.signature_header.parse::<HeaderName>()?,
+            HeaderValue::from_str(&signature)?,
+        );
+        headers.insert(
+            self.timestamp_header.parse::<HeaderName>()?,
+            HeaderValue::from_str(&timestamp)?,
+        );
+        Ok(())
+    }
+}
+
+/// Signs requests using [AWS Signature Version 4](https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html)
+///
+/// Host, `x-amz-*` and all the headers already present on a request are signed. Values of a header
+/// given several times are joined with commas in the order they are added.
+#[derive(Debug)]
+pub struct AwsSigV4Signer {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    pub region: String,
+    pub service: String,
+}
+
+impl RequestSigner for AwsSigV4Signer {
+    fn sign(&self, request: &mut Request) -> Result<()> {
+        self.sign_at(request, Utc::now())
+    }
+}
+
+impl AwsSigV4Signer {
+    fn sign_at(&self, request: &mut Request, time: DateTime<Utc>) -> Result<()> {
+        let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = time.format("%Y%m%d").to_string();
+        let payload_hash = hex_sha256(body_bytes(request)?);
+
+        let headers = request.headers_mut();
+        headers.insert("x-amz-date", HeaderValue::from_str(&amz_date)?);
+        if let Some(token) = &self.session_token {
+            headers.insert("x-amz-security-token", HeaderValue::from_str(token)?);
+        }
+        if self.service == "s3" {
+            headers.insert(
+                "x-amz-content-sha256",
+                HeaderValue::from_str(&payload_hash)?,
+            );
+        }
+
+        let url = request.url();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let canonical_headers = canonical_headers(request, host)?;
+        let signed_headers = canonical_headers
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+
+        let mut query = url
+            .query_pairs()
+            .map(|(k, v)| (aws_uri_encode(&k), aws_uri_encode(&v)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            request.method(),
+            url.path(),
+            query,
+            canonical_headers
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            payload_hash
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex_sha256(canonical_request.as_bytes())
+        );
+
+        let secret = format!("AWS4{}", self.secret_access_key);
+        let mut key = hmac(secret.as_bytes(), date.as_bytes())?;
+        for part in [&self.region, &self.service, "aws4_request"] {
+            key = hmac(&key, part.as_bytes())?;
+        }
+        let signature = hex_hmac(&key, string_to_sign.as_bytes())?;
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, signed_headers, signature
+        );
+        request
+            .headers_mut()
+            .insert("authorization", HeaderValue::from_str(&authorization)?);
+        Ok(())
+    }
+}
+
+/// Lowercase names and values of the headers signed by SigV4 sorted by name
+///
+/// Values are trimmed and sequential spaces are collapsed, values of a repeated header are joined
+/// with commas.
+fn canonical_headers(request: &Request, host: String) -> Result<Vec<(String, String)>> {
+    let mut headers = BTreeMap::from([("host".to_string(), vec![host])]);
+    for (name, value) in request.headers() {
+        let value = value.to_str()?.split_whitespace().collect::<Vec<_>>();
+        headers
+            .entry(name.as_str().to_lowercase())
+            .or_insert_with(Vec::new)
+            .push(value.join(" "));
+    }
+    Ok(headers
+        .into_iter()
+        .map(|(name, values)| (name, values.join(",")))
+        .collect())
+}
+
+fn body_bytes(request: &Request) -> Result<&[u8]> {
+    match request.body() {
+        Some(body) => Ok(body.as_bytes().ok_or(AppError::StreamingBodySigning)?),
+        None => Ok(&[]),
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = HmacSha256::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+fn hex_hmac(key: &[u8], data: &[u8]) -> Result<String> {
+    Ok(hmac(key, data)?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Percent-encodes everything except unreserved characters as required by AWS
+fn aws_uri_encode(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(byte as char)
+            }
+            _ => result.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::{header::CONTENT_TYPE, Method};
+
+    /// Example from AWS documentation
+    #[test]
+    fn aws_sigv4_signature() -> Result<()> {
+        let signer = AwsSigV4Signer {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            session_token: None,
+            region: "us-east-1".into(),
+            service: "iam".into(),
+        };
+        let url = "https://iam.amazonaws.com/?Action=ListUsers&Version=2010-05-08".parse()?;
+        let mut request = Request::new(Method::GET, url);
+        request.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded; charset=utf-8"),
+        );
+        let time = DateTime::parse_from_rfc3339("2015-08-30T12:36:00Z")?.with_timezone(&Utc);
+        signer.sign_at(&mut request, time)?;
+
+        let authorization = request.headers()["authorization"].to_str()?;
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+            SignedHeaders=content-type;host;x-amz-date, \
+            Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hmac_signature() -> Result<()> {
+        let signer = HmacSigner {
+            key: "secret".into(),
+            signature_header: "X-Signature".into(),
+            timestamp_header: "X-Timestamp".into(),
+        };
+        let url = "https://api.example.com/v1/items?page=2".parse()?;
+        let mut request = Request::new(Method::POST, url);
+        *request.body_mut() = Some(r#"{"id":1}"#.into());
+        let time = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        signer.sign_at(&mut request, time)?;
+
+        let headers = request.headers();
+        assert_eq!(headers["x-timestamp"], "1700000000");
+        assert_eq!(
+            headers["x-signature"],
+            "9c9dc87118feafc848b4f1d88310b6fc116b2aa0eb0f8acb06c2d805c3246fdc"
+        );
+        Ok(())
+    }
+
+    /// `get-header-value-multiline` and `get-header-key-duplicate` cases of AWS test suite
+    #[test]
+    fn aws_sigv4_repeated_headers() -> Result<()> {
+        let mut request = Request::new(Method::GET, "https://example.amazonaws.com/".parse()?);
+        let headers = request.headers_mut();
+        headers.append("My-Header1", HeaderValue::from_static("value2"));
+        headers.append("My-Header1", HeaderValue::from_static("value2"));
+        headers.append("My-Header1", HeaderValue::from_static("value1"));
+        headers.append("My-Header2", HeaderValue::from_static("  a   b  c "));
+
+        let headers = canonical_headers(&request, "example.amazonaws.com".into())?;
+        assert_eq!(
+            headers,
+            vec![
+                ("host".to_string(), "example.amazonaws.com".to_string()),
+                ("my-header1".to_string(), "value2,value2,value1".to_string()),
+                ("my-header2".to_string(), "a b c".to_string()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,572 @@
+//! Destinations exported rows are written to
+//!
+//! Built-in sinks are registered in [`SinkRegistry::with_builtins()`]:
+//!
+//! - `csv` – CSV file (stdout if no location given);
+//! - `json` – JSON Lines file, one object per row (stdout if no location given);
+//! - `sqlite` – table in a SQLite database, columns are added as they appear;
+//! - `webhook` – rows are POSTed to a given URL as JSON arrays in batches.
+//!
+//! Additional sinks can be registered from library code using [`SinkRegistry::register()`].
+//!
+//! Rows may have different sets of columns. How cells missing in a row are written by `csv` and
+//! `json` sinks is chosen with [`MissingValue`], `sqlite` sink always writes `NULL`.
+use crate::prelude::*;
+use csv::Writer;
+use futures::{
+    future::{ready, BoxFuture},
+    FutureExt,
+};
+use reqwest::Client;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{stdout, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+/// Column name → value pairs of a single exported row
+pub type Row = Vec<(String, String)>;
+
+/// Number of rows sent in a single request by [`WebhookSink`]
+const WEBHOOK_BATCH_SIZE: usize = 100;
+
+/// Destination exported rows are written to
+///
+/// Methods return boxed futures, so sinks can be used as trait objects.
+pub trait OutputSink: Send {
+    fn write_row(&mut self, row: Row) -> BoxFuture<'_, Result<()>>;
+
+    /// Called once after all rows are written
+    fn finish(&mut self) -> BoxFuture<'_, Result<()>> {
+        ready(Ok(())).boxed()
+    }
+}
+
+/// Where sink should write rows to
+#[derive(Debug, Clone)]
+pub struct SinkTarget {
+    /// name of the exported table
+    pub table: String,
+    /// path or URL, meaning depends on the sink
+    pub location: Option<String>,
+    /// how cells missing in a row are written
+    pub missing: MissingValue,
+}
+
+/// Representation of a cell missing in a row
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MissingValue {
+    /// empty CSV cell, column is omitted from JSON object
+    #[default]
+    Empty,
+    /// `NULL` in CSV, `null` in JSON
+    Null,
+    /// given text in both formats
+    Sentinel(String),
+}
+
+impl MissingValue {
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Empty => Some(""),
+            Self::Null => None,
+            Self::Sentinel(value) => Some(value),
+        }
+    }
+}
+
+impl FromStr for MissingValue {
+    type Err = std::convert::Infallible;
+
+    /// Parses `empty`, `null` or any other text used as a sentinel
+    fn from_str(s: &str) -> StdResult<Self, Self::Err> {
+        Ok(match s {
+            "empty" => Self::Empty,
+            "null" => Self::Null,
+            _ => Self::Sentinel(s.to_string()),
+        })
+    }
+}
+
+type SinkFactory = Box<dyn Fn(&SinkTarget) -> Result<Box<dyn OutputSink>>>;
+
+/// Sink name → factory creating the sink
+pub struct SinkRegistry(HashMap<String, SinkFactory>);
+
+impl SinkRegistry {
+    /// Returns registry with `csv`, `json`, `sqlite` and `webhook` sinks
+    pub fn with_builtins() -> Self {
+        let mut registry = Self(HashMap::new());
+        registry.register("csv", |target| {
+            let sink = CsvSink::new(open_output(target)?);
+            Ok(Box::new(sink.with_missing_value(target.missing.clone())))
+        });
+        registry.register("json", |target| {
+            let sink = JsonSink::new(open_output(target)?);
+            Ok(Box::new(sink.with_missing_value(target.missing.clone())))
+        });
+        registry.register("sqlite", |target| {
+            let path = required_location(target, "sqlite")?;
+            Ok(Box::new(SqliteSink::open(path, &target.table)?))
+        });
+        registry.register("webhook", |target| {
+            let url = required_location(target, "webhook")?;
+            Ok(Box::new(WebhookSink::new(url.parse()?)))
+        });
+        registry
+    }
+
+    /// Registers sink under a given name, replacing the sink registered earlier (if any)
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&SinkTarget) -> Result<Box<dyn OutputSink>> + 'static,
+    {
+        self.0.insert(name.to_string(), Box::new(factory));
+    }
+
+    pub fn create(&self, name: &str, target: &SinkTarget) -> Result<Box<dyn OutputSink>> {
+        let factory = self
+            .0
+            .get(name)
+            .ok_or_else(|| AppError::UnknownSink(name.to_string()))?;
+        factory(target)
+    }
+
+    /// Names of all registered sinks in alphabetical order
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.0.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+}
+
+fn open_output(target: &SinkTarget) -> Result<Box<dyn Write + Send>> {
+    Ok(match &target.location {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(stdout()),
+    })
+}
+
+fn required_location<'a>(target: &'a SinkTarget, sink: &str) -> Result<&'a str> {
+    target
+        .location
+        .as_deref()
+        .ok_or_else(|| AppError::MissingSinkLocation(sink.to_string()).into())
+}
+
+/// Writes rows as CSV
+///
+/// Set of columns is not known until all rows are written, so rows are buffered in memory and
+/// written on [`OutputSink::finish()`].
+pub struct CsvSink<W> {
+    out: W,
+    columns: Vec<String>,
+    rows: Vec<Vec<(usize, String)>>,
+    missing: MissingValue,
+}
+
+impl<W: Write + Send> CsvSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            columns: vec![],
+            rows: vec![],
+            missing: MissingValue::Empty,
+        }
+    }
+
+    pub fn with_missing_value(mut self, missing: MissingValue) -> Self {
+        self.missing = missing;
+        self
+    }
+
+    fn add_row(&mut self, row: Row) {
+        let mut row_as_vec = vec![];
+        for (key, value) in row.into_iter() {
+            let column = self.columns.iter().enumerate().find(|c| c.1 == &key);
+            let column_idx = match column {
+                Some((idx, _)) => idx,
+                None => {
+                    self.columns.push(key);
+                    self.columns.len() - 1
+                }
+            };
+            row_as_vec.push((column_idx, value));
+        }
+        if !row_as_vec.is_empty() {
+            row_as_vec.sort_by_key(|(idx, _)| *idx);
+            self.rows.push(row_as_vec);
+        }
+    }
+
+    fn write(&mut self) -> Result<()> {
+        if self.columns.is_empty() {
+            return Err(AppError::NoColumns.into());
+        }
+        let mut csv = Writer::from_writer(&mut self.out);
+        csv.write_record(&self.columns)?;
+
+        let missing = self.missing.as_str().unwrap_or("NULL");
+        for columns in &self.rows {
+            let mut row: Vec<&str> = Vec::with_capacity(self.columns.len());
+            row.resize(self.columns.len(), missing);
+
+            for (column_idx, value) in columns {
+                row[*column_idx] = value;
+            }
+
+            csv.write_record(row)?;
+        }
+        csv.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> OutputSink for CsvSink<W> {
+    fn write_row(&mut self, row: Row) -> BoxFuture<'_, Result<()>> {
+        self.add_row(row);
+        ready(Ok(())).boxed()
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<()>> {
+        ready(self.write()).boxed()
+    }
+}
+
+/// Writes rows as JSON Lines keeping column order
+///
+/// Rows are written as they come if missing columns are omitted. Otherwise the set of columns is
+/// not known until all rows are written, so rows are buffered the same way [`CsvSink`] does.
+pub struct JsonSink<W> {
+    out: W,
+    missing: MissingValue,
+    columns: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl<W: Write + Send> JsonSink<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            missing: MissingValue::Empty,
+            columns: vec![],
+            rows: vec![],
+        }
+    }
+
+    pub fn with_missing_value(mut self, missing: MissingValue) -> Self {
+        self.missing = missing;
+        self
+    }
+
+    fn write(&mut self, row: &[(String, impl Serialize)]) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &JsonRow(row))?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    fn add_row(&mut self, row: Row) -> Result<()> {
+        if self.missing == MissingValue::Empty {
+            return self.write(&row);
+        }
+        for (column, _) in &row {
+            if !self.columns.contains(column) {
+                self.columns.push(column.clone());
+            }
+        }
+        self.rows.push(row);
+        Ok(())
+    }
+
+    fn write_buffered(&mut self) -> Result<()> {
+        let rows = std::mem::take(&mut self.rows);
+        let missing = self.missing.as_str().map(str::to_string);
+        for mut row in rows {
+            let row = self
+                .columns
+                .iter()
+                .map(|column| {
+                    let value = match row.iter().position(|(c, _)| c == column) {
+                        Some(idx) => Some(row.swap_remove(idx).1),
+                        None => missing.clone(),
+                    };
+                    (column.clone(), value)
+                })
+                .collect::<Vec<_>>();
+            self.write(&row)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> OutputSink for JsonSink<W> {
+    fn write_row(&mut self, row: Row) -> BoxFuture<'_, Result<()>> {
+        ready(self.add_row(row)).boxed()
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<()>> {
+        ready(self.write_buffered()).boxed()
+    }
+}
+
+/// Serializes row as JSON object with columns in the row order
+struct JsonRow<'a, V>(&'a [(String, V)]);
+
+impl<V: Serialize> Serialize for JsonRow<'_, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> StdResult<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (column, value) in self.0 {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Writes rows to a table in SQLite database
+///
+/// Table is created on the first row, all columns are `TEXT` and added as they appear in rows.
+/// All rows are written in a single transaction committed on [`OutputSink::finish()`].
+pub struct SqliteSink {
+    connection: rusqlite::Connection,
+    table: String,
+    columns: Vec<String>,
+}
+
+impl SqliteSink {
+    pub fn open(path: impl AsRef<Path>, table: &str) -> Result<Self> {
+        let connection = rusqlite::Connection::open(path)?;
+        let columns = {
+            let mut statement = connection.prepare("SELECT name FROM pragma_table_info(?)")?;
+            let columns = statement.query_map([table], |row| row.get(0))?;
+            columns.collect::<StdResult<Vec<String>, _>>()?
+        };
+        connection.execute_batch("BEGIN")?;
+        Ok(Self {
+            connection,
+            table: table.to_string(),
+            columns,
+        })
+    }
+
+    fn write(&mut self, row: &Row) -> Result<()> {
+        let table = quote(&self.table);
+        for (column, _) in row {
+            if self.columns.contains(column) {
+                continue;
+            }
+            let sql = if self.columns.is_empty() {
+                format!("CREATE TABLE {} ({} TEXT)", table, quote(column))
+            } else {
+                format!("ALTER TABLE {} ADD COLUMN {} TEXT", table, quote(column))
+            };
+            self.connection.execute(&sql, [])?;
+            self.columns.push(column.clone());
+        }
+        if row.is_empty() {
+            return Ok(());
+        }
+        let columns = row.iter().map(|(c, _)| quote(c)).collect::<Vec<_>>();
+        let placeholders = vec!["?"; row.len()];
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            table,
+            columns.join(", "),
+            placeholders.join(", ")
+        );
+        let values = row.iter().map(|(_, v)| v);
+        self.connection
+            .execute(&sql, rusqlite::params_from_iter(values))?;
+        Ok(())
+    }
+}
+
+impl OutputSink for SqliteSink {
+    fn write_row(&mut self, row: Row) -> BoxFuture<'_, Result<()>> {
+        ready(self.write(&row)).boxed()
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<()>> {
+        ready(self.connection.execute_batch("COMMIT").map_err(Into::into)).boxed()
+    }
+}
+
+/// Quotes SQLite identifier
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// POSTs rows to a given URL as JSON arrays of objects in batches
+pub struct WebhookSink {
+    client: Client,
+    url: reqwest::Url,
+    rows: Vec<Row>,
+}
+
+impl WebhookSink {
+    pub fn new(url: reqwest::Url) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            rows: vec![],
+        }
+    }
+
+    async fn send(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let batch = self.rows.iter().map(|row| JsonRow(row)).collect::<Vec<_>>();
+        self.client
+            .post(self.url.clone())
+            .json(&batch)
+            .send()
+            .await?
+            .error_for_status()?;
+        self.rows.clear();
+        Ok(())
+    }
+}
+
+impl OutputSink for WebhookSink {
+    fn write_row(&mut self, row: Row) -> BoxFuture<'_, Result<()>> {
+        self.rows.push(row);
+        async move {
+            if self.rows.len() >= WEBHOOK_BATCH_SIZE {
+                self.send().await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn finish(&mut self) -> BoxFuture<'_, Result<()>> {
+        self.send().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn row(pairs: &[(&str, &str)]) -> Row {
+        pairs
+            .iter()
+            .map(|(c, v)| (c.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn check_table_add_column() -> Result<()> {
+        let mut sink = CsvSink::new(Cursor::new(Vec::new()));
+        sink.write_row(row(&[("foo", "bar")])).await?;
+        sink.write_row(row(&[("bar", "baz")])).await?;
+        sink.finish().await?;
+
+        let expected_csv = "foo,bar\nbar,\n,baz\n";
+        assert_eq!(expected_csv, String::from_utf8(sink.out.into_inner())?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn json_lines_keep_column_order() -> Result<()> {
+        let mut sink = JsonSink::new(Cursor::new(Vec::new()));
+        sink.write_row(row(&[("name", "crab"), ("legs", "10")]))
+            .await?;
+        sink.write_row(row(&[("b", "1"), ("a", "2")])).await?;
+        sink.finish().await?;
+
+        let expected = "{\"name\":\"crab\",\"legs\":\"10\"}\n{\"b\":\"1\",\"a\":\"2\"}\n";
+        assert_eq!(expected, String::from_utf8(sink.out.into_inner())?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_values_are_written_as_configured() -> Result<()> {
+        let rows = [row(&[("a", "1")]), row(&[("b", "2"), ("a", "3")])];
+
+        let missing = MissingValue::from_str("null")?;
+        let mut sink = CsvSink::new(Cursor::new(Vec::new())).with_missing_value(missing.clone());
+        for row in rows.clone() {
+            sink.write_row(row).await?;
+        }
+        sink.finish().await?;
+        let expected = "a,b\n1,NULL\n3,2\n";
+        assert_eq!(expected, String::from_utf8(sink.out.into_inner())?);
+
+        let mut sink = JsonSink::new(Cursor::new(Vec::new())).with_missing_value(missing);
+        for row in rows.clone() {
+            sink.write_row(row).await?;
+        }
+        sink.finish().await?;
+        let expected = "{\"a\":\"1\",\"b\":null}\n{\"a\":\"3\",\"b\":\"2\"}\n";
+        assert_eq!(expected, String::from_utf8(sink.out.into_inner())?);
+
+        let missing = MissingValue::from_str("N/A")?;
+        let mut sink = JsonSink::new(Cursor::new(Vec::new())).with_missing_value(missing);
+        sink.write_row(rows[0].clone()).await?;
+        sink.write_row(rows[1].clone()).await?;
+        sink.finish().await?;
+        let expected = "{\"a\":\"1\",\"b\":\"N/A\"}\n{\"a\":\"3\",\"b\":\"2\"}\n";
+        assert_eq!(expected, String::from_utf8(sink.out.into_inner())?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqlite_sink_adds_columns() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("export.sqlite");
+
+        let mut sink = SqliteSink::open(&path, "items")?;
+        sink.write_row(row(&[("name", "crab")])).await?;
+        sink.write_row(row(&[("name", "lobster"), ("price", "10")]))
+            .await?;
+        sink.finish().await?;
+        drop(sink);
+
+        let connection = rusqlite::Connection::open(path)?;
+        let mut statement = connection.prepare("SELECT name, price FROM items ORDER BY rowid")?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<StdResult<Vec<(String, Option<String>)>, _>>()?;
+        assert_eq!(
+            rows,
+            [
+                ("crab".to_string(), None),
+                ("lobster".to_string(), Some("10".to_string()))
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn register_custom_sink() {
+        struct NullSink;
+
+        impl OutputSink for NullSink {
+            fn write_row(&mut self, _row: Row) -> BoxFuture<'_, Result<()>> {
+                ready(Ok(())).boxed()
+            }
+        }
+
+        let mut registry = SinkRegistry::with_builtins();
+        registry.register("null", |_| Ok(Box::new(NullSink)));
+        assert_eq!(
+            registry.names(),
+            ["csv", "json", "null", "sqlite", "webhook"]
+        );
+
+        let target = SinkTarget {
+            table: "items".into(),
+            location: None,
+            missing: MissingValue::Empty,
+        };
+        assert!(registry.create("null", &target).is_ok());
+        assert!(registry.create("parquet", &target).is_err());
+        assert!(registry.create("sqlite", &target).is_err());
+    }
+}
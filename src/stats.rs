@@ -0,0 +1,87 @@
+//! Aggregated timings of requests
+//!
+//! ```console
+//! $ crab stats
+//! ```
+//!
+//! Time to the first byte, total time and response size are recorded for each downloaded page
+//! (see [`RequestTiming`]). Crawler screen shows averages of the current run (per proxy as well)
+//! and `crab stats` aggregates stored timings by host, so slow hosts and dying proxies stand out.
+use crate::storage::RequestTiming;
+use std::{collections::BTreeMap, time::Duration};
+use url::Url;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// number of timed requests
+    pub requests: u32,
+    /// sum of times to the first byte
+    pub ttfb: Duration,
+    /// sum of total times
+    pub total: Duration,
+    /// sum of response sizes
+    pub body_bytes: u64,
+}
+
+impl Timings {
+    pub fn record(&mut self, timing: &RequestTiming) {
+        self.requests += 1;
+        self.ttfb += Duration::from_millis(timing.ttfb_ms.into());
+        self.total += Duration::from_millis(timing.total_ms.into());
+        self.body_bytes += timing.body_bytes;
+    }
+
+    pub fn average_ttfb(&self) -> Option<Duration> {
+        (self.requests > 0).then(|| self.ttfb / self.requests)
+    }
+
+    pub fn average_total(&self) -> Option<Duration> {
+        (self.requests > 0).then(|| self.total / self.requests)
+    }
+}
+
+/// Aggregates timings by URL host, hosts are ordered by name
+pub fn by_host<'a>(
+    timings: impl IntoIterator<Item = &'a (Url, RequestTiming)>,
+) -> Vec<(String, Timings)> {
+    let mut hosts = BTreeMap::<String, Timings>::new();
+    for (url, timing) in timings {
+        let host = url.host_str().unwrap_or_default();
+        hosts.entry(host.to_string()).or_default().record(timing);
+    }
+    hosts.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    #[test]
+    fn timings_are_aggregated_by_host() -> Result<()> {
+        let timing = |ttfb_ms, total_ms| RequestTiming {
+            ttfb_ms,
+            total_ms,
+            body_bytes: 100,
+        };
+        let timings = [
+            (Url::parse("http://b.com/1")?, timing(100, 300)),
+            (Url::parse("http://a.com/1")?, timing(10, 20)),
+            (Url::parse("http://b.com/2")?, timing(200, 500)),
+        ];
+        let hosts = by_host(&timings);
+        assert_eq!(hosts.len(), 2);
+        let (host, a) = &hosts[0];
+        assert_eq!(host, "a.com");
+        assert_eq!(a.average_total(), Some(Duration::from_millis(20)));
+        let (host, b) = &hosts[1];
+        assert_eq!(host, "b.com");
+        assert_eq!(b.requests, 2);
+        assert_eq!(b.average_ttfb(), Some(Duration::from_millis(150)));
+        assert_eq!(b.average_total(), Some(Duration::from_millis(400)));
+        assert_eq!(b.body_bytes, 200);
+
+        assert_eq!(Timings::default().average_total(), None);
+        Ok(())
+    }
+}
@@ -1,26 +1,51 @@
-use crate::{prelude::*, PageTypeId};
-use futures::{stream::BoxStream, StreamExt};
+use crate::{dedup, prelude::*, Link, PageTypeId};
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future::ready, stream::BoxStream, Future, StreamExt};
 use int_enum::IntEnum;
-use refinery::{
-    config::{Config, ConfigDbType},
-    embed_migrations,
-};
+use rand::Rng;
+use refinery::{embed_migrations, Target};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::{
-    sqlite::{SqlitePoolOptions, SqliteRow},
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
     Row, SqlitePool,
 };
-use std::{fmt, io::Cursor, path::Path};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, time::sleep};
 use url::Url;
 use zstd::bulk::compress;
 embed_migrations!("./migrations");
 
-pub struct Storage {
+pub struct SqliteStorage {
     connection: SqlitePool,
 
-    /// `sqlite3_last_insert_rowid()` doesn't change return value when INSERT OR IGNORE statement
-    /// fails to insert new row in a table. We rely on last insert id when detecting if record is
-    /// present in a database already. [Last Insert Rowid](https://www.sqlite.org/c3ref/last_insert_rowid.html)
-    last_insert_id: i64,
+    /// Pages larger than this size (in bytes) are skipped when listing downloaded pages
+    max_page_size: PageSizeLimit,
+
+    /// Files page content is stored in when sharding is enabled
+    shards: Option<Arc<Shards>>,
+
+    /// Database is opened in read-only mode (see [`SqliteStorage::new_read_only()`])
+    read_only: bool,
+
+    /// Order not downloaded pages are listed in
+    crawl_order: CrawlOrder,
+
+    /// Pages marked as duplicates are not listed when reading downloaded pages
+    skip_duplicates: bool,
 }
 
 #[repr(u8)]
@@ -28,6 +53,10 @@ pub struct Storage {
 pub enum PageStatus {
     NotDownloaded = 1,
     Downloaded = 2,
+    /// Crawler decided not to download the page. See [`SkipReason`] for details
+    Skipped = 3,
+    /// Page download failed after all the retries
+    Failed = 4,
 }
 
 impl fmt::Display for PageStatus {
@@ -35,11 +64,121 @@ impl fmt::Display for PageStatus {
         let display_value = match self {
             PageStatus::NotDownloaded => "not downloaded",
             PageStatus::Downloaded => "downloaded",
+            PageStatus::Skipped => "skipped",
+            PageStatus::Failed => "failed",
+        };
+        f.pad(display_value)
+    }
+}
+
+/// The reason why crawler chose not to download a page
+#[repr(u8)]
+#[derive(Debug, PartialEq, Clone, Copy, IntEnum, Eq, Hash)]
+pub enum SkipReason {
+    /// Page is disallowed by robots rules or asks not to be indexed (`<meta name="robots">`)
+    Robots = 1,
+    /// Page URL is filtered out by configured patterns (only if `record_filtered` is set)
+    Pattern = 2,
+    /// Page is deeper than allowed crawl depth
+    Depth = 3,
+    /// Page is redirected to the URL of another page (or another page is redirected to it)
+    Duplicate = 5,
+    /// Content type of the response is not allowed
+    ContentType = 6,
+    /// Page redirects to another URL with `<meta http-equiv="refresh">` or a script
+    Redirect = 7,
+    /// Page redirects with an HTTP status to a URL not allowed by redirect policy (see [`crate::redirect`])
+    RedirectPolicy = 8,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_value = match self {
+            SkipReason::Robots => "robots",
+            SkipReason::Pattern => "pattern",
+            SkipReason::Depth => "depth",
+            SkipReason::Duplicate => "duplicate",
+            SkipReason::ContentType => "content-type",
+            SkipReason::Redirect => "redirect",
+            SkipReason::RedirectPolicy => "redirect-policy",
+        };
+        f.pad(display_value)
+    }
+}
+
+/// Order crawler downloads registered pages in
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CrawlOrder {
+    /// Shallow pages first, pages of the same depth in order of registration
+    #[default]
+    BreadthFirst,
+    /// Deep pages first, the most recently registered pages of the same depth go first
+    DepthFirst,
+    /// Pages are picked randomly, so requests are spread across site sections
+    Random,
+}
+
+impl CrawlOrder {
+    pub(crate) fn order_by(self) -> &'static str {
+        match self {
+            CrawlOrder::BreadthFirst => "depth ASC, id ASC",
+            CrawlOrder::DepthFirst => "depth DESC, id DESC",
+            CrawlOrder::Random => "random()",
+        }
+    }
+
+    /// Restores the order of rows returned by `UPDATE ... RETURNING`
+    pub(crate) fn sort(self, pages: &mut [Page]) {
+        match self {
+            CrawlOrder::BreadthFirst => pages.sort_by_key(|page| (page.depth, page.id)),
+            CrawlOrder::DepthFirst => {
+                pages.sort_by_key(|page| (Reverse(page.depth), Reverse(page.id)))
+            }
+            CrawlOrder::Random => {}
+        }
+    }
+}
+
+/// Category of the last failed download of a page
+#[repr(u8)]
+#[derive(Debug, PartialEq, Clone, Copy, IntEnum, Eq, Hash)]
+pub enum FailureReason {
+    /// Request is timed out
+    Timeout = 1,
+    /// Connection to the host (or proxy) can not be established
+    Connect = 2,
+    /// Server responded with an error status which is not accepted by validation rules
+    HttpStatus = 3,
+    /// Content is rejected by validation rules
+    Invalid = 4,
+    Other = 5,
+    /// Anti-bot challenge page is served instead of the content (see [`crate::challenge`])
+    Challenge = 6,
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let display_value = match self {
+            FailureReason::Timeout => "timeout",
+            FailureReason::Connect => "connect",
+            FailureReason::HttpStatus => "http status",
+            FailureReason::Invalid => "invalid",
+            FailureReason::Other => "other",
+            FailureReason::Challenge => "challenge",
         };
         f.pad(display_value)
     }
 }
 
+/// The last failed download of a page (see [`SqliteStorage::list_failed_downloads()`])
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DownloadFailure {
+    pub reason: FailureReason,
+    pub message: String,
+    pub failed_at: DateTime<Utc>,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct Page {
     pub id: i64,
@@ -47,18 +186,391 @@ pub struct Page {
     pub type_id: PageTypeId,
     pub depth: u16,
     pub status: PageStatus,
+    /// The time page content was downloaded last time
+    pub downloaded_at: Option<DateTime<Utc>>,
+    /// HTTP status code of the last download (see [`Storage::read_page_meta()`] for the rest of metadata)
+    pub http_status: Option<u16>,
+    /// Request used to download the page, plain `GET` if not set
+    pub request: Option<RequestSpec>,
+    /// URL of the last download after following redirects
+    pub final_url: Option<Url>,
+}
+
+impl Page {
+    /// Page registered at depth 0 and not downloaded yet
+    pub fn new(id: i64, url: Url, type_id: PageTypeId) -> Self {
+        Self {
+            id,
+            url,
+            type_id,
+            depth: 0,
+            status: PageStatus::NotDownloaded,
+            downloaded_at: None,
+            http_status: None,
+            request: None,
+            final_url: None,
+        }
+    }
+}
+
+/// Request crawler makes to download a page instead of plain `GET`
+///
+/// Allows to reach pages available only by submitting a form. Pages with the same URL, but
+/// different requests are stored as separate pages.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Serialize, Deserialize)]
+pub struct RequestSpec {
+    pub method: String,
+    /// fields sent as `application/x-www-form-urlencoded` body
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub form: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
 }
 
-type PageRow = (i64, String, PageTypeId, u16, u8);
+/// HTTP response metadata recorded alongside the page content
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ResponseMeta {
+    pub status: u16,
+    /// URL of the response after following redirects
+    pub final_url: Url,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// URLs requested before the final one if redirects were followed, starting with the page URL
+    pub redirects: Vec<Url>,
+    /// `true` if response body is larger than `max_body_bytes` and only its beginning is read
+    pub truncated: bool,
+    /// `None` if request is not timed (eg. page is rendered by a browser)
+    pub timing: Option<RequestTiming>,
+}
 
-impl Storage {
-    pub async fn new(url: &str) -> Result<Self> {
-        let connection = SqlitePoolOptions::new().connect(url).await?;
-        let last_insert_id = 0;
-        Ok(Self {
+/// Durations of a request and size of the response
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RequestTiming {
+    /// time until response headers are received in milliseconds
+    pub ttfb_ms: u32,
+    /// time until the whole body is received in milliseconds
+    pub total_ms: u32,
+    /// size of the response body in bytes
+    pub body_bytes: u64,
+}
+
+/// Number of requests made to a host in a quota window (see [`crate::quota`])
+#[derive(Debug, PartialEq, Eq, Clone, sqlx::FromRow)]
+pub struct RequestCounter {
+    pub host: String,
+    /// name of the window (eg. `minute` or `day`)
+    pub window: String,
+    /// unix time the window started at
+    pub window_start: i64,
+    pub count: u32,
+}
+
+/// Throttling state of a host which responded with rate-limiting status (see [`crate::throttle`])
+#[derive(Debug, PartialEq, Eq, Clone, sqlx::FromRow)]
+pub struct HostBackoff {
+    pub host: String,
+    /// current delay between requests to the host in milliseconds
+    pub delay_ms: i64,
+    /// unix time of the last rate-limiting response in milliseconds
+    pub rate_limited_at: i64,
+    /// unix time the next request to the host is allowed at in milliseconds
+    pub retry_at: i64,
+}
+
+impl ResponseMeta {
+    /// Returns the value of a header (name is case insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Storage of pages and crawl state
+///
+/// Covers everything the crawler needs, as well as registering seed pages and reading downloaded
+/// pages for export. [`SqliteStorage`] implements the rest of maintenance commands (merging, moving,
+/// migrations and so on) on top of it. With `postgres` feature the crawl can be stored in
+/// PostgreSQL instead (see `postgres` module), so several crawlers can share a database.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Sets the order not downloaded pages are listed (and leased) in
+    fn set_crawl_order(&mut self, crawl_order: CrawlOrder);
+
+    /// Excludes pages marked as duplicates (see [`Storage::set_duplicate_of()`]) from the pages
+    /// read by `read_*` methods
+    fn set_skip_duplicates(&mut self, skip_duplicates: bool);
+
+    /// Writes a consistent copy of the database to a given path
+    async fn snapshot(&self, path: &Path) -> Result<()>;
+
+    /// Number of downloaded pages (of a given type if any)
+    async fn count_downloaded_pages(&self, type_id: Option<PageTypeId>) -> Result<i64>;
+
+    /// Lists pages registered by user (with depth 0) the crawl is started from
+    async fn list_seed_pages(&self) -> Result<Vec<Page>>;
+
+    /// Registers page found by navigation rules
+    ///
+    /// If page with given URL and request already exists, [`Option::None`] is returned.
+    async fn register_link(&mut self, link: Link<Url>, depth: u16) -> Result<Option<i64>>;
+
+    /// Registers page found by navigation rules in [`PageStatus::Skipped`] state
+    async fn register_skipped_link(
+        &mut self,
+        link: Link<Url>,
+        depth: u16,
+        reason: SkipReason,
+    ) -> Result<Option<i64>>;
+
+    /// Finds a page by its URL
+    async fn find_page_by_url(&self, url: &Url) -> Result<Option<Page>>;
+
+    /// Finds another page having a given URL either as its own URL or as the final URL of its download
+    ///
+    /// Allows to detect pages which are aliases of the same target (eg. redirected to the same URL).
+    /// Pages with a request, failed and skipped pages are not considered.
+    async fn find_page_by_final_url(&self, url: &Url, except_id: i64) -> Result<Option<Page>>;
+
+    /// Lists downloaded pages (of a given type if any) with id greater than `after_id` in id order
+    async fn list_downloaded_pages(
+        &self,
+        after_id: i64,
+        count: u16,
+        type_id: Option<PageTypeId>,
+    ) -> Result<Vec<Page>>;
+
+    async fn list_not_downloaded_pages(&self, count: u16) -> Result<Vec<Page>>;
+
+    /// Lists the first not downloaded page (the shallowest one) of each page type
+    async fn list_canary_pages(&self) -> Result<Vec<Page>>;
+
+    /// Lists not downloaded pages and leases them to a given owner for a given time
+    ///
+    /// Pages leased to other owners are not listed until the lease expires, so several crawler
+    /// processes can work on the same database without downloading the same pages. Pages already
+    /// leased to the owner are listed again and their lease is extended.
+    async fn lease_not_downloaded_pages(
+        &self,
+        count: u16,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Vec<Page>>;
+
+    /// Lists downloaded pages older than the recrawl interval of their type and leases them
+    ///
+    /// Pages leased to any owner are not listed until the lease expires, so a page failed to
+    /// refresh is not retried by the same crawler right away.
+    async fn lease_expired_pages(
+        &self,
+        recrawl_after: &[(PageTypeId, Duration)],
+        count: u16,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Vec<Page>>;
+
+    /// Releases all pages leased to a given owner
+    async fn release_leases(&self, owner: &str) -> Result<()>;
+
+    /// Updates download time of the page content, used when server confirms content is not modified
+    async fn touch_page(&self, page_id: i64) -> Result<()>;
+
+    /// Marks page as [`PageStatus::Failed`], so crawler doesn't try to download it anymore
+    async fn fail_page(&self, page_id: i64) -> Result<()>;
+
+    /// Marks page as [`PageStatus::Skipped`] so it will not be downloaded by crawler
+    async fn skip_page(&self, page_id: i64, reason: SkipReason) -> Result<()>;
+
+    /// Same as [`Storage::skip_page()`], but also records the response the page is skipped after
+    ///
+    /// Response metadata replaces the metadata of the previous download (if any), content is kept.
+    /// Body of the response is not read, so request timing is not recorded.
+    async fn skip_page_with_meta(
+        &self,
+        page_id: i64,
+        reason: SkipReason,
+        meta: &ResponseMeta,
+    ) -> Result<()>;
+
+    /// Lists pages crawler chose not to download as well as the reason of skipping
+    async fn list_skipped_pages(&self) -> Result<Vec<(Page, SkipReason)>>;
+
+    /// Adds a tag to the page, does nothing if page is already tagged
+    async fn tag_page(&self, page_id: i64, tag: &str) -> Result<()>;
+
+    /// Lists request counters of all hosts
+    async fn list_request_counters(&self) -> Result<Vec<RequestCounter>>;
+
+    /// Writes request counter replacing the previous value of the counter
+    async fn write_request_counter(&self, counter: &RequestCounter) -> Result<()>;
+
+    /// Lists throttling state of all hosts which are rate-limiting crawler
+    async fn list_host_backoffs(&self) -> Result<Vec<HostBackoff>>;
+
+    /// Writes throttling state of a host replacing the previous one
+    async fn write_host_backoff(&self, backoff: &HostBackoff) -> Result<()>;
+
+    /// Removes throttling state of a host once it's not rate-limiting crawler anymore
+    async fn delete_host_backoff(&self, host: &str) -> Result<()>;
+
+    /// Returns the first downloaded page (other than `except_id`) with a given content hash
+    ///
+    /// Pages which are duplicates themselves are not considered, so all the duplicates refer to
+    /// the same original page.
+    async fn find_page_by_content_hash(&self, hash: &str, except_id: i64) -> Result<Option<i64>>;
+
+    /// Marks page as a duplicate of another page (or clears the mark if `None` is given)
+    async fn set_duplicate_of(&self, page_id: i64, original_id: Option<i64>) -> Result<()>;
+
+    /// Lists simhashes of downloaded pages which are not duplicates
+    async fn list_simhashes(&self) -> Result<Vec<(i64, u64)>>;
+
+    /// Records the reason of a failed download attempt, so it can be inspected later
+    ///
+    /// Only the last failure of a page is kept, it's cleared once the page is downloaded.
+    async fn record_failure(
+        &self,
+        page_id: i64,
+        reason: FailureReason,
+        message: &str,
+    ) -> Result<()>;
+
+    /// Writes page content and marks page as [`PageStatus::Downloaded`], previous content goes to
+    /// the page history
+    ///
+    /// Returns `true` if content differs from the previously stored one.
+    async fn write_encoded_content(
+        &self,
+        page_id: i64,
+        content: EncodedContent,
+        meta: Option<&ResponseMeta>,
+    ) -> Result<bool>;
+
+    /// Reads HTTP response metadata of the last page download
+    async fn read_page_meta(&self, id: i64) -> Result<Option<ResponseMeta>>;
+
+    /// Lists downloaded pages and its content
+    ///
+    /// Pages larger than the maximum page size are skipped (see [`Storage::count_oversized_pages()`]).
+    fn read_downloaded_pages(&self) -> BoxStream<'_, Result<(Page, String)>>;
+
+    /// Lists downloaded pages which content changed since a given time and its content
+    fn read_changed_pages(&self, since: DateTime<Utc>) -> BoxStream<'_, Result<(Page, String)>>;
+
+    /// Lists pages and its content as they were at a given point in time
+    ///
+    /// For each page the latest version of the content downloaded not later than `as_of` is returned.
+    /// Content downloaded before history tracking was introduced is considered as the oldest version.
+    fn read_downloaded_pages_as_of(
+        &self,
+        as_of: DateTime<Utc>,
+    ) -> BoxStream<'_, Result<(Page, String)>>;
+
+    /// Lists downloaded pages and its content in batches of a given size prefetched in background
+    fn read_downloaded_pages_batched(
+        &self,
+        batch_size: usize,
+    ) -> BoxStream<'static, Result<Vec<(Page, String)>>>;
+
+    /// Number of pages the reads above have skipped because they exceed the maximum page size
+    fn count_oversized_pages(&self) -> u64;
+}
+
+pub(crate) type PageRow = (
+    i64,
+    String,
+    PageTypeId,
+    u16,
+    u8,
+    Option<i64>,
+    Option<u16>,
+    Option<String>,
+    Option<String>,
+);
+
+/// `http_status`, `final_url`, `content_type`, `headers`, `redirects`, `truncated`, `ttfb_ms`,
+/// `total_ms` and `body_bytes` columns
+pub(crate) type MetaRow = (
+    Option<u16>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    bool,
+    Option<u32>,
+    Option<u32>,
+    Option<i64>,
+);
+
+/// Number of times a write is repeated if the database is busy (see [`retry_busy()`])
+const BUSY_RETRIES: u32 = 5;
+
+/// Delay before the first repeated write, doubled on each subsequent one
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Columns required to build a [`Page`] using [`page_from_tuple()`]
+pub(crate) const PAGE_COLUMNS: &str =
+    "id, url, type, depth, status, downloaded_at, http_status, request, final_url";
+
+impl SqliteStorage {
+    /// Opens database file at a given path
+    pub async fn new(path: impl AsRef<Path>) -> Result<Self> {
+        Self::connect(SqliteConnectOptions::new().filename(path), false).await
+    }
+
+    /// Opens database with given connection options, optionally in read-only mode
+    ///
+    /// Read-only mode requested by options (`mode=ro`) is kept if `read_only` is `false`.
+    pub async fn connect(options: SqliteConnectOptions, read_only: bool) -> Result<Self> {
+        let options = if read_only {
+            options.read_only(true)
+        } else {
+            options
+        };
+        let connection = SqlitePoolOptions::new().connect_with(options).await?;
+        Ok(Self::from_pool(connection, read_only))
+    }
+
+    /// Opens database in read-only mode
+    ///
+    /// Any attempt to write is rejected by SQLite, so analysis can run alongside active crawl
+    /// without taking write locks.
+    pub async fn new_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Self::connect(SqliteConnectOptions::new().filename(path), true).await
+    }
+
+    fn from_pool(connection: SqlitePool, read_only: bool) -> Self {
+        Self {
             connection,
-            last_insert_id,
-        })
+            max_page_size: PageSizeLimit::default(),
+            shards: None,
+            read_only,
+            crawl_order: CrawlOrder::default(),
+            skip_duplicates: false,
+        }
+    }
+
+    /// Enables storing page content in `count` separate SQLite files next to the main database
+    ///
+    /// Page metadata stays in the main database, only content (and its history) is sharded by
+    /// page id. Content written before sharding was enabled is still readable from the main
+    /// database. The number of shards should not be changed after pages are downloaded, otherwise
+    /// their content becomes unreachable.
+    pub async fn open_shards(&mut self, database: impl AsRef<Path>, count: u16) -> Result<()> {
+        let shards = Shards::open(database.as_ref(), count, self.read_only)
+            .await
+            .context(AppError::OpeningShards)?;
+        self.shards = Some(Arc::new(shards));
+        Ok(())
+    }
+
+    /// Sets the maximum size of a page content returned by [`Storage::read_downloaded_pages()`]
+    ///
+    /// Pages exceeding the limit (either stored or decompressed size) are skipped with a warning, so
+    /// a handful of huge pages doesn't spike memory usage when processing all the pages.
+    pub fn set_max_page_size(&mut self, max_page_size: Option<usize>) {
+        self.max_page_size.max = max_page_size;
     }
 
     pub async fn count_all_pages(&self) -> Result<i64> {
@@ -69,8 +581,8 @@ impl Storage {
     }
 
     pub async fn list_pages(&self) -> Result<Vec<Page>> {
-        let query = "SELECT id, url, type, depth, status FROM pages";
-        let result_set: Vec<PageRow> = sqlx::query_as(query).fetch_all(&self.connection).await?;
+        let query = format!("SELECT {PAGE_COLUMNS} FROM pages");
+        let result_set: Vec<PageRow> = sqlx::query_as(&query).fetch_all(&self.connection).await?;
         let mut pages = vec![];
         for row in result_set {
             pages.push(page_from_tuple(row)?);
@@ -90,61 +602,424 @@ impl Storage {
     where
         U::Error: Sync + Send + std::error::Error + 'static,
     {
-        let new_id = sqlx::query(
-            "INSERT OR IGNORE INTO pages (url, type, depth, compressed) VALUES (?, ?, ?, 0)",
-        )
-        .bind(url.try_into()?.to_string())
-        .bind(type_id)
-        .bind(depth)
-        .execute(&self.connection)
-        .await?
-        .last_insert_rowid();
-        if new_id > 0 && new_id != self.last_insert_id {
-            self.last_insert_id = new_id;
-            Ok(Some(new_id))
-        } else {
-            Ok(None)
+        self.insert_page(url.try_into()?, type_id, depth, None, None)
+            .await
+    }
+
+    /// Registers new page in [`PageStatus::Skipped`] state
+    ///
+    /// Page will never be downloaded by crawler, but it is kept in the database so user can audit
+    /// which pages crawler chose not to fetch. If page with given URL already exists,
+    /// [`Option::None`] is returned.
+    pub async fn register_skipped_page<U: TryInto<Url>>(
+        &mut self,
+        url: U,
+        type_id: PageTypeId,
+        depth: u16,
+        reason: SkipReason,
+    ) -> Result<Option<i64>>
+    where
+        U::Error: Sync + Send + std::error::Error + 'static,
+    {
+        self.insert_page(url.try_into()?, type_id, depth, Some(reason), None)
+            .await
+    }
+
+    async fn insert_page(
+        &mut self,
+        url: Url,
+        type_id: PageTypeId,
+        depth: u16,
+        skip_reason: Option<SkipReason>,
+        request: Option<&RequestSpec>,
+    ) -> Result<Option<i64>> {
+        let status = match skip_reason {
+            Some(_) => PageStatus::Skipped,
+            None => PageStatus::NotDownloaded,
+        };
+        let request = request.map(serde_json::to_string).transpose()?;
+        let (url, request, connection) = (url.as_str(), request.as_deref(), &self.connection);
+        retry_busy(|| async move {
+            let result = sqlx::query(
+                "INSERT OR IGNORE INTO pages (url, url_hash, type, depth, status, skip_reason, request, compressed) VALUES (?, ?, ?, ?, ?, ?, ?, 0)",
+            )
+            .bind(url)
+            .bind(url_hash(url))
+            .bind(type_id)
+            .bind(depth)
+            .bind(status.int_value())
+            .bind(skip_reason.map(SkipReason::int_value))
+            .bind(request)
+            .execute(connection)
+            .await?;
+            // `sqlite3_last_insert_rowid()` doesn't change when INSERT OR IGNORE fails to insert a row
+            // and is tracked per connection, so it can't be used to detect if the page already exists
+            Ok((result.rows_affected() > 0).then(|| result.last_insert_rowid()))
+        })
+        .await
+    }
+
+    pub async fn reset_page(&self, page_id: i64) -> Result<()> {
+        self.reset_pages(&[page_id]).await
+    }
+
+    /// Resets several pages in a single transaction (see [`SqliteStorage::reset_page()`])
+    pub async fn reset_pages(&self, page_ids: &[i64]) -> Result<()> {
+        retry_busy(|| async move {
+            let mut tx = self.connection.begin().await?;
+            for page_id in page_ids {
+                sqlx::query(
+                    "UPDATE pages SET status = ?, skip_reason = NULL,
+                        failure_reason = NULL, failure_message = NULL, failed_at = NULL
+                    WHERE id = ?",
+                )
+                .bind(PageStatus::NotDownloaded.int_value())
+                .bind(page_id)
+                .execute(&mut tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Changes type of the page, returns `false` if there is no such page
+    ///
+    /// Download status and content are kept, so the page is parsed by parsers of the new type.
+    pub async fn set_page_type(&self, page_id: i64, type_id: PageTypeId) -> Result<bool> {
+        retry_busy(|| async move {
+            let result = sqlx::query("UPDATE pages SET type = ? WHERE id = ?")
+                .bind(type_id)
+                .bind(page_id)
+                .execute(&self.connection)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+        .await
+    }
+
+    /// Changes type of all pages which URL contains a given text, returns the number of changed pages
+    pub async fn set_page_type_by_url(&self, url_part: &str, type_id: PageTypeId) -> Result<u64> {
+        retry_busy(|| async move {
+            let result =
+                sqlx::query("UPDATE pages SET type = ? WHERE instr(url, ?) > 0 AND type != ?")
+                    .bind(type_id)
+                    .bind(url_part)
+                    .bind(type_id)
+                    .execute(&self.connection)
+                    .await?;
+            Ok(result.rows_affected())
+        })
+        .await
+    }
+
+    /// Changes URL of the page keeping its content and history
+    ///
+    /// Fails with [`AppError::PageUrlTaken`] if there is another page with the same URL (and
+    /// request), such pages should be merged instead (see [`SqliteStorage::merge_pages()`]).
+    pub async fn move_page(&self, page_id: i64, url: &Url) -> Result<()> {
+        let (url, connection) = (url.as_str(), &self.connection);
+        retry_busy(|| async move {
+            let mut tx = connection.begin().await?;
+            let taken: Option<i64> = sqlx::query_scalar(
+                "SELECT other.id FROM pages page, pages other
+                WHERE page.id = ? AND other.url_hash = ? AND other.url = ? AND other.id != page.id
+                    AND IFNULL(other.request, '') = IFNULL(page.request, '')",
+            )
+            .bind(page_id)
+            .bind(url_hash(url))
+            .bind(url)
+            .fetch_optional(&mut tx)
+            .await?;
+            if let Some(other_id) = taken {
+                return Err(AppError::PageUrlTaken(other_id).into());
+            }
+            let result = sqlx::query("UPDATE pages SET url = ?, url_hash = ? WHERE id = ?")
+                .bind(url)
+                .bind(url_hash(url))
+                .bind(page_id)
+                .execute(&mut tx)
+                .await?;
+            if result.rows_affected() == 0 {
+                return Err(AppError::PageNotFound(page_id).into());
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Merges page `from_id` into page `into_id`, page `from_id` is removed
+    ///
+    /// URL, type and request of `into_id` are kept. The most recently downloaded content of two
+    /// pages (along with its response metadata) becomes the current content, the other one is
+    /// moved to the history. History and tags of both pages are combined.
+    pub async fn merge_pages(&self, from_id: i64, into_id: i64) -> Result<()> {
+        if from_id == into_id {
+            return Err(AppError::MergingSamePage(from_id).into());
+        }
+        let downloaded_at = |id| async move {
+            let row: Option<Option<i64>> =
+                sqlx::query_scalar("SELECT downloaded_at FROM pages WHERE id = ?")
+                    .bind(id)
+                    .fetch_optional(&self.connection)
+                    .await?;
+            row.ok_or_else(|| anyhow::Error::from(AppError::PageNotFound(id)))
+        };
+        let from_newer = downloaded_at(from_id).await?.unwrap_or(-1)
+            > downloaded_at(into_id).await?.unwrap_or(-1);
+        if let Some(shards) = &self.shards {
+            shards.copy(from_id, into_id, from_newer).await?;
+        }
+        retry_busy(|| async move {
+            let mut tx = self.connection.begin().await?;
+            // Current content of the page which is older goes to the history of the merged page
+            let (older_id, newer_id) = if from_newer {
+                (into_id, from_id)
+            } else {
+                (from_id, into_id)
+            };
+            sqlx::query(
+                "INSERT INTO page_history (page_id, downloaded_at, content, compressed)
+                SELECT ?, downloaded_at, content, compressed FROM pages WHERE id = ? AND content IS NOT NULL",
+            )
+            .bind(into_id)
+            .bind(older_id)
+            .execute(&mut tx)
+            .await?;
+            if newer_id == from_id {
+                sqlx::query(
+                    "UPDATE pages SET (content, compressed, status, skip_reason, downloaded_at,
+                        http_status, final_url, content_type, headers, redirects, truncated,
+                        ttfb_ms, total_ms, body_bytes, failure_reason, failure_message, failed_at,
+                        content_hash, changed_at, simhash, duplicate_of) =
+                    (SELECT content, compressed, status, skip_reason, downloaded_at,
+                        http_status, final_url, content_type, headers, redirects, truncated,
+                        ttfb_ms, total_ms, body_bytes, failure_reason, failure_message, failed_at,
+                        content_hash, changed_at, simhash, duplicate_of
+                    FROM pages WHERE id = ?)
+                    WHERE id = ?",
+                )
+                .bind(from_id)
+                .bind(into_id)
+                .execute(&mut tx)
+                .await?;
+            }
+            sqlx::query("UPDATE page_history SET page_id = ? WHERE page_id = ?")
+                .bind(into_id)
+                .bind(from_id)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query("UPDATE pages SET duplicate_of = ? WHERE duplicate_of = ? AND id != ?")
+                .bind(into_id)
+                .bind(from_id)
+                .bind(into_id)
+                .execute(&mut tx)
+                .await?;
+            // Merged page can't be a duplicate of itself
+            sqlx::query(
+                "UPDATE pages SET duplicate_of = NULL WHERE id = ? AND duplicate_of IN (?, ?)",
+            )
+            .bind(into_id)
+            .bind(from_id)
+            .bind(into_id)
+            .execute(&mut tx)
+            .await?;
+            sqlx::query(
+                "INSERT OR IGNORE INTO page_tags (page_id, tag)
+                SELECT ?, tag FROM page_tags WHERE page_id = ?",
+            )
+            .bind(into_id)
+            .bind(from_id)
+            .execute(&mut tx)
+            .await?;
+            sqlx::query("DELETE FROM page_tags WHERE page_id = ?")
+                .bind(from_id)
+                .execute(&mut tx)
+                .await?;
+            sqlx::query("DELETE FROM pages WHERE id = ?")
+                .bind(from_id)
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await?;
+        if let Some(shards) = &self.shards {
+            shards.delete(from_id).await?;
         }
+        Ok(())
     }
 
-    pub async fn list_not_downloaded_pages(&self, count: u16) -> Result<Vec<Page>> {
-        let query =
-            "SELECT id, url, type, depth, status FROM pages WHERE status = ? ORDER BY depth ASC LIMIT ?";
-        let result_set: Vec<PageRow> = sqlx::query_as(query)
-            .bind(PageStatus::NotDownloaded.int_value())
-            .bind(count)
+    /// Removes all tags of the page
+    pub async fn untag_page(&self, page_id: i64) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query("DELETE FROM page_tags WHERE page_id = ?")
+                .bind(page_id)
+                .execute(&self.connection)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Lists tags of the page in alphabetical order
+    pub async fn list_page_tags(&self, page_id: i64) -> Result<Vec<String>> {
+        let tags: Vec<(String,)> =
+            sqlx::query_as("SELECT tag FROM page_tags WHERE page_id = ? ORDER BY tag")
+                .bind(page_id)
+                .fetch_all(&self.connection)
+                .await?;
+        Ok(tags.into_iter().map(|(tag,)| tag).collect())
+    }
+
+    /// Lists pages with a given tag
+    pub async fn list_tagged_pages(&self, tag: &str) -> Result<Vec<Page>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS} FROM pages WHERE id IN (SELECT page_id FROM page_tags WHERE tag = ?) ORDER BY id"
+        );
+        let result_set: Vec<PageRow> = sqlx::query_as(&query)
+            .bind(tag)
             .fetch_all(&self.connection)
             .await?;
+        result_set.into_iter().map(page_from_tuple).collect()
+    }
+
+    /// Lists pages marked as duplicates along with the id of the original page
+    pub async fn list_duplicate_pages(&self) -> Result<Vec<(Page, i64)>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS}, duplicate_of FROM pages WHERE duplicate_of IS NOT NULL ORDER BY id"
+        );
+        let result_set = sqlx::query(&query).fetch_all(&self.connection).await?;
         let mut pages = vec![];
         for row in result_set {
-            pages.push(page_from_tuple(row)?);
+            let original_id: i64 = row.try_get("duplicate_of")?;
+            pages.push((page_from_columns(&row)?, original_id));
         }
         Ok(pages)
     }
 
-    pub async fn reset_page(&self, page_id: i64) -> Result<()> {
-        sqlx::query("UPDATE pages SET status = ? WHERE id = ?")
-            .bind(PageStatus::NotDownloaded.int_value())
-            .bind(page_id)
-            .execute(&self.connection)
-            .await?;
-        Ok(())
+    /// Lists pages which last download attempt failed along with the reason of the failure
+    pub async fn list_failed_downloads(&self) -> Result<Vec<(Page, DownloadFailure)>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS}, failure_reason, failure_message, failed_at FROM pages
+            WHERE failure_reason IS NOT NULL ORDER BY failed_at, id"
+        );
+        let result_set = sqlx::query(&query).fetch_all(&self.connection).await?;
+        let mut pages = vec![];
+        for row in result_set {
+            let reason: u8 = row.try_get("failure_reason")?;
+            let failed_at: i64 = row.try_get("failed_at")?;
+            let failure = DownloadFailure {
+                reason: FailureReason::from_int(reason)?,
+                message: row.try_get("failure_message")?,
+                failed_at: DateTime::from_timestamp(failed_at, 0).unwrap_or_default(),
+            };
+            pages.push((page_from_columns(&row)?, failure));
+        }
+        Ok(pages)
     }
 
     /// Writes page content in storage and marks page as [`PageStatus::Downloaded`]
-    pub async fn write_page_content(&self, page_id: i64, content: &str) -> Result<()> {
-        let compressed = compress(content.as_bytes(), 3)?;
-        sqlx::query("UPDATE pages SET content = ?, compressed = 1, status = ? WHERE id = ?")
-            .bind(compressed)
-            .bind(PageStatus::Downloaded.int_value())
-            .bind(page_id)
-            .execute(&self.connection)
+    ///
+    /// Previous content of the page (if any) is moved to the page history, so the dataset can be
+    /// inspected as it was at any point in time (see [`Storage::read_downloaded_pages_as_of()`]).
+    /// Content which is not changed is not copied to the history (see [`SqliteStorage::prune_history()`]
+    /// for limiting the number of stored versions). Response metadata (if given) replaces the metadata of the previous download.
+    ///
+    /// Returns `true` if content differs from the previously stored one (or page was not
+    /// downloaded before), in that case page change time is updated as well
+    /// (see [`SqliteStorage::list_changed_pages()`]).
+    pub async fn write_page_content(
+        &self,
+        page_id: i64,
+        content: &str,
+        meta: Option<&ResponseMeta>,
+    ) -> Result<bool> {
+        let content = EncodedContent::new(content)?;
+        self.write_encoded_content(page_id, content, meta).await
+    }
+
+    /// Removes all but `keep` latest previous versions of each page content, returns the number of
+    /// removed versions
+    ///
+    /// Current content of pages is never removed. Space is reclaimed by SQLite only after `VACUUM`.
+    pub async fn prune_history(&self, keep: u32) -> Result<u64> {
+        let mut removed = match &self.shards {
+            Some(shards) => shards.prune(keep).await?,
+            None => 0,
+        };
+        let connection = &self.connection;
+        removed += retry_busy(|| async move {
+            let result = sqlx::query(PRUNE_HISTORY.replace("{table}", "page_history").as_str())
+                .bind(keep)
+                .execute(connection)
+                .await?;
+            Ok(result.rows_affected())
+        })
+        .await?;
+        Ok(removed)
+    }
+
+    /// Returns hash of the stored page content, `None` if page has no content
+    ///
+    /// Hash is calculated from the content if page was downloaded before hashes were introduced.
+    async fn read_content_hash(&self, page_id: i64) -> Result<Option<String>> {
+        let hash: Option<Option<String>> =
+            sqlx::query_scalar("SELECT content_hash FROM pages WHERE id = ?")
+                .bind(page_id)
+                .fetch_optional(&self.connection)
+                .await?;
+        if let Some(hash) = hash.flatten() {
+            return Ok(Some(hash));
+        }
+        let content = self.read_page_content(page_id).await?;
+        Ok(content.map(|(content, _)| content_hash(&content)))
+    }
+
+    /// Lists pages which content changed since a given time along with the time of the change
+    pub async fn list_changed_pages(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(Page, DateTime<Utc>)>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS}, changed_at FROM pages WHERE changed_at >= ? ORDER BY changed_at, id"
+        );
+        let rows = sqlx::query(&query)
+            .bind(since.timestamp())
+            .fetch_all(&self.connection)
             .await?;
-        Ok(())
+        let mut pages = vec![];
+        for row in rows {
+            let changed_at: i64 = row.try_get("changed_at")?;
+            let changed_at = DateTime::from_timestamp(changed_at, 0).unwrap_or_default();
+            pages.push((page_from_columns(&row)?, changed_at));
+        }
+        Ok(pages)
+    }
+
+    /// Lists URLs of downloaded pages along with timing of the request they are downloaded with
+    pub async fn list_request_timings(&self) -> Result<Vec<(Url, RequestTiming)>> {
+        let rows: Vec<(String, u32, u32, i64)> = sqlx::query_as(
+            "SELECT url, ttfb_ms, total_ms, body_bytes FROM pages WHERE total_ms IS NOT NULL",
+        )
+        .fetch_all(&self.connection)
+        .await?;
+        let mut result = Vec::with_capacity(rows.len());
+        for (url, ttfb_ms, total_ms, body_bytes) in rows {
+            let timing = RequestTiming {
+                ttfb_ms,
+                total_ms,
+                body_bytes: body_bytes as u64,
+            };
+            result.push((Url::parse(&url)?, timing));
+        }
+        Ok(result)
     }
 
     pub async fn read_page(&self, id: i64) -> Result<Option<Page>> {
-        sqlx::query_as("SELECT id, url, type, depth, status FROM pages WHERE id = ?")
+        sqlx::query_as(&format!("SELECT {PAGE_COLUMNS} FROM pages WHERE id = ?"))
             .bind(id)
             .fetch_optional(&self.connection)
             .await?
@@ -153,54 +1028,1136 @@ impl Storage {
     }
 
     pub async fn read_page_content(&self, id: i64) -> Result<Option<(String, PageTypeId)>> {
-        let content: Option<(Vec<u8>, PageTypeId, u8)> =
+        let row: Option<(Option<Vec<u8>>, PageTypeId, u8)> =
             sqlx::query_as("SELECT content, type, compressed FROM pages WHERE id = ?")
                 .bind(id)
                 .fetch_optional(&self.connection)
                 .await?;
-        if let Some((content, type_id, compressed)) = content {
-            let content = decompress_zstd(content, compressed > 0)?;
-            Ok(Some((content, type_id)))
-        } else {
-            Ok(None)
+        match (row, &self.shards) {
+            (Some((Some(content), type_id, compressed)), _) => {
+                Ok(Some((decompress_zstd(content, compressed > 0)?, type_id)))
+            }
+            (Some((None, type_id, _)), Some(shards)) => {
+                let content = match shards.read(id, None, None).await? {
+                    Some(row) => read_content(&row, id, &PageSizeLimit::default())?,
+                    None => None,
+                };
+                Ok(content.map(|content| (content, type_id)))
+            }
+            _ => Ok(None),
         }
     }
 
-    /// Lists downloaded pages and its content
-    pub fn read_downloaded_pages(&self) -> BoxStream<Result<(Page, String)>> {
-        let sql = "SELECT id, url, type, depth, status, content, compressed FROM pages WHERE content IS NOT NULL AND status = ?";
+    fn read_pages_changed_since(
+        &self,
+        since: Option<i64>,
+    ) -> BoxStream<'_, Result<(Page, String)>> {
+        let sql =
+            "SELECT id, url, type, depth, status, downloaded_at, http_status, request, final_url, compressed,
+                length(content) AS content_size,
+                CASE WHEN ? IS NULL OR length(content) <= ? THEN content END AS content
+            FROM pages WHERE status = ? AND (? IS NULL OR changed_at >= ?)
+                AND (? = 0 OR duplicate_of IS NULL)";
+        let max_page_size = self.max_page_size.clone();
+        let shards = self.shards.clone();
         let r = sqlx::query(sql)
+            .bind(max_page_size.bind())
+            .bind(max_page_size.bind())
             .bind(PageStatus::Downloaded.int_value())
+            .bind(since)
+            .bind(since)
+            .bind(self.skip_duplicates)
             .fetch(&self.connection)
-            .map(page_from_row);
+            .then(move |row| {
+                let (shards, max_page_size) = (shards.clone(), max_page_size.clone());
+                async move { page_from_row(row?, shards.as_deref(), &max_page_size).await }
+            })
+            .filter_map(|page| ready(page.transpose()));
+        Box::pin(r)
+    }
+
+    fn read_sharded_pages_as_of(
+        &self,
+        shards: Arc<Shards>,
+        as_of: DateTime<Utc>,
+    ) -> BoxStream<'_, Result<(Page, String)>> {
+        let max_page_size = self.max_page_size.clone();
+        let r = sqlx::query(
+            "SELECT id, url, type, depth, status, downloaded_at, http_status, request, final_url FROM pages",
+        )
+        .fetch(&self.connection)
+        .then(move |row| {
+            let (shards, max_page_size) = (shards.clone(), max_page_size.clone());
+            async move {
+                let mut page = page_from_columns(&row?)?;
+                let Some(row) = shards.read(page.id, Some(as_of), max_page_size.max).await? else {
+                    return Ok(None);
+                };
+                let downloaded_at: Option<i64> = row.try_get("downloaded_at")?;
+                page.downloaded_at = downloaded_at.and_then(|t| DateTime::from_timestamp(t, 0));
+                let content = read_content(&row, page.id, &max_page_size)?;
+                Ok(content.map(|content| (page, content)))
+            }
+        })
+        .filter_map(|page| ready(page.transpose()));
         Box::pin(r)
     }
 }
 
-fn decompress_zstd(data: Vec<u8>, compressed: bool) -> Result<String> {
-    if compressed {
-        let mut out = Cursor::new(vec![]);
-        zstd::stream::copy_decode(data.as_slice(), &mut out)?;
-        Ok(String::from_utf8(out.into_inner())?)
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn set_crawl_order(&mut self, crawl_order: CrawlOrder) {
+        self.crawl_order = crawl_order;
+    }
+
+    fn set_skip_duplicates(&mut self, skip_duplicates: bool) {
+        self.skip_duplicates = skip_duplicates;
+    }
+
+    /// Uses `VACUUM INTO`, so it is safe to call while crawler is running. Target file (and its
+    /// shards) should not exist.
+    async fn snapshot(&self, path: &Path) -> Result<()> {
+        sqlx::query("VACUUM INTO ?")
+            .bind(utf8_path(path)?)
+            .execute(&self.connection)
+            .await?;
+        if let Some(shards) = &self.shards {
+            shards.snapshot(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn count_downloaded_pages(&self, type_id: Option<PageTypeId>) -> Result<i64> {
+        let row: (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM pages WHERE status = ? AND (? IS NULL OR type = ?)",
+        )
+        .bind(PageStatus::Downloaded.int_value())
+        .bind(type_id)
+        .bind(type_id)
+        .fetch_one(&self.connection)
+        .await?;
+        Ok(row.0)
+    }
+
+    async fn list_seed_pages(&self) -> Result<Vec<Page>> {
+        let query = format!("SELECT {PAGE_COLUMNS} FROM pages WHERE depth = 0 ORDER BY id");
+        let result_set: Vec<PageRow> = sqlx::query_as(&query).fetch_all(&self.connection).await?;
+        result_set.into_iter().map(page_from_tuple).collect()
+    }
+
+    async fn register_link(&mut self, link: Link<Url>, depth: u16) -> Result<Option<i64>> {
+        let Link {
+            url,
+            type_id,
+            request,
+        } = link;
+        self.insert_page(url, type_id, depth, None, request.as_ref())
+            .await
+    }
+
+    async fn register_skipped_link(
+        &mut self,
+        link: Link<Url>,
+        depth: u16,
+        reason: SkipReason,
+    ) -> Result<Option<i64>> {
+        let Link {
+            url,
+            type_id,
+            request,
+        } = link;
+        self.insert_page(url, type_id, depth, Some(reason), request.as_ref())
+            .await
+    }
+
+    async fn find_page_by_url(&self, url: &Url) -> Result<Option<Page>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS} FROM pages WHERE url_hash = ? AND url = ? AND request IS NULL"
+        );
+        let row: Option<PageRow> = sqlx::query_as(&query)
+            .bind(url_hash(url.as_str()))
+            .bind(url.as_str())
+            .fetch_optional(&self.connection)
+            .await?;
+        row.map(page_from_tuple).transpose()
+    }
+
+    async fn find_page_by_final_url(&self, url: &Url, except_id: i64) -> Result<Option<Page>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS} FROM pages
+            WHERE id != ? AND request IS NULL AND status IN (?, ?)
+                AND (final_url = ? OR (url_hash = ? AND url = ?))
+            ORDER BY id LIMIT 1"
+        );
+        let row: Option<PageRow> = sqlx::query_as(&query)
+            .bind(except_id)
+            .bind(PageStatus::NotDownloaded.int_value())
+            .bind(PageStatus::Downloaded.int_value())
+            .bind(url.as_str())
+            .bind(url_hash(url.as_str()))
+            .bind(url.as_str())
+            .fetch_optional(&self.connection)
+            .await?;
+        row.map(page_from_tuple).transpose()
+    }
+
+    async fn list_downloaded_pages(
+        &self,
+        after_id: i64,
+        count: u16,
+        type_id: Option<PageTypeId>,
+    ) -> Result<Vec<Page>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS} FROM pages
+            WHERE status = ? AND id > ? AND (? IS NULL OR type = ?) ORDER BY id LIMIT ?"
+        );
+        let result_set: Vec<PageRow> = sqlx::query_as(&query)
+            .bind(PageStatus::Downloaded.int_value())
+            .bind(after_id)
+            .bind(type_id)
+            .bind(type_id)
+            .bind(count)
+            .fetch_all(&self.connection)
+            .await?;
+        let mut pages = vec![];
+        for row in result_set {
+            pages.push(page_from_tuple(row)?);
+        }
+        Ok(pages)
+    }
+
+    async fn list_not_downloaded_pages(&self, count: u16) -> Result<Vec<Page>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS} FROM pages WHERE status = ? ORDER BY {} LIMIT ?",
+            self.crawl_order.order_by()
+        );
+        let result_set: Vec<PageRow> = sqlx::query_as(&query)
+            .bind(PageStatus::NotDownloaded.int_value())
+            .bind(count)
+            .fetch_all(&self.connection)
+            .await?;
+        let mut pages = vec![];
+        for row in result_set {
+            pages.push(page_from_tuple(row)?);
+        }
+        Ok(pages)
+    }
+
+    async fn list_canary_pages(&self) -> Result<Vec<Page>> {
+        let query = format!(
+            "SELECT {PAGE_COLUMNS} FROM pages WHERE id IN (
+                SELECT (
+                    SELECT id FROM pages p WHERE p.type = t.type AND p.status = ?
+                    ORDER BY depth, id LIMIT 1
+                )
+                FROM (SELECT DISTINCT type FROM pages WHERE status = ?) t
+            )
+            ORDER BY type"
+        );
+        let result_set: Vec<PageRow> = sqlx::query_as(&query)
+            .bind(PageStatus::NotDownloaded.int_value())
+            .bind(PageStatus::NotDownloaded.int_value())
+            .fetch_all(&self.connection)
+            .await?;
+        result_set.into_iter().map(page_from_tuple).collect()
+    }
+
+    async fn lease_not_downloaded_pages(
+        &self,
+        count: u16,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Vec<Page>> {
+        retry_busy(|| async move {
+            let now = Utc::now().timestamp();
+            let expires_at = now + lease.as_secs() as i64;
+            let query = format!(
+                "UPDATE pages SET lease_owner = ?, lease_expires_at = ?
+                WHERE id IN (
+                    SELECT id FROM pages
+                    WHERE status = ? AND (lease_owner IS NULL OR lease_owner = ? OR lease_expires_at < ?)
+                    ORDER BY {} LIMIT ?
+                )
+                RETURNING {PAGE_COLUMNS}",
+                self.crawl_order.order_by()
+            );
+            let result_set: Vec<PageRow> = sqlx::query_as(&query)
+                .bind(owner)
+                .bind(expires_at)
+                .bind(PageStatus::NotDownloaded.int_value())
+                .bind(owner)
+                .bind(now)
+                .bind(count)
+                .fetch_all(&self.connection)
+                .await?;
+            let mut pages = result_set
+                .into_iter()
+                .map(page_from_tuple)
+                .collect::<Result<Vec<_>>>()?;
+            // RETURNING doesn't guarantee the order of rows
+            self.crawl_order.sort(&mut pages);
+            Ok(pages)
+        })
+        .await
+    }
+
+    async fn lease_expired_pages(
+        &self,
+        recrawl_after: &[(PageTypeId, Duration)],
+        count: u16,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Vec<Page>> {
+        retry_busy(|| async move {
+            if recrawl_after.is_empty() {
+                return Ok(vec![]);
+            }
+            let now = Utc::now().timestamp();
+            let expires_at = now + lease.as_secs() as i64;
+            let types = vec!["(type = ? AND downloaded_at <= ?)"; recrawl_after.len()].join(" OR ");
+            let query = format!(
+                "UPDATE pages SET lease_owner = ?, lease_expires_at = ?
+                WHERE id IN (
+                    SELECT id FROM pages
+                    WHERE status = ? AND ({types}) AND (lease_owner IS NULL OR lease_expires_at < ?)
+                    ORDER BY downloaded_at ASC LIMIT ?
+                )
+                RETURNING {PAGE_COLUMNS}"
+            );
+            let mut query = sqlx::query_as(&query)
+                .bind(owner)
+                .bind(expires_at)
+                .bind(PageStatus::Downloaded.int_value());
+            for (type_id, interval) in recrawl_after {
+                query = query.bind(type_id).bind(now - interval.as_secs() as i64);
+            }
+            let result_set: Vec<PageRow> = query
+                .bind(now)
+                .bind(count)
+                .fetch_all(&self.connection)
+                .await?;
+            let mut pages = result_set
+                .into_iter()
+                .map(page_from_tuple)
+                .collect::<Result<Vec<_>>>()?;
+            // RETURNING doesn't guarantee the order of rows
+            pages.sort_by_key(|page| (page.downloaded_at, page.id));
+            Ok(pages)
+        })
+        .await
+    }
+
+    async fn release_leases(&self, owner: &str) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query(
+                "UPDATE pages SET lease_owner = NULL, lease_expires_at = NULL WHERE lease_owner = ?",
+            )
+            .bind(owner)
+            .execute(&self.connection)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn touch_page(&self, page_id: i64) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query(
+                "UPDATE pages SET downloaded_at = ?,
+                    failure_reason = NULL, failure_message = NULL, failed_at = NULL
+                WHERE id = ?",
+            )
+            .bind(Utc::now().timestamp())
+            .bind(page_id)
+            .execute(&self.connection)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn fail_page(&self, page_id: i64) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query("UPDATE pages SET status = ? WHERE id = ?")
+                .bind(PageStatus::Failed.int_value())
+                .bind(page_id)
+                .execute(&self.connection)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn skip_page(&self, page_id: i64, reason: SkipReason) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query("UPDATE pages SET status = ?, skip_reason = ? WHERE id = ?")
+                .bind(PageStatus::Skipped.int_value())
+                .bind(reason.int_value())
+                .bind(page_id)
+                .execute(&self.connection)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn skip_page_with_meta(
+        &self,
+        page_id: i64,
+        reason: SkipReason,
+        meta: &ResponseMeta,
+    ) -> Result<()> {
+        let headers = serde_json::to_string(&meta.headers)?;
+        let redirects = redirects_json(meta)?;
+        let (headers, redirects) = (&headers, &redirects);
+        retry_busy(|| async move {
+            sqlx::query(
+                "UPDATE pages SET status = ?, skip_reason = ?, http_status = ?, final_url = ?,
+                    content_type = ?, headers = ?, redirects = ?, truncated = 0,
+                    ttfb_ms = NULL, total_ms = NULL, body_bytes = NULL
+                WHERE id = ?",
+            )
+            .bind(PageStatus::Skipped.int_value())
+            .bind(reason.int_value())
+            .bind(meta.status)
+            .bind(meta.final_url.as_str())
+            .bind(meta.content_type.as_deref())
+            .bind(headers.as_str())
+            .bind(redirects.as_deref())
+            .bind(page_id)
+            .execute(&self.connection)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_skipped_pages(&self) -> Result<Vec<(Page, SkipReason)>> {
+        let query =
+            format!("SELECT {PAGE_COLUMNS}, skip_reason FROM pages WHERE status = ? ORDER BY id");
+        let result_set = sqlx::query(&query)
+            .bind(PageStatus::Skipped.int_value())
+            .fetch_all(&self.connection)
+            .await?;
+        let mut pages = vec![];
+        for row in result_set {
+            let reason: u8 = row.try_get("skip_reason")?;
+            pages.push((page_from_columns(&row)?, SkipReason::from_int(reason)?));
+        }
+        Ok(pages)
+    }
+
+    async fn tag_page(&self, page_id: i64, tag: &str) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query("INSERT OR IGNORE INTO page_tags (page_id, tag) VALUES (?, ?)")
+                .bind(page_id)
+                .bind(tag)
+                .execute(&self.connection)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_request_counters(&self) -> Result<Vec<RequestCounter>> {
+        let counters = sqlx::query_as(
+            "SELECT host, window, window_start, count FROM request_counters ORDER BY host, window",
+        )
+        .fetch_all(&self.connection)
+        .await?;
+        Ok(counters)
+    }
+
+    async fn write_request_counter(&self, counter: &RequestCounter) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query(
+                "INSERT OR REPLACE INTO request_counters (host, window, window_start, count) VALUES (?, ?, ?, ?)",
+            )
+            .bind(&counter.host)
+            .bind(&counter.window)
+            .bind(counter.window_start)
+            .bind(counter.count)
+            .execute(&self.connection)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_host_backoffs(&self) -> Result<Vec<HostBackoff>> {
+        let backoffs = sqlx::query_as(
+            "SELECT host, delay_ms, rate_limited_at, retry_at FROM host_backoffs ORDER BY host",
+        )
+        .fetch_all(&self.connection)
+        .await?;
+        Ok(backoffs)
+    }
+
+    async fn write_host_backoff(&self, backoff: &HostBackoff) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query(
+                "INSERT OR REPLACE INTO host_backoffs (host, delay_ms, rate_limited_at, retry_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(&backoff.host)
+            .bind(backoff.delay_ms)
+            .bind(backoff.rate_limited_at)
+            .bind(backoff.retry_at)
+            .execute(&self.connection)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_host_backoff(&self, host: &str) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query("DELETE FROM host_backoffs WHERE host = ?")
+                .bind(host)
+                .execute(&self.connection)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_page_by_content_hash(&self, hash: &str, except_id: i64) -> Result<Option<i64>> {
+        let id = sqlx::query_scalar(
+            "SELECT id FROM pages
+            WHERE content_hash = ? AND id != ? AND status = ? AND duplicate_of IS NULL
+            ORDER BY id LIMIT 1",
+        )
+        .bind(hash)
+        .bind(except_id)
+        .bind(PageStatus::Downloaded.int_value())
+        .fetch_optional(&self.connection)
+        .await?;
+        Ok(id)
+    }
+
+    async fn set_duplicate_of(&self, page_id: i64, original_id: Option<i64>) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query("UPDATE pages SET duplicate_of = ? WHERE id = ?")
+                .bind(original_id)
+                .bind(page_id)
+                .execute(&self.connection)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_simhashes(&self) -> Result<Vec<(i64, u64)>> {
+        let rows: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT id, simhash FROM pages
+            WHERE status = ? AND simhash IS NOT NULL AND duplicate_of IS NULL",
+        )
+        .bind(PageStatus::Downloaded.int_value())
+        .fetch_all(&self.connection)
+        .await?;
+        Ok(rows.into_iter().map(|(id, h)| (id, h as u64)).collect())
+    }
+
+    async fn record_failure(
+        &self,
+        page_id: i64,
+        reason: FailureReason,
+        message: &str,
+    ) -> Result<()> {
+        retry_busy(|| async move {
+            sqlx::query(
+                "UPDATE pages SET failure_reason = ?, failure_message = ?, failed_at = ? WHERE id = ?",
+            )
+            .bind(reason.int_value())
+            .bind(message)
+            .bind(Utc::now().timestamp())
+            .bind(page_id)
+            .execute(&self.connection)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn write_encoded_content(
+        &self,
+        page_id: i64,
+        content: EncodedContent,
+        meta: Option<&ResponseMeta>,
+    ) -> Result<bool> {
+        let EncodedContent {
+            hash,
+            compressed,
+            simhash,
+        } = content;
+        let changed = self.read_content_hash(page_id).await?.as_ref() != Some(&hash);
+        let downloaded_at = Utc::now().timestamp();
+        let compressed = match &self.shards {
+            Some(shards) => {
+                // Unchanged content is kept as is, so its version keeps the time it was downloaded at
+                if changed {
+                    shards.write(page_id, compressed, downloaded_at).await?;
+                }
+                None
+            }
+            None => Some(compressed),
+        };
+        let headers = meta
+            .map(|m| serde_json::to_string(&m.headers))
+            .transpose()?;
+        let timing = meta.and_then(|m| m.timing);
+        let redirects = meta.map(redirects_json).transpose()?.flatten();
+        let (hash, connection) = (&hash, &self.connection);
+        let (compressed, headers, redirects) = (&compressed, &headers, &redirects);
+        retry_busy(|| async move {
+            let mut tx = connection.begin().await?;
+            // Unchanged content is not copied, the current version starts at `changed_at`
+            if changed {
+                sqlx::query(
+                    "INSERT INTO page_history (page_id, downloaded_at, content, compressed)
+                    SELECT id, COALESCE(changed_at, downloaded_at), content, compressed
+                    FROM pages WHERE id = ? AND content IS NOT NULL",
+                )
+                .bind(page_id)
+                .execute(&mut tx)
+                .await?;
+            }
+            sqlx::query(
+                "UPDATE pages SET content = ?, compressed = 1, status = ?, downloaded_at = ?,
+                    http_status = ?, final_url = ?, content_type = ?, headers = ?, redirects = ?,
+                    truncated = ?, ttfb_ms = ?, total_ms = ?, body_bytes = ?,
+                    failure_reason = NULL, failure_message = NULL, failed_at = NULL,
+                    content_hash = ?, simhash = ?,
+                    changed_at = CASE WHEN ? THEN ? ELSE COALESCE(changed_at, downloaded_at) END
+                WHERE id = ?",
+            )
+            .bind(compressed.as_deref())
+            .bind(PageStatus::Downloaded.int_value())
+            .bind(downloaded_at)
+            .bind(meta.map(|m| m.status))
+            .bind(meta.map(|m| m.final_url.to_string()))
+            .bind(meta.and_then(|m| m.content_type.clone()))
+            .bind(headers.as_deref())
+            .bind(redirects.as_deref())
+            .bind(meta.is_some_and(|m| m.truncated))
+            .bind(timing.map(|t| t.ttfb_ms))
+            .bind(timing.map(|t| t.total_ms))
+            .bind(timing.map(|t| t.body_bytes as i64))
+            .bind(hash.as_str())
+            .bind(simhash.map(|h| h as i64))
+            .bind(changed)
+            .bind(downloaded_at)
+            .bind(page_id)
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await?;
+        Ok(changed)
+    }
+
+    async fn read_page_meta(&self, id: i64) -> Result<Option<ResponseMeta>> {
+        let row: Option<MetaRow> = sqlx::query_as(
+"SELECT http_status, final_url, content_type, headers, redirects, truncated, ttfb_ms, total_ms, body_bytes
+FROM pages WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.connection)
+        .await?;
+        row.map(meta_from_tuple).transpose().map(Option::flatten)
+    }
+
+    fn read_downloaded_pages(&self) -> BoxStream<'_, Result<(Page, String)>> {
+        self.read_pages_changed_since(None)
+    }
+
+    fn read_changed_pages(&self, since: DateTime<Utc>) -> BoxStream<'_, Result<(Page, String)>> {
+        self.read_pages_changed_since(Some(since.timestamp()))
+    }
+
+    /// When sharding is enabled only the content stored in shards is considered.
+    fn read_downloaded_pages_as_of(
+        &self,
+        as_of: DateTime<Utc>,
+    ) -> BoxStream<'_, Result<(Page, String)>> {
+        if let Some(shards) = &self.shards {
+            return self.read_sharded_pages_as_of(shards.clone(), as_of);
+        }
+        let sql = "SELECT id, url, type, depth, status, downloaded_at, http_status, request, final_url, compressed,
+                length(content) AS content_size,
+                CASE WHEN ? IS NULL OR length(content) <= ? THEN content END AS content
+            FROM (
+                SELECT p.id, p.url, p.type, p.depth, p.status, v.downloaded_at, p.http_status, p.request, p.final_url,
+                    v.content,
+                    v.compressed,
+                    ROW_NUMBER() OVER (
+                        PARTITION BY v.page_id
+                        ORDER BY COALESCE(v.downloaded_at, 0) DESC, v.current DESC, v.seq DESC
+                    ) AS version
+                FROM pages p JOIN (
+                    SELECT page_id, downloaded_at, 0 AS current, rowid AS seq, content, compressed
+                    FROM page_history
+                    UNION ALL
+                    SELECT id, COALESCE(changed_at, downloaded_at), 1, 0, content, compressed
+                    FROM pages WHERE content IS NOT NULL
+                ) v ON v.page_id = p.id
+                WHERE COALESCE(v.downloaded_at, 0) <= ?
+            ) WHERE version = 1";
+        let max_page_size = self.max_page_size.clone();
+        let r = sqlx::query(sql)
+            .bind(max_page_size.bind())
+            .bind(max_page_size.bind())
+            .bind(as_of.timestamp())
+            .fetch(&self.connection)
+            .then(move |row| {
+                let max_page_size = max_page_size.clone();
+                async move { page_from_row(row?, None, &max_page_size).await }
+            })
+            .filter_map(|page| ready(page.transpose()));
+        Box::pin(r)
+    }
+
+    fn read_downloaded_pages_batched(
+        &self,
+        batch_size: usize,
+    ) -> BoxStream<'static, Result<Vec<(Page, String)>>> {
+        let connection = self.connection.clone();
+        let max_page_size = self.max_page_size.clone();
+        let shards = self.shards.clone();
+        let skip_duplicates = self.skip_duplicates;
+        let (tx, rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            let sql = "SELECT id, url, type, depth, status, downloaded_at, http_status, request, final_url, compressed,
+                    length(content) AS content_size,
+                    CASE WHEN ? IS NULL OR length(content) <= ? THEN content END AS content
+                FROM pages WHERE status = ? AND id > ? AND (? = 0 OR duplicate_of IS NULL)
+                ORDER BY id LIMIT ?";
+            let mut last_id = 0i64;
+            loop {
+                let rows = sqlx::query(sql)
+                    .bind(max_page_size.bind())
+                    .bind(max_page_size.bind())
+                    .bind(PageStatus::Downloaded.int_value())
+                    .bind(last_id)
+                    .bind(skip_duplicates)
+                    .bind(batch_size as i64)
+                    .fetch_all(&connection)
+                    .await;
+                let batch = match rows {
+                    Ok(rows) if rows.is_empty() => break,
+                    Ok(rows) => {
+                        read_batch(rows, shards.as_deref(), &max_page_size, &mut last_id).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                let failed = batch.is_err();
+                // Receiving side is dropped, no need to read further
+                if tx.send(batch).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let r = futures::stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|batch| (batch, rx))
+        });
+        Box::pin(r)
+    }
+
+    fn count_oversized_pages(&self) -> u64 {
+        self.max_page_size.skipped()
+    }
+}
+
+/// Page content split across several SQLite files by page id
+///
+/// Each shard keeps current content of a page as well as its history, the same way main database does.
+struct Shards(Vec<SqlitePool>);
+
+/// Removes all but the latest versions of each page from a history table (`{table}` placeholder)
+const PRUNE_HISTORY: &str = "DELETE FROM {table} WHERE rowid IN (
+    SELECT rowid FROM (
+        SELECT rowid, ROW_NUMBER() OVER (
+            PARTITION BY page_id ORDER BY COALESCE(downloaded_at, 0) DESC, rowid DESC
+        ) AS version
+        FROM {table}
+    ) WHERE version > ?
+)";
+
+impl Shards {
+    async fn open(database: &Path, count: u16, read_only: bool) -> Result<Self> {
+        let mut pools = vec![];
+        for i in 0..count.max(1) {
+            let path = shard_path(database, i);
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .read_only(read_only)
+                .create_if_missing(!read_only);
+            let pool = SqlitePoolOptions::new().connect_with(options).await?;
+            if read_only {
+                pools.push(pool);
+                continue;
+            }
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS contents (
+                    page_id INTEGER PRIMARY KEY,
+                    downloaded_at INT NULL,
+                    content BLOB NOT NULL,
+                    compressed INT NOT NULL
+                )",
+            )
+            .execute(&pool)
+            .await?;
+            sqlx::query(
+                "CREATE TABLE IF NOT EXISTS content_history (
+                    page_id INT NOT NULL,
+                    downloaded_at INT NULL,
+                    content BLOB NOT NULL,
+                    compressed INT NOT NULL
+                )",
+            )
+            .execute(&pool)
+            .await?;
+            sqlx::query(
+                "CREATE INDEX IF NOT EXISTS content_history_page_id ON content_history (page_id)",
+            )
+            .execute(&pool)
+            .await?;
+            pools.push(pool);
+        }
+        Ok(Self(pools))
+    }
+
+    /// Writes a copy of each shard next to a given main database snapshot
+    async fn snapshot(&self, database: &Path) -> Result<()> {
+        for (i, pool) in self.0.iter().enumerate() {
+            let path = shard_path(database, i as u16);
+            sqlx::query("VACUUM INTO ?")
+                .bind(utf8_path(&path)?)
+                .execute(pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn shard(&self, page_id: i64) -> &SqlitePool {
+        &self.0[page_id.rem_euclid(self.0.len() as i64) as usize]
+    }
+
+    /// Writes compressed content of a page, moving previous content to the history
+    async fn write(&self, page_id: i64, compressed: Vec<u8>, downloaded_at: i64) -> Result<()> {
+        let compressed = compressed.as_slice();
+        retry_busy(|| async move {
+            let mut tx = self.shard(page_id).begin().await?;
+            sqlx::query(
+                "INSERT INTO content_history (page_id, downloaded_at, content, compressed)
+                SELECT page_id, downloaded_at, content, compressed FROM contents WHERE page_id = ?",
+            )
+            .bind(page_id)
+            .execute(&mut tx)
+            .await?;
+            sqlx::query(
+                "INSERT OR REPLACE INTO contents (page_id, downloaded_at, content, compressed)
+                VALUES (?, ?, ?, 1)",
+            )
+            .bind(page_id)
+            .bind(downloaded_at)
+            .bind(compressed)
+            .execute(&mut tx)
+            .await?;
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Copies content and history of page `from_id` to the history of page `into_id`
+    ///
+    /// If `replace` is `true`, current content of `from_id` becomes the current content of `into_id`
+    /// and the content it replaces is moved to the history.
+    async fn copy(&self, from_id: i64, into_id: i64, replace: bool) -> Result<()> {
+        let current: Option<(Option<i64>, Vec<u8>, i64)> = sqlx::query_as(
+            "SELECT downloaded_at, content, compressed FROM contents WHERE page_id = ?",
+        )
+        .bind(from_id)
+        .fetch_optional(self.shard(from_id))
+        .await?;
+        let history: Vec<(Option<i64>, Vec<u8>, i64)> = sqlx::query_as(
+            "SELECT downloaded_at, content, compressed FROM content_history
+            WHERE page_id = ? ORDER BY rowid",
+        )
+        .bind(from_id)
+        .fetch_all(self.shard(from_id))
+        .await?;
+        let (current, history) = (&current, &history);
+        retry_busy(|| async move {
+            let mut tx = self.shard(into_id).begin().await?;
+            for (downloaded_at, content, compressed) in history {
+                sqlx::query(
+                    "INSERT INTO content_history (page_id, downloaded_at, content, compressed)
+                    VALUES (?, ?, ?, ?)",
+                )
+                .bind(into_id)
+                .bind(downloaded_at)
+                .bind(content)
+                .bind(compressed)
+                .execute(&mut tx)
+                .await?;
+            }
+            if let Some((downloaded_at, content, compressed)) = current {
+                let table = if replace {
+                    sqlx::query(
+                        "INSERT INTO content_history (page_id, downloaded_at, content, compressed)
+                        SELECT page_id, downloaded_at, content, compressed FROM contents WHERE page_id = ?",
+                    )
+                    .bind(into_id)
+                    .execute(&mut tx)
+                    .await?;
+                    "contents"
+                } else {
+                    "content_history"
+                };
+                sqlx::query(&format!(
+                    "INSERT OR REPLACE INTO {table} (page_id, downloaded_at, content, compressed)
+                    VALUES (?, ?, ?, ?)"
+                ))
+                .bind(into_id)
+                .bind(downloaded_at)
+                .bind(content)
+                .bind(compressed)
+                .execute(&mut tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes all but `keep` latest versions of each page from the history of all shards
+    async fn prune(&self, keep: u32) -> Result<u64> {
+        let mut removed = 0;
+        for pool in &self.0 {
+            removed += retry_busy(|| async move {
+                let result =
+                    sqlx::query(PRUNE_HISTORY.replace("{table}", "content_history").as_str())
+                        .bind(keep)
+                        .execute(pool)
+                        .await?;
+                Ok(result.rows_affected())
+            })
+            .await?;
+        }
+        Ok(removed)
+    }
+
+    /// Removes content and history of a page
+    async fn delete(&self, page_id: i64) -> Result<()> {
+        retry_busy(|| async move {
+            let mut tx = self.shard(page_id).begin().await?;
+            for table in ["contents", "content_history"] {
+                sqlx::query(&format!("DELETE FROM {table} WHERE page_id = ?"))
+                    .bind(page_id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Reads the latest content of a page (downloaded not later than `as_of` if given)
+    ///
+    /// Returned row has the same content columns as the main database queries (see [`read_content()`]).
+    async fn read(
+        &self,
+        page_id: i64,
+        as_of: Option<DateTime<Utc>>,
+        max_page_size: Option<usize>,
+    ) -> Result<Option<SqliteRow>> {
+        let sql = "SELECT downloaded_at, compressed,
+                length(content) AS content_size,
+                CASE WHEN ? IS NULL OR length(content) <= ? THEN content END AS content
+            FROM (
+                SELECT downloaded_at, 1 AS current, 0 AS seq, content, compressed
+                FROM contents WHERE page_id = ?
+                UNION ALL
+                SELECT downloaded_at, 0, rowid, content, compressed
+                FROM content_history WHERE page_id = ? AND ? IS NOT NULL
+            )
+            WHERE ? IS NULL OR COALESCE(downloaded_at, 0) <= ?
+            ORDER BY COALESCE(downloaded_at, 0) DESC, current DESC, seq DESC
+            LIMIT 1";
+        let as_of = as_of.map(|t| t.timestamp());
+        let row = sqlx::query(sql)
+            .bind(max_page_size.map(|s| s as i64))
+            .bind(max_page_size.map(|s| s as i64))
+            .bind(page_id)
+            .bind(page_id)
+            .bind(as_of)
+            .bind(as_of)
+            .bind(as_of)
+            .fetch_optional(self.shard(page_id))
+            .await?;
+        Ok(row)
+    }
+}
+
+pub(crate) fn decompress_zstd(data: Vec<u8>, compressed: bool) -> Result<String> {
+    if compressed {
+        let mut out = Cursor::new(vec![]);
+        zstd::stream::copy_decode(data.as_slice(), &mut out)?;
+        Ok(String::from_utf8(out.into_inner())?)
     } else {
         Ok(String::from_utf8(data)?)
     }
 }
 
-fn page_from_row(row: StdResult<SqliteRow, sqlx::Error>) -> Result<(Page, String)> {
-    let row = row?;
+/// Decompresses content, returns [`Option::None`] if decompressed content is larger than a limit
+pub(crate) fn decompress_zstd_limited(
+    data: Vec<u8>,
+    compressed: bool,
+    limit: usize,
+) -> Result<Option<String>> {
+    if !compressed {
+        return Ok(Some(String::from_utf8(data)?));
+    }
+    let mut out = vec![];
+    zstd::stream::read::Decoder::new(data.as_slice())?
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)?;
+    if out.len() > limit {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(out)?))
+}
+
+/// Path of the shard file: `<database stem>.shard<N>.sqlite` in the directory of the main database
+fn shard_path(database: &Path, shard: u16) -> PathBuf {
+    let stem = database
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    database.with_file_name(format!("{}.shard{}.sqlite", stem, shard))
+}
+
+/// SQLite accepts file names only as UTF-8 strings in SQL statements
+fn utf8_path(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| AppError::NonUtf8Path(path.to_path_buf()).into())
+}
+
+/// Reads pages from rows of a batch, updating the id of the last page read
+async fn read_batch(
+    rows: Vec<SqliteRow>,
+    shards: Option<&Shards>,
+    max_page_size: &PageSizeLimit,
+    last_id: &mut i64,
+) -> Result<Vec<(Page, String)>> {
+    let mut pages = Vec::with_capacity(rows.len());
+    for row in rows {
+        *last_id = row.try_get("id")?;
+        if let Some(page) = page_from_row(row, shards, max_page_size).await? {
+            pages.push(page);
+        }
+    }
+    Ok(pages)
+}
+
+/// Reads page and its content from a row
+///
+/// If the content is not stored in the main database it is read from shards (if enabled). Pages
+/// without content are skipped.
+async fn page_from_row(
+    row: SqliteRow,
+    shards: Option<&Shards>,
+    max_page_size: &PageSizeLimit,
+) -> Result<Option<(Page, String)>> {
+    let page = page_from_columns(&row)?;
+    let content_size: Option<i64> = row.try_get("content_size")?;
+    let content = match (content_size, shards) {
+        (Some(_), _) => read_content(&row, page.id, max_page_size)?,
+        (None, Some(shards)) => match shards.read(page.id, None, max_page_size.max).await? {
+            Some(row) => read_content(&row, page.id, max_page_size)?,
+            None => None,
+        },
+        (None, None) => None,
+    };
+    Ok(content.map(|content| (page, content)))
+}
+
+/// Reads and decompresses content from `compressed`, `content_size` and `content` columns of a row
+///
+/// If content is larger than `max_page_size`, it is skipped with a warning
+fn read_content(
+    row: &SqliteRow,
+    page_id: i64,
+    max_page_size: &PageSizeLimit,
+) -> Result<Option<String>> {
+    let compressed: u8 = row.try_get("compressed")?;
+    let content: Option<Vec<u8>> = row.try_get("content")?;
+    let content = match (content, max_page_size.max) {
+        (Some(content), None) => Some(decompress_zstd(content, compressed > 0)?),
+        (Some(content), Some(limit)) => decompress_zstd_limited(content, compressed > 0, limit)?,
+        (None, _) => None,
+    };
+    if content.is_none() {
+        max_page_size.skip(page_id, row.try_get("content_size")?);
+    }
+    Ok(content)
+}
+
+/// Maximum size of page content read by bulk reads along with the number of pages skipped
+///
+/// Clones share the counter, so pages skipped by streams are counted by the storage.
+#[derive(Clone, Default, Debug)]
+pub(crate) struct PageSizeLimit {
+    pub(crate) max: Option<usize>,
+    skipped: Arc<AtomicU64>,
+}
+
+impl PageSizeLimit {
+    /// The limit as an SQL parameter
+    pub(crate) fn bind(&self) -> Option<i64> {
+        self.max.map(|s| s as i64)
+    }
+
+    /// Records a page skipped because it's larger than the limit
+    pub(crate) fn skip(&self, page_id: i64, stored_size: i64) {
+        warn!(
+            "Page #{} is skipped, it's size exceeds the limit (stored size: {} bytes)",
+            page_id, stored_size
+        );
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
 
+/// Creates page from [`PAGE_COLUMNS`] of a row
+fn page_from_columns(row: &SqliteRow) -> Result<Page> {
     let page_id: i64 = row.try_get("id")?;
     let url: String = row.try_get("url")?;
     let depth: u16 = row.try_get("depth")?;
     let type_id: PageTypeId = row.try_get("type")?;
     let status: u8 = row.try_get("status")?;
-    let page = page_from_tuple((page_id, url, type_id, depth, status))?;
-
-    let compressed: u8 = row.try_get("compressed")?;
-    let content = decompress_zstd(row.try_get("content")?, compressed > 0)?;
-
-    Ok((page, content))
+    let downloaded_at: Option<i64> = row.try_get("downloaded_at")?;
+    let http_status: Option<u16> = row.try_get("http_status")?;
+    let request: Option<String> = row.try_get("request")?;
+    let final_url: Option<String> = row.try_get("final_url")?;
+    page_from_tuple((
+        page_id,
+        url,
+        type_id,
+        depth,
+        status,
+        downloaded_at,
+        http_status,
+        request,
+        final_url,
+    ))
 }
 
 /// Creates pages from tuple of its attributes
@@ -210,22 +2167,230 @@ fn page_from_row(row: StdResult<SqliteRow, sqlx::Error>) -> Result<(Page, String
 /// - type_id - PageType
 /// - depth - u16
 /// - status - u8
-fn page_from_tuple(row: PageRow) -> Result<Page> {
-    let (id, url, type_id, depth, status) = row;
+/// - downloaded_at - Option<i64> (unix timestamp)
+/// - http_status - Option<u16>
+/// - request - Option<String> (JSON of [`RequestSpec`])
+/// - final_url - Option<String>
+pub(crate) fn page_from_tuple(row: PageRow) -> Result<Page> {
+    let (id, url, type_id, depth, status, downloaded_at, http_status, request, final_url) = row;
     let url = Url::parse(&url)?;
     let status = PageStatus::from_int(status)?;
+    let downloaded_at = downloaded_at.and_then(|ts| DateTime::from_timestamp(ts, 0));
+    let request = request.map(|r| serde_json::from_str(&r)).transpose()?;
+    let final_url = final_url.map(|u| Url::parse(&u)).transpose()?;
     Ok(Page {
         id,
         url,
         type_id,
         depth,
         status,
+        downloaded_at,
+        http_status,
+        request,
+        final_url,
     })
 }
 
+/// Creates response metadata from [`MetaRow`], `None` if page has no recorded response
+pub(crate) fn meta_from_tuple(row: MetaRow) -> Result<Option<ResponseMeta>> {
+    let (status, final_url, content_type, headers, redirects, truncated, ..) = row;
+    let (Some(status), Some(final_url)) = (status, final_url) else {
+        return Ok(None);
+    };
+    let timing = match (row.6, row.7, row.8) {
+        (Some(ttfb_ms), Some(total_ms), Some(body_bytes)) => Some(RequestTiming {
+            ttfb_ms,
+            total_ms,
+            body_bytes: body_bytes as u64,
+        }),
+        _ => None,
+    };
+    let headers = match headers {
+        Some(headers) => serde_json::from_str(&headers)?,
+        None => vec![],
+    };
+    let redirects = match redirects {
+        Some(redirects) => serde_json::from_str::<Vec<String>>(&redirects)?
+            .iter()
+            .map(|url| Url::parse(url))
+            .collect::<StdResult<_, _>>()?,
+        None => vec![],
+    };
+    Ok(Some(ResponseMeta {
+        status,
+        final_url: Url::parse(&final_url)?,
+        content_type,
+        headers,
+        redirects,
+        truncated,
+        timing,
+    }))
+}
+
+/// JSON array of redirect URLs stored in `redirects` column, `None` if there were no redirects
+pub(crate) fn redirects_json(meta: &ResponseMeta) -> Result<Option<String>> {
+    if meta.redirects.is_empty() {
+        return Ok(None);
+    }
+    let redirects = meta.redirects.iter().map(Url::as_str).collect::<Vec<_>>();
+    Ok(Some(serde_json::to_string(&redirects)?))
+}
+
+/// Hex-encoded SHA-256 of the page content used to detect content changes
+/// Page content prepared for writing in storage
+///
+/// Hashing and compression of large pages are CPU bound, so the crawler does it on parser threads
+/// instead of the async executor (see [`Storage::write_encoded_content()`]).
+pub struct EncodedContent {
+    pub(crate) hash: String,
+    pub(crate) compressed: Vec<u8>,
+    pub(crate) simhash: Option<u64>,
+}
+
+impl EncodedContent {
+    pub fn new(content: &str) -> Result<Self> {
+        Ok(Self {
+            hash: content_hash(content),
+            compressed: compress(content.as_bytes(), 3)?,
+            simhash: None,
+        })
+    }
+
+    /// Calculates [`dedup::simhash()`] of the content, so near-duplicate pages can be found
+    pub fn with_simhash(self, content: &str) -> Result<Self> {
+        let simhash = dedup::simhash(content)?;
+        Ok(Self { simhash, ..self })
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn simhash(&self) -> Option<u64> {
+        self.simhash
+    }
+}
+
+fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+/// Runs a write repeating it with exponential backoff (and jitter) if the database is busy
+///
+/// SQLite waits for a lock held by another connection only up to the busy timeout and doesn't wait
+/// at all if waiting could deadlock (a transaction which started reading tries to write), so CLI
+/// commands writing to the database of an active crawl fail on transient lock contention otherwise.
+async fn retry_busy<T, F, Fut>(mut write: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Err(e) if attempt < BUSY_RETRIES && is_busy(&e) => {
+                let jitter = rand::thread_rng().gen_range(0..BUSY_RETRY_DELAY.as_millis() as u64);
+                let delay = BUSY_RETRY_DELAY * 2u32.pow(attempt) + Duration::from_millis(jitter);
+                attempt += 1;
+                debug!("Database is busy, retrying in {:?}", delay);
+                sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns `true` if error is caused by `SQLITE_BUSY` or `SQLITE_LOCKED` (including extended codes)
+fn is_busy(error: &anyhow::Error) -> bool {
+    const SQLITE_BUSY: i32 = 5;
+    const SQLITE_LOCKED: i32 = 6;
+    error
+        .chain()
+        .filter_map(|e| match e.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::Database(e)) => e.code()?.parse::<i32>().ok(),
+            _ => None,
+        })
+        .any(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+}
+
+/// Hash of a page URL used for fast lookups (see [`Storage::find_page_by_url()`])
+///
+/// First 8 bytes of SHA-256, so the index is much smaller than the one on a full URL text.
+pub(crate) fn url_hash(url: &str) -> i64 {
+    let digest = Sha256::digest(url.as_bytes());
+    i64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
 pub fn migrate(path: impl AsRef<Path>) -> Result<()> {
-    let database_path = path.as_ref().to_string_lossy();
-    let mut config = Config::new(ConfigDbType::Sqlite).set_db_path(database_path.as_ref());
-    migrations::runner().run(&mut config)?;
+    migrate_with_progress(path, |_, _| {})
+}
+
+/// Applies pending migrations one by one
+///
+/// `on_applied` is called after each migration with the number of migrations applied so far and
+/// the total number of pending migrations.
+pub fn migrate_with_progress(
+    path: impl AsRef<Path>,
+    mut on_applied: impl FnMut(usize, usize),
+) -> Result<()> {
+    let mut connection = rusqlite::Connection::open(path)?;
+    let pending = pending_versions(&connection)?;
+    for (i, version) in pending.iter().enumerate() {
+        migrations::runner()
+            .set_target(Target::Version(*version))
+            .run(&mut connection)?;
+        on_applied(i + 1, pending.len());
+    }
+    backfill_url_hashes(&mut connection)?;
+    // Keeping statistics up to date so query planner picks up indices on large tables
+    connection.execute_batch("ANALYZE")?;
+    Ok(())
+}
+
+/// Returns number of migrations not yet applied to the database
+pub fn pending_migrations(path: impl AsRef<Path>) -> Result<usize> {
+    let connection =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok(pending_versions(&connection)?.len())
+}
+
+/// Versions of migrations not yet applied in ascending order
+fn pending_versions(connection: &rusqlite::Connection) -> Result<Vec<u32>> {
+    let has_history: bool = connection.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'refinery_schema_history')",
+        [],
+        |row| row.get(0),
+    )?;
+    let applied = if has_history {
+        let mut statement = connection.prepare("SELECT version FROM refinery_schema_history")?;
+        let versions = statement.query_map([], |row| row.get(0))?;
+        versions.collect::<StdResult<HashSet<u32>, _>>()?
+    } else {
+        HashSet::new()
+    };
+    let mut pending = migrations::runner()
+        .get_migrations()
+        .iter()
+        .map(|m| m.version())
+        .filter(|version| !applied.contains(version))
+        .collect::<Vec<_>>();
+    pending.sort();
+    Ok(pending)
+}
+
+/// Calculates [`url_hash()`] for pages registered before the column was introduced
+fn backfill_url_hashes(connection: &mut rusqlite::Connection) -> Result<()> {
+    let tx = connection.transaction()?;
+    {
+        let mut select = tx.prepare("SELECT id, url FROM pages WHERE url_hash IS NULL")?;
+        let mut update = tx.prepare("UPDATE pages SET url_hash = ? WHERE id = ?")?;
+        let mut rows = select.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let url: String = row.get(1)?;
+            update.execute(rusqlite::params![url_hash(&url), id])?;
+        }
+    }
+    tx.commit()?;
     Ok(())
 }
@@ -1,15 +1,20 @@
-use crab::{crawler::CrawlerState, prelude::*, CrawlerReport, Shared};
+use crab::{
+    crawler::{CrawlerCommand, CrawlerState},
+    prelude::*,
+    CrawlerReports,
+};
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    cursor::Show,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use std::{
     fmt::Display,
     io,
-    sync::atomic::Ordering,
     time::{Duration, Instant},
 };
+use tokio::sync::mpsc::UnboundedSender;
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout},
@@ -25,7 +30,11 @@ enum MainPanelMode {
     Proxies,
 }
 
-pub(crate) fn ui(state: Shared<CrawlerReport>, tick_rate: Duration) -> Result<()> {
+pub(crate) fn ui(
+    reports: CrawlerReports,
+    commands: UnboundedSender<CrawlerCommand>,
+    tick_rate: Duration,
+) -> Result<()> {
     let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
 
@@ -37,40 +46,42 @@ pub(crate) fn ui(state: Shared<CrawlerReport>, tick_rate: Duration) -> Result<()
         EnableMouseCapture
     )?;
 
-    let res = run_terminal(&mut terminal, state, tick_rate);
+    let res = run_terminal(&mut terminal, reports, commands, tick_rate);
 
-    // restore terminal
+    restore()?;
+    res?;
+    Ok(())
+}
+
+/// Returns terminal to the normal mode
+///
+/// Can be called from any thread, eg. when process is about to exit because of a signal.
+pub(crate) fn restore() -> io::Result<()> {
     disable_raw_mode()?;
     execute!(
-        terminal.backend_mut(),
+        io::stdout(),
         LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
-
-    res?;
-    Ok(())
+        DisableMouseCapture,
+        Show
+    )
 }
 
 fn run_terminal<B: Backend>(
     terminal: &mut Terminal<B>,
-    state: Shared<CrawlerReport>,
+    mut reports: CrawlerReports,
+    commands: UnboundedSender<CrawlerCommand>,
     tick_duration: Duration,
 ) -> io::Result<()> {
     let mut last_tick = Instant::now();
-    let mut current_state = None;
     let mut main_panel_mode = MainPanelMode::InFlightRequests;
+    let mut shutdown_requested = false;
     loop {
-        current_state = state.take(Ordering::Relaxed).or(current_state);
-
-        if let Some(report) = &current_state {
-            match report.as_ref() {
-                CrawlerReport::Report(report) => {
-                    terminal.draw(|f| draw_widgets(f, report, main_panel_mode))?;
-                }
-                CrawlerReport::Finished => return Ok(()),
-            }
+        // Channel is closed when crawler is finished
+        if reports.has_changed().is_err() {
+            return Ok(());
         }
+        let report = reports.borrow_and_update().clone();
+        terminal.draw(|f| draw_widgets(f, &report, main_panel_mode))?;
 
         let timeout = tick_duration
             .checked_sub(last_tick.elapsed())
@@ -80,6 +91,17 @@ fn run_terminal<B: Backend>(
                 match key.code {
                     KeyCode::Char('p') => main_panel_mode = MainPanelMode::Proxies,
                     KeyCode::Char('r') => main_panel_mode = MainPanelMode::InFlightRequests,
+                    KeyCode::Char('s') => {
+                        // Crawler might be already finished, nothing to do in this case
+                        let _ = commands.send(CrawlerCommand::Snapshot);
+                    }
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        // Second Ctrl+C exits without waiting for requests in flight
+                        if shutdown_requested || commands.send(CrawlerCommand::Shutdown).is_err() {
+                            return Ok(());
+                        }
+                        shutdown_requested = true;
+                    }
                     KeyCode::Char('q') => return Ok(()),
                     _ => {}
                 }
@@ -104,12 +126,53 @@ fn draw_widgets(f: &mut Frame<impl Backend>, state: &CrawlerState, main_panel_mo
         ),
         metric("Number of successfull requests", state.successfull_requests),
         metric("Number of new links found", state.new_links_found),
+        metric("Number of filtered links", state.filtered_links),
+        metric("Number of failed pages", state.failed_pages),
+        metric(
+            "Number of rate-limited requests",
+            state.rate_limited_requests,
+        ),
+        metric("Number of duplicate pages", state.duplicate_pages),
+        metric(
+            "Number of duplicate content pages",
+            state.duplicate_content_pages,
+        ),
+        metric("Number of expired pages", state.expired_pages),
+        metric("Number of changed pages", state.changed_pages),
+        metric("Number of not modified pages", state.not_modified_pages),
+        metric("Number of truncated pages", state.truncated_pages),
+        metric("Number of retired sessions", state.retired_sessions),
+        metric("Number of challenged requests", state.challenged_requests),
+        metric("Number of solved challenges", state.solved_challenges),
+        metric(
+            "Average response time",
+            format_duration(state.timings.average_total()),
+        ),
+        metric(
+            "Downloaded",
+            format!(
+                "{:.1} MiB",
+                state.timings.body_bytes as f64 / (1 << 20) as f64
+            ),
+        ),
+        metric(
+            "Last snapshot",
+            state
+                .last_snapshot
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|| "-".into()),
+        ),
     ])
-    .block(create_block("Metrics"));
+    .block(create_block(if state.shutting_down {
+        "Metrics (shutting down, Ctrl+C to exit now)"
+    } else {
+        "Metrics"
+    }));
 
     let layout = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Max(6), Constraint::Percentage(50)].as_ref())
+        .constraints([Constraint::Max(21), Constraint::Percentage(50)].as_ref())
         .margin(1)
         .split(f.size());
     let metrics_panel = layout[0];
@@ -136,16 +199,18 @@ fn draw_widgets(f: &mut Frame<impl Backend>, state: &CrawlerState, main_panel_mo
                     Row::new(vec![
                         format!("{:>5}", stat.requests.to_string()),
                         format!("{:>5}", stat.successfull_requests.to_string()),
+                        format!("{:>7}", format_duration(stat.timings.average_total())),
                         format!("{:?}", proxy),
                     ])
                 })
                 .collect::<Vec<_>>();
 
-            let header = Row::new(vec!["Requests", "Successfull", "Proxy"])
+            let header = Row::new(vec!["Requests", "Successfull", "Avg time", "Proxy"])
                 .style(Style::default().fg(Color::Yellow));
             let table = Table::new(proxies).header(header).widths(&[
                 Constraint::Length(5),
                 Constraint::Length(5),
+                Constraint::Length(7),
                 Constraint::Percentage(80),
             ]);
             f.render_widget(table, main_panel);
@@ -153,6 +218,13 @@ fn draw_widgets(f: &mut Frame<impl Backend>, state: &CrawlerState, main_panel_mo
     };
 }
 
-fn create_block(title: &str) -> Block {
+fn format_duration(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => format!("{} ms", duration.as_millis()),
+        None => "-".into(),
+    }
+}
+
+fn create_block(title: &str) -> Block<'_> {
     Block::default().borders(Borders::ALL).title(title)
 }
@@ -0,0 +1,452 @@
+//! Adaptive per-host delay for hosts responding with `429 Too Many Requests` or
+//! `503 Service Unavailable`
+//!
+//! ```toml
+//! [crawler]
+//! max_throttle_sec = 300
+//! ```
+//!
+//! Each rate-limiting response doubles the delay between requests to the host (starting from 1
+//! second, up to `max_throttle_sec`) and `Retry-After` header is honored if present. Each
+//! successful response halves the delay, so it decays back once the host recovers. Throttling
+//! state is persisted in the database, so restarted crawler doesn't hammer hosts which were
+//! rate-limiting it minutes ago.
+//!
+//! Number of requests in flight to a single host can be limited as well, requests to other hosts
+//! are made in the meantime:
+//!
+//! ```toml
+//! [crawler]
+//! max_concurrent_per_domain = 2
+//! ```
+//!
+//! Crawl-wide bandwidth is limited by pacing requests according to the size of downloaded
+//! responses (see [`Bandwidth`]):
+//!
+//! ```toml
+//! [crawler]
+//! max_bytes_per_sec = 500_000
+//! ```
+use crate::{
+    prelude::*,
+    storage::{HostBackoff, ResponseMeta, Storage},
+    CrawlerConfig,
+};
+use chrono::{DateTime, Utc};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// Delay given to the host after the first rate-limiting response
+const MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between requests to the host if `max_throttle_sec` is not set
+const DEFAULT_MAX_DELAY_SEC: f32 = 300.;
+
+struct HostThrottle {
+    /// current delay between requests to the host
+    delay: Duration,
+    /// time the next request to the host is allowed at
+    next_request_at: Instant,
+    /// time of the last rate-limiting response
+    rate_limited_at: Instant,
+}
+
+pub struct Throttle {
+    max_delay: Duration,
+    /// host → throttling state, only hosts which responded with rate-limiting status are here
+    hosts: HashMap<String, HostThrottle>,
+    /// maximum number of requests in flight to a single host
+    max_in_flight: Option<usize>,
+    /// host → number of requests in flight to the host
+    in_flight: HashMap<String, usize>,
+}
+
+impl Throttle {
+    pub fn new(opts: &CrawlerConfig) -> Self {
+        let max_delay = opts.max_throttle_sec.unwrap_or(DEFAULT_MAX_DELAY_SEC);
+        Self {
+            max_delay: Duration::from_secs_f32(max_delay.max(0.)),
+            hosts: HashMap::new(),
+            max_in_flight: opts.max_concurrent_per_domain,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Creates throttle from config restoring throttling state of hosts persisted by previous runs
+    pub async fn load(opts: &CrawlerConfig, storage: &dyn Storage) -> Result<Self> {
+        let mut throttle = Self::new(opts);
+        let (now, now_utc) = (Instant::now(), Utc::now());
+        for backoff in storage.list_host_backoffs().await? {
+            throttle.restore(backoff, now, now_utc);
+        }
+        Ok(throttle)
+    }
+
+    fn restore(&mut self, backoff: HostBackoff, now: Instant, now_utc: DateTime<Utc>) {
+        let now_ms = now_utc.timestamp_millis();
+        let millis = |ms: i64| Duration::from_millis(ms.max(0) as u64);
+        let host = HostThrottle {
+            delay: millis(backoff.delay_ms).min(self.max_delay),
+            next_request_at: now + millis(backoff.retry_at - now_ms),
+            rate_limited_at: now
+                .checked_sub(millis(now_ms - backoff.rate_limited_at))
+                .unwrap_or(now),
+        };
+        self.hosts.insert(backoff.host, host);
+    }
+
+    /// Throttling state of a host to be persisted, `None` if the host is not throttled
+    pub fn backoff(&self, host: &str, now: Instant, now_utc: DateTime<Utc>) -> Option<HostBackoff> {
+        let state = self.hosts.get(host)?;
+        let now_ms = now_utc.timestamp_millis();
+        let since_rate_limited = now.saturating_duration_since(state.rate_limited_at);
+        let until_retry = state.next_request_at.saturating_duration_since(now);
+        Some(HostBackoff {
+            host: host.to_string(),
+            delay_ms: state.delay.as_millis() as i64,
+            rate_limited_at: now_ms - since_rate_limited.as_millis() as i64,
+            retry_at: now_ms + until_retry.as_millis() as i64,
+        })
+    }
+
+    /// Returns `true` if no more requests to the URL host can be made until one in flight is completed
+    pub fn is_saturated(&self, url: &Url) -> bool {
+        let (Some(max), Some(host)) = (self.max_in_flight, url.host_str()) else {
+            return false;
+        };
+        self.in_flight.get(host).is_some_and(|n| *n >= max.max(1))
+    }
+
+    /// Time left until a request to the URL host is allowed, `None` if request can be made now
+    pub fn wait_time(&self, url: &Url, now: Instant) -> Option<Duration> {
+        let host = self.hosts.get(url.host_str()?)?;
+        (host.next_request_at > now).then(|| host.next_request_at - now)
+    }
+
+    /// Registers a request to the URL host, so the next one is made not earlier than the host delay
+    pub fn dispatched(&mut self, url: &Url, now: Instant) {
+        let Some(name) = url.host_str() else {
+            return;
+        };
+        if let Some(host) = self.hosts.get_mut(name) {
+            host.next_request_at = host.next_request_at.max(now + host.delay);
+        }
+        if self.max_in_flight.is_some() {
+            *self.in_flight.entry(name.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Registers completion of a request to the URL host made after [`Self::dispatched`]
+    pub fn completed(&mut self, url: &Url) {
+        let Some(name) = url.host_str() else {
+            return;
+        };
+        if let Some(count) = self.in_flight.get_mut(name) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(name);
+            }
+        }
+    }
+
+    /// Increases the delay of the URL host, returns time the page should wait for before retrying
+    pub fn rate_limited(
+        &mut self,
+        url: &Url,
+        retry_after: Option<Duration>,
+        now: Instant,
+    ) -> Duration {
+        let Some(host) = url.host_str() else {
+            return retry_after.unwrap_or(MIN_DELAY);
+        };
+        let host = self
+            .hosts
+            .entry(host.to_string())
+            .or_insert_with(|| HostThrottle {
+                delay: Duration::ZERO,
+                next_request_at: now,
+                rate_limited_at: now,
+            });
+        host.rate_limited_at = now;
+        host.delay = host
+            .delay
+            .saturating_mul(2)
+            .min(self.max_delay)
+            // max delay below a second doesn't make a host go without a delay at all
+            .max(MIN_DELAY);
+        let wait = retry_after.map_or(host.delay, |retry_after| retry_after.max(host.delay));
+        host.next_request_at = host.next_request_at.max(now + wait);
+        wait
+    }
+
+    /// Decays the delay of the URL host after successful response
+    ///
+    /// Returns `true` if the host was throttled, so its state is changed.
+    pub fn succeeded(&mut self, url: &Url) -> bool {
+        let Some(name) = url.host_str() else {
+            return false;
+        };
+        let Some(host) = self.hosts.get_mut(name) else {
+            return false;
+        };
+        host.delay /= 2;
+        if host.delay < MIN_DELAY {
+            self.hosts.remove(name);
+        }
+        true
+    }
+}
+
+/// Crawl-wide limit of downloaded bytes per second
+///
+/// Size of a response is known only after it is downloaded, so each downloaded response postpones
+/// dispatching of the next requests by the time its transfer should have taken at the allowed rate.
+/// Idle time is not accumulated, so there are no bursts after a pause.
+pub struct Bandwidth {
+    bytes_per_sec: Option<u64>,
+    /// time the next request is allowed to be dispatched at
+    next_dispatch_at: Instant,
+}
+
+impl Bandwidth {
+    pub fn new(opts: &CrawlerConfig) -> Self {
+        Self {
+            bytes_per_sec: opts.max_bytes_per_sec.filter(|&rate| rate > 0),
+            next_dispatch_at: Instant::now(),
+        }
+    }
+
+    /// Called when a response of a given size is downloaded
+    pub fn transferred(&mut self, bytes: u64, now: Instant) {
+        if let Some(rate) = self.bytes_per_sec {
+            let transfer_time = Duration::from_secs_f64(bytes as f64 / rate as f64);
+            self.next_dispatch_at = self.next_dispatch_at.max(now) + transfer_time;
+        }
+    }
+
+    /// Returns how long to wait before the next request can be dispatched
+    pub fn wait_time(&self, now: Instant) -> Option<Duration> {
+        let wait = self.next_dispatch_at.saturating_duration_since(now);
+        (!wait.is_zero()).then_some(wait)
+    }
+}
+
+/// Returns `true` if server asks to slow down
+pub fn is_rate_limited(meta: &ResponseMeta) -> bool {
+    meta.status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+        || meta.status == StatusCode::SERVICE_UNAVAILABLE.as_u16()
+}
+
+/// Parses `Retry-After` header given either in seconds or as an HTTP date
+pub fn retry_after(meta: &ResponseMeta, now: DateTime<Utc>) -> Option<Duration> {
+    let value = meta.header(RETRY_AFTER.as_str())?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(max_delay_sec: u64) -> Throttle {
+        Throttle {
+            max_delay: Duration::from_secs(max_delay_sec),
+            hosts: HashMap::new(),
+            max_in_flight: None,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    fn response(status: u16, retry_after: Option<&str>) -> Result<ResponseMeta> {
+        Ok(ResponseMeta {
+            status,
+            final_url: Url::parse("http://test.com")?,
+            content_type: None,
+            headers: retry_after
+                .map(|value| ("Retry-After".to_string(), value.to_string()))
+                .into_iter()
+                .collect(),
+            redirects: vec![],
+            truncated: false,
+            timing: None,
+        })
+    }
+
+    #[test]
+    fn delay_grows_and_decays() -> Result<()> {
+        let mut throttle = throttle(3);
+        let url = Url::parse("http://test.com/page")?;
+        let other = Url::parse("http://other.com/page")?;
+        let now = Instant::now();
+
+        assert_eq!(
+            throttle.rate_limited(&url, None, now),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            throttle.rate_limited(&url, None, now),
+            Duration::from_secs(2)
+        );
+        // limited by max delay
+        assert_eq!(
+            throttle.rate_limited(&url, None, now),
+            Duration::from_secs(3)
+        );
+        assert_eq!(throttle.wait_time(&url, now), Some(Duration::from_secs(3)));
+        assert_eq!(throttle.wait_time(&other, now), None);
+
+        let later = now + Duration::from_secs(3);
+        assert_eq!(throttle.wait_time(&url, later), None);
+        throttle.dispatched(&url, later);
+        assert_eq!(
+            throttle.wait_time(&url, later),
+            Some(Duration::from_secs(3))
+        );
+
+        throttle.succeeded(&url);
+        throttle.dispatched(&url, later + Duration::from_secs(3));
+        let wait = throttle.wait_time(&url, later + Duration::from_secs(3));
+        assert_eq!(wait, Some(Duration::from_millis(1500)));
+
+        throttle.succeeded(&url);
+        assert!(throttle.hosts.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn delay_is_at_least_a_second() -> Result<()> {
+        let mut throttle = throttle(0);
+        let url = Url::parse("http://test.com/page")?;
+        let now = Instant::now();
+
+        assert_eq!(
+            throttle.rate_limited(&url, None, now),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            throttle.rate_limited(&url, None, now),
+            Duration::from_secs(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retry_after_is_honored() -> Result<()> {
+        let mut throttle = throttle(300);
+        let url = Url::parse("http://test.com/page")?;
+        let now = Instant::now();
+        let wait = throttle.rate_limited(&url, Some(Duration::from_secs(120)), now);
+        assert_eq!(wait, Duration::from_secs(120));
+        assert_eq!(
+            throttle.wait_time(&url, now),
+            Some(Duration::from_secs(120))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_is_restored() -> Result<()> {
+        let mut throttle = throttle(300);
+        let url = Url::parse("http://test.com/page")?;
+        let (now, now_utc) = (Instant::now(), Utc::now());
+        throttle.rate_limited(&url, Some(Duration::from_secs(60)), now);
+        assert_eq!(throttle.backoff("other.com", now, now_utc), None);
+        let backoff = throttle.backoff("test.com", now, now_utc).unwrap();
+        assert_eq!(backoff.delay_ms, 1000);
+        assert_eq!(backoff.rate_limited_at, now_utc.timestamp_millis());
+        assert_eq!(backoff.retry_at, now_utc.timestamp_millis() + 60_000);
+
+        // crawler is restarted 20 seconds later
+        let mut restored = self::throttle(300);
+        let later = Instant::now();
+        restored.restore(
+            backoff.clone(),
+            later,
+            now_utc + chrono::Duration::seconds(20),
+        );
+        let wait = restored.wait_time(&url, later);
+        assert_eq!(wait, Some(Duration::from_secs(40)));
+        let restored = restored.backoff("test.com", later, now_utc + chrono::Duration::seconds(20));
+        assert_eq!(restored, Some(backoff));
+        Ok(())
+    }
+
+    #[test]
+    fn requests_in_flight_are_limited() -> Result<()> {
+        let mut throttle = throttle(300);
+        throttle.max_in_flight = Some(2);
+        let url = Url::parse("http://test.com/page")?;
+        let other = Url::parse("http://other.com/page")?;
+        let now = Instant::now();
+
+        throttle.dispatched(&url, now);
+        assert!(!throttle.is_saturated(&url));
+        throttle.dispatched(&url, now);
+        assert!(throttle.is_saturated(&url));
+        assert!(!throttle.is_saturated(&other));
+
+        throttle.completed(&url);
+        assert!(!throttle.is_saturated(&url));
+        throttle.completed(&url);
+        assert!(throttle.in_flight.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_retry_after() -> Result<()> {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")?.into();
+        let meta = response(429, Some("120"))?;
+        assert_eq!(retry_after(&meta, now), Some(Duration::from_secs(120)));
+
+        let meta = response(503, Some("Wed, 21 Oct 2015 07:30:00 GMT"))?;
+        assert_eq!(retry_after(&meta, now), Some(Duration::from_secs(120)));
+
+        let meta = response(503, Some("Wed, 21 Oct 2015 07:00:00 GMT"))?;
+        assert_eq!(retry_after(&meta, now), Some(Duration::ZERO));
+
+        assert_eq!(retry_after(&response(429, Some("soon"))?, now), None);
+        assert_eq!(retry_after(&response(429, None)?, now), None);
+        Ok(())
+    }
+
+    #[test]
+    fn rate_limiting_statuses() -> Result<()> {
+        assert!(is_rate_limited(&response(429, None)?));
+        assert!(is_rate_limited(&response(503, None)?));
+        assert!(!is_rate_limited(&response(500, None)?));
+        assert!(!is_rate_limited(&response(200, None)?));
+        Ok(())
+    }
+
+    #[test]
+    fn dispatching_is_paced_by_transferred_bytes() {
+        let now = Instant::now();
+        let mut bandwidth = Bandwidth {
+            bytes_per_sec: Some(1000),
+            next_dispatch_at: now,
+        };
+        assert_eq!(bandwidth.wait_time(now), None);
+
+        bandwidth.transferred(500, now);
+        bandwidth.transferred(1500, now);
+        assert_eq!(bandwidth.wait_time(now), Some(Duration::from_secs(2)));
+        let later = now + Duration::from_secs(2);
+        assert_eq!(bandwidth.wait_time(later), None);
+
+        // Idle time is not accumulated
+        let idle = now + Duration::from_secs(10);
+        bandwidth.transferred(1000, idle);
+        assert_eq!(bandwidth.wait_time(idle), Some(Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,274 @@
+//! Log of crawler scheduling decisions and their replay
+//!
+//! When `crab run-crawler --trace <file>` is used, each scheduling decision (pages listed from
+//! the database, page and proxy chosen, postponed pages, pauses and request outcomes) is written
+//! to a file as a JSON line. RNG of the crawler is seeded (`--seed`, random by default) and the
+//! seed is written to the trace as well.
+//!
+//! `crab replay <file>` replays proxy choices of the recorded run using the same seed and
+//! request outcomes and reports the first decision which can not be reproduced.
+use crate::{prelude::*, proxy::Proxies};
+use reqwest::Proxy;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Crawler started
+    Start {
+        seed: u64,
+        /// number of proxies in the proxy list
+        proxies: usize,
+        threads: usize,
+    },
+    /// Pages listed from the database or taken from pages waiting for retry
+    Refill { pages: Vec<i64> },
+    /// Request to a page is made
+    Dispatch {
+        page_id: i64,
+        url: String,
+        proxy: Option<usize>,
+        delay_ms: u64,
+    },
+    /// Page is put aside for a given time
+    Postpone {
+        page_id: i64,
+        reason: PostponeReason,
+        wait_ms: u64,
+    },
+    /// Request to a page is completed
+    Complete {
+        page_id: i64,
+        proxy: Option<usize>,
+        success: bool,
+    },
+    /// Crawling is paused because it's outside of allowed hours
+    Pause { wait_ms: u64 },
+    /// Shutdown is requested
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PostponeReason {
+    Retry,
+    Quota,
+    Throttle,
+    Challenge,
+}
+
+/// Single line of the trace
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Record {
+    /// milliseconds since the crawler start
+    pub at_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Writes trace records, does nothing if no trace file is given
+pub struct Tracer {
+    out: Option<BufWriter<File>>,
+    started: Instant,
+}
+
+impl Tracer {
+    pub fn new(path: Option<&Path>) -> Result<Self> {
+        let out = path.map(File::create).transpose()?.map(BufWriter::new);
+        Ok(Self {
+            out,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn log(&mut self, event: Event) -> Result<()> {
+        let Some(out) = &mut self.out else {
+            return Ok(());
+        };
+        let record = Record {
+            at_ms: millis(self.started.elapsed()),
+            event,
+        };
+        serde_json::to_writer(&mut *out, &record)?;
+        writeln!(out)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(out) = &mut self.out {
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Outcome of a trace replay
+#[derive(Debug, PartialEq)]
+pub enum Replay {
+    /// All proxy choices are reproduced, the number of replayed records is given
+    Reproduced(usize),
+    /// Proxy choice at a given line of the trace differs from the recorded one
+    Diverged {
+        line: usize,
+        recorded: Option<usize>,
+        replayed: Option<usize>,
+    },
+}
+
+/// Replays proxy choices of the recorded run, each record is passed to `on_record` as it is read
+pub fn replay(trace: impl BufRead, mut on_record: impl FnMut(&Record)) -> Result<Replay> {
+    let mut proxies = None;
+    let mut records = 0;
+    for (idx, line) in trace.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record =
+            serde_json::from_str(&line).map_err(|_| AppError::InvalidTraceRecord(idx + 1))?;
+        on_record(&record);
+        records += 1;
+        match record.event {
+            Event::Start {
+                seed,
+                proxies: count,
+                ..
+            } => {
+                // Only the number of proxies affects the choice, so placeholders are used
+                let list = (0..count)
+                    .map(|port| Proxy::all(format!("http://127.0.0.1:{port}")))
+                    .collect::<StdResult<Vec<_>, _>>()?;
+                proxies = Some(Proxies::new(list, seed));
+            }
+            Event::Dispatch { proxy, .. } => {
+                let proxies = proxies
+                    .as_mut()
+                    .ok_or(AppError::InvalidTraceRecord(idx + 1))?;
+                // Pages rendered in a browser are fetched without a proxy
+                let replayed = match (proxy, proxies.len()) {
+                    (None, _) | (_, 0) => None,
+                    _ => proxies.next().map(|(_, id)| id),
+                };
+                if replayed != proxy {
+                    return Ok(Replay::Diverged {
+                        line: idx + 1,
+                        recorded: proxy,
+                        replayed,
+                    });
+                }
+            }
+            Event::Complete {
+                proxy: Some(proxy),
+                success,
+                ..
+            } => {
+                let proxies = proxies
+                    .as_mut()
+                    .ok_or(AppError::InvalidTraceRecord(idx + 1))?;
+                if success {
+                    proxies.proxy_succeseed(proxy);
+                } else {
+                    proxies.proxy_failed(proxy);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Replay::Reproduced(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(event: Event) -> String {
+        serde_json::to_string(&Record { at_ms: 0, event }).unwrap()
+    }
+
+    /// Records a run of 20 requests through 3 proxies with every third request failed
+    fn recorded_run(seed: u64) -> Vec<String> {
+        let list = (0..3)
+            .map(|port| Proxy::all(format!("http://127.0.0.1:{port}")).unwrap())
+            .collect();
+        let mut proxies = Proxies::new(list, seed);
+        let mut lines = vec![record(Event::Start {
+            seed,
+            proxies: 3,
+            threads: 1,
+        })];
+        for page_id in 0..20 {
+            let proxy = proxies.next().map(|(_, id)| id);
+            let success = page_id % 3 != 0;
+            lines.push(record(Event::Dispatch {
+                page_id,
+                url: format!("http://test.com/{page_id}"),
+                proxy,
+                delay_ms: 0,
+            }));
+            if success {
+                proxies.proxy_succeseed(proxy.unwrap());
+            } else {
+                proxies.proxy_failed(proxy.unwrap());
+            }
+            lines.push(record(Event::Complete {
+                page_id,
+                proxy,
+                success,
+            }));
+        }
+        lines
+    }
+
+    #[test]
+    fn proxy_choices_are_reproduced() -> Result<()> {
+        let trace = recorded_run(7).join("\n");
+        let mut records = vec![];
+        let result = replay(trace.as_bytes(), |r| records.push(r.event.clone()))?;
+        assert_eq!(result, Replay::Reproduced(41));
+        assert_eq!(records.len(), 41);
+        Ok(())
+    }
+
+    #[test]
+    fn divergence_is_reported() -> Result<()> {
+        let mut lines = recorded_run(7);
+        let Ok(Record {
+            event:
+                Event::Dispatch {
+                    proxy,
+                    page_id,
+                    url,
+                    delay_ms,
+                },
+            ..
+        }) = serde_json::from_str(&lines[1])
+        else {
+            panic!("dispatch record expected");
+        };
+        let tampered = proxy.map(|id| (id + 1) % 3);
+        lines[1] = record(Event::Dispatch {
+            page_id,
+            url,
+            proxy: tampered,
+            delay_ms,
+        });
+        let result = replay(lines.join("\n").as_bytes(), |_| {})?;
+        let expected = Replay::Diverged {
+            line: 2,
+            recorded: tampered,
+            replayed: proxy,
+        };
+        assert_eq!(result, expected);
+        Ok(())
+    }
+}
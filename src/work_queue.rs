@@ -0,0 +1,579 @@
+//! Distributing downloads across crawler workers running on other machines
+//!
+//! ```console
+//! $ CRAB_QUEUE_TOKEN=secret crab run-crawler --listen-workers 0.0.0.0:7878
+//! $ CRAB_QUEUE_TOKEN=secret crab worker http://crawler-host:7878 --threads 10
+//! ```
+//!
+//! Crawler keeps the frontier, the database and parsers: pages are scheduled as usual (quotas,
+//! throttling, retries), but requests are put in the queue instead of being sent. Workers
+//! long-poll the queue over HTTP, send requests from their own network and post responses back,
+//! so each page is downloaded by exactly one worker and all the results end up in the crawler
+//! database. Proxies are not used by workers and OAuth 2.0 tokens are not refreshed on
+//! `401 Unauthorized`.
+//!
+//! Protocol is two JSON endpoints: `POST /jobs/next` returns the next request (`204 No Content`
+//! if there are no requests for 30 seconds) and `POST /jobs/<id>` takes the response. Jobs carry
+//! authentication headers and cookies, so queue listening on a non-loopback address requires
+//! `CRAB_QUEUE_TOKEN` environment variable to be set, it's required from workers as a bearer token.
+//! Requests no worker takes in 2 minutes fail.
+use crate::{
+    auth::AuthRules,
+    crawler::{read_response, redirecting_client, user_agent, BodyLimits},
+    prelude::*,
+    redirect::{RedirectConfig, RedirectPolicy},
+    storage::{RequestTiming, ResponseMeta},
+    CrawlerConfig, UrlFilterConfig,
+};
+use futures::future::try_join_all;
+use hyper::{
+    header::AUTHORIZATION,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use reqwest::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, FROM, USER_AGENT},
+    Client, RequestBuilder,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    env,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    sync::{oneshot, Notify},
+    time::{sleep, timeout},
+};
+use url::Url;
+
+/// Environment variable with a token workers are authenticated with
+pub const TOKEN_ENV: &str = "CRAB_QUEUE_TOKEN";
+
+/// Time a worker request for the next job is held for if there are no jobs
+const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a request waits in the queue for a worker to take it
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Time given to a worker to respond on top of request timeouts
+const RESPONSE_MARGIN: Duration = Duration::from_secs(30);
+
+/// Delay before a worker tries to reach the queue again after error
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Request sent to a worker
+#[derive(Debug, Serialize, Deserialize)]
+struct Job {
+    id: u64,
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+    connect_timeout_ms: u64,
+    read_timeout_ms: u64,
+    limits: BodyLimits,
+    #[serde(default)]
+    redirects: Option<RedirectConfig>,
+    #[serde(default)]
+    url_filters: Option<UrlFilterConfig>,
+}
+
+/// Response posted back by a worker, error message if request failed
+#[derive(Debug, Serialize, Deserialize)]
+struct JobResult(StdResult<FetchedPage, String>);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FetchedPage {
+    status: u16,
+    final_url: String,
+    headers: Vec<(String, String)>,
+    redirects: Vec<String>,
+    content: String,
+    truncated: bool,
+    timing: Option<RequestTiming>,
+}
+
+/// Job waiting to be completed by a worker
+#[derive(Debug)]
+struct Pending {
+    /// signalled when a worker takes the job
+    taken: Option<oneshot::Sender<()>>,
+    result: oneshot::Sender<JobResult>,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    next_id: u64,
+    jobs: VecDeque<Job>,
+    pending: HashMap<u64, Pending>,
+}
+
+#[derive(Debug)]
+pub struct WorkQueue {
+    state: Mutex<QueueState>,
+    /// notified when a new job is queued
+    queued: Notify,
+    /// headers crawler client sends with all requests (`User-Agent`, `From`)
+    headers: Vec<(String, String)>,
+    /// time a request waits for a worker to take it
+    queue_timeout: Duration,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    limits: BodyLimits,
+    redirects: Option<RedirectConfig>,
+    url_filters: Option<UrlFilterConfig>,
+    token: Option<String>,
+}
+
+impl WorkQueue {
+    pub fn new(opts: &CrawlerConfig) -> Self {
+        let headers = [
+            user_agent(opts).map(|v| (USER_AGENT.to_string(), v)),
+            opts.from.clone().map(|v| (FROM.to_string(), v)),
+        ];
+        Self {
+            state: Mutex::default(),
+            queued: Notify::new(),
+            headers: headers.into_iter().flatten().collect(),
+            queue_timeout: QUEUE_TIMEOUT,
+            connect_timeout: Duration::from_secs_f32(opts.connect_timeout_sec.unwrap_or(5.0)),
+            read_timeout: Duration::from_secs_f32(opts.read_timeout_sec.unwrap_or(5.0)),
+            limits: BodyLimits::new(opts),
+            redirects: opts.redirects.clone(),
+            url_filters: opts.url_filters.clone(),
+            token: env::var(TOKEN_ENV).ok(),
+        }
+    }
+
+    /// Queues a request and waits for a worker to download it
+    ///
+    /// Fails if no worker takes the request in time or the worker doesn't respond in time after
+    /// taking the request.
+    pub async fn fetch(
+        &self,
+        auth: &AuthRules,
+        request: RequestBuilder,
+        url: &Url,
+    ) -> Result<(String, ResponseMeta)> {
+        let (_, request) = auth.prepare(url, request).await?;
+        let mut headers = self.headers.clone();
+        headers.retain(|(name, _)| !request.headers().contains_key(name.as_str()));
+        headers.extend(request.headers().iter().map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.to_string(), value)
+        }));
+        let (taken, taken_signal) = oneshot::channel();
+        let (result, response) = oneshot::channel();
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.jobs.push_back(Job {
+                id,
+                method: request.method().to_string(),
+                url: request.url().to_string(),
+                headers,
+                body: request.body().and_then(|b| b.as_bytes()).map(Vec::from),
+                connect_timeout_ms: self.connect_timeout.as_millis() as u64,
+                read_timeout_ms: self.read_timeout.as_millis() as u64,
+                limits: self.limits.clone(),
+                redirects: self.redirects.clone(),
+                url_filters: self.url_filters.clone(),
+            });
+            let taken = Some(taken);
+            state.pending.insert(id, Pending { taken, result });
+            id
+        };
+        self.queued.notify_one();
+
+        if timeout(self.queue_timeout, taken_signal).await.is_err() {
+            let mut state = self.state.lock().unwrap();
+            // job might be taken right after the timeout, then its response is waited for as usual
+            if let Some(idx) = state.jobs.iter().position(|job| job.id == id) {
+                state.jobs.remove(idx);
+                state.pending.remove(&id);
+                return Err(AppError::NoWorkers.into());
+            }
+        }
+        let deadline = self.connect_timeout + self.read_timeout + RESPONSE_MARGIN;
+        match timeout(deadline, response).await {
+            Ok(Ok(JobResult(Ok(page)))) => page.into_response(),
+            Ok(Ok(JobResult(Err(e)))) => Err(AppError::WorkerFailed(e).into()),
+            Ok(Err(_)) | Err(_) => {
+                self.state.lock().unwrap().pending.remove(&id);
+                Err(AppError::WorkerTimeout.into())
+            }
+        }
+    }
+
+    /// Takes the next job, waiting for it for a given time
+    async fn take(&self, wait: Duration) -> Option<Job> {
+        let wait = sleep(wait);
+        tokio::pin!(wait);
+        loop {
+            let notified = self.queued.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(job) = state.jobs.pop_front() {
+                    let taken = state.pending.get_mut(&job.id).and_then(|p| p.taken.take());
+                    if let Some(taken) = taken {
+                        let _ = taken.send(());
+                    }
+                    return Some(job);
+                }
+            }
+            tokio::select! {
+                _ = notified => {}
+                _ = &mut wait => return None,
+            }
+        }
+    }
+
+    /// Passes the result to the crawler, returns `false` if job is unknown or timed out
+    fn complete(&self, id: u64, result: JobResult) -> bool {
+        let pending = self.state.lock().unwrap().pending.remove(&id);
+        pending.is_some_and(|pending| pending.result.send(result).is_ok())
+    }
+
+    /// Starts HTTP server workers connect to, returns the address it's listening on
+    ///
+    /// Fails if the address is not a loopback one and no token is set.
+    pub fn serve(
+        self: Arc<Self>,
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, impl Future<Output = Result<()>>)> {
+        if self.token.is_none() && !addr.ip().is_loopback() {
+            return Err(AppError::UnauthenticatedWorkQueue(addr).into());
+        }
+        let make_service = make_service_fn(move |_| {
+            let queue = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let queue = queue.clone();
+                    async move { Ok::<_, Infallible>(queue.handle(request).await) }
+                }))
+            }
+        });
+        let server = Server::try_bind(&addr)?.serve(make_service);
+        let addr = server.local_addr();
+        info!("Waiting for workers on {}", addr);
+        Ok((addr, async move { Ok(server.await?) }))
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if let Some(token) = &self.token {
+            let expected = format!("Bearer {}", token);
+            let authorization = request.headers().get(AUTHORIZATION);
+            let authorized =
+                authorization.is_some_and(|v| bool::from(v.as_bytes().ct_eq(expected.as_bytes())));
+            if !authorized {
+                return status(StatusCode::UNAUTHORIZED);
+            }
+        }
+        let path = request.uri().path().to_string();
+        let id = path.strip_prefix("/jobs/").unwrap_or_default();
+        match (request.method(), id) {
+            (&Method::POST, "next") => match self.take(POLL_TIMEOUT).await {
+                Some(job) => json(&job),
+                None => status(StatusCode::NO_CONTENT),
+            },
+            (&Method::POST, id) => {
+                let Ok(id) = id.parse() else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+                let body = hyper::body::to_bytes(request.into_body()).await;
+                let Some(result) = body.ok().and_then(|b| serde_json::from_slice(&b).ok()) else {
+                    return status(StatusCode::BAD_REQUEST);
+                };
+                match self.complete(id, result) {
+                    true => status(StatusCode::NO_CONTENT),
+                    false => status(StatusCode::NOT_FOUND),
+                }
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+impl FetchedPage {
+    fn into_response(self) -> Result<(String, ResponseMeta)> {
+        let content_type = self
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE.as_str()))
+            .map(|(_, value)| value.clone());
+        let meta = ResponseMeta {
+            status: self.status,
+            final_url: Url::parse(&self.final_url)?,
+            content_type,
+            headers: self.headers,
+            redirects: self
+                .redirects
+                .iter()
+                .map(|u| Url::parse(u))
+                .collect::<StdResult<_, _>>()?,
+            truncated: self.truncated,
+            timing: self.timing,
+        };
+        Ok((self.content, meta))
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+fn json(value: &impl Serialize) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::new(Body::from(body)),
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Downloads pages from the queue of a crawler at a given URL until stopped
+pub async fn run_worker(queue: Url, threads: usize) -> Result<()> {
+    let client = Client::builder()
+        .timeout(POLL_TIMEOUT + RESPONSE_MARGIN)
+        .build()?;
+    let token = env::var(TOKEN_ENV).ok();
+    let workers = (0..threads).map(|_| {
+        let worker = Worker {
+            client: client.clone(),
+            queue: queue.clone(),
+            token: token.clone(),
+        };
+        tokio::spawn(async move { worker.run().await })
+    });
+    for result in try_join_all(workers).await? {
+        result?;
+    }
+    Ok(())
+}
+
+struct Worker {
+    client: Client,
+    queue: Url,
+    token: Option<String>,
+}
+
+impl Worker {
+    async fn run(&self) -> Result<()> {
+        loop {
+            let job = match self.next_job().await {
+                Ok(Some(job)) => job,
+                Ok(None) => continue,
+                Err(e) if e.is::<AppError>() => return Err(e),
+                Err(e) => {
+                    warn!("Unable to reach work queue: {}", e);
+                    sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            };
+            debug!("Downloading: {}", job.url);
+            let result = execute(&job).await.map_err(|e| format!("{:#}", e));
+            if let Err(e) = self.submit(job.id, JobResult(result)).await {
+                warn!("Unable to submit page to work queue: {}", e);
+            }
+        }
+    }
+
+    async fn next_job(&self) -> Result<Option<Job>> {
+        let response = self.post("jobs/next")?.send().await?;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(None),
+            StatusCode::UNAUTHORIZED => Err(AppError::WorkQueueUnauthorized.into()),
+            _ => Ok(Some(response.error_for_status()?.json().await?)),
+        }
+    }
+
+    async fn submit(&self, id: u64, result: JobResult) -> Result<()> {
+        let request = self.post(&format!("jobs/{}", id))?.json(&result);
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    fn post(&self, path: &str) -> Result<RequestBuilder> {
+        let request = self.client.post(self.queue.join(path)?);
+        Ok(match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+}
+
+/// Sends a job request from the worker
+async fn execute(job: &Job) -> Result<FetchedPage> {
+    let policy = RedirectPolicy::new(job.redirects.as_ref(), job.url_filters.as_ref())?;
+    let (builder, redirects) = redirecting_client(policy);
+    let client = builder
+        .connect_timeout(Duration::from_millis(job.connect_timeout_ms))
+        .timeout(Duration::from_millis(job.read_timeout_ms))
+        .danger_accept_invalid_certs(true)
+        .build()?;
+    let url = Url::parse(&job.url)?;
+    let mut request = client.request(job.method.parse()?, url.clone());
+    for (name, value) in &job.headers {
+        request = request.header(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+    }
+    if let Some(body) = &job.body {
+        request = request.body(body.clone());
+    }
+    let sent_at = Instant::now();
+    let response = request.send().await?;
+    let (content, meta) = read_response(response, &redirects, &url, sent_at, &job.limits).await?;
+    Ok(FetchedPage {
+        status: meta.status,
+        final_url: meta.final_url.to_string(),
+        headers: meta.headers,
+        redirects: meta.redirects.iter().map(Url::to_string).collect(),
+        content,
+        truncated: meta.truncated,
+        timing: meta.timing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(token: Option<&str>) -> Arc<WorkQueue> {
+        Arc::new(WorkQueue {
+            state: Mutex::default(),
+            queued: Notify::new(),
+            headers: vec![("user-agent".into(), "crab".into())],
+            queue_timeout: QUEUE_TIMEOUT,
+            connect_timeout: Duration::from_secs(1),
+            read_timeout: Duration::from_secs(1),
+            limits: BodyLimits::default(),
+            redirects: None,
+            url_filters: None,
+            token: token.map(String::from),
+        })
+    }
+
+    fn page(content: &str) -> FetchedPage {
+        FetchedPage {
+            status: 200,
+            final_url: "http://test.com/final".into(),
+            headers: vec![("content-type".into(), "text/html".into())],
+            redirects: vec!["http://test.com/".into()],
+            content: content.into(),
+            truncated: false,
+            timing: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_are_completed_by_workers() -> Result<()> {
+        let queue = queue(None);
+        let (addr, server) = queue.clone().serve("127.0.0.1:0".parse()?)?;
+        tokio::spawn(server);
+
+        let url = Url::parse("http://test.com/")?;
+        let request = Client::new().get(url.clone());
+        let fetch = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.fetch(&AuthRules::default(), request, &url).await })
+        };
+
+        let client = Client::new();
+        let job: Job = client
+            .post(format!("http://{}/jobs/next", addr))
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(job.url, "http://test.com/");
+        assert!(job.headers.contains(&("user-agent".into(), "crab".into())));
+
+        let response = client
+            .post(format!("http://{}/jobs/{}", addr, job.id))
+            .json(&JobResult(Ok(page("content"))))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let (content, meta) = fetch.await??;
+        assert_eq!(content, "content");
+        assert_eq!(meta.final_url.as_str(), "http://test.com/final");
+        assert_eq!(meta.content_type.as_deref(), Some("text/html"));
+        assert_eq!(meta.redirects.len(), 1);
+
+        // job is completed only once
+        let response = client
+            .post(format!("http://{}/jobs/{}", addr, job.id))
+            .json(&JobResult(Ok(page("content"))))
+            .send()
+            .await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_errors_are_reported() -> Result<()> {
+        let queue = queue(None);
+        let url = Url::parse("http://test.com/")?;
+        let request = Client::new().get(url.clone());
+        let fetch = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.fetch(&AuthRules::default(), request, &url).await })
+        };
+        let job = queue.take(Duration::from_secs(5)).await.unwrap();
+        assert!(queue.take(Duration::ZERO).await.is_none());
+        assert!(queue.complete(job.id, JobResult(Err("timeout".into()))));
+        assert!(fetch.await?.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn token_is_required() -> Result<()> {
+        let queue = queue(Some("secret"));
+        let (addr, server) = queue.serve("127.0.0.1:0".parse()?)?;
+        tokio::spawn(server);
+
+        let client = Client::new();
+        let url = format!("http://{}/jobs/next", addr);
+        let response = client.post(&url).send().await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client.post(&url).bearer_auth("wrong").send().await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn public_queue_requires_token() -> Result<()> {
+        let error = queue(None).serve("0.0.0.0:0".parse()?).err().unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(AppError::UnauthenticatedWorkQueue(_))
+        ));
+        assert!(queue(Some("secret")).serve("0.0.0.0:0".parse()?).is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn requests_not_taken_by_workers_fail() -> Result<()> {
+        let mut queue = WorkQueue::new(&crate::CrabConfig::default_config().crawler);
+        queue.queue_timeout = Duration::from_millis(100);
+        let url = Url::parse("http://test.com/")?;
+        let request = Client::new().get(url.clone());
+        let error = queue
+            .fetch(&AuthRules::default(), request, &url)
+            .await
+            .unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(AppError::NoWorkers)));
+        assert!(queue.take(Duration::ZERO).await.is_none());
+        Ok(())
+    }
+}
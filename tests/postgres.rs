@@ -0,0 +1,731 @@
+//! Flows of `sqlite.rs` run against PostgreSQL database given in `CRAB_TEST_POSTGRES_URL`
+//!
+//! Tests pass without checking anything if the variable is not set. Each test works in its own
+//! schema, which is recreated when the test starts.
+#![cfg(feature = "postgres")]
+
+use chrono::{Duration, Utc};
+use crab::{
+    database::DatabaseUrl,
+    postgres::PgStorage,
+    prelude::*,
+    storage::{
+        CrawlOrder, EncodedContent, HostBackoff, Page, PageStatus, RequestCounter, RequestSpec,
+        RequestTiming, ResponseMeta, SkipReason, Storage,
+    },
+    Link, PageTypeId,
+};
+use futures::StreamExt;
+use sqlx::{Connection, PgConnection};
+use std::env;
+use tokio::test;
+use url::Url;
+
+const URL_VAR: &str = "CRAB_TEST_POSTGRES_URL";
+
+#[test]
+pub async fn write_and_read_pages_to_database() -> Result<()> {
+    let Some(mut storage) = new_storage("write_and_read_pages").await? else {
+        return Ok(());
+    };
+
+    let url = "http://test.com";
+    let new_id = register(&mut storage, url, 1, 0).await?;
+    assert_eq!(new_id, Some(1));
+
+    let pages = storage.list_not_downloaded_pages(10).await?;
+    assert_eq!(pages, [Page::new(1, Url::parse(url)?, 1)]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_downloaded_pages() -> Result<()> {
+    let Some(mut storage) = new_storage("read_downloaded_pages").await? else {
+        return Ok(());
+    };
+
+    let new_id = register(&mut storage, "http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    write_content(&storage, new_id, "<html>", None).await?;
+
+    let pages = storage.read_downloaded_pages().collect::<Vec<_>>().await;
+    assert_eq!(pages.len(), 1);
+    let (page, content) = pages.into_iter().next().unwrap()?;
+    assert_eq!(page.id, new_id);
+    assert_eq!(page.status, PageStatus::Downloaded);
+    assert!(page.downloaded_at.is_some());
+    assert_eq!(content, "<html>");
+
+    Ok(())
+}
+
+#[test]
+pub async fn page_should_be_registered_only_once() -> Result<()> {
+    let Some(mut storage) = new_storage("registered_only_once").await? else {
+        return Ok(());
+    };
+
+    assert_eq!(
+        register(&mut storage, "http://test.com", 1, 0).await?,
+        Some(1)
+    );
+    assert_eq!(register(&mut storage, "http://test.com", 1, 0).await?, None);
+    assert_eq!(register(&mut storage, "http://test.com", 1, 0).await?, None);
+
+    Ok(())
+}
+
+#[test]
+pub async fn skipped_pages_are_not_downloaded() -> Result<()> {
+    let Some(mut storage) = new_storage("skipped_pages").await? else {
+        return Ok(());
+    };
+
+    let skipped_id = storage
+        .register_skipped_link(link("http://test.com/deep", 1), 5, SkipReason::Depth)
+        .await?
+        .unwrap();
+    let page_id = register(&mut storage, "http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    storage.skip_page(page_id, SkipReason::Pattern).await?;
+
+    assert!(storage.list_not_downloaded_pages(10).await?.is_empty());
+
+    let skipped = storage.list_skipped_pages().await?;
+    assert_eq!(skipped.len(), 2);
+    assert_eq!(skipped[0].0.id, skipped_id);
+    assert_eq!(skipped[0].0.status, PageStatus::Skipped);
+    assert_eq!(skipped[0].1, SkipReason::Depth);
+    assert_eq!(skipped[1].0.id, page_id);
+    assert_eq!(skipped[1].1, SkipReason::Pattern);
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_downloaded_pages_as_of() -> Result<()> {
+    let Some(mut storage) = new_storage("read_downloaded_pages_as_of").await? else {
+        return Ok(());
+    };
+
+    let page_id = register(&mut storage, "http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    write_content(&storage, page_id, "<html>1</html>", None).await?;
+    write_content(&storage, page_id, "<html>2</html>", None).await?;
+
+    let before_crawl = Utc::now() - Duration::days(1);
+    let pages = storage.read_downloaded_pages_as_of(before_crawl);
+    assert_eq!(pages.count().await, 0);
+
+    let pages = storage
+        .read_downloaded_pages_as_of(Utc::now())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(pages.len(), 1);
+    let (page, content) = pages.into_iter().next().unwrap()?;
+    assert_eq!(page.id, page_id);
+    assert_eq!(content, "<html>2</html>");
+
+    Ok(())
+}
+
+#[test]
+pub async fn pages_exceeding_max_size_are_skipped() -> Result<()> {
+    let Some(mut storage) = new_storage("pages_exceeding_max_size").await? else {
+        return Ok(());
+    };
+    storage.set_max_page_size(Some(1000));
+
+    let small_id = register(&mut storage, "http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    let large_id = register(&mut storage, "http://test.com/2", 1, 0)
+        .await?
+        .unwrap();
+    write_content(&storage, small_id, "<html></html>", None).await?;
+    // compresses well below the limit, so decompressed size should be checked as well
+    write_content(&storage, large_id, &"a".repeat(10_000), None).await?;
+
+    let pages = storage.read_downloaded_pages().collect::<Vec<_>>().await;
+    assert_eq!(pages.len(), 1);
+    let (page, _) = pages.into_iter().next().unwrap()?;
+    assert_eq!(page.id, small_id);
+    assert_eq!(storage.count_oversized_pages(), 1);
+
+    storage.set_max_page_size(None);
+    assert_eq!(storage.read_downloaded_pages().count().await, 2);
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_downloaded_pages_in_batches() -> Result<()> {
+    let Some(mut storage) = new_storage("read_in_batches").await? else {
+        return Ok(());
+    };
+    for i in 0..5 {
+        let url = format!("http://test.com/{}", i);
+        let page_id = register(&mut storage, &url, 1, 0).await?.unwrap();
+        write_content(&storage, page_id, "<html></html>", None).await?;
+    }
+
+    let batches = storage.read_downloaded_pages_batched(2);
+    let sizes = batches
+        .map(|batch| batch.map(|b| b.len()))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(sizes, vec![2, 2, 1]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn find_page_by_url() -> Result<()> {
+    let Some(mut storage) = new_storage("find_page_by_url").await? else {
+        return Ok(());
+    };
+    let url = Url::parse("http://test.com/page")?;
+    let page_id = register(&mut storage, url.as_str(), 1, 0).await?.unwrap();
+
+    let page = storage.find_page_by_url(&url).await?;
+    assert_eq!(page.map(|p| p.id), Some(page_id));
+
+    let missing = Url::parse("http://test.com/missing")?;
+    assert!(storage.find_page_by_url(&missing).await?.is_none());
+
+    Ok(())
+}
+
+#[test]
+pub async fn register_form_requests() -> Result<()> {
+    let Some(mut storage) = new_storage("register_form_requests").await? else {
+        return Ok(());
+    };
+    let url = "http://test.com/search";
+    let form = |query: &str| Link {
+        request: Some(RequestSpec {
+            method: "POST".into(),
+            form: vec![("q".into(), query.into())],
+            headers: vec![],
+        }),
+        ..link(url, 2)
+    };
+
+    register(&mut storage, url, 1, 0).await?;
+    let first = storage.register_link(form("crab"), 1).await?;
+    let second = storage.register_link(form("lobster"), 1).await?;
+    assert!(first.is_some() && second.is_some());
+    assert_eq!(storage.register_link(form("crab"), 1).await?, None);
+
+    let pages = storage.list_not_downloaded_pages(10).await?;
+    let page = pages.iter().find(|p| Some(p.id) == first).unwrap();
+    assert_eq!(page.request, form("crab").request);
+    let page = storage.find_page_by_url(&Url::parse(url)?).await?.unwrap();
+    assert_eq!(page.request, None);
+
+    Ok(())
+}
+
+#[test]
+pub async fn list_seed_pages() -> Result<()> {
+    let Some(mut storage) = new_storage("list_seed_pages").await? else {
+        return Ok(());
+    };
+    register(&mut storage, "http://test.com/1", 1, 0).await?;
+    register(&mut storage, "http://test.com/2", 2, 1).await?;
+    register(&mut storage, "http://test.com/3", 1, 0).await?;
+
+    let seeds = storage.list_seed_pages().await?;
+    let urls = seeds.iter().map(|p| p.url.as_str()).collect::<Vec<_>>();
+    assert_eq!(urls, ["http://test.com/1", "http://test.com/3"]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn list_downloaded_pages_of_type() -> Result<()> {
+    let Some(mut storage) = new_storage("list_downloaded_pages_of_type").await? else {
+        return Ok(());
+    };
+    for (url, type_id) in [("http://test.com/1", 1), ("http://test.com/2", 2)] {
+        let id = register(&mut storage, url, type_id, 0).await?.unwrap();
+        write_content(&storage, id, "<html>", None).await?;
+    }
+    register(&mut storage, "http://test.com/3", 1, 0).await?;
+
+    let pages = storage.list_downloaded_pages(0, 10, Some(1)).await?;
+    let urls = pages.iter().map(|p| p.url.as_str()).collect::<Vec<_>>();
+    assert_eq!(urls, ["http://test.com/1"]);
+    assert_eq!(storage.list_downloaded_pages(0, 10, None).await?.len(), 2);
+    assert_eq!(storage.list_downloaded_pages(1, 10, None).await?.len(), 1);
+    assert_eq!(storage.count_downloaded_pages(Some(2)).await?, 1);
+    assert_eq!(storage.count_downloaded_pages(None).await?, 2);
+
+    Ok(())
+}
+
+#[test]
+pub async fn write_and_read_response_meta() -> Result<()> {
+    let Some(mut storage) = new_storage("write_and_read_response_meta").await? else {
+        return Ok(());
+    };
+    let page_id = register(&mut storage, "http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    let meta = ResponseMeta {
+        status: 200,
+        final_url: Url::parse("https://test.com/")?,
+        content_type: Some("text/html".into()),
+        headers: vec![("content-type".into(), "text/html".into())],
+        redirects: vec![Url::parse("http://test.com/")?],
+        truncated: true,
+        timing: Some(RequestTiming {
+            ttfb_ms: 120,
+            total_ms: 350,
+            body_bytes: 13,
+        }),
+    };
+    write_content(&storage, page_id, "<html></html>", Some(&meta)).await?;
+
+    assert_eq!(storage.read_page_meta(page_id).await?, Some(meta.clone()));
+    let page = storage.list_downloaded_pages(0, 1, None).await?.remove(0);
+    assert_eq!(page.http_status, Some(200));
+    assert_eq!(page.final_url, Some(meta.final_url));
+
+    Ok(())
+}
+
+#[test]
+pub async fn skip_page_with_response_meta() -> Result<()> {
+    let Some(mut storage) = new_storage("skip_page_with_response_meta").await? else {
+        return Ok(());
+    };
+    let page_id = register(&mut storage, "http://test.com/report.pdf", 1, 0)
+        .await?
+        .unwrap();
+    let meta = ResponseMeta {
+        status: 200,
+        final_url: Url::parse("http://test.com/report.pdf")?,
+        content_type: Some("application/pdf".into()),
+        headers: vec![("content-type".into(), "application/pdf".into())],
+        redirects: vec![],
+        truncated: false,
+        timing: Some(RequestTiming {
+            ttfb_ms: 120,
+            total_ms: 130,
+            body_bytes: 0,
+        }),
+    };
+    storage
+        .skip_page_with_meta(page_id, SkipReason::ContentType, &meta)
+        .await?;
+
+    let skipped = storage.list_skipped_pages().await?;
+    assert_eq!(skipped[0].1, SkipReason::ContentType);
+    let stored = storage.read_page_meta(page_id).await?.unwrap();
+    assert_eq!(stored.content_type.as_deref(), Some("application/pdf"));
+    assert_eq!(stored.headers, meta.headers);
+    assert_eq!(stored.timing, None);
+    assert_eq!(storage.read_downloaded_pages().count().await, 0);
+
+    Ok(())
+}
+
+#[test]
+pub async fn find_page_by_final_url() -> Result<()> {
+    let Some(mut storage) = new_storage("find_page_by_final_url").await? else {
+        return Ok(());
+    };
+    let first = register(&mut storage, "http://test.com/a", 1, 0).await?;
+    let second = register(&mut storage, "http://test.com/b", 1, 0).await?;
+    let (first, second) = (first.unwrap(), second.unwrap());
+    let target = Url::parse("http://test.com/target")?;
+    assert_eq!(storage.find_page_by_final_url(&target, second).await?, None);
+
+    let meta = ResponseMeta {
+        status: 200,
+        final_url: target.clone(),
+        content_type: None,
+        headers: vec![],
+        redirects: vec![Url::parse("http://test.com/a")?],
+        truncated: false,
+        timing: None,
+    };
+    write_content(&storage, first, "<html></html>", Some(&meta)).await?;
+    let page = storage.find_page_by_final_url(&target, second).await?;
+    assert_eq!(page.map(|p| p.id), Some(first));
+    // page itself is not an alias of itself
+    assert_eq!(storage.find_page_by_final_url(&target, first).await?, None);
+
+    // redirect to the URL of another page
+    let url = Url::parse("http://test.com/b")?;
+    let page = storage.find_page_by_final_url(&url, first).await?;
+    assert_eq!(page.map(|p| p.id), Some(second));
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_only_storage_rejects_writes() -> Result<()> {
+    let Some(mut storage) = new_storage("read_only_storage").await? else {
+        return Ok(());
+    };
+    register(&mut storage, "http://test.com", 1, 0).await?;
+
+    let mut read_only = PgStorage::connect(&storage.url, true).await?;
+    assert_eq!(read_only.list_not_downloaded_pages(10).await?.len(), 1);
+    assert!(register(&mut read_only, "http://test.com/other", 1, 0)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+pub async fn migrations_are_applied_once() -> Result<()> {
+    let Some(storage) = new_storage("migrations_are_applied_once").await? else {
+        return Ok(());
+    };
+    PgStorage::connect(&storage.url, false).await?;
+
+    let mut connection = PgConnection::connect(&storage.url.to_string()).await?;
+    let versions: Vec<i32> = sqlx::query_scalar("SELECT version FROM refinery_schema_history")
+        .fetch_all(&mut connection)
+        .await?;
+    assert_eq!(versions, [1]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn leased_pages_are_not_listed_to_other_owners() -> Result<()> {
+    let Some(mut storage) = new_storage("leased_pages").await? else {
+        return Ok(());
+    };
+    for i in 0..3 {
+        register(&mut storage, &format!("http://test.com/{i}"), 1, 0).await?;
+    }
+    let lease = std::time::Duration::from_secs(60);
+
+    let first = storage
+        .lease_not_downloaded_pages(2, "first", lease)
+        .await?;
+    let second = storage
+        .lease_not_downloaded_pages(2, "second", lease)
+        .await?;
+    assert_eq!(ids(&first), [1, 2]);
+    assert_eq!(ids(&second), [3]);
+
+    // owner gets its pages again
+    let first = storage
+        .lease_not_downloaded_pages(10, "first", lease)
+        .await?;
+    assert_eq!(ids(&first), [1, 2]);
+
+    storage.release_leases("first").await?;
+    let second = storage
+        .lease_not_downloaded_pages(10, "second", lease)
+        .await?;
+    assert_eq!(ids(&second), [1, 2, 3]);
+
+    // expired leases are taken over
+    let expired = std::time::Duration::ZERO;
+    storage
+        .lease_not_downloaded_pages(10, "second", expired)
+        .await?;
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let first = storage
+        .lease_not_downloaded_pages(10, "first", lease)
+        .await?;
+    assert_eq!(ids(&first), [1, 2, 3]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn crawl_order() -> Result<()> {
+    let Some(mut storage) = new_storage("crawl_order").await? else {
+        return Ok(());
+    };
+    for (path, depth) in [("a", 0), ("b", 1), ("c", 1), ("d", 2)] {
+        register(&mut storage, &format!("http://test.com/{path}"), 1, depth).await?;
+    }
+    let lease = std::time::Duration::from_secs(60);
+
+    let pages = storage.list_not_downloaded_pages(10).await?;
+    assert_eq!(ids(&pages), [1, 2, 3, 4]);
+
+    storage.set_crawl_order(CrawlOrder::DepthFirst);
+    let pages = storage.list_not_downloaded_pages(10).await?;
+    assert_eq!(ids(&pages), [4, 3, 2, 1]);
+    let pages = storage
+        .lease_not_downloaded_pages(3, "first", lease)
+        .await?;
+    assert_eq!(ids(&pages), [4, 3, 2]);
+
+    storage.set_crawl_order(CrawlOrder::Random);
+    let mut pages = ids(&storage.list_not_downloaded_pages(10).await?);
+    pages.sort();
+    assert_eq!(pages, [1, 2, 3, 4]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn lease_expired_pages() -> Result<()> {
+    let Some(mut storage) = new_storage("lease_expired_pages").await? else {
+        return Ok(());
+    };
+    let expired = register(&mut storage, "http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    let fresh = register(&mut storage, "http://test.com/2", 2, 0)
+        .await?
+        .unwrap();
+    register(&mut storage, "http://test.com/3", 1, 0).await?;
+    write_content(&storage, expired, "", None).await?;
+    write_content(&storage, fresh, "", None).await?;
+
+    let lease = std::time::Duration::from_secs(60);
+    let recrawl_after = [
+        (1, std::time::Duration::ZERO),
+        (2, std::time::Duration::from_secs(3600)),
+    ];
+    let pages = storage
+        .lease_expired_pages(&recrawl_after, 10, "first", lease)
+        .await?;
+    assert_eq!(ids(&pages), [expired]);
+
+    // leased pages are not listed again even for the same owner
+    let pages = storage
+        .lease_expired_pages(&recrawl_after, 10, "first", lease)
+        .await?;
+    assert!(pages.is_empty());
+
+    assert!(storage
+        .lease_expired_pages(&[], 10, "second", lease)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+pub async fn list_canary_pages() -> Result<()> {
+    let Some(mut storage) = new_storage("list_canary_pages").await? else {
+        return Ok(());
+    };
+    register(&mut storage, "http://test.com/deep", 1, 2).await?;
+    let first = register(&mut storage, "http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    register(&mut storage, "http://test.com/2", 1, 0).await?;
+    let downloaded = register(&mut storage, "http://test.com/3", 2, 0)
+        .await?
+        .unwrap();
+    let second = register(&mut storage, "http://test.com/4", 2, 1)
+        .await?
+        .unwrap();
+    write_content(&storage, downloaded, "", None).await?;
+
+    let pages = storage.list_canary_pages().await?;
+    assert_eq!(ids(&pages), [first, second]);
+    Ok(())
+}
+
+#[test]
+pub async fn content_changes() -> Result<()> {
+    let Some(mut storage) = new_storage("content_changes").await? else {
+        return Ok(());
+    };
+    let page_id = register(&mut storage, "http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    register(&mut storage, "http://test.com/other", 1, 0).await?;
+
+    assert!(write_content(&storage, page_id, "first", None).await?);
+    assert!(!write_content(&storage, page_id, "first", None).await?);
+    assert!(write_content(&storage, page_id, "second", None).await?);
+
+    let pages = storage
+        .read_changed_pages(Utc::now() - Duration::hours(1))
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(pages.len(), 1);
+    assert_eq!(pages[0].as_ref().unwrap().1, "second");
+
+    let since = Utc::now() + Duration::hours(1);
+    assert_eq!(storage.read_changed_pages(since).count().await, 0);
+    Ok(())
+}
+
+#[test]
+pub async fn host_backoffs() -> Result<()> {
+    let Some(storage) = new_storage("host_backoffs").await? else {
+        return Ok(());
+    };
+    let mut backoff = HostBackoff {
+        host: "test.com".into(),
+        delay_ms: 1000,
+        rate_limited_at: 1_700_000_000_000,
+        retry_at: 1_700_000_060_000,
+    };
+    storage.write_host_backoff(&backoff).await?;
+    backoff.delay_ms = 2000;
+    storage.write_host_backoff(&backoff).await?;
+    assert_eq!(storage.list_host_backoffs().await?, vec![backoff]);
+
+    storage.delete_host_backoff("test.com").await?;
+    assert!(storage.list_host_backoffs().await?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub async fn request_counters() -> Result<()> {
+    let Some(storage) = new_storage("request_counters").await? else {
+        return Ok(());
+    };
+    let mut counter = RequestCounter {
+        host: "api.test.com".into(),
+        window: "minute".into(),
+        window_start: 60,
+        count: 1,
+    };
+    storage.write_request_counter(&counter).await?;
+    counter.count = 2;
+    storage.write_request_counter(&counter).await?;
+
+    assert_eq!(storage.list_request_counters().await?, vec![counter]);
+    Ok(())
+}
+
+#[test]
+pub async fn mark_duplicate_pages() -> Result<()> {
+    let Some(mut storage) = new_storage("mark_duplicate_pages").await? else {
+        return Ok(());
+    };
+    let mut ids = vec![];
+    for (url, content) in [
+        ("/1", "<p>Crab of the day with fresh lemon</p>"),
+        ("/2", "<p>Lobster of the week with melted butter</p>"),
+        ("/3", "<p>Crab of the day with fresh lemon</p>"),
+    ] {
+        let url = format!("http://test.com{url}");
+        let id = register(&mut storage, &url, 1, 0).await?.unwrap();
+        let encoded = EncodedContent::new(content)?.with_simhash(content)?;
+        storage.write_encoded_content(id, encoded, None).await?;
+        ids.push(id);
+    }
+    let hash = EncodedContent::new("<p>Crab of the day with fresh lemon</p>")?
+        .hash()
+        .to_owned();
+    assert_eq!(
+        storage.find_page_by_content_hash(&hash, ids[2]).await?,
+        Some(ids[0])
+    );
+    assert_eq!(
+        storage.find_page_by_content_hash(&hash, ids[0]).await?,
+        Some(ids[2])
+    );
+
+    storage.set_duplicate_of(ids[2], Some(ids[0])).await?;
+    // duplicates are not considered originals
+    assert_eq!(
+        storage.find_page_by_content_hash(&hash, ids[0]).await?,
+        None
+    );
+    assert_eq!(storage.list_simhashes().await?.len(), 2);
+
+    assert_eq!(read_page_ids(&storage).await?, ids);
+    storage.set_skip_duplicates(true);
+    assert_eq!(read_page_ids(&storage).await?, &ids[..2]);
+
+    storage.set_duplicate_of(ids[2], None).await?;
+    assert_eq!(read_page_ids(&storage).await?, ids);
+    Ok(())
+}
+
+fn link(url: &str, type_id: PageTypeId) -> Link<Url> {
+    Link {
+        url: Url::parse(url).unwrap(),
+        type_id,
+        request: None,
+    }
+}
+
+async fn register(
+    storage: &mut PgStorage,
+    url: &str,
+    type_id: PageTypeId,
+    depth: u16,
+) -> Result<Option<i64>> {
+    storage.register_link(link(url, type_id), depth).await
+}
+
+async fn write_content(
+    storage: &PgStorage,
+    page_id: i64,
+    content: &str,
+    meta: Option<&ResponseMeta>,
+) -> Result<bool> {
+    let content = EncodedContent::new(content)?;
+    storage.write_encoded_content(page_id, content, meta).await
+}
+
+fn ids(pages: &[Page]) -> Vec<i64> {
+    pages.iter().map(|p| p.id).collect()
+}
+
+async fn read_page_ids(storage: &PgStorage) -> Result<Vec<i64>> {
+    let mut ids = vec![];
+    let mut pages = storage.read_downloaded_pages();
+    while let Some(page) = pages.next().await {
+        ids.push(page?.0.id);
+    }
+    Ok(ids)
+}
+
+/// Storage working in a schema of its own along with the URL connecting to the schema
+struct TestStorage {
+    storage: PgStorage,
+    url: DatabaseUrl,
+}
+
+impl std::ops::Deref for TestStorage {
+    type Target = PgStorage;
+
+    fn deref(&self) -> &Self::Target {
+        &self.storage
+    }
+}
+
+impl std::ops::DerefMut for TestStorage {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.storage
+    }
+}
+
+/// Recreates a schema for a test and connects to it, `None` if no database is given
+async fn new_storage(name: &str) -> Result<Option<TestStorage>> {
+    let Ok(url) = env::var(URL_VAR) else {
+        return Ok(None);
+    };
+    let schema = format!("crab_test_{name}");
+    let mut connection = PgConnection::connect(&url).await?;
+    sqlx::query(&format!("DROP SCHEMA IF EXISTS {schema} CASCADE"))
+        .execute(&mut connection)
+        .await?;
+    sqlx::query(&format!("CREATE SCHEMA {schema}"))
+        .execute(&mut connection)
+        .await?;
+
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let url: DatabaseUrl = format!("{url}{separator}options=-csearch_path%3D{schema}").parse()?;
+    let storage = PgStorage::connect(&url, false).await?;
+    Ok(Some(TestStorage { storage, url }))
+}
@@ -1,6 +1,12 @@
+use chrono::{Duration, Utc};
 use crab::{
     prelude::*,
-    storage::{self, Page, PageStatus, Storage},
+    storage::{
+        self, CrawlOrder, EncodedContent, FailureReason, HostBackoff, Page, PageStatus,
+        RequestCounter, RequestSpec, RequestTiming, ResponseMeta, SkipReason, SqliteStorage,
+        Storage,
+    },
+    Link,
 };
 use futures::StreamExt;
 use std::ops::Deref;
@@ -28,13 +34,7 @@ pub async fn write_and_read_pages_to_database() -> Result<()> {
 
     let pages = storage.list_not_downloaded_pages(10).await?;
 
-    let expected_page = Page {
-        id: new_id.unwrap(),
-        url: Url::parse(url)?,
-        type_id,
-        depth: 0,
-        status: PageStatus::NotDownloaded,
-    };
+    let expected_page = Page::new(new_id.unwrap(), Url::parse(url)?, type_id);
     assert_eq!(pages.len(), 1);
     assert_eq!(pages[0], expected_page);
 
@@ -48,7 +48,9 @@ pub async fn read_downloaded_pages() -> Result<()> {
     let url = "http://test.com";
     let expected_content = "<html>";
     let new_id = storage.register_page(url, 1, 0).await?.unwrap();
-    storage.write_page_content(new_id, expected_content).await?;
+    storage
+        .write_page_content(new_id, expected_content, None)
+        .await?;
 
     let mut pages = storage.read_downloaded_pages();
     let Some(row) = pages.next().await else {
@@ -89,7 +91,9 @@ pub async fn write_and_read_page_content() -> Result<()> {
         .await?
         .unwrap();
 
-    storage.write_page_content(page_id, expected_html).await?;
+    storage
+        .write_page_content(page_id, expected_html, None)
+        .await?;
 
     let (html, type_id) = storage
         .read_page_content(page_id)
@@ -100,14 +104,565 @@ pub async fn write_and_read_page_content() -> Result<()> {
 
     let page = storage.read_page(page_id).await?.unwrap();
     assert_eq!(page.status, PageStatus::Downloaded);
+    assert!(page.downloaded_at.is_some());
+
+    Ok(())
+}
+
+#[test]
+pub async fn skipped_pages_are_not_downloaded() -> Result<()> {
+    let mut storage = new_storage().await?;
+
+    let skipped_id = storage
+        .register_skipped_page("http://test.com/deep", 1, 5, SkipReason::Depth)
+        .await?
+        .unwrap();
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    storage.skip_page(page_id, SkipReason::Pattern).await?;
+
+    assert!(storage.list_not_downloaded_pages(10).await?.is_empty());
+
+    let skipped = storage.list_skipped_pages().await?;
+    assert_eq!(skipped.len(), 2);
+    assert_eq!(skipped[0].0.id, skipped_id);
+    assert_eq!(skipped[0].0.status, PageStatus::Skipped);
+    assert_eq!(skipped[0].1, SkipReason::Depth);
+    assert_eq!(skipped[1].0.id, page_id);
+    assert_eq!(skipped[1].1, SkipReason::Pattern);
+
+    storage.reset_page(page_id).await?;
+    assert_eq!(storage.list_not_downloaded_pages(10).await?.len(), 1);
+
+    storage.reset_pages(&[skipped_id]).await?;
+    assert_eq!(storage.list_not_downloaded_pages(10).await?.len(), 2);
+    assert!(storage.list_skipped_pages().await?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_downloaded_pages_as_of() -> Result<()> {
+    let mut storage = new_storage().await?;
+
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    storage
+        .write_page_content(page_id, "<html>1</html>", None)
+        .await?;
+    storage
+        .write_page_content(page_id, "<html>2</html>", None)
+        .await?;
+
+    let before_crawl = Utc::now() - Duration::days(1);
+    let pages = storage.read_downloaded_pages_as_of(before_crawl);
+    assert_eq!(pages.count().await, 0);
+
+    let pages = storage
+        .read_downloaded_pages_as_of(Utc::now())
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(pages.len(), 1);
+    let (page, content) = pages.into_iter().next().unwrap()?;
+    assert_eq!(page.id, page_id);
+    assert_eq!(content, "<html>2</html>");
+
+    Ok(())
+}
+
+#[test]
+pub async fn unchanged_content_is_not_kept_in_history() -> Result<()> {
+    let mut storage = new_storage().await?;
+
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    for content in [
+        "<html>1</html>",
+        "<html>1</html>",
+        "<html>2</html>",
+        "<html>3</html>",
+    ] {
+        storage.write_page_content(page_id, content, None).await?;
+    }
+
+    // Versions 1 and 2 are in the history
+    assert_eq!(storage.prune_history(1).await?, 1);
+    assert_eq!(storage.prune_history(1).await?, 0);
+    assert_eq!(storage.prune_history(0).await?, 1);
+
+    let (content, _) = storage.read_page_content(page_id).await?.unwrap();
+    assert_eq!(content, "<html>3</html>");
+    let pages = storage.read_downloaded_pages_as_of(Utc::now());
+    assert_eq!(pages.count().await, 1);
+
+    Ok(())
+}
+
+#[test]
+pub async fn pages_exceeding_max_size_are_skipped() -> Result<()> {
+    let mut storage = new_storage().await?;
+    storage.set_max_page_size(Some(1000));
+
+    let small_id = storage
+        .register_page("http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    let large_id = storage
+        .register_page("http://test.com/2", 1, 0)
+        .await?
+        .unwrap();
+    storage
+        .write_page_content(small_id, "<html></html>", None)
+        .await?;
+    // compresses well below the limit, so decompressed size should be checked as well
+    let large_content = "a".repeat(10_000);
+    storage
+        .write_page_content(large_id, &large_content, None)
+        .await?;
+
+    let pages = storage.read_downloaded_pages().collect::<Vec<_>>().await;
+    assert_eq!(pages.len(), 1);
+    let (page, _) = pages.into_iter().next().unwrap()?;
+    assert_eq!(page.id, small_id);
+    assert_eq!(storage.count_oversized_pages(), 1);
+
+    storage.set_max_page_size(None);
+    assert_eq!(storage.read_downloaded_pages().count().await, 2);
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_downloaded_pages_in_batches() -> Result<()> {
+    let mut storage = new_storage().await?;
+    for i in 0..5 {
+        let url = format!("http://test.com/{}", i);
+        let page_id = storage.register_page(url.as_str(), 1, 0).await?.unwrap();
+        storage
+            .write_page_content(page_id, "<html></html>", None)
+            .await?;
+    }
+
+    let batches = storage.read_downloaded_pages_batched(2);
+    let sizes = batches
+        .map(|batch| batch.map(|b| b.len()))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(sizes, vec![2, 2, 1]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn find_page_by_url() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let url = Url::parse("http://test.com/page")?;
+    let page_id = storage.register_page(url.as_str(), 1, 0).await?.unwrap();
+
+    let page = storage.find_page_by_url(&url).await?;
+    assert_eq!(page.map(|p| p.id), Some(page_id));
+
+    let missing = Url::parse("http://test.com/missing")?;
+    assert!(storage.find_page_by_url(&missing).await?.is_none());
+
+    Ok(())
+}
+
+#[test]
+pub async fn register_form_requests() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let url = Url::parse("http://test.com/search")?;
+    let link = |query: &str| Link {
+        url: url.clone(),
+        type_id: 2,
+        request: Some(RequestSpec {
+            method: "POST".into(),
+            form: vec![("q".into(), query.into())],
+            headers: vec![],
+        }),
+    };
+
+    storage.register_page(url.as_str(), 1, 0).await?;
+    let first = storage.register_link(link("crab"), 1).await?;
+    let second = storage.register_link(link("lobster"), 1).await?;
+    assert!(first.is_some() && second.is_some());
+    assert_eq!(storage.register_link(link("crab"), 1).await?, None);
+
+    let page = storage.read_page(first.unwrap()).await?.unwrap();
+    assert_eq!(page.request, link("crab").request);
+    let page = storage.find_page_by_url(&url).await?.unwrap();
+    assert_eq!(page.request, None);
+
+    Ok(())
+}
+
+#[test]
+pub async fn tag_pages() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let first = storage
+        .register_page("http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    let second = storage
+        .register_page("http://test.com/2", 1, 0)
+        .await?
+        .unwrap();
+
+    storage.tag_page(first, "wrong-price").await?;
+    storage.tag_page(first, "checked").await?;
+    storage.tag_page(first, "checked").await?;
+    storage.tag_page(second, "checked").await?;
+    assert_eq!(
+        storage.list_page_tags(first).await?,
+        ["checked", "wrong-price"]
+    );
+
+    let tagged = storage.list_tagged_pages("checked").await?;
+    assert_eq!(
+        tagged.iter().map(|p| p.id).collect::<Vec<_>>(),
+        [first, second]
+    );
+
+    storage.untag_page(first).await?;
+    assert!(storage.list_page_tags(first).await?.is_empty());
+    assert_eq!(storage.list_tagged_pages("wrong-price").await?, []);
+
+    Ok(())
+}
+
+#[test]
+pub async fn list_seed_pages() -> Result<()> {
+    let mut storage = new_storage().await?;
+    storage.register_page("http://test.com/1", 1, 0).await?;
+    storage.register_page("http://test.com/2", 2, 1).await?;
+    storage.register_page("http://test.com/3", 1, 0).await?;
+
+    let seeds = storage.list_seed_pages().await?;
+    let urls = seeds.iter().map(|p| p.url.as_str()).collect::<Vec<_>>();
+    assert_eq!(urls, ["http://test.com/1", "http://test.com/3"]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn list_downloaded_pages_of_type() -> Result<()> {
+    let mut storage = new_storage().await?;
+    for (url, type_id) in [("http://test.com/1", 1), ("http://test.com/2", 2)] {
+        let id = storage.register_page(url, type_id, 0).await?.unwrap();
+        storage.write_page_content(id, "<html>", None).await?;
+    }
+    storage.register_page("http://test.com/3", 1, 0).await?;
+
+    let pages = storage.list_downloaded_pages(0, 10, Some(1)).await?;
+    let urls = pages.iter().map(|p| p.url.as_str()).collect::<Vec<_>>();
+    assert_eq!(urls, ["http://test.com/1"]);
+    assert_eq!(storage.list_downloaded_pages(0, 10, None).await?.len(), 2);
+    assert_eq!(storage.list_downloaded_pages(1, 10, None).await?.len(), 1);
+    assert_eq!(storage.count_downloaded_pages(Some(2)).await?, 1);
+    assert_eq!(storage.count_downloaded_pages(None).await?, 2);
+
+    Ok(())
+}
+
+#[test]
+pub async fn sharded_page_content() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let database = storage.1.path().join("sqlite.db");
+    storage.open_shards(&database, 2).await?;
+    assert!(storage.1.path().join("sqlite.shard1.sqlite").exists());
+
+    let first_id = storage
+        .register_page("http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    let second_id = storage
+        .register_page("http://test.com/2", 1, 0)
+        .await?
+        .unwrap();
+    storage
+        .write_page_content(first_id, "<html>1</html>", None)
+        .await?;
+    storage
+        .write_page_content(second_id, "<html>2</html>", None)
+        .await?;
+    storage
+        .write_page_content(second_id, "<html>3</html>", None)
+        .await?;
+
+    let (content, _) = storage.read_page_content(second_id).await?.unwrap();
+    assert_eq!(content, "<html>3</html>");
+
+    let mut pages = storage
+        .read_downloaded_pages()
+        .map(|row| row.map(|(page, content)| (page.id, content)))
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    pages.sort();
+    assert_eq!(
+        pages,
+        vec![
+            (first_id, "<html>1</html>".to_string()),
+            (second_id, "<html>3</html>".to_string())
+        ]
+    );
+
+    let before_crawl = Utc::now() - Duration::days(1);
+    let pages = storage.read_downloaded_pages_as_of(before_crawl);
+    assert_eq!(pages.count().await, 0);
+    let pages = storage.read_downloaded_pages_as_of(Utc::now());
+    assert_eq!(pages.count().await, 2);
+
+    assert_eq!(storage.prune_history(0).await?, 1);
+    let (content, _) = storage.read_page_content(second_id).await?.unwrap();
+    assert_eq!(content, "<html>3</html>");
+
+    Ok(())
+}
+
+#[test]
+pub async fn move_page() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com/old", 1, 0)
+        .await?
+        .unwrap();
+    let other_id = storage
+        .register_page("http://test.com/other", 1, 0)
+        .await?
+        .unwrap();
+    storage
+        .write_page_content(page_id, "<html>1</html>", None)
+        .await?;
+
+    let url = Url::parse("http://test.com/new")?;
+    storage.move_page(page_id, &url).await?;
+    assert_eq!(storage.find_page_by_url(&url).await?.unwrap().id, page_id);
+    let (content, _) = storage.read_page_content(page_id).await?.unwrap();
+    assert_eq!(content, "<html>1</html>");
+
+    let taken = storage
+        .move_page(page_id, &Url::parse("http://test.com/other")?)
+        .await;
+    assert!(matches!(
+        taken.unwrap_err().downcast_ref::<AppError>(),
+        Some(AppError::PageUrlTaken(id)) if *id == other_id
+    ));
+    Ok(())
+}
+
+#[test]
+pub async fn merge_pages() -> Result<()> {
+    merge_pages_with_shards(None).await
+}
+
+#[test]
+pub async fn merge_sharded_pages() -> Result<()> {
+    merge_pages_with_shards(Some(2)).await
+}
+
+async fn merge_pages_with_shards(shards: Option<u16>) -> Result<()> {
+    let mut storage = new_storage().await?;
+    if let Some(count) = shards {
+        let database = storage.1.path().join("sqlite.db");
+        storage.open_shards(&database, count).await?;
+    }
+    let old_id = storage
+        .register_page("http://test.com/old", 1, 0)
+        .await?
+        .unwrap();
+    let new_id = storage
+        .register_page("http://test.com/new", 2, 0)
+        .await?
+        .unwrap();
+    storage
+        .write_page_content(old_id, "<html>old</html>", None)
+        .await?;
+    storage.tag_page(old_id, "migrated").await?;
+    storage.tag_page(new_id, "new").await?;
+
+    // not yet downloaded page gets content of the merged one
+    storage.merge_pages(old_id, new_id).await?;
+    assert!(storage.read_page(old_id).await?.is_none());
+    let page = storage.read_page(new_id).await?.unwrap();
+    assert_eq!(page.url.as_str(), "http://test.com/new");
+    assert_eq!(page.type_id, 2);
+    assert_eq!(page.status, PageStatus::Downloaded);
+    let (content, _) = storage.read_page_content(new_id).await?.unwrap();
+    assert_eq!(content, "<html>old</html>");
+    assert_eq!(
+        storage.list_page_tags(new_id).await?,
+        vec!["migrated", "new"]
+    );
+
+    // page which was never downloaded doesn't replace the content
+    let other_id = storage
+        .register_page("http://test.com/other", 1, 0)
+        .await?
+        .unwrap();
+    storage.merge_pages(other_id, new_id).await?;
+    let (content, _) = storage.read_page_content(new_id).await?.unwrap();
+    assert_eq!(content, "<html>old</html>");
+    assert!(storage.read_page(other_id).await?.is_none());
+
+    assert!(storage.merge_pages(new_id, new_id).await.is_err());
+    assert!(storage.merge_pages(100, new_id).await.is_err());
+    Ok(())
+}
+
+#[test]
+pub async fn snapshot_database() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    storage
+        .write_page_content(page_id, "<html></html>", None)
+        .await?;
+
+    let snapshot_path = storage.1.path().join("snapshot.db");
+    storage.snapshot(&snapshot_path).await?;
+
+    let snapshot = SqliteStorage::new(&snapshot_path).await?;
+    assert_eq!(snapshot.count_all_pages().await?, 1);
+    let (content, _) = snapshot.read_page_content(page_id).await?.unwrap();
+    assert_eq!(content, "<html></html>");
+
+    Ok(())
+}
+
+#[test]
+pub async fn write_and_read_response_meta() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    let meta = ResponseMeta {
+        status: 200,
+        final_url: Url::parse("https://test.com/")?,
+        content_type: Some("text/html".into()),
+        headers: vec![("content-type".into(), "text/html".into())],
+        redirects: vec![Url::parse("http://test.com/")?],
+        truncated: true,
+        timing: Some(RequestTiming {
+            ttfb_ms: 120,
+            total_ms: 350,
+            body_bytes: 13,
+        }),
+    };
+    storage
+        .write_page_content(page_id, "<html></html>", Some(&meta))
+        .await?;
+
+    assert_eq!(storage.read_page_meta(page_id).await?, Some(meta.clone()));
+    let page = storage.read_page(page_id).await?.unwrap();
+    assert_eq!(page.http_status, Some(200));
+    assert_eq!(page.final_url, Some(meta.final_url));
 
     Ok(())
 }
 
-struct TempStorage(Storage, TempDir);
+#[test]
+pub async fn skip_page_with_response_meta() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com/report.pdf", 1, 0)
+        .await?
+        .unwrap();
+    let meta = ResponseMeta {
+        status: 200,
+        final_url: Url::parse("http://test.com/report.pdf")?,
+        content_type: Some("application/pdf".into()),
+        headers: vec![("content-type".into(), "application/pdf".into())],
+        redirects: vec![],
+        truncated: false,
+        timing: Some(RequestTiming {
+            ttfb_ms: 120,
+            total_ms: 130,
+            body_bytes: 0,
+        }),
+    };
+    storage
+        .skip_page_with_meta(page_id, SkipReason::ContentType, &meta)
+        .await?;
+
+    let skipped = storage.list_skipped_pages().await?;
+    assert_eq!(skipped[0].1, SkipReason::ContentType);
+    let stored = storage.read_page_meta(page_id).await?.unwrap();
+    assert_eq!(stored.content_type.as_deref(), Some("application/pdf"));
+    assert_eq!(stored.headers, meta.headers);
+    assert_eq!(stored.timing, None);
+    assert!(storage.read_page_content(page_id).await?.is_none());
+
+    Ok(())
+}
+
+#[test]
+pub async fn find_page_by_final_url() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let first = storage.register_page("http://test.com/a", 1, 0).await?;
+    let second = storage.register_page("http://test.com/b", 1, 0).await?;
+    let (first, second) = (first.unwrap(), second.unwrap());
+    let target = Url::parse("http://test.com/target")?;
+    assert_eq!(storage.find_page_by_final_url(&target, second).await?, None);
+
+    let meta = ResponseMeta {
+        status: 200,
+        final_url: target.clone(),
+        content_type: None,
+        headers: vec![],
+        redirects: vec![Url::parse("http://test.com/a")?],
+        truncated: false,
+        timing: None,
+    };
+    storage
+        .write_page_content(first, "<html></html>", Some(&meta))
+        .await?;
+    let page = storage.find_page_by_final_url(&target, second).await?;
+    assert_eq!(page.map(|p| p.id), Some(first));
+    // page itself is not an alias of itself
+    assert_eq!(storage.find_page_by_final_url(&target, first).await?, None);
+
+    // redirect to the URL of another page
+    let url = Url::parse("http://test.com/b")?;
+    let page = storage.find_page_by_final_url(&url, first).await?;
+    assert_eq!(page.map(|p| p.id), Some(second));
+
+    Ok(())
+}
+
+#[test]
+pub async fn read_only_storage_rejects_writes() -> Result<()> {
+    let mut storage = new_storage().await?;
+    storage.register_page("http://test.com", 1, 0).await?;
+
+    let database = storage.1.path().join("sqlite.db");
+    let mut read_only = SqliteStorage::new_read_only(&database).await?;
+    assert_eq!(read_only.list_pages().await?.len(), 1);
+    assert!(read_only
+        .register_page("http://test.com/other", 1, 0)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+/// Storage backed by a temporary directory which is removed on drop
+struct TempStorage(SqliteStorage, #[allow(dead_code)] TempDir);
 
 impl Deref for TempStorage {
-    type Target = Storage;
+    type Target = SqliteStorage;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -120,12 +675,396 @@ impl DerefMut for TempStorage {
     }
 }
 
+#[test]
+pub async fn leased_pages_are_not_listed_to_other_owners() -> Result<()> {
+    let mut storage = new_storage().await?;
+    for i in 0..3 {
+        storage
+            .register_page(format!("http://test.com/{i}").as_str(), 1, 0)
+            .await?;
+    }
+    let lease = std::time::Duration::from_secs(60);
+
+    let first = storage
+        .lease_not_downloaded_pages(2, "first", lease)
+        .await?;
+    let second = storage
+        .lease_not_downloaded_pages(2, "second", lease)
+        .await?;
+    let ids = |pages: &[Page]| pages.iter().map(|p| p.id).collect::<Vec<_>>();
+    assert_eq!(ids(&first), [1, 2]);
+    assert_eq!(ids(&second), [3]);
+
+    // owner gets its pages again
+    let first = storage
+        .lease_not_downloaded_pages(10, "first", lease)
+        .await?;
+    assert_eq!(ids(&first), [1, 2]);
+
+    storage.release_leases("first").await?;
+    let second = storage
+        .lease_not_downloaded_pages(10, "second", lease)
+        .await?;
+    assert_eq!(ids(&second), [1, 2, 3]);
+
+    // expired leases are taken over
+    let expired = std::time::Duration::ZERO;
+    storage
+        .lease_not_downloaded_pages(10, "second", expired)
+        .await?;
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let first = storage
+        .lease_not_downloaded_pages(10, "first", lease)
+        .await?;
+    assert_eq!(ids(&first), [1, 2, 3]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn crawl_order() -> Result<()> {
+    let mut storage = new_storage().await?;
+    for (path, depth) in [("a", 0), ("b", 1), ("c", 1), ("d", 2)] {
+        storage
+            .register_page(format!("http://test.com/{path}").as_str(), 1, depth)
+            .await?;
+    }
+    let lease = std::time::Duration::from_secs(60);
+    let ids = |pages: &[Page]| pages.iter().map(|p| p.id).collect::<Vec<_>>();
+
+    let pages = storage.list_not_downloaded_pages(10).await?;
+    assert_eq!(ids(&pages), [1, 2, 3, 4]);
+
+    storage.set_crawl_order(CrawlOrder::DepthFirst);
+    let pages = storage.list_not_downloaded_pages(10).await?;
+    assert_eq!(ids(&pages), [4, 3, 2, 1]);
+    let pages = storage
+        .lease_not_downloaded_pages(3, "first", lease)
+        .await?;
+    assert_eq!(ids(&pages), [4, 3, 2]);
+
+    storage.set_crawl_order(CrawlOrder::Random);
+    let mut pages = ids(&storage.list_not_downloaded_pages(10).await?);
+    pages.sort();
+    assert_eq!(pages, [1, 2, 3, 4]);
+
+    Ok(())
+}
+
+#[test]
+pub async fn lease_expired_pages() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let expired = storage
+        .register_page("http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    let fresh = storage
+        .register_page("http://test.com/2", 2, 0)
+        .await?
+        .unwrap();
+    storage.register_page("http://test.com/3", 1, 0).await?;
+    storage.write_page_content(expired, "", None).await?;
+    storage.write_page_content(fresh, "", None).await?;
+
+    let lease = std::time::Duration::from_secs(60);
+    let recrawl_after = [
+        (1, std::time::Duration::ZERO),
+        (2, std::time::Duration::from_secs(3600)),
+    ];
+    let pages = storage
+        .lease_expired_pages(&recrawl_after, 10, "first", lease)
+        .await?;
+    assert_eq!(pages.iter().map(|p| p.id).collect::<Vec<_>>(), [expired]);
+
+    // leased pages are not listed again even for the same owner
+    let pages = storage
+        .lease_expired_pages(&recrawl_after, 10, "first", lease)
+        .await?;
+    assert!(pages.is_empty());
+
+    assert!(storage
+        .lease_expired_pages(&[], 10, "second", lease)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[test]
+pub async fn list_canary_pages() -> Result<()> {
+    let mut storage = new_storage().await?;
+    storage.register_page("http://test.com/deep", 1, 2).await?;
+    let first = storage
+        .register_page("http://test.com/1", 1, 0)
+        .await?
+        .unwrap();
+    storage.register_page("http://test.com/2", 1, 0).await?;
+    let downloaded = storage
+        .register_page("http://test.com/3", 2, 0)
+        .await?
+        .unwrap();
+    let second = storage
+        .register_page("http://test.com/4", 2, 1)
+        .await?
+        .unwrap();
+    storage.write_page_content(downloaded, "", None).await?;
+
+    let pages = storage.list_canary_pages().await?;
+    assert_eq!(
+        pages.iter().map(|p| p.id).collect::<Vec<_>>(),
+        [first, second]
+    );
+    Ok(())
+}
+
+#[test]
+pub async fn pending_migrations() -> Result<()> {
+    let storage = new_storage().await?;
+    assert_eq!(
+        storage::pending_migrations(storage.1.path().join("sqlite.db"))?,
+        0
+    );
+
+    let empty = storage.1.path().join("empty.db");
+    File::create(&empty)?;
+    assert!(storage::pending_migrations(&empty)? > 0);
+    storage::migrate(&empty)?;
+    assert_eq!(storage::pending_migrations(&empty)?, 0);
+    Ok(())
+}
+
+#[test]
+pub async fn content_changes() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    storage.register_page("http://test.com/other", 1, 0).await?;
+
+    assert!(storage.write_page_content(page_id, "first", None).await?);
+    assert!(!storage.write_page_content(page_id, "first", None).await?);
+    assert!(storage.write_page_content(page_id, "second", None).await?);
+
+    let changed = storage
+        .list_changed_pages(Utc::now() - Duration::hours(1))
+        .await?;
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].0.id, page_id);
+
+    let pages = storage
+        .read_changed_pages(Utc::now() - Duration::hours(1))
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(pages.len(), 1);
+    assert_eq!(pages[0].as_ref().unwrap().1, "second");
+
+    let since = Utc::now() + Duration::hours(1);
+    assert!(storage.list_changed_pages(since).await?.is_empty());
+    assert_eq!(storage.read_changed_pages(since).count().await, 0);
+    Ok(())
+}
+
+#[test]
+pub async fn failed_downloads() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com", 1, 0)
+        .await?
+        .unwrap();
+    storage.register_page("http://test.com/other", 1, 0).await?;
+    assert!(storage.list_failed_downloads().await?.is_empty());
+
+    storage
+        .record_failure(page_id, FailureReason::Timeout, "operation timed out")
+        .await?;
+    storage
+        .record_failure(page_id, FailureReason::HttpStatus, "HTTP 403 Forbidden")
+        .await?;
+    let failures = storage.list_failed_downloads().await?;
+    assert_eq!(failures.len(), 1);
+    let (page, failure) = &failures[0];
+    assert_eq!(page.id, page_id);
+    assert_eq!(failure.reason, FailureReason::HttpStatus);
+    assert_eq!(failure.message, "HTTP 403 Forbidden");
+
+    // failure is cleared once page is downloaded
+    storage.write_page_content(page_id, "", None).await?;
+    assert!(storage.list_failed_downloads().await?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub async fn host_backoffs() -> Result<()> {
+    let storage = new_storage().await?;
+    let mut backoff = HostBackoff {
+        host: "test.com".into(),
+        delay_ms: 1000,
+        rate_limited_at: 1_700_000_000_000,
+        retry_at: 1_700_000_060_000,
+    };
+    storage.write_host_backoff(&backoff).await?;
+    backoff.delay_ms = 2000;
+    storage.write_host_backoff(&backoff).await?;
+    assert_eq!(storage.list_host_backoffs().await?, vec![backoff]);
+
+    storage.delete_host_backoff("test.com").await?;
+    assert!(storage.list_host_backoffs().await?.is_empty());
+    Ok(())
+}
+
+#[test]
+pub async fn request_counters() -> Result<()> {
+    let storage = new_storage().await?;
+    let mut counter = RequestCounter {
+        host: "api.test.com".into(),
+        window: "minute".into(),
+        window_start: 60,
+        count: 1,
+    };
+    storage.write_request_counter(&counter).await?;
+    counter.count = 2;
+    storage.write_request_counter(&counter).await?;
+
+    assert_eq!(storage.list_request_counters().await?, vec![counter]);
+    Ok(())
+}
+
+#[test]
+pub async fn change_page_type() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let page_id = storage
+        .register_page("http://test.com/item/1", 1, 0)
+        .await?
+        .unwrap();
+    storage
+        .register_page("http://test.com/item/2", 1, 0)
+        .await?;
+    storage.register_page("http://test.com/list", 1, 0).await?;
+
+    assert!(storage.set_page_type(page_id, 3).await?);
+    assert_eq!(storage.read_page(page_id).await?.unwrap().type_id, 3);
+    assert!(!storage.set_page_type(100, 3).await?);
+
+    // page which already has the type is not counted
+    assert_eq!(storage.set_page_type_by_url("/item/", 3).await?, 1);
+    let types = storage
+        .list_pages()
+        .await?
+        .iter()
+        .map(|p| p.type_id)
+        .collect::<Vec<_>>();
+    assert_eq!(types, vec![3, 3, 1]);
+    Ok(())
+}
+
+#[test]
+pub async fn mark_duplicate_pages() -> Result<()> {
+    let mut storage = new_storage().await?;
+    let mut ids = vec![];
+    for (url, content) in [
+        ("/1", "<p>Item</p>"),
+        ("/2", "<p>Other</p>"),
+        ("/3", "<p>Item</p>"),
+    ] {
+        let id = storage
+            .register_page(format!("http://test.com{url}").as_str(), 1, 0)
+            .await?
+            .unwrap();
+        storage.write_page_content(id, content, None).await?;
+        ids.push(id);
+    }
+    let hash = EncodedContent::new("<p>Item</p>")?.hash().to_owned();
+    assert_eq!(
+        storage.find_page_by_content_hash(&hash, ids[2]).await?,
+        Some(ids[0])
+    );
+    assert_eq!(
+        storage.find_page_by_content_hash(&hash, ids[0]).await?,
+        Some(ids[2])
+    );
+
+    storage.set_duplicate_of(ids[2], Some(ids[0])).await?;
+    // duplicates are not considered originals
+    assert_eq!(
+        storage.find_page_by_content_hash(&hash, ids[0]).await?,
+        None
+    );
+    let duplicates = storage.list_duplicate_pages().await?;
+    assert_eq!(duplicates.len(), 1);
+    assert_eq!((duplicates[0].0.id, duplicates[0].1), (ids[2], ids[0]));
+
+    assert_eq!(read_page_ids(&storage).await?, ids);
+    storage.set_skip_duplicates(true);
+    assert_eq!(read_page_ids(&storage).await?, &ids[..2]);
+
+    storage.set_duplicate_of(ids[2], None).await?;
+    assert_eq!(read_page_ids(&storage).await?, ids);
+    Ok(())
+}
+
+async fn read_page_ids(storage: &SqliteStorage) -> Result<Vec<i64>> {
+    let mut ids = vec![];
+    let mut pages = storage.read_downloaded_pages();
+    while let Some(page) = pages.next().await {
+        ids.push(page?.0.id);
+    }
+    Ok(ids)
+}
+
 async fn new_storage() -> Result<TempStorage> {
     let temp_dir = tempdir()?;
     let file_name = temp_dir.path().join("sqlite.db");
-    let file_name = file_name.to_str().unwrap();
-    File::create(file_name)?;
-    storage::migrate(file_name)?;
-    let storage = Storage::new(&file_name).await?;
+    File::create(&file_name)?;
+    storage::migrate(&file_name)?;
+    let storage = SqliteStorage::new(&file_name).await?;
     Ok(TempStorage(storage, temp_dir))
 }
+
+#[cfg(unix)]
+#[test]
+async fn non_utf8_paths_are_reported_as_errors() -> Result<()> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let storage = new_storage().await?;
+    let dir = storage.1.path().join(OsStr::from_bytes(b"crab-\xff"));
+    std::fs::create_dir(&dir)?;
+    let file_name = dir.join("sqlite.db");
+    File::create(&file_name)?;
+
+    // SQLite accepts only UTF-8 file names
+    assert!(SqliteStorage::new(&file_name).await.is_err());
+    assert!(SqliteStorage::new_read_only(&file_name).await.is_err());
+    assert!(storage.snapshot(&dir.join("snapshot.db")).await.is_err());
+    Ok(())
+}
+
+#[test]
+async fn writes_are_retried_while_database_is_locked() -> Result<()> {
+    use sqlx::sqlite::SqliteConnectOptions;
+    use std::time::Duration;
+
+    let temp_dir = tempdir()?;
+    let file_name = temp_dir.path().join("sqlite.db");
+    File::create(&file_name)?;
+    storage::migrate(&file_name)?;
+    // No busy timeout, so lock contention is reported to the storage right away
+    let options = SqliteConnectOptions::new()
+        .filename(&file_name)
+        .busy_timeout(Duration::ZERO);
+    let mut storage = SqliteStorage::connect(options, false).await?;
+
+    let lock = rusqlite::Connection::open(&file_name)?;
+    lock.execute_batch("BEGIN EXCLUSIVE")?;
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        lock.execute_batch("COMMIT")
+    });
+
+    storage.register_page("http://test.com", 1, 0).await?;
+    release.join().unwrap()?;
+
+    let reader = rusqlite::Connection::open(&file_name)?;
+    let pages: i64 = reader.query_row("SELECT COUNT(*) FROM pages", [], |row| row.get(0))?;
+    assert_eq!(pages, 1);
+    Ok(())
+}